@@ -198,6 +198,11 @@ pub enum BlobFormat {
     Raw,
     /// A sequence of BLAKE3 hashes
     HashSeq,
+    /// A sequence of BLAKE3 hashes whose children can be sequences themselves
+    ///
+    /// The first child is a metadata blob that contains the formats of the other
+    /// children, so a DAG can be traversed without knowing what it contains.
+    Dag,
 }
 
 impl From<BlobFormat> for u64 {
@@ -205,6 +210,7 @@ impl From<BlobFormat> for u64 {
         match value {
             BlobFormat::Raw => 0,
             BlobFormat::HashSeq => 1,
+            BlobFormat::Dag => 2,
         }
     }
 }
@@ -219,6 +225,11 @@ impl BlobFormat {
     pub const fn is_hash_seq(&self) -> bool {
         matches!(self, BlobFormat::HashSeq)
     }
+
+    /// Is DAG format
+    pub const fn is_dag(&self) -> bool {
+        matches!(self, BlobFormat::Dag)
+    }
 }
 
 /// A hash and format pair
@@ -326,6 +337,14 @@ impl HashAndFormat {
             format: BlobFormat::HashSeq,
         }
     }
+
+    /// Create a new hash and format pair, using the DAG format.
+    pub fn dag(hash: Hash) -> Self {
+        Self {
+            hash,
+            format: BlobFormat::Dag,
+        }
+    }
 }
 
 impl fmt::Display for HashAndFormat {
@@ -340,6 +359,10 @@ impl fmt::Display for HashAndFormat {
                 slice[0] = b's';
                 write!(f, "{}", std::str::from_utf8(&slice).unwrap())
             }
+            BlobFormat::Dag => {
+                slice[0] = b'd';
+                write!(f, "{}", std::str::from_utf8(&slice).unwrap())
+            }
         }
     }
 }
@@ -359,6 +382,10 @@ impl FromStr for HashAndFormat {
                 hex::decode_to_slice(&s[1..], &mut hash)?;
                 Ok(Self::hash_seq(hash.into()))
             }
+            65 if s[0].to_ascii_lowercase() == b'd' => {
+                hex::decode_to_slice(&s[1..], &mut hash)?;
+                Ok(Self::dag(hash.into()))
+            }
             _ => anyhow::bail!("invalid hash and format"),
         }
     }
@@ -546,6 +573,10 @@ mod tests {
         let expected = HashAndFormat::hash_seq(hash);
         let actual = expected.to_string().parse::<HashAndFormat>().unwrap();
         assert_eq!(expected, actual);
+
+        let expected = HashAndFormat::dag(hash);
+        let actual = expected.to_string().parse::<HashAndFormat>().unwrap();
+        assert_eq!(expected, actual);
     }
 
     #[test]
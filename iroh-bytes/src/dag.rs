@@ -0,0 +1,162 @@
+//! Nested hash sequences, stored in [`BlobFormat::Dag`].
+//!
+//! A DAG node is a hash sequence whose first child is a metadata blob. The metadata
+//! blob starts with a postcard encoded header that contains the format of each of the
+//! other children. Everything after the header is application data.
+//!
+//! Children in [`BlobFormat::HashSeq`] or [`BlobFormat::Dag`] are followed when getting
+//! or garbage collecting a DAG, so a DAG can be traversed without knowing what the
+//! application data means.
+use anyhow::Context;
+use bytes::Bytes;
+use iroh_io::AsyncSliceReaderExt;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    hashseq::HashSeq,
+    store::{Map, MapEntry, Store},
+    util::TempTag,
+    BlobFormat, Hash, HashAndFormat,
+};
+
+/// A single node of a DAG.
+#[derive(Clone, Debug, PartialEq, Eq, Default)]
+pub struct DagNode {
+    /// Links to the children of this node
+    children: Vec<HashAndFormat>,
+    /// Application data, stored in the metadata blob after the header
+    data: Bytes,
+}
+
+/// The start of the metadata blob of a DAG node.
+///
+/// This is the wire format for the metadata blob, followed by the application data.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+struct DagMeta {
+    header: [u8; 6], // Must contain "DagV0."
+    formats: Vec<BlobFormat>,
+}
+
+impl DagNode {
+    /// The header for the DAG format.
+    ///
+    /// This is the start of the metadata blob.
+    pub const HEADER: &'static [u8; 6] = b"DagV0.";
+
+    /// Create a new DAG node from its children and application data.
+    pub fn new(children: impl IntoIterator<Item = HashAndFormat>, data: impl Into<Bytes>) -> Self {
+        Self {
+            children: children.into_iter().collect(),
+            data: data.into(),
+        }
+    }
+
+    /// The children of this node.
+    pub fn children(&self) -> &[HashAndFormat] {
+        &self.children
+    }
+
+    /// The application data of this node.
+    pub fn data(&self) -> &Bytes {
+        &self.data
+    }
+
+    /// Convert the node to an iterator of blobs, with the last being the
+    /// root blob.
+    ///
+    /// To persist the node, write all the blobs to storage, and use the
+    /// hash of the last blob as the node hash, in [`BlobFormat::Dag`].
+    pub fn to_blobs(&self) -> impl Iterator<Item = Bytes> {
+        let meta_bytes = self.meta_bytes();
+        let meta_hash = Hash::new(&meta_bytes);
+        let links = std::iter::once(meta_hash)
+            .chain(self.children.iter().map(|child| child.hash))
+            .collect::<HashSeq>();
+        [meta_bytes, links.into_inner()].into_iter()
+    }
+
+    /// Create a node from the links of the hash sequence, including the link to the
+    /// metadata blob, and the content of the metadata blob.
+    pub fn from_parts(mut links: HashSeq, meta: &[u8]) -> anyhow::Result<Self> {
+        links.pop_front().context("meta link not found")?;
+        let (meta, data) = postcard::take_from_bytes::<DagMeta>(meta)?;
+        anyhow::ensure!(
+            meta.header == *Self::HEADER,
+            "expected header {:?}, got {:?}",
+            Self::HEADER,
+            meta.header
+        );
+        anyhow::ensure!(
+            meta.formats.len() == links.len(),
+            "formats and links length mismatch"
+        );
+        let children = links
+            .into_iter()
+            .zip(meta.formats)
+            .map(|(hash, format)| HashAndFormat { hash, format })
+            .collect();
+        Ok(Self {
+            children,
+            data: Bytes::copy_from_slice(data),
+        })
+    }
+
+    /// Load a node from a store given its hash.
+    ///
+    /// This requires the links and the metadata blob of the node to be complete.
+    /// It does not require that any children are stored in the store.
+    pub async fn load<D: Map>(db: &D, root: &Hash) -> anyhow::Result<Self> {
+        let links_entry = db.get(root).await?.context("links not found")?;
+        anyhow::ensure!(links_entry.is_complete(), "links not complete");
+        let links_bytes = links_entry.data_reader().await?.read_to_end().await?;
+        let links = HashSeq::try_from(links_bytes)?;
+        let meta_hash = links.get(0).context("meta hash not found")?;
+        let meta_entry = db.get(&meta_hash).await?.context("meta not found")?;
+        anyhow::ensure!(meta_entry.is_complete(), "meta not complete");
+        let meta_bytes = meta_entry.data_reader().await?.read_to_end().await?;
+        Self::from_parts(links, &meta_bytes)
+    }
+
+    /// Store a node in a store. Returns the hash of the node as a [`TempTag`].
+    ///
+    /// The children are expected to be stored separately.
+    pub async fn store<D: Store>(&self, db: &D) -> anyhow::Result<TempTag> {
+        let meta_tag = db.import_bytes(self.meta_bytes(), BlobFormat::Raw).await?;
+        let links = std::iter::once(*meta_tag.hash())
+            .chain(self.children.iter().map(|child| child.hash))
+            .collect::<HashSeq>();
+        let links_tag = db.import_bytes(links.into(), BlobFormat::Dag).await?;
+        Ok(links_tag)
+    }
+
+    fn meta_bytes(&self) -> Bytes {
+        let meta = DagMeta {
+            header: *Self::HEADER,
+            formats: self.children.iter().map(|child| child.format).collect(),
+        };
+        let mut meta_bytes = postcard::to_stdvec(&meta).expect("serializing to vec can not fail");
+        meta_bytes.extend_from_slice(&self.data);
+        meta_bytes.into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip_dag_node() {
+        let node = DagNode::new(
+            [
+                HashAndFormat::raw(Hash::new("file")),
+                HashAndFormat::dag(Hash::new("dir")),
+            ],
+            &b"application data"[..],
+        );
+        let blobs = node.to_blobs().collect::<Vec<_>>();
+        let links = HashSeq::try_from(blobs[1].clone()).unwrap();
+        assert_eq!(links.get(0), Some(Hash::new(&blobs[0])));
+        let actual = DagNode::from_parts(links, &blobs[0]).unwrap();
+        assert_eq!(node, actual);
+    }
+}
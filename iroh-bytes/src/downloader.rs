@@ -16,6 +16,8 @@
 //!    to wait for the new connection to be established if necessary.
 //! 3. Once a request is ready to be sent after a delay (initial or for a retry), the preferred
 //!    node is used if available. The request is now considered active.
+//! 4. While a request is active, other connected provider nodes with free capacity join it. The
+//!    download is then split into pieces, which are fetched from all of these nodes at once.
 //!
 //! Concurrency is limited in different ways:
 //! - *Total number of active request:* This is a way to prevent a self DoS by overwhelming our own
@@ -25,6 +27,7 @@
 //!   strictly needed since it's likely they will be useful soon again.
 //! - *Requests per node*: to avoid overwhelming nodes with requests, the number of concurrent
 //!   requests to a single node is also limited.
+//! - *Nodes per request*: the number of nodes a single download is split across.
 
 use std::{
     collections::{hash_map::Entry, HashMap, HashSet},
@@ -58,6 +61,7 @@ use crate::{
 mod get;
 mod invariants;
 mod progress;
+mod swarm;
 mod test;
 
 use self::progress::{BroadcastProgressSender, ProgressSubscriber, ProgressTracker};
@@ -106,6 +110,10 @@ pub trait Getter {
     /// Type of connections the Getter requires to perform a download.
     type Connection;
     /// Return a future that performs the download using the given connection.
+    ///
+    /// The same download can be performed over several connections to different nodes at the
+    /// same time. The getter is expected to split the work between them, and each future should
+    /// only resolve successfully once the entire download is complete.
     fn get(
         &mut self,
         kind: DownloadKind,
@@ -125,6 +133,10 @@ pub struct ConcurrencyLimits {
     pub max_open_connections: usize,
     /// Maximum number of nodes to dial concurrently for a single request.
     pub max_concurrent_dials_per_hash: usize,
+    /// Maximum number of nodes a single download is split across.
+    ///
+    /// Setting this to `1` downloads everything from a single node.
+    pub max_nodes_per_download: usize,
 }
 
 impl Default for ConcurrencyLimits {
@@ -135,6 +147,7 @@ impl Default for ConcurrencyLimits {
             max_concurrent_requests_per_node: 4,
             max_open_connections: 25,
             max_concurrent_dials_per_hash: 5,
+            max_nodes_per_download: 4,
         }
     }
 }
@@ -164,6 +177,11 @@ impl ConcurrencyLimits {
     fn at_dials_per_hash_capacity(&self, concurrent_dials: usize) -> bool {
         concurrent_dials >= self.max_concurrent_dials_per_hash
     }
+
+    /// Checks if the maximum number of nodes for a single download has been reached.
+    fn at_nodes_per_download_capacity(&self, nodes: usize) -> bool {
+        nodes >= self.max_nodes_per_download
+    }
 }
 
 /// Configuration for retry behavior of the [`Downloader`].
@@ -349,9 +367,7 @@ impl Downloader {
         let dialer = iroh_net::dialer::Dialer::new(endpoint);
 
         let create_future = move || {
-            let getter = get::IoGetter {
-                store: store.clone(),
-            };
+            let getter = get::IoGetter::new(store.clone());
 
             let service = Service::new(
                 store,
@@ -459,13 +475,25 @@ struct RequestInfo {
 /// Information about a request in progress.
 #[derive(derive_more::Debug)]
 struct ActiveRequestInfo {
-    /// Token used to cancel the future doing the request.
+    /// Token used to cancel the futures doing the request.
     #[debug(skip)]
     cancellation: CancellationToken,
-    /// Peer doing this request attempt.
-    node: NodeId,
+    /// Nodes doing this request attempt.
+    nodes: HashSet<NodeId>,
+    /// Progress sender shared by the transfers from all nodes.
+    #[debug(skip)]
+    progress_sender: BroadcastProgressSender,
     /// Temporary tag to protect the partial blob from being garbage collected.
     temp_tag: TempTag,
+    /// Accumulated stats, set once a transfer completed the download.
+    stats: Option<Stats>,
+}
+
+impl ActiveRequestInfo {
+    /// Whether the download is complete or cancelled, and only waits for transfers to finish.
+    fn is_finishing(&self) -> bool {
+        self.stats.is_some() || self.cancellation.is_cancelled()
+    }
 }
 
 #[derive(Debug, Default)]
@@ -560,8 +588,8 @@ struct Service<G: Getter, D: Dialer, DB: Store> {
     requests: HashMap<DownloadKind, RequestInfo>,
     /// State of running downloads.
     active_requests: HashMap<DownloadKind, ActiveRequestInfo>,
-    /// Tasks for currently running downloads.
-    in_progress_downloads: JoinSet<(DownloadKind, InternalDownloadResult)>,
+    /// Tasks for currently running transfers, one per download and node.
+    in_progress_downloads: JoinSet<(DownloadKind, NodeId, InternalDownloadResult)>,
     /// Progress tracker
     progress_tracker: ProgressTracker,
     /// The [`Store`] where tags are saved after a download completes.
@@ -614,9 +642,9 @@ impl<DB: Store, G: Getter<Connection = D::Connection>, D: Dialer> Service<G, D,
                 }
                 Some(res) = self.in_progress_downloads.join_next(), if !self.in_progress_downloads.is_empty() => {
                     match res {
-                        Ok((kind, result)) => {
-                            trace!(%kind, "tick: transfer completed");
-                            self.on_download_completed(kind, result).await;
+                        Ok((kind, node, result)) => {
+                            trace!(%kind, node=%node.fmt_short(), "tick: transfer completed");
+                            self.on_download_completed(kind, node, result).await;
                         }
                         Err(err) => {
                             warn!(?err, "transfer task panicked");
@@ -770,12 +798,14 @@ impl<DB: Store, G: Getter<Connection = D::Connection>, D: Dialer> Service<G, D,
 
         if request_info.intents.is_empty() {
             occupied_entry.remove();
-            if let Entry::Occupied(occupied_entry) = self.active_requests.entry(kind) {
-                occupied_entry.remove().cancellation.cancel();
+            if let Some(active_request_info) = self.active_requests.get(&kind) {
+                // the transfers will finish with `FailureAction::AllIntentsDropped`, and the
+                // request is cleaned up once all of them are done.
+                active_request_info.cancellation.cancel();
             } else {
                 self.queue.remove(&kind);
+                self.remove_hash_if_not_queued(&kind.hash());
             }
-            self.remove_hash_if_not_queued(&kind.hash());
         }
     }
 
@@ -799,17 +829,19 @@ impl<DB: Store, G: Getter<Connection = D::Connection>, D: Dialer> Service<G, D,
         }
     }
 
-    async fn on_download_completed(&mut self, kind: DownloadKind, result: InternalDownloadResult) {
-        // first remove the request
+    async fn on_download_completed(
+        &mut self,
+        kind: DownloadKind,
+        node: NodeId,
+        result: InternalDownloadResult,
+    ) {
+        // first remove the node from the request
         let active_request_info = self
             .active_requests
-            .remove(&kind)
+            .get_mut(&kind)
             .expect("request was active");
-
-        // get general request info
-        let request_info = self.requests.remove(&kind).expect("request was active");
-
-        let ActiveRequestInfo { node, temp_tag, .. } = active_request_info;
+        let removed = active_request_info.nodes.remove(&node);
+        debug_assert!(removed, "node was active for the request");
 
         // get node info
         let node_info = self
@@ -828,8 +860,18 @@ impl<DB: Store, G: Getter<Connection = D::Connection>, D: Dialer> Service<G, D,
         };
 
         match &result {
-            Ok(_) => {
+            Ok(stats) => {
                 debug!(%kind, node=%node.fmt_short(), "download successful");
+                // the download is complete, stop the transfers from other nodes
+                active_request_info.cancellation.cancel();
+                active_request_info.stats = Some(match active_request_info.stats.take() {
+                    None => stats.clone(),
+                    Some(prev) => Stats {
+                        bytes_written: prev.bytes_written + stats.bytes_written,
+                        bytes_read: prev.bytes_read + stats.bytes_read,
+                        elapsed: prev.elapsed.max(stats.elapsed),
+                    },
+                });
                 // clear retry state if operation was successful
                 self.retry_node_state.remove(&node);
             }
@@ -859,16 +901,32 @@ impl<DB: Store, G: Getter<Connection = D::Connection>, D: Dialer> Service<G, D,
             }
         };
 
+        // wait for the transfers from the other nodes to finish
+        if !self.active_requests[&kind].nodes.is_empty() {
+            return;
+        }
+        let ActiveRequestInfo {
+            temp_tag, stats, ..
+        } = self
+            .active_requests
+            .remove(&kind)
+            .expect("request was active");
+
+        // get general request info
+        let Some(request_info) = self.requests.remove(&kind) else {
+            // all intents were dropped while the download was running
+            drop(temp_tag);
+            self.progress_tracker.remove(&kind);
+            self.remove_hash_if_not_queued(&kind.hash());
+            return;
+        };
+
         // we finalize the download if either the download was successful,
-        // or if it should never proceed because all intents were dropped,
         // or if we don't have any candidates to proceed with anymore.
-        let finalize = match &result {
-            Ok(_) | Err(FailureAction::AllIntentsDropped) => true,
-            _ => !self.providers.has_candidates(&kind.hash()),
-        };
+        let finalize = stats.is_some() || !self.providers.has_candidates(&kind.hash());
 
         if finalize {
-            let result = result.map_err(|_| DownloadError::DownloadFailed);
+            let result = stats.ok_or(DownloadError::DownloadFailed);
             if result.is_ok() {
                 request_info.tags.apply(&self.db, kind.0).await.ok();
             }
@@ -970,6 +1028,60 @@ impl<DB: Store, G: Getter<Connection = D::Connection>, D: Dialer> Service<G, D,
                 }
             }
         }
+        self.join_swarms();
+    }
+
+    /// Add connected provider nodes with free capacity to running downloads.
+    ///
+    /// Downloads only ever grow by nodes we are already connected to, so this never dials. Nodes
+    /// are added until [`ConcurrencyLimits::max_nodes_per_download`] is reached for the
+    /// download, or until the total or per-node request limits are reached.
+    fn join_swarms(&mut self) {
+        if self.concurrency_limits.max_nodes_per_download <= 1 {
+            return;
+        }
+        let mut joins = Vec::new();
+        let mut in_progress = self.in_progress_downloads.len();
+        let mut added: HashMap<NodeId, usize> = HashMap::default();
+        for (kind, info) in self.active_requests.iter() {
+            if info.is_finishing() {
+                continue;
+            }
+            let mut nodes = info.nodes.len();
+            for node in self.providers.get_candidates(&kind.hash()) {
+                if self.concurrency_limits.at_requests_capacity(in_progress) {
+                    break;
+                }
+                if self
+                    .concurrency_limits
+                    .at_nodes_per_download_capacity(nodes)
+                {
+                    break;
+                }
+                if info.nodes.contains(node) {
+                    continue;
+                }
+                let Some(node_info) = self.connected_nodes.get(node) else {
+                    continue;
+                };
+                let active_requests =
+                    node_info.active_requests() + added.get(node).copied().unwrap_or_default();
+                if self
+                    .concurrency_limits
+                    .node_at_request_capacity(active_requests)
+                {
+                    continue;
+                }
+                *added.entry(*node).or_default() += 1;
+                joins.push((*kind, *node));
+                nodes += 1;
+                in_progress += 1;
+            }
+        }
+        for (kind, node) in joins {
+            debug!(%kind, node=%node.fmt_short(), "join transfer");
+            self.start_download(kind, node);
+        }
     }
 
     /// Drop the connection to a node and insert it into the the retry queue.
@@ -1001,7 +1113,7 @@ impl<DB: Store, G: Getter<Connection = D::Connection>, D: Dialer> Service<G, D,
         // completes.
         if self
             .concurrency_limits
-            .at_requests_capacity(self.active_requests.len())
+            .at_requests_capacity(self.in_progress_downloads.len())
         {
             return NextStep::Wait;
         };
@@ -1115,22 +1227,35 @@ impl<DB: Store, G: Getter<Connection = D::Connection>, D: Dialer> Service<G, D,
     /// Panics if hash is not in self.requests or node is not in self.nodes.
     fn start_download(&mut self, kind: DownloadKind, node: NodeId) {
         let node_info = self.connected_nodes.get_mut(&node).expect("node exists");
-        let request_info = self.requests.get(&kind).expect("hash exists");
-
-        // create a progress sender and subscribe all intents to the progress sender
-        let subscribers = request_info
-            .intents
-            .values()
-            .flat_map(|state| state.on_progress.clone());
-        let progress_sender = self.progress_tracker.track(kind, subscribers);
-
-        // create the active request state
-        let cancellation = CancellationToken::new();
-        let temp_tag = self.db.temp_tag(kind.0);
-        let state = ActiveRequestInfo {
-            cancellation: cancellation.clone(),
-            node,
-            temp_tag,
+        let (cancellation, progress_sender) = match self.active_requests.get_mut(&kind) {
+            // the download is already running, join it with another node
+            Some(state) => {
+                state.nodes.insert(node);
+                (state.cancellation.clone(), state.progress_sender.clone())
+            }
+            None => {
+                let request_info = self.requests.get(&kind).expect("hash exists");
+
+                // create a progress sender and subscribe all intents to the progress sender
+                let subscribers = request_info
+                    .intents
+                    .values()
+                    .flat_map(|state| state.on_progress.clone());
+                let progress_sender = self.progress_tracker.track(kind, subscribers);
+
+                // create the active request state
+                let cancellation = CancellationToken::new();
+                let temp_tag = self.db.temp_tag(kind.0);
+                let state = ActiveRequestInfo {
+                    cancellation: cancellation.clone(),
+                    nodes: HashSet::from([node]),
+                    progress_sender: progress_sender.clone(),
+                    temp_tag,
+                    stats: None,
+                };
+                self.active_requests.insert(kind, state);
+                (cancellation, progress_sender)
+            }
         };
         let conn = node_info.conn.clone();
         let get_fut = self.getter.get(kind, conn, progress_sender);
@@ -1147,7 +1272,7 @@ impl<DB: Store, G: Getter<Connection = D::Connection>, D: Dialer> Service<G, D,
             };
             trace!("transfer finished");
 
-            (kind, res)
+            (kind, node, res)
         }
         .instrument(error_span!("transfer", %kind, node=%node.fmt_short()));
         node_info.state = match &node_info.state {
@@ -1161,7 +1286,6 @@ impl<DB: Store, G: Getter<Connection = D::Connection>, D: Dialer> Service<G, D,
                 }
             }
         };
-        self.active_requests.insert(kind, state);
         self.in_progress_downloads.spawn_local(fut);
     }
 
@@ -1454,7 +1578,7 @@ impl Queue {
 }
 
 impl Dialer for iroh_net::dialer::Dialer {
-    type Connection = quinn::Connection;
+    type Connection = iroh_net::pool::PooledConnection;
 
     fn queue_dial(&mut self, node_id: NodeId) {
         self.queue_dial(node_id, crate::protocol::ALPN)
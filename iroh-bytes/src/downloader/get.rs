@@ -1,5 +1,10 @@
 //! [`Getter`] implementation that performs requests over [`quinn::Connection`]s.
 
+use std::{
+    collections::HashMap,
+    sync::{Arc, Weak},
+};
+
 use crate::{
     get::{db::get_to_db, error::GetError},
     store::Store,
@@ -7,11 +12,14 @@ use crate::{
 use futures::FutureExt;
 #[cfg(feature = "metrics")]
 use iroh_metrics::{inc, inc_by};
+use iroh_net::pool::PooledConnection;
 
 #[cfg(feature = "metrics")]
 use crate::metrics::Metrics;
 
-use super::{progress::BroadcastProgressSender, DownloadKind, FailureAction, GetFut, Getter};
+use super::{
+    progress::BroadcastProgressSender, swarm::Swarm, DownloadKind, FailureAction, GetFut, Getter,
+};
 
 impl From<GetError> for FailureAction {
     fn from(e: GetError) -> Self {
@@ -28,12 +36,37 @@ impl From<GetError> for FailureAction {
 }
 
 /// [`Getter`] implementation that performs requests over [`quinn::Connection`]s.
+///
+/// Transfers for the same [`DownloadKind`] that run at the same time join the same [`Swarm`],
+/// which splits the download between them.
 pub(crate) struct IoGetter<S: Store> {
     pub store: S,
+    /// Swarms of the downloads that currently have running transfers.
+    pub swarms: HashMap<DownloadKind, Weak<Swarm<S>>>,
+}
+
+impl<S: Store> IoGetter<S> {
+    pub fn new(store: S) -> Self {
+        Self {
+            store,
+            swarms: Default::default(),
+        }
+    }
+
+    /// Get the swarm for a download, creating it if no transfer for it is running.
+    fn swarm(&mut self, kind: DownloadKind) -> Arc<Swarm<S>> {
+        self.swarms.retain(|_, swarm| swarm.strong_count() > 0);
+        if let Some(swarm) = self.swarms.get(&kind).and_then(Weak::upgrade) {
+            return swarm;
+        }
+        let swarm = Arc::new(Swarm::new(self.store.clone(), kind));
+        self.swarms.insert(kind, Arc::downgrade(&swarm));
+        swarm
+    }
 }
 
 impl<S: Store> Getter for IoGetter<S> {
-    type Connection = quinn::Connection;
+    type Connection = PooledConnection;
 
     fn get(
         &mut self,
@@ -41,10 +74,26 @@ impl<S: Store> Getter for IoGetter<S> {
         conn: Self::Connection,
         progress_sender: BroadcastProgressSender,
     ) -> GetFut {
+        // DAGs are fetched level by level from a single node, the other formats are split
+        // between all transfers of the same download
+        let swarm = (!kind.format().is_dag()).then(|| self.swarm(kind));
         let store = self.store.clone();
         let fut = async move {
-            let get_conn = || async move { Ok(conn) };
-            let res = get_to_db(&store, get_conn, &kind.hash_and_format(), progress_sender).await;
+            let connection = conn.connection().clone();
+            let res = match swarm {
+                Some(swarm) => swarm.run(connection, progress_sender).await,
+                None => {
+                    get_to_db(
+                        &store,
+                        || async move { Ok(connection) },
+                        &kind.hash_and_format(),
+                        progress_sender,
+                    )
+                    .await
+                }
+            };
+            // keep the connection in use in the pool until the transfer is done
+            drop(conn);
             match res {
                 Ok(stats) => {
                     #[cfg(feature = "metrics")]
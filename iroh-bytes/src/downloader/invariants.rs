@@ -24,6 +24,7 @@ impl<G: Getter<Connection = D::Connection>, D: Dialer, S: Store> Service<G, D, S
             max_concurrent_requests_per_node,
             max_open_connections,
             max_concurrent_dials_per_hash,
+            max_nodes_per_download,
         } = &self.concurrency_limits;
 
         // check the total number of active requests to ensure it stays within the limit
@@ -53,6 +54,14 @@ impl<G: Getter<Connection = D::Connection>, D: Dialer, S: Store> Service<G, D, S
             )
         }
 
+        // check that downloads are not split across more nodes than allowed
+        for (kind, info) in self.active_requests.iter() {
+            assert!(
+                info.nodes.len() <= *max_nodes_per_download,
+                "max_nodes_per_download exceeded for {kind}"
+            )
+        }
+
         // check that we do not dial more nodes than allowed for the next pending hashes
         if let Some(kind) = self.queue.front() {
             let hash = kind.hash();
@@ -78,7 +87,10 @@ impl<G: Getter<Connection = D::Connection>, D: Dialer, S: Store> Service<G, D, S
         // number of requests
         assert_eq!(
             self.in_progress_downloads.len(),
-            self.active_requests.len(),
+            self.active_requests
+                .values()
+                .map(|info| info.nodes.len())
+                .sum::<usize>(),
             "active_requests and in_progress_downloads are out of sync"
         );
         // check that the count of requests per peer matches the number of requests that have that
@@ -87,7 +99,9 @@ impl<G: Getter<Connection = D::Connection>, D: Dialer, S: Store> Service<G, D, S
             HashMap::with_capacity(self.connected_nodes.len());
         for req_info in self.active_requests.values() {
             // nothing like some classic word count
-            *real_count.entry(req_info.node).or_default() += 1;
+            for node in req_info.nodes.iter() {
+                *real_count.entry(*node).or_default() += 1;
+            }
         }
         for (peer, info) in self.connected_nodes.iter() {
             assert_eq!(
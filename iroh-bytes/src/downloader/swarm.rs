@@ -0,0 +1,793 @@
+//! Splitting a single download across several provider nodes.
+//!
+//! All transfers the [`super::Service`] starts for the same [`DownloadKind`] share a [`Swarm`].
+//! The first transfer to join the swarm creates a [`Plan`]: it requests only the last chunk of
+//! every missing blob, which gives us the verified sizes, and splits the remaining missing ranges
+//! into pieces of at most [`PIECE_CHUNKS`] chunks. This way even the data of small blobs is
+//! spread across nodes, instead of being fetched from the first node as part of the plan.
+//!
+//! Afterwards each transfer repeatedly claims unclaimed pieces, requests them from its node and
+//! writes them into the same store entries. Once there are no unclaimed pieces left, transfers
+//! that are done with their own pieces re-request pieces which are still in flight on another
+//! node. This way work is rebalanced away from slow nodes towards fast ones.
+//!
+//! A blob is marked as complete once all of its pieces have been received.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use anyhow::anyhow;
+use bao_tree::{ChunkNum, ChunkRanges};
+use parking_lot::Mutex;
+use range_collections::range_set::RangeSetRange;
+use tokio::sync::{Notify, OnceCell};
+use tracing::{debug, trace};
+
+use crate::{
+    get::{
+        db::{valid_ranges, BlobId, DownloadProgress},
+        error::GetError,
+        fsm::{self, AtBlobHeader, AtEndBlob, ConnectedNext, EndBlobNext},
+        Stats,
+    },
+    hashseq::parse_hash_seq,
+    protocol::{GetRequest, RangeSpec, RangeSpecSeq},
+    store::{BaoBatchWriter, FallibleProgressBatchWriter, MapEntry, MapEntryMut, Store},
+    util::progress::{IdGenerator, ProgressSender},
+    BlobFormat, Hash,
+};
+
+use super::{progress::BroadcastProgressSender, DownloadKind};
+
+/// Maximum size of a piece in chunks (4 MiB).
+///
+/// This must be a multiple of the chunk group size, so that pieces do not overlap after the
+/// provider rounded them up to chunk groups.
+const PIECE_CHUNKS: u64 = 4096;
+
+/// State shared by all transfers of a single download.
+#[derive(derive_more::Debug)]
+pub(super) struct Swarm<S: Store> {
+    #[debug(skip)]
+    db: S,
+    kind: DownloadKind,
+    /// The plan is created by the first transfer that manages to talk to its node.
+    #[debug(skip)]
+    plan: OnceCell<Plan<S::EntryMut>>,
+    /// Notified whenever a piece is completed or released.
+    #[debug(skip)]
+    notify: Notify,
+    /// Id for the next transfer joining the swarm.
+    next_worker: AtomicUsize,
+}
+
+impl<S: Store> Swarm<S> {
+    pub fn new(db: S, kind: DownloadKind) -> Self {
+        Self {
+            db,
+            kind,
+            plan: OnceCell::new(),
+            notify: Notify::new(),
+            next_worker: AtomicUsize::new(0),
+        }
+    }
+
+    /// Take part in the download using the given connection.
+    ///
+    /// Returns once all pieces of the download have been received, by this transfer or by
+    /// others.
+    pub async fn run(
+        self: Arc<Self>,
+        conn: quinn::Connection,
+        progress: BroadcastProgressSender,
+    ) -> Result<Stats, GetError> {
+        let worker = self.next_worker.fetch_add(1, Ordering::SeqCst);
+        let mut stats = Stats::default();
+        let plan = self
+            .plan
+            .get_or_try_init(|| self.make_plan(&conn, &progress, &mut stats))
+            .await?;
+        loop {
+            // register for notifications before looking at the pieces, to not miss any
+            let notified = self.notify.notified();
+            let claimed = match plan.claim(worker) {
+                Claim::Pieces(claimed) => claimed,
+                Claim::Wait => {
+                    trace!(worker, "all pieces in flight, waiting");
+                    notified.await;
+                    continue;
+                }
+                Claim::Finished => break,
+            };
+            let request = plan.request(self.kind.hash(), &claimed);
+            trace!(worker, ?claimed, "requesting pieces");
+            let mut entries = plan.entries.clone();
+            let res = fetch(
+                &self.db,
+                conn.clone(),
+                request,
+                Some(plan.children.clone()),
+                &mut entries,
+                &progress,
+            )
+            .await;
+            match res {
+                Ok(fetched) => {
+                    add_stats(&mut stats, fetched.stats);
+                    let completed = plan.complete(worker, &claimed);
+                    let res = self.insert_complete(plan, completed).await;
+                    plan.pieces.lock().completing -= 1;
+                    self.notify.notify_waiters();
+                    res?;
+                }
+                Err(err) => {
+                    plan.release(worker, &claimed);
+                    self.notify.notify_waiters();
+                    return Err(err);
+                }
+            }
+        }
+        Ok(stats)
+    }
+
+    /// Mark blobs for which all pieces have been received as complete.
+    async fn insert_complete(
+        &self,
+        plan: &Plan<S::EntryMut>,
+        offsets: Vec<u64>,
+    ) -> Result<(), GetError> {
+        for offset in offsets {
+            let hash = plan.hash_at(self.kind.hash(), offset);
+            if let Some(entry) = plan.entries.get(&hash) {
+                debug!(%hash, "all pieces received");
+                self.db.insert_complete(entry.clone()).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Create the plan for this download.
+    ///
+    /// This requests the last chunk of every blob that is not yet complete, so that the sizes of
+    /// all blobs are known and verified.
+    async fn make_plan(
+        &self,
+        conn: &quinn::Connection,
+        progress: &BroadcastProgressSender,
+        stats: &mut Stats,
+    ) -> Result<Plan<S::EntryMut>, GetError> {
+        let root = self.kind.hash();
+        let head = ChunkRanges::from(ChunkNum(u64::MAX)..);
+        let mut entries = HashMap::new();
+        // missing ranges for each offset, before the head request
+        let mut missing = BTreeMap::new();
+        let (request, children) = match self.kind.format() {
+            BlobFormat::Dag => {
+                return Err(GetError::BadRequest(anyhow!(
+                    "DAGs are not downloaded in swarms"
+                )))
+            }
+            BlobFormat::Raw => {
+                let ranges = match self.local_info(root, BlobId::Root, progress).await? {
+                    LocalInfo::Complete => return Ok(Plan::empty(vec![])),
+                    LocalInfo::Partial(entry, ranges) => {
+                        entries.insert(root, entry);
+                        ranges
+                    }
+                    LocalInfo::Missing => ChunkRanges::all(),
+                };
+                // always request the last chunk, even if we have it, to learn the size
+                let request = RangeSpecSeq::from_ranges([head.clone()]);
+                missing.insert(0, ranges);
+                (GetRequest::new(root, request), Some(vec![]))
+            }
+            BlobFormat::HashSeq => match self.db.get_mut(&root).await? {
+                Some(entry) if entry.is_complete() => {
+                    progress
+                        .send(DownloadProgress::FoundLocal {
+                            child: BlobId::Root,
+                            hash: root,
+                            size: entry.size(),
+                            valid_ranges: RangeSpec::all(),
+                        })
+                        .await?;
+                    let children = read_children(entry, progress).await?;
+                    let mut ranges = vec![ChunkRanges::empty()];
+                    for (i, hash) in children.iter().enumerate() {
+                        let offset = i as u64 + 1;
+                        let child = match self
+                            .local_info(*hash, BlobId::from_offset(offset), progress)
+                            .await?
+                        {
+                            LocalInfo::Complete => ChunkRanges::empty(),
+                            LocalInfo::Partial(entry, ranges) => {
+                                entries.insert(*hash, entry);
+                                ranges
+                            }
+                            LocalInfo::Missing => ChunkRanges::all(),
+                        };
+                        if child.is_empty() {
+                            ranges.push(ChunkRanges::empty());
+                        } else {
+                            ranges.push(head.clone());
+                        }
+                        missing.insert(offset, child);
+                    }
+                    if ranges.iter().all(|ranges| ranges.is_empty()) {
+                        debug!("nothing to do");
+                        return Ok(Plan::empty(children));
+                    }
+                    let request = GetRequest::new(root, RangeSpecSeq::from_ranges(ranges));
+                    (request, Some(children))
+                }
+                _ => {
+                    // we don't know the children yet, so get the entire root
+                    // and the last chunk of every child
+                    let request =
+                        RangeSpecSeq::from_ranges_infinite([ChunkRanges::all(), head.clone()]);
+                    (GetRequest::new(root, request), None)
+                }
+            },
+        };
+        debug!(?request, "requesting head");
+        let fetched = fetch(
+            &self.db,
+            conn.clone(),
+            request,
+            children,
+            &mut entries,
+            progress,
+        )
+        .await?;
+        add_stats(stats, fetched.stats);
+        let children = fetched.children;
+
+        // now that we know the sizes, split what is still missing into pieces
+        let mut pieces = Vec::new();
+        let mut planned = HashMap::new();
+        let offsets = std::iter::once((0, root))
+            .filter(|_| self.kind.format() == BlobFormat::Raw)
+            .chain((1u64..).zip(children.iter().copied()));
+        for (offset, hash) in offsets {
+            if planned.contains_key(&hash) {
+                // duplicate child, the pieces of the first occurrence cover it
+                continue;
+            }
+            let Some(entry) = entries.get(&hash) else {
+                // either complete, or the node did not send it
+                continue;
+            };
+            if self.db.entry_status(&hash).await? == crate::store::EntryStatus::Complete {
+                continue;
+            }
+            let Some(size) = fetched.sizes.get(&hash) else {
+                return Err(GetError::NoncompliantNode(anyhow!(
+                    "missing size for blob {hash}"
+                )));
+            };
+            let remaining = missing
+                .get(&offset)
+                .cloned()
+                .unwrap_or_else(ChunkRanges::all)
+                .difference(&last_chunk(*size));
+            let blob_pieces = split_into_pieces(&remaining, *size);
+            if blob_pieces.is_empty() {
+                // the head request already contained everything
+                self.db.insert_complete(entry.clone()).await?;
+                continue;
+            }
+            planned.insert(hash, blob_pieces.len());
+            for ranges in blob_pieces {
+                pieces.push(Piece {
+                    offset,
+                    ranges,
+                    holders: Vec::new(),
+                    done: false,
+                });
+            }
+        }
+        entries.retain(|hash, _| planned.contains_key(hash));
+        debug!(pieces = pieces.len(), blobs = entries.len(), "created plan");
+        let open = pieces.iter().fold(BTreeMap::new(), |mut open, piece| {
+            *open.entry(piece.offset).or_default() += 1;
+            open
+        });
+        Ok(Plan {
+            children,
+            entries,
+            pieces: Mutex::new(Pieces {
+                pieces,
+                open,
+                completing: 0,
+            }),
+        })
+    }
+
+    /// Find out what we have locally for a blob, and report it as progress.
+    async fn local_info(
+        &self,
+        hash: Hash,
+        child: BlobId,
+        progress: &BroadcastProgressSender,
+    ) -> Result<LocalInfo<S::EntryMut>, GetError> {
+        let Some(entry) = self.db.get_mut(&hash).await? else {
+            return Ok(LocalInfo::Missing);
+        };
+        if entry.is_complete() {
+            progress
+                .send(DownloadProgress::FoundLocal {
+                    child,
+                    hash,
+                    size: entry.size(),
+                    valid_ranges: RangeSpec::all(),
+                })
+                .await?;
+            return Ok(LocalInfo::Complete);
+        }
+        let valid = valid_ranges::<S>(&entry)
+            .await
+            .ok()
+            .unwrap_or_else(ChunkRanges::empty);
+        progress
+            .send(DownloadProgress::FoundLocal {
+                child,
+                hash,
+                size: entry.size(),
+                valid_ranges: RangeSpec::new(&valid),
+            })
+            .await?;
+        Ok(LocalInfo::Partial(
+            entry,
+            ChunkRanges::all().difference(&valid),
+        ))
+    }
+}
+
+/// What we have locally for a blob.
+enum LocalInfo<E> {
+    Complete,
+    /// Partial entry, and the ranges that are missing.
+    Partial(E, ChunkRanges),
+    Missing,
+}
+
+/// The pieces a download was split into.
+#[derive(Debug)]
+struct Plan<E> {
+    /// Hashes of the children of a hash seq, empty for raw blobs.
+    children: Vec<Hash>,
+    /// Entries of all blobs that still have pieces.
+    entries: HashMap<Hash, E>,
+    pieces: Mutex<Pieces>,
+}
+
+#[derive(Debug)]
+struct Pieces {
+    pieces: Vec<Piece>,
+    /// Number of pieces that are not done yet, per offset.
+    open: BTreeMap<u64, usize>,
+    /// Number of transfers currently marking blobs as complete.
+    completing: usize,
+}
+
+#[derive(Debug)]
+struct Piece {
+    /// Offset of the blob in the request, `0` for the root.
+    offset: u64,
+    /// Chunk ranges of the blob covered by this piece.
+    ranges: ChunkRanges,
+    /// Transfers currently requesting this piece.
+    holders: Vec<usize>,
+    done: bool,
+}
+
+/// Outcome of [`Plan::claim`].
+#[derive(Debug)]
+enum Claim {
+    /// Indices of the pieces to request.
+    Pieces(Vec<usize>),
+    /// All remaining pieces are in flight on other nodes.
+    Wait,
+    /// All pieces are done.
+    Finished,
+}
+
+impl<E> Plan<E> {
+    fn empty(children: Vec<Hash>) -> Self {
+        Self {
+            children,
+            entries: HashMap::new(),
+            pieces: Mutex::new(Pieces {
+                pieces: Vec::new(),
+                open: BTreeMap::new(),
+                completing: 0,
+            }),
+        }
+    }
+
+    fn hash_at(&self, root: Hash, offset: u64) -> Hash {
+        match offset {
+            0 => root,
+            offset => self.children[offset as usize - 1],
+        }
+    }
+
+    /// Claim pieces for a transfer.
+    ///
+    /// Unclaimed pieces are handed out first, up to [`PIECE_CHUNKS`] chunks at a time. If there
+    /// are none left, a piece that is in flight on exactly one other transfer is handed out.
+    fn claim(&self, worker: usize) -> Claim {
+        let mut state = self.pieces.lock();
+        let mut claimed = Vec::new();
+        let mut chunks = 0;
+        for (i, piece) in state.pieces.iter_mut().enumerate() {
+            if piece.done || !piece.holders.is_empty() {
+                continue;
+            }
+            let len = chunk_count(&piece.ranges);
+            if !claimed.is_empty() && chunks + len > PIECE_CHUNKS {
+                break;
+            }
+            piece.holders.push(worker);
+            chunks += len;
+            claimed.push(i);
+        }
+        if !claimed.is_empty() {
+            return Claim::Pieces(claimed);
+        }
+        let steal = state.pieces.iter_mut().enumerate().find(|(_, piece)| {
+            !piece.done && piece.holders.len() == 1 && piece.holders[0] != worker
+        });
+        if let Some((i, piece)) = steal {
+            piece.holders.push(worker);
+            return Claim::Pieces(vec![i]);
+        }
+        if state.open.is_empty() && state.completing == 0 {
+            Claim::Finished
+        } else {
+            Claim::Wait
+        }
+    }
+
+    /// Mark claimed pieces as done.
+    ///
+    /// Returns the offsets of the blobs that are now complete. The caller is responsible for
+    /// decrementing [`Pieces::completing`] once they are marked as complete in the store.
+    fn complete(&self, worker: usize, claimed: &[usize]) -> Vec<u64> {
+        let mut state = self.pieces.lock();
+        let mut completed = Vec::new();
+        for i in claimed {
+            let piece = &mut state.pieces[*i];
+            piece.holders.retain(|w| *w != worker);
+            if piece.done {
+                continue;
+            }
+            piece.done = true;
+            let offset = piece.offset;
+            let open = state.open.get_mut(&offset).expect("piece is open");
+            *open -= 1;
+            if *open == 0 {
+                state.open.remove(&offset);
+                completed.push(offset);
+            }
+        }
+        state.completing += 1;
+        completed
+    }
+
+    /// Release claimed pieces after a failed request, so that other transfers can claim them.
+    fn release(&self, worker: usize, claimed: &[usize]) {
+        let mut state = self.pieces.lock();
+        for i in claimed {
+            state.pieces[*i].holders.retain(|w| *w != worker);
+        }
+    }
+
+    /// Create a request for the claimed pieces.
+    fn request(&self, root: Hash, claimed: &[usize]) -> GetRequest {
+        let state = self.pieces.lock();
+        let mut ranges: Vec<ChunkRanges> = Vec::new();
+        for i in claimed {
+            let piece = &state.pieces[*i];
+            let offset = piece.offset as usize;
+            if ranges.len() <= offset {
+                ranges.resize(offset + 1, ChunkRanges::empty());
+            }
+            ranges[offset] |= piece.ranges.clone();
+        }
+        GetRequest::new(root, RangeSpecSeq::from_ranges(ranges))
+    }
+}
+
+/// Result of a single request made by [`fetch`].
+#[derive(Debug)]
+struct Fetched {
+    stats: Stats,
+    /// Children of the hash seq, if any.
+    children: Vec<Hash>,
+    /// Sizes of the blobs for which we received data, as reported by the node.
+    sizes: HashMap<Hash, u64>,
+}
+
+/// Perform a single request and write all received data into the store.
+///
+/// `children` must be `None` if the request contains the root of a hash seq that is not yet
+/// available locally. It is read from the response in this case, and the root is marked as
+/// complete. Entries for blobs that are not in `entries` yet are created and added.
+async fn fetch<S: Store>(
+    db: &S,
+    conn: quinn::Connection,
+    request: GetRequest,
+    mut children: Option<Vec<Hash>>,
+    entries: &mut HashMap<Hash, S::EntryMut>,
+    progress: &BroadcastProgressSender,
+) -> Result<Fetched, GetError> {
+    let root = request.hash;
+    let mut sizes = HashMap::new();
+    let connected = fsm::start(conn, request).next().await?;
+    let mut next = match connected.next().await? {
+        ConnectedNext::StartRoot(start) => {
+            let end = write_blob(db, start.next(), entries, &mut sizes, progress).await?;
+            if children.is_none() {
+                let entry = entries
+                    .get(&root)
+                    .cloned()
+                    .ok_or_else(|| GetError::LocalFailure(anyhow!("root entry not created")))?;
+                db.insert_complete(entry).await?;
+                let entry = db.get_mut(&root).await?.ok_or_else(|| {
+                    GetError::LocalFailure(anyhow!("just downloaded but not in db"))
+                })?;
+                children = Some(read_children(entry, progress).await?);
+            }
+            end.next()
+        }
+        ConnectedNext::StartChild(start) => EndBlobNext::MoreChildren(start),
+        ConnectedNext::Closing(finish) => EndBlobNext::Closing(finish),
+    };
+    let children = children.unwrap_or_default();
+    let finishing = loop {
+        let start = match next {
+            EndBlobNext::MoreChildren(start) => start,
+            EndBlobNext::Closing(finish) => break finish,
+        };
+        let child_offset = usize::try_from(start.child_offset())
+            .map_err(|_| GetError::NoncompliantNode(anyhow!("child offset too large")))?;
+        let Some(hash) = children.get(child_offset) else {
+            break start.finish();
+        };
+        let end = write_blob(db, start.next(*hash), entries, &mut sizes, progress).await?;
+        next = end.next();
+    };
+    let stats = finishing.next().await?;
+    Ok(Fetched {
+        stats,
+        children,
+        sizes,
+    })
+}
+
+/// Write the data for a single blob into its entry, creating the entry if needed.
+///
+/// Unlike [`crate::get::db::get_to_db`] this never marks the entry as complete, since the
+/// received data might only be a part of what is missing.
+async fn write_blob<S: Store>(
+    db: &S,
+    header: AtBlobHeader,
+    entries: &mut HashMap<Hash, S::EntryMut>,
+    sizes: &mut HashMap<Hash, u64>,
+    progress: &BroadcastProgressSender,
+) -> Result<AtEndBlob, GetError> {
+    let (content, size) = header.next().await?;
+    let hash = content.hash();
+    let child = BlobId::from_offset(content.offset());
+    let entry = match entries.get(&hash) {
+        Some(entry) => entry.clone(),
+        None => {
+            let entry = db.get_or_create(hash, size).await?;
+            entries.insert(hash, entry.clone());
+            entry
+        }
+    };
+    sizes.insert(hash, size);
+    let bw = entry.batch_writer().await?;
+    let id = progress.new_id();
+    progress
+        .send(DownloadProgress::Found {
+            id,
+            hash,
+            size,
+            child,
+        })
+        .await?;
+    let progress2 = progress.clone();
+    let on_write = move |offset: u64, _length: usize| {
+        // if try send fails it means that the receiver has been dropped.
+        // in that case we want to abort the write.
+        progress2
+            .try_send(DownloadProgress::Progress { id, offset })
+            .map_err(|e| {
+                tracing::info!("aborting download of {}", hash);
+                e
+            })?;
+        Ok(())
+    };
+    let mut bw = FallibleProgressBatchWriter::new(bw, on_write);
+    let end = content.write_all_batch(&mut bw).await?;
+    bw.sync().await?;
+    drop(bw);
+    progress.send(DownloadProgress::Done { id }).await?;
+    Ok(end)
+}
+
+/// Read the children of a complete hash seq, and report them as progress.
+async fn read_children<E: MapEntry>(
+    entry: E,
+    progress: &BroadcastProgressSender,
+) -> Result<Vec<Hash>, GetError> {
+    let reader = entry.data_reader().await?;
+    let (mut stream, count) = parse_hash_seq(reader).await.map_err(|err| {
+        GetError::NoncompliantNode(anyhow!("Failed to parse downloaded HashSeq: {err}"))
+    })?;
+    progress
+        .send(DownloadProgress::FoundHashSeq {
+            hash: entry.hash(),
+            children: count,
+        })
+        .await?;
+    let mut children = Vec::new();
+    while let Some(hash) = stream.next().await? {
+        children.push(hash);
+    }
+    Ok(children)
+}
+
+/// Split the missing ranges of a blob of the given size into pieces.
+///
+/// Each piece covers at most [`PIECE_CHUNKS`] chunks, and pieces never share a chunk group.
+fn split_into_pieces(ranges: &ChunkRanges, size: u64) -> Vec<ChunkRanges> {
+    let end = ChunkNum::chunks(size).0;
+    let mut pieces: BTreeMap<u64, ChunkRanges> = BTreeMap::new();
+    for range in ranges.iter() {
+        let (start, stop) = match range {
+            RangeSetRange::Range(r) => (r.start.0, r.end.0.min(end)),
+            RangeSetRange::RangeFrom(r) => (r.start.0, end),
+        };
+        let mut current = start;
+        while current < stop {
+            let window = current / PIECE_CHUNKS;
+            let piece_end = ((window + 1) * PIECE_CHUNKS).min(stop);
+            *pieces.entry(window).or_insert_with(ChunkRanges::empty) |=
+                ChunkRanges::from(ChunkNum(current)..ChunkNum(piece_end));
+            current = piece_end;
+        }
+    }
+    pieces.into_values().collect()
+}
+
+/// The last chunk of a blob of the given size, as received for the plan.
+///
+/// The node only sends this chunk, not its entire chunk group: it computes the hashes within
+/// the chunk group on the fly. So the rest of the last chunk group is still missing and must
+/// stay part of the pieces.
+fn last_chunk(size: u64) -> ChunkRanges {
+    ChunkRanges::from(ChunkNum(ChunkNum::chunks(size).0.saturating_sub(1))..)
+}
+
+/// Number of chunks in a set of bounded chunk ranges.
+fn chunk_count(ranges: &ChunkRanges) -> u64 {
+    ranges
+        .iter()
+        .map(|range| match range {
+            RangeSetRange::Range(r) => r.end.0 - r.start.0,
+            RangeSetRange::RangeFrom(_) => PIECE_CHUNKS,
+        })
+        .sum()
+}
+
+/// Add the stats of a single request to the stats of a transfer.
+fn add_stats(stats: &mut Stats, other: Stats) {
+    stats.bytes_written += other.bytes_written;
+    stats.bytes_read += other.bytes_read;
+    stats.elapsed += other.elapsed;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_into_pieces_aligned() {
+        let size = (PIECE_CHUNKS * 2 + 10) * 1024;
+        let pieces = split_into_pieces(&ChunkRanges::all(), size);
+        assert_eq!(
+            pieces,
+            vec![
+                ChunkRanges::from(ChunkNum(0)..ChunkNum(PIECE_CHUNKS)),
+                ChunkRanges::from(ChunkNum(PIECE_CHUNKS)..ChunkNum(PIECE_CHUNKS * 2)),
+                ChunkRanges::from(ChunkNum(PIECE_CHUNKS * 2)..ChunkNum(PIECE_CHUNKS * 2 + 10)),
+            ]
+        );
+
+        // ranges within a single window end up in the same piece
+        let ranges = ChunkRanges::from(ChunkNum(10)..ChunkNum(20))
+            | ChunkRanges::from(ChunkNum(30)..ChunkNum(PIECE_CHUNKS + 5));
+        let pieces = split_into_pieces(&ranges, size);
+        assert_eq!(
+            pieces,
+            vec![
+                ChunkRanges::from(ChunkNum(10)..ChunkNum(20))
+                    | ChunkRanges::from(ChunkNum(30)..ChunkNum(PIECE_CHUNKS)),
+                ChunkRanges::from(ChunkNum(PIECE_CHUNKS)..ChunkNum(PIECE_CHUNKS + 5)),
+            ]
+        );
+
+        assert!(split_into_pieces(&ChunkRanges::all(), 0).is_empty());
+    }
+
+    #[test]
+    fn last_chunk_of_size() {
+        assert_eq!(last_chunk(0), ChunkRanges::from(ChunkNum(0)..));
+        assert_eq!(last_chunk(1024), ChunkRanges::from(ChunkNum(0)..));
+        assert_eq!(last_chunk(1024 * 40 + 7), ChunkRanges::from(ChunkNum(40)..));
+    }
+
+    #[test]
+    fn claim_and_steal() {
+        let pieces = (0..3)
+            .map(|i| Piece {
+                offset: 0,
+                ranges: ChunkRanges::from(
+                    ChunkNum(i * PIECE_CHUNKS)..ChunkNum((i + 1) * PIECE_CHUNKS),
+                ),
+                holders: Vec::new(),
+                done: false,
+            })
+            .collect::<Vec<_>>();
+        let plan: Plan<()> = Plan {
+            children: vec![],
+            entries: HashMap::new(),
+            pieces: Mutex::new(Pieces {
+                pieces,
+                open: [(0, 3)].into(),
+                completing: 0,
+            }),
+        };
+        let Claim::Pieces(a) = plan.claim(0) else {
+            panic!("expected pieces");
+        };
+        let Claim::Pieces(b) = plan.claim(1) else {
+            panic!("expected pieces");
+        };
+        let Claim::Pieces(c) = plan.claim(2) else {
+            panic!("expected pieces");
+        };
+        assert_eq!(
+            (a.as_slice(), b.as_slice(), c.as_slice()),
+            (&[0][..], &[1][..], &[2][..])
+        );
+
+        // worker 0 is done and steals the piece of the slowest transfer
+        assert!(plan.complete(0, &a).is_empty());
+        plan.pieces.lock().completing -= 1;
+        let Claim::Pieces(stolen) = plan.claim(0) else {
+            panic!("expected pieces");
+        };
+        assert_eq!(stolen, vec![1]);
+
+        // worker 1 fails, a new worker steals the piece still in flight on worker 0
+        plan.release(1, &b);
+        assert!(matches!(plan.claim(3), Claim::Pieces(p) if p == vec![1]));
+
+        assert!(plan.complete(0, &stolen).is_empty());
+        plan.pieces.lock().completing -= 1;
+        assert_eq!(plan.complete(2, &c), vec![0]);
+        assert!(matches!(plan.claim(0), Claim::Wait));
+        plan.pieces.lock().completing -= 1;
+        assert!(matches!(plan.claim(0), Claim::Finished));
+    }
+}
@@ -218,6 +218,72 @@ async fn max_concurrent_requests_per_peer() {
     futures::future::join_all(handles).await;
 }
 
+/// Tests that a download is split across all connected providers, up to the node limit.
+/// NOTE: The limit is internally tested by [`Service::check_invariants`].
+#[tokio::test]
+async fn swarm_across_providers() {
+    let _guard = iroh_test::logging::setup();
+    let dialer = dialer::TestingDialer::default();
+    let getter = getter::TestingGetter::default();
+    // make request take some time to ensure all providers join the download
+    getter.set_request_duration(Duration::from_millis(200));
+    let concurrency_limits = ConcurrencyLimits {
+        max_nodes_per_download: 3,
+        ..Default::default()
+    };
+
+    let downloader = Downloader::spawn_for_test(dialer.clone(), getter.clone(), concurrency_limits);
+
+    let peers = (0..4)
+        .map(|_| SecretKey::generate().public())
+        .collect::<Vec<_>>();
+    let kind: DownloadKind = HashAndFormat::raw(Hash::new([0u8; 32])).into();
+    let req = DownloadRequest::new(kind, peers.clone());
+    let handle = downloader.queue(req).await;
+    handle.await.expect("should report success");
+
+    // only three of the four providers joined the download
+    let history = getter.request_history();
+    let nodes = history
+        .iter()
+        .map(|(k, node)| {
+            assert_eq!(*k, kind);
+            *node
+        })
+        .collect::<HashSet<_>>();
+    assert_eq!(history.len(), 3);
+    assert_eq!(nodes.len(), 3);
+    assert!(nodes.iter().all(|node| peers.contains(node)));
+}
+
+/// Tests that downloads are performed from a single node if splitting is disabled.
+#[tokio::test]
+async fn swarm_disabled() {
+    let _guard = iroh_test::logging::setup();
+    let dialer = dialer::TestingDialer::default();
+    let getter = getter::TestingGetter::default();
+    getter.set_request_duration(Duration::from_millis(200));
+    let concurrency_limits = ConcurrencyLimits {
+        max_nodes_per_download: 1,
+        ..Default::default()
+    };
+
+    let downloader = Downloader::spawn_for_test(dialer.clone(), getter.clone(), concurrency_limits);
+
+    let peers = (0..3)
+        .map(|_| SecretKey::generate().public())
+        .collect::<Vec<_>>();
+    let kind: DownloadKind = HashAndFormat::raw(Hash::new([0u8; 32])).into();
+    let req = DownloadRequest::new(kind, peers.clone());
+    let handle = downloader.queue(req).await;
+    handle.await.expect("should report success");
+
+    // a single provider performs the whole download
+    let history = getter.request_history();
+    assert_eq!(history.len(), 1);
+    assert!(peers.contains(&history[0].1));
+}
+
 /// Tests concurrent progress reporting for multiple intents.
 ///
 /// This first registers two intents for a download, and then proceeds until the `Found` event is
@@ -71,4 +71,8 @@ impl TestingGetter {
     pub(super) fn assert_history(&self, history: &[(DownloadKind, NodeId)]) {
         assert_eq!(self.0.read().request_history, history);
     }
+    /// Get the requests performed so far.
+    pub(super) fn request_history(&self) -> Vec<(DownloadKind, NodeId)> {
+        self.0.read().request_history.clone()
+    }
 }
@@ -1,18 +1,19 @@
 //! Functions to export data from a store
 
-use std::path::PathBuf;
+use std::path::{Component, Path, PathBuf};
 
 use anyhow::Context;
 use bytes::Bytes;
 use iroh_base::rpc::RpcError;
+use iroh_io::{AsyncSliceReader, AsyncSliceWriter};
 use serde::{Deserialize, Serialize};
 use tracing::trace;
 
 use crate::{
-    format::collection::Collection,
+    format::{chunked::ChunkedReader, collection::Collection, directory::Directory},
     store::{BaoBlobSize, ExportFormat, ExportMode, MapEntry, Store as BaoStore},
     util::progress::{IdGenerator, ProgressSender},
-    Hash,
+    BlobFormat, Hash, HashAndFormat,
 };
 
 /// Export a hash to the local file system.
@@ -34,6 +35,8 @@ pub async fn export<D: BaoStore>(
     match format {
         ExportFormat::Blob => export_blob(db, hash, outpath, mode, progress).await,
         ExportFormat::Collection => export_collection(db, hash, outpath, mode, progress).await,
+        ExportFormat::Directory => export_directory(db, hash, outpath, mode, progress).await,
+        ExportFormat::Chunked => export_chunked(db, hash, outpath, progress).await,
     }
 }
 
@@ -55,6 +58,72 @@ pub async fn export_collection<D: BaoStore>(
     Ok(())
 }
 
+/// Export a directory and all its subdirectories to the local filesystem.
+pub async fn export_directory<D: BaoStore>(
+    db: &D,
+    hash: Hash,
+    outpath: PathBuf,
+    mode: ExportMode,
+    progress: impl ProgressSender<Msg = ExportProgress> + IdGenerator,
+) -> anyhow::Result<()> {
+    let mut stack = vec![(hash, outpath)];
+    while let Some((hash, outpath)) = stack.pop() {
+        tokio::fs::create_dir_all(&outpath).await?;
+        let directory = Directory::load(db, &hash).await?;
+        for (name, HashAndFormat { hash, format }) in directory.into_iter() {
+            let path = outpath.join(path_component_from_name(&name)?);
+            match format {
+                BlobFormat::Dag => stack.push((hash, path)),
+                _ => export_blob(db, hash, path, mode, progress.clone()).await?,
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Reassemble a chunked blob into a single file on the local filesystem.
+///
+/// The data is always copied, since the file is made up of several blobs.
+pub async fn export_chunked<D: BaoStore>(
+    db: &D,
+    hash: Hash,
+    outpath: PathBuf,
+    progress: impl ProgressSender<Msg = ExportProgress> + IdGenerator,
+) -> anyhow::Result<()> {
+    if let Some(parent) = outpath.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    trace!("exporting chunked blob {} to {}", hash, outpath.display());
+    let mut reader = ChunkedReader::load(db, &hash).await?;
+    let size = reader.len();
+    let id = progress.new_id();
+    progress
+        .send(ExportProgress::Found {
+            id,
+            hash,
+            outpath: outpath.clone(),
+            size: BaoBlobSize::Verified(size),
+            meta: None,
+        })
+        .await?;
+    let path = outpath.clone();
+    let mut file = iroh_io::File::create(move || std::fs::File::create(path)).await?;
+    let mut offset = 0;
+    while offset < size {
+        let data = reader.read_at(offset, EXPORT_CHUNKED_BUFFER_SIZE).await?;
+        let len = data.len() as u64;
+        file.write_bytes_at(offset, data).await?;
+        offset += len;
+        progress.try_send(ExportProgress::Progress { id, offset })?;
+    }
+    file.sync().await?;
+    progress.send(ExportProgress::Done { id }).await?;
+    Ok(())
+}
+
+/// How much data to read at once when reassembling a chunked blob.
+const EXPORT_CHUNKED_BUFFER_SIZE: usize = 1024 * 1024;
+
 /// Export a single blob to a file on the local fileystem.
 pub async fn export_blob<D: BaoStore>(
     db: &D,
@@ -127,6 +196,20 @@ pub enum ExportProgress {
     Abort(RpcError),
 }
 
+/// Directory entry names must be a single, normal path component.
+fn path_component_from_name(name: &str) -> anyhow::Result<&Path> {
+    let path = Path::new(name);
+    let mut components = path.components();
+    anyhow::ensure!(
+        matches!(
+            (components.next(), components.next()),
+            (Some(Component::Normal(_)), None)
+        ),
+        "invalid directory entry name {name:?}"
+    );
+    Ok(path)
+}
+
 fn pathbuf_from_name(name: &str) -> PathBuf {
     let mut path = PathBuf::new();
     for part in name.split('/') {
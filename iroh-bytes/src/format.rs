@@ -13,4 +13,6 @@
 //! n-1 items, where n is the number of blobs in the HashSeq.
 //!
 //! [postcard]: https://docs.rs/postcard/latest/postcard/
+pub mod chunked;
 pub mod collection;
+pub mod directory;
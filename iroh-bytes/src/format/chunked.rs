@@ -0,0 +1,483 @@
+//! Large files split into content defined chunks
+//!
+//! A chunked blob is a [`HashSeq`] whose first child is a metadata blob with the
+//! sizes of all chunks, and whose other children are the chunks. The chunk
+//! boundaries depend only on the content around them, so two versions of a file
+//! that differ in a few places share all chunks except the ones around the changes.
+//! Those shared chunks are only stored and transferred once.
+//!
+//! Use [`Chunker`] to split data into chunks, [`ChunkedBlob`] to store the manifest,
+//! and [`ChunkedReader`] to read the chunks back as one logical blob.
+use std::{fmt, io};
+
+use anyhow::Context;
+use bytes::{Bytes, BytesMut};
+use iroh_io::AsyncSliceReader;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    hashseq::HashSeq,
+    store::{Map, MapEntry, Store},
+    util::TempTag,
+    BlobFormat, Hash,
+};
+
+/// Parameters for content defined chunking.
+///
+/// Chunks are cut where a rolling hash of the data matches a mask, but never before
+/// `min_size` and never after `max_size` bytes. The mask is chosen so that chunks
+/// are `avg_size` bytes on average.
+///
+/// Data chunked with different parameters does not share chunks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkerConfig {
+    min_size: usize,
+    avg_size: usize,
+    max_size: usize,
+}
+
+impl Default for ChunkerConfig {
+    fn default() -> Self {
+        Self {
+            min_size: 64 * 1024,
+            avg_size: 256 * 1024,
+            max_size: 1024 * 1024,
+        }
+    }
+}
+
+impl ChunkerConfig {
+    /// Create a new config.
+    ///
+    /// `avg_size` must be a power of two of at least 64 bytes, and the sizes must be
+    /// in increasing order.
+    pub fn new(min_size: usize, avg_size: usize, max_size: usize) -> anyhow::Result<Self> {
+        anyhow::ensure!(
+            avg_size.is_power_of_two() && avg_size >= 64,
+            "average chunk size must be a power of two of at least 64 bytes"
+        );
+        anyhow::ensure!(
+            0 < min_size && min_size <= avg_size && avg_size <= max_size,
+            "chunk sizes must be in increasing order"
+        );
+        Ok(Self {
+            min_size,
+            avg_size,
+            max_size,
+        })
+    }
+
+    /// The minimum chunk size, except for the last chunk.
+    pub fn min_size(&self) -> usize {
+        self.min_size
+    }
+
+    /// The average chunk size.
+    pub fn avg_size(&self) -> usize {
+        self.avg_size
+    }
+
+    /// The maximum chunk size.
+    pub fn max_size(&self) -> usize {
+        self.max_size
+    }
+
+    /// Find the end of the first chunk in `data`.
+    ///
+    /// Unless `data` is the end of the input, it must be at least `max_size` bytes long.
+    fn cut(&self, data: &[u8]) -> usize {
+        if data.len() <= self.min_size {
+            return data.len();
+        }
+        let len = data.len().min(self.max_size);
+        let normal = self.avg_size.min(len);
+        // use a stricter mask before the average size and a looser one after it,
+        // to get chunk sizes closer to the average
+        let bits = self.avg_size.trailing_zeros();
+        let mask_small = mask(bits + 1);
+        let mask_large = mask(bits - 1);
+        let mut hash = 0u64;
+        for (i, byte) in data.iter().enumerate().take(len).skip(self.min_size) {
+            hash = (hash << 1).wrapping_add(GEAR[*byte as usize]);
+            let mask = if i < normal { mask_small } else { mask_large };
+            if hash & mask == 0 {
+                return i + 1;
+            }
+        }
+        len
+    }
+}
+
+/// A mask of the `bits` highest bits.
+///
+/// The rolling hash shifts to the left, so the highest bits depend on the most data.
+const fn mask(bits: u32) -> u64 {
+    !0u64 << (64 - bits)
+}
+
+/// Random values for the rolling hash, generated with splitmix64.
+///
+/// Changing these changes all chunk boundaries.
+const GEAR: [u64; 256] = {
+    let mut table = [0u64; 256];
+    let mut state = 0u64;
+    let mut i = 0;
+    while i < table.len() {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+};
+
+/// Splits the data of a reader into content defined chunks.
+///
+/// At most `max_size` bytes are buffered.
+#[derive(Debug)]
+pub struct Chunker<R> {
+    reader: R,
+    config: ChunkerConfig,
+    buf: Vec<u8>,
+    eof: bool,
+}
+
+impl<R: io::Read> Chunker<R> {
+    /// Create a new chunker.
+    pub fn new(reader: R, config: ChunkerConfig) -> Self {
+        Self {
+            reader,
+            config,
+            buf: Vec::new(),
+            eof: false,
+        }
+    }
+}
+
+impl<R: io::Read> Iterator for Chunker<R> {
+    type Item = io::Result<Bytes>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // fill the buffer, so the chunk boundary does not depend on how the data is read
+        while !self.eof && self.buf.len() < self.config.max_size {
+            let start = self.buf.len();
+            self.buf.resize(self.config.max_size, 0);
+            let res = self.reader.read(&mut self.buf[start..]);
+            let n = *res.as_ref().unwrap_or(&0);
+            self.buf.truncate(start + n);
+            match res {
+                Ok(0) => self.eof = true,
+                Ok(_) => {}
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Some(Err(err)),
+            }
+        }
+        if self.buf.is_empty() {
+            return None;
+        }
+        let end = self.config.cut(&self.buf);
+        let chunk = Bytes::copy_from_slice(&self.buf[..end]);
+        self.buf.drain(..end);
+        Some(Ok(chunk))
+    }
+}
+
+/// The manifest of a chunked blob
+///
+/// Note that the format is subject to change.
+#[derive(Clone, Debug, PartialEq, Eq, Default)]
+pub struct ChunkedBlob {
+    /// The hashes and sizes of the chunks, in order
+    chunks: Vec<(Hash, u64)>,
+}
+
+/// Metadata for a chunked blob
+///
+/// This is the wire format for the metadata blob.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+struct ChunkedMeta {
+    header: [u8; 10], // Must contain "ChunkedV0."
+    sizes: Vec<u64>,
+}
+
+impl FromIterator<(Hash, u64)> for ChunkedBlob {
+    fn from_iter<T: IntoIterator<Item = (Hash, u64)>>(iter: T) -> Self {
+        Self {
+            chunks: iter.into_iter().collect(),
+        }
+    }
+}
+
+impl ChunkedBlob {
+    /// The header for the chunked format.
+    ///
+    /// This is the start of the metadata blob.
+    pub const HEADER: &'static [u8; 10] = b"ChunkedV0.";
+
+    /// Convert the manifest to an iterator of blobs, with the last being the
+    /// root blob.
+    ///
+    /// To persist the manifest, write all the blobs to storage, and use the
+    /// hash of the last blob as the root hash, in [`BlobFormat::HashSeq`].
+    pub fn to_blobs(&self) -> impl Iterator<Item = Bytes> {
+        let (meta, links) = self.to_meta_and_links();
+        [meta, links].into_iter()
+    }
+
+    /// Convert the manifest to the metadata blob and the root blob.
+    ///
+    /// The root blob is a [`HashSeq`] of the hash of the metadata blob followed by the
+    /// hashes of all chunks.
+    pub fn to_meta_and_links(&self) -> (Bytes, Bytes) {
+        let meta_bytes = self.meta_bytes();
+        let links = std::iter::once(Hash::new(&meta_bytes))
+            .chain(self.chunks.iter().map(|(hash, _)| *hash))
+            .collect::<HashSeq>();
+        (meta_bytes, links.into_inner())
+    }
+
+    /// Load a manifest from a store given a root hash
+    ///
+    /// This assumes that both the links and the metadata are stored in the store.
+    /// It does not require that the chunks are stored in the store.
+    pub async fn load<D: Map>(db: &D, root: &Hash) -> anyhow::Result<Self> {
+        use iroh_io::AsyncSliceReaderExt;
+        let links_entry = db.get(root).await?.context("links not found")?;
+        anyhow::ensure!(links_entry.is_complete(), "links not complete");
+        let links_bytes = links_entry.data_reader().await?.read_to_end().await?;
+        let mut links = HashSeq::try_from(links_bytes)?;
+        let meta_hash = links.pop_front().context("meta hash not found")?;
+        let meta_entry = db.get(&meta_hash).await?.context("meta not found")?;
+        anyhow::ensure!(meta_entry.is_complete(), "meta not complete");
+        let meta_bytes = meta_entry.data_reader().await?.read_to_end().await?;
+        let meta: ChunkedMeta = postcard::from_bytes(&meta_bytes)?;
+        anyhow::ensure!(
+            meta.header == *Self::HEADER,
+            "expected header {:?}, got {:?}",
+            Self::HEADER,
+            meta.header
+        );
+        anyhow::ensure!(
+            meta.sizes.len() == links.len(),
+            "sizes and links length mismatch"
+        );
+        Ok(links.into_iter().zip(meta.sizes).collect())
+    }
+
+    /// Store a manifest in a store. returns the root hash of the manifest
+    /// as a TempTag.
+    ///
+    /// The chunks are expected to be stored separately.
+    pub async fn store<D: Store>(&self, db: &D) -> anyhow::Result<TempTag> {
+        let meta_tag = db.import_bytes(self.meta_bytes(), BlobFormat::Raw).await?;
+        let links = std::iter::once(*meta_tag.hash())
+            .chain(self.chunks.iter().map(|(hash, _)| *hash))
+            .collect::<HashSeq>();
+        let links_tag = db.import_bytes(links.into(), BlobFormat::HashSeq).await?;
+        Ok(links_tag)
+    }
+
+    /// Iterate over the hashes and sizes of the chunks
+    pub fn iter(&self) -> impl Iterator<Item = &(Hash, u64)> {
+        self.chunks.iter()
+    }
+
+    /// Get the number of chunks
+    pub fn len(&self) -> usize {
+        self.chunks.len()
+    }
+
+    /// Check if there are no chunks
+    pub fn is_empty(&self) -> bool {
+        self.chunks.is_empty()
+    }
+
+    /// The total size of the data in bytes
+    pub fn size(&self) -> u64 {
+        self.chunks.iter().map(|(_, size)| size).sum()
+    }
+
+    /// Append a chunk.
+    pub fn push(&mut self, hash: Hash, size: u64) {
+        self.chunks.push((hash, size));
+    }
+
+    fn meta_bytes(&self) -> Bytes {
+        let meta = ChunkedMeta {
+            header: *Self::HEADER,
+            sizes: self.chunks.iter().map(|(_, size)| *size).collect(),
+        };
+        postcard::to_stdvec(&meta)
+            .expect("serializing to vec can not fail")
+            .into()
+    }
+}
+
+/// Reads the chunks of a [`ChunkedBlob`] as one logical blob.
+pub struct ChunkedReader<D: Map> {
+    entries: Vec<D::Entry>,
+    /// Start offset of every chunk, followed by the total size
+    offsets: Vec<u64>,
+}
+
+impl<D: Map> fmt::Debug for ChunkedReader<D> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ChunkedReader")
+            .field("chunks", &self.entries.len())
+            .field("size", &self.offsets.last())
+            .finish()
+    }
+}
+
+impl<D: Map> ChunkedReader<D> {
+    /// Create a reader for the chunks of `blob`.
+    ///
+    /// All chunks must be complete, and have the size listed in the manifest.
+    pub async fn new(db: &D, blob: &ChunkedBlob) -> anyhow::Result<Self> {
+        let mut entries = Vec::with_capacity(blob.len());
+        let mut offsets = Vec::with_capacity(blob.len() + 1);
+        let mut offset = 0u64;
+        for (hash, size) in blob.iter() {
+            let entry = db.get(hash).await?.context("chunk not found")?;
+            anyhow::ensure!(entry.is_complete(), "chunk {hash} not complete");
+            anyhow::ensure!(
+                entry.size().value() == *size,
+                "chunk {hash} has unexpected size"
+            );
+            entries.push(entry);
+            offsets.push(offset);
+            offset += size;
+        }
+        offsets.push(offset);
+        Ok(Self { entries, offsets })
+    }
+
+    /// Load the manifest with the given root hash, and create a reader for it.
+    pub async fn load(db: &D, root: &Hash) -> anyhow::Result<Self> {
+        let blob = ChunkedBlob::load(db, root).await?;
+        Self::new(db, &blob).await
+    }
+
+    /// The total size of the data in bytes
+    pub fn len(&self) -> u64 {
+        *self.offsets.last().expect("offsets are never empty")
+    }
+
+    /// Check if there is no data
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<D: Map> AsyncSliceReader for ChunkedReader<D> {
+    async fn read_at(&mut self, offset: u64, len: usize) -> io::Result<Bytes> {
+        let end = offset.saturating_add(len as u64).min(self.len());
+        if offset >= end {
+            return Ok(Bytes::new());
+        }
+        // the last chunk that starts at or before the offset
+        let mut index = self.offsets[..self.entries.len()].partition_point(|&o| o <= offset) - 1;
+        let mut res = BytesMut::with_capacity((end - offset) as usize);
+        let mut pos = offset;
+        while pos < end {
+            let start = self.offsets[index];
+            let n = (end.min(self.offsets[index + 1]) - pos) as usize;
+            let mut reader = self.entries[index].data_reader().await?;
+            let data = reader.read_at(pos - start, n).await?;
+            if data.len() != n {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "chunk is shorter than expected",
+                ));
+            }
+            res.extend_from_slice(&data);
+            pos += n as u64;
+            index += 1;
+        }
+        Ok(res.freeze())
+    }
+
+    async fn size(&mut self) -> io::Result<u64> {
+        Ok(self.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iroh_io::AsyncSliceReaderExt;
+    use rand::{Rng, RngCore, SeedableRng};
+
+    use super::*;
+
+    fn test_data(len: usize) -> Vec<u8> {
+        let mut data = vec![0u8; len];
+        rand::rngs::StdRng::seed_from_u64(0).fill_bytes(&mut data);
+        data
+    }
+
+    fn chunks(data: &[u8], config: ChunkerConfig) -> Vec<Bytes> {
+        Chunker::new(data, config)
+            .collect::<io::Result<Vec<_>>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn chunk_sizes() {
+        let config = ChunkerConfig::new(1024, 4096, 16384).unwrap();
+        let data = test_data(1024 * 1024);
+        let chunks = chunks(&data, config);
+        assert_eq!(chunks.concat(), data);
+        let (last, rest) = chunks.split_last().unwrap();
+        assert!(!last.is_empty() && last.len() <= 16384);
+        for chunk in rest {
+            assert!((1024..=16384).contains(&chunk.len()));
+        }
+        // roughly the average size, with a lot of tolerance
+        assert!((32..=512).contains(&chunks.len()), "{}", chunks.len());
+        assert!(Chunker::new(&[][..], config).next().is_none());
+    }
+
+    #[test]
+    fn chunks_survive_edits() {
+        let config = ChunkerConfig::new(1024, 4096, 16384).unwrap();
+        let data = test_data(1024 * 1024);
+        let mut edited = data.clone();
+        // insert a few bytes, which shifts all data after it
+        let at = rand::rngs::StdRng::seed_from_u64(1).gen_range(0..data.len());
+        edited.splice(at..at, *b"hello");
+        let a = chunks(&data, config);
+        let b = chunks(&edited, config);
+        let shared = b.iter().filter(|chunk| a.contains(chunk)).count();
+        // all chunks except the ones around the edit are shared
+        assert!(shared + 3 >= b.len(), "{shared} of {} shared", b.len());
+    }
+
+    #[tokio::test]
+    async fn chunked_reader() {
+        let config = ChunkerConfig::new(1024, 4096, 16384).unwrap();
+        let data = test_data(100 * 1024);
+        let db = crate::store::mem::Store::new();
+        let mut blob = ChunkedBlob::default();
+        let mut tags = Vec::new();
+        for chunk in Chunker::new(&data[..], config) {
+            let chunk = chunk.unwrap();
+            let size = chunk.len() as u64;
+            let tag = db.import_bytes(chunk, BlobFormat::Raw).await.unwrap();
+            blob.push(*tag.hash(), size);
+            tags.push(tag);
+        }
+        let root = blob.store(&db).await.unwrap();
+        assert_eq!(ChunkedBlob::load(&db, root.hash()).await.unwrap(), blob);
+        let mut reader = ChunkedReader::load(&db, root.hash()).await.unwrap();
+        assert_eq!(reader.size().await.unwrap(), data.len() as u64);
+        assert_eq!(reader.read_to_end().await.unwrap(), data);
+        for (offset, len) in [(0, 10), (5000, 20000), (100 * 1024 - 1, 10), (200000, 1)] {
+            let start = offset.min(data.len());
+            let end = (offset + len).min(data.len());
+            let actual = reader.read_at(offset as u64, len).await.unwrap();
+            assert_eq!(actual, data[start..end]);
+        }
+    }
+}
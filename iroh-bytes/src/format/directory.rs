@@ -0,0 +1,170 @@
+//! A nested directory type
+//!
+//! Unlike a [`Collection`](super::collection::Collection), a directory can contain
+//! other directories, so a tree of files can be stored without flattening it.
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    dag::DagNode,
+    store::{Map, Store},
+    util::TempTag,
+    BlobFormat, Hash, HashAndFormat,
+};
+
+/// A directory of named entries
+///
+/// Entries in [`BlobFormat::Raw`] are files, entries in [`BlobFormat::Dag`] are
+/// subdirectories. The directory is stored as a [`DagNode`], so getting or
+/// garbage collecting a directory includes all its subdirectories.
+///
+/// Note that the format is subject to change.
+#[derive(Clone, Debug, PartialEq, Eq, Default)]
+pub struct Directory {
+    /// The entries of this directory
+    entries: Vec<(String, HashAndFormat)>,
+}
+
+/// Metadata for a directory
+///
+/// This is the application data of the [`DagNode`].
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+struct DirectoryMeta {
+    header: [u8; 12], // Must contain "DirectoryV0."
+    names: Vec<String>,
+}
+
+impl<K> FromIterator<(K, HashAndFormat)> for Directory
+where
+    K: Into<String>,
+{
+    fn from_iter<T: IntoIterator<Item = (K, HashAndFormat)>>(iter: T) -> Self {
+        Self {
+            entries: iter.into_iter().map(|(k, v)| (k.into(), v)).collect(),
+        }
+    }
+}
+
+impl IntoIterator for Directory {
+    type Item = (String, HashAndFormat);
+    type IntoIter = std::vec::IntoIter<Self::Item>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.entries.into_iter()
+    }
+}
+
+impl Directory {
+    /// The header for the directory format.
+    ///
+    /// This is the start of the application data of the node.
+    pub const HEADER: &'static [u8; 12] = b"DirectoryV0.";
+
+    /// Convert the directory to a [`DagNode`].
+    pub fn to_node(&self) -> DagNode {
+        let meta = DirectoryMeta {
+            header: *Self::HEADER,
+            names: self.entries.iter().map(|(name, _)| name.clone()).collect(),
+        };
+        let data = postcard::to_stdvec(&meta).expect("serializing to vec can not fail");
+        DagNode::new(self.entries.iter().map(|(_, content)| *content), data)
+    }
+
+    /// Create a directory from a [`DagNode`].
+    pub fn from_node(node: DagNode) -> anyhow::Result<Self> {
+        let meta: DirectoryMeta =
+            postcard::from_bytes(node.data()).context("invalid directory metadata")?;
+        anyhow::ensure!(
+            meta.header == *Self::HEADER,
+            "expected header {:?}, got {:?}",
+            Self::HEADER,
+            meta.header
+        );
+        anyhow::ensure!(
+            meta.names.len() == node.children().len(),
+            "names and links length mismatch"
+        );
+        for content in node.children() {
+            anyhow::ensure!(
+                matches!(content.format, BlobFormat::Raw | BlobFormat::Dag),
+                "unexpected entry format {:?}",
+                content.format
+            );
+        }
+        Ok(meta
+            .names
+            .into_iter()
+            .zip(node.children().iter().copied())
+            .collect())
+    }
+
+    /// Load a directory from a store given a root hash
+    ///
+    /// This assumes that the node of the directory is stored in the store. It
+    /// does not require that the entries are stored in the store.
+    pub async fn load<D: Map>(db: &D, root: &Hash) -> anyhow::Result<Self> {
+        Self::from_node(DagNode::load(db, root).await?)
+    }
+
+    /// Store a directory in a store. returns the root hash of the directory
+    /// as a TempTag.
+    ///
+    /// The entries are expected to be stored separately.
+    pub async fn store<D: Store>(&self, db: &D) -> anyhow::Result<TempTag> {
+        self.to_node().store(db).await
+    }
+
+    /// Iterate over the entries of this directory
+    pub fn iter(&self) -> impl Iterator<Item = &(String, HashAndFormat)> {
+        self.entries.iter()
+    }
+
+    /// Get the number of entries in this directory
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Check if this directory is empty
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Add a file to the directory.
+    pub fn push_file(&mut self, name: String, hash: Hash) {
+        self.entries.push((name, HashAndFormat::raw(hash)));
+    }
+
+    /// Add a subdirectory to the directory.
+    ///
+    /// `hash` is the root hash of the stored subdirectory.
+    pub fn push_dir(&mut self, name: String, hash: Hash) {
+        self.entries.push((name, HashAndFormat::dag(hash)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip_directory() {
+        let mut dir = Directory::default();
+        dir.push_file("a.txt".to_string(), Hash::new("a"));
+        dir.push_dir("sub".to_string(), Hash::new("sub"));
+        let actual = Directory::from_node(dir.to_node()).unwrap();
+        assert_eq!(dir, actual);
+    }
+
+    #[test]
+    fn reject_hash_seq_entries() {
+        let node = DagNode::new(
+            [HashAndFormat::hash_seq(Hash::new("a"))],
+            postcard::to_stdvec(&DirectoryMeta {
+                header: *Directory::HEADER,
+                names: vec!["a".to_string()],
+            })
+            .unwrap(),
+        );
+        assert!(Directory::from_node(node).is_err());
+    }
+}
@@ -60,7 +60,9 @@ pub mod fsm {
     use std::{io, result};
 
     use crate::{
-        protocol::{GetRequest, NonEmptyRequestRangeSpecIter, Request, MAX_MESSAGE_SIZE},
+        protocol::{
+            CustomGetRequest, GetRequest, NonEmptyRequestRangeSpecIter, Request, MAX_MESSAGE_SIZE,
+        },
         store::BaoBatchWriter,
     };
 
@@ -89,6 +91,14 @@ pub mod fsm {
         AtInitial::new(connection, request)
     }
 
+    /// The entry point of the get response machine for provider defined requests
+    pub fn start_custom(
+        connection: quinn::Connection,
+        request: CustomGetRequest,
+    ) -> AtInitialCustom {
+        AtInitialCustom::new(connection, request)
+    }
+
     /// Owned iterator for the ranges in a request
     ///
     /// We need an owned iterator for a fsm style API, otherwise we would have
@@ -240,7 +250,9 @@ pub mod fsm {
                 let wrapped = Request::Get(request);
                 let request_bytes =
                     postcard::to_stdvec(&wrapped).map_err(ConnectedNextError::PostcardSer)?;
-                let Request::Get(x) = wrapped;
+                let Request::Get(x) = wrapped else {
+                    unreachable!("wrapped a get request");
+                };
                 request = x;
 
                 if request_bytes.len() > MAX_MESSAGE_SIZE {
@@ -258,39 +270,191 @@ pub mod fsm {
             let (mut writer, bytes_written) = writer.into_parts();
             writer.finish().await?;
 
-            let hash = request.hash;
-            let ranges_iter = RangesIter::new(request.ranges);
-            // this is in a box so we don't have to memcpy it on every state transition
-            let mut misc = Box::new(Misc {
-                start,
-                bytes_written,
-                ranges_iter,
-            });
-            Ok(match misc.ranges_iter.next() {
-                Some((offset, ranges)) => {
-                    if offset == 0 {
-                        AtStartRoot {
-                            reader,
-                            ranges,
-                            misc,
-                            hash,
-                        }
-                        .into()
-                    } else {
-                        AtStartChild {
-                            reader,
-                            ranges,
-                            misc,
-                            child_offset: offset - 1,
-                        }
-                        .into()
+            Ok(connected_next(start, reader, bytes_written, request))
+        }
+    }
+
+    /// Create the first state for reading the response to `request`.
+    fn connected_next(
+        start: Instant,
+        reader: WrappedRecvStream,
+        bytes_written: u64,
+        request: GetRequest,
+    ) -> ConnectedNext {
+        let hash = request.hash;
+        let ranges_iter = RangesIter::new(request.ranges);
+        // this is in a box so we don't have to memcpy it on every state transition
+        let mut misc = Box::new(Misc {
+            start,
+            bytes_written,
+            ranges_iter,
+        });
+        match misc.ranges_iter.next() {
+            Some((offset, ranges)) => {
+                if offset == 0 {
+                    AtStartRoot {
+                        reader,
+                        ranges,
+                        misc,
+                        hash,
+                    }
+                    .into()
+                } else {
+                    AtStartChild {
+                        reader,
+                        ranges,
+                        misc,
+                        child_offset: offset - 1,
                     }
+                    .into()
                 }
-                None => AtClosing::new(misc, reader, true).into(),
+            }
+            None => AtClosing::new(misc, reader, true).into(),
+        }
+    }
+
+    /// Initial state of the get response machine for a provider defined request
+    #[derive(Debug)]
+    pub struct AtInitialCustom {
+        connection: quinn::Connection,
+        request: CustomGetRequest,
+    }
+
+    impl AtInitialCustom {
+        /// Create a new get response for a provider defined request
+        ///
+        /// `connection` is an existing connection
+        /// `request` is the query to be sent
+        pub fn new(connection: quinn::Connection, request: CustomGetRequest) -> Self {
+            Self {
+                connection,
+                request,
+            }
+        }
+
+        /// Initiate a new bidi stream to use for the get response
+        pub async fn next(self) -> Result<AtConnectedCustom, quinn::ConnectionError> {
+            let start = Instant::now();
+            let (writer, reader) = self.connection.open_bi().await?;
+            let reader = TrackingReader::new(TokioStreamReader::new(reader));
+            let writer = TrackingWriter::new(writer);
+            Ok(AtConnectedCustom {
+                start,
+                reader,
+                writer,
+                request: self.request,
             })
         }
     }
 
+    /// State of the get response machine for a provider defined request after the
+    /// handshake has been sent
+    #[derive(Debug)]
+    pub struct AtConnectedCustom {
+        start: Instant,
+        reader: WrappedRecvStream,
+        writer: TrackingWriter<quinn::SendStream>,
+        request: CustomGetRequest,
+    }
+
+    /// Error that you can get from [`AtConnectedCustom::next`]
+    #[derive(Debug, thiserror::Error)]
+    pub enum AtConnectedCustomNextError {
+        /// Error when sending the request
+        #[error("send: {0}")]
+        Send(#[from] ConnectedNextError),
+        /// Eof when reading the request chosen by the provider
+        ///
+        /// This indicates that the provider could not answer the query.
+        #[error("not found")]
+        NotFound,
+        /// The request chosen by the provider is too long
+        #[error("response request too big")]
+        RequestTooBig,
+        /// Error when deserializing the request chosen by the provider
+        #[error("postcard de: {0}")]
+        PostcardDe(postcard::Error),
+        /// Quinn read error when reading the request chosen by the provider
+        #[error("read: {0}")]
+        Read(quinn::ReadError),
+        /// Generic io error
+        #[error("io: {0}")]
+        Io(io::Error),
+    }
+
+    impl AtConnectedCustomNextError {
+        fn from_read(cause: io::Error) -> Self {
+            if cause.kind() == io::ErrorKind::UnexpectedEof {
+                Self::NotFound
+            } else if let Some(e) = cause
+                .get_ref()
+                .and_then(|x| x.downcast_ref::<quinn::ReadError>())
+            {
+                Self::Read(e.clone())
+            } else {
+                Self::Io(cause)
+            }
+        }
+    }
+
+    impl AtConnectedCustom {
+        /// Send the query, and read the request the provider chose to answer it
+        ///
+        /// Returns the request chosen by the provider, and the next state to read
+        /// the response, just like [`AtConnected::next`].
+        pub async fn next(self) -> Result<(GetRequest, ConnectedNext), AtConnectedCustomNextError> {
+            let Self {
+                start,
+                mut reader,
+                mut writer,
+                request,
+            } = self;
+            // 1. Send Request
+            {
+                debug!("sending custom request");
+                let request_bytes = postcard::to_stdvec(&Request::CustomGet(request))
+                    .map_err(ConnectedNextError::PostcardSer)?;
+
+                if request_bytes.len() > MAX_MESSAGE_SIZE {
+                    return Err(ConnectedNextError::RequestTooBig.into());
+                }
+
+                // write the request itself
+                writer
+                    .write_all(&request_bytes)
+                    .await
+                    .map_err(ConnectedNextError::from_io)?;
+            }
+
+            // 2. Finish writing before expecting a response
+            let (mut writer, bytes_written) = writer.into_parts();
+            writer.finish().await.map_err(ConnectedNextError::from)?;
+
+            // 3. Read the request chosen by the provider
+            let len = reader
+                .read::<8>()
+                .await
+                .map_err(AtConnectedCustomNextError::from_read)?;
+            let len = u64::from_le_bytes(len);
+            if len > MAX_MESSAGE_SIZE as u64 {
+                return Err(AtConnectedCustomNextError::RequestTooBig);
+            }
+            let request_bytes = reader
+                .read_bytes(len as usize)
+                .await
+                .map_err(AtConnectedCustomNextError::from_read)?;
+            if request_bytes.len() as u64 != len {
+                return Err(AtConnectedCustomNextError::NotFound);
+            }
+            let request: GetRequest = postcard::from_bytes(&request_bytes)
+                .map_err(AtConnectedCustomNextError::PostcardDe)?;
+            debug!(hash = %request.hash, "provider chose request");
+
+            let next = connected_next(start, reader, bytes_written, request.clone());
+            Ok((request, next))
+        }
+    }
+
     /// State of the get response when we start reading a collection
     #[derive(Debug)]
     pub struct AtStartRoot {
@@ -8,6 +8,7 @@ use serde::{Deserialize, Serialize};
 use crate::protocol::RangeSpec;
 use crate::store::BaoBlobSize;
 use crate::store::FallibleProgressBatchWriter;
+use std::collections::BTreeSet;
 use std::io;
 use std::num::NonZeroU64;
 
@@ -15,6 +16,7 @@ use crate::hashseq::parse_hash_seq;
 use crate::store::BaoBatchWriter;
 
 use crate::{
+    dag::DagNode,
     get::{
         self,
         error::GetError,
@@ -54,6 +56,7 @@ pub async fn get_to_db<
     match format {
         BlobFormat::Raw => get_blob(db, get_conn, hash, sender).await,
         BlobFormat::HashSeq => get_hash_seq(db, get_conn, hash, sender).await,
+        BlobFormat::Dag => get_dag(db, get_conn, hash, sender).await,
     }
 }
 
@@ -144,6 +147,11 @@ async fn get_blob<
 
 /// Given a partial entry, get the valid ranges.
 pub async fn valid_ranges<D: MapMut>(entry: &D::EntryMut) -> anyhow::Result<ChunkRanges> {
+    entry_valid_ranges(entry).await
+}
+
+/// Given any entry, get the valid ranges.
+pub(crate) async fn entry_valid_ranges(entry: &impl MapEntry) -> anyhow::Result<ChunkRanges> {
     use tracing::trace as log;
     // compute the valid range from just looking at the data file
     let mut data_reader = entry.data_reader().await?;
@@ -468,6 +476,82 @@ async fn get_hash_seq<
     Ok(stats)
 }
 
+/// Get a DAG, one node at a time.
+///
+/// Every node is requested like a hash sequence. Once a node is complete, its children in
+/// [`BlobFormat::HashSeq`] or [`BlobFormat::Dag`] are requested with separate requests over
+/// the same connection. Nodes that are already complete locally only cost a request if some
+/// of their children are missing.
+///
+/// [`DownloadProgress::FoundHashSeq`] and [`DownloadProgress::FoundLocal`] are only reported
+/// for the root node.
+async fn get_dag<
+    D: BaoStore,
+    C: FnOnce() -> F,
+    F: Future<Output = anyhow::Result<quinn::Connection>>,
+>(
+    db: &D,
+    get_conn: C,
+    root_hash: &Hash,
+    sender: impl ProgressSender<Msg = DownloadProgress> + IdGenerator,
+) -> Result<Stats, GetError> {
+    let mut pending = Some(get_conn);
+    let mut conn = None;
+    let nested_sender = sender.clone().with_filter_map(|msg| match msg {
+        DownloadProgress::FoundHashSeq { .. } | DownloadProgress::FoundLocal { .. } => None,
+        msg => Some(msg),
+    });
+    let mut stats = Stats::default();
+    let mut visited = BTreeSet::new();
+    let mut stack = vec![HashAndFormat::dag(*root_hash)];
+    while let Some(HashAndFormat { hash, format }) = stack.pop() {
+        if !visited.insert(hash) {
+            continue;
+        }
+        let state = (&mut pending, &mut conn);
+        let get_conn = move || {
+            let (pending, conn) = state;
+            lazy_connect(pending, conn)
+        };
+        let level = if hash == *root_hash {
+            get_hash_seq(db, get_conn, &hash, sender.clone()).await?
+        } else {
+            get_hash_seq(db, get_conn, &hash, nested_sender.clone()).await?
+        };
+        stats.bytes_written += level.bytes_written;
+        stats.bytes_read += level.bytes_read;
+        stats.elapsed += level.elapsed;
+        if format.is_dag() {
+            let node = DagNode::load(db, &hash)
+                .await
+                .map_err(GetError::NoncompliantNode)?;
+            // push in reverse order, so children are visited in order
+            for child in node.children().iter().rev() {
+                if !child.format.is_raw() {
+                    stack.push(*child);
+                }
+            }
+        }
+    }
+    Ok(stats)
+}
+
+/// Open the connection on first use, and reuse it afterwards.
+async fn lazy_connect<C: FnOnce() -> F, F: Future<Output = anyhow::Result<quinn::Connection>>>(
+    get_conn: &mut Option<C>,
+    conn: &mut Option<quinn::Connection>,
+) -> anyhow::Result<quinn::Connection> {
+    if let Some(conn) = conn {
+        return Ok(conn.clone());
+    }
+    let get_conn = get_conn
+        .take()
+        .ok_or_else(|| anyhow!("failed to connect before"))?;
+    let res = get_conn().await?;
+    *conn = Some(res.clone());
+    Ok(res)
+}
+
 /// Information about a the status of a blob in a store.
 #[derive(Debug, Clone)]
 pub enum BlobInfo<D: BaoStore> {
@@ -596,7 +680,7 @@ pub enum BlobId {
 }
 
 impl BlobId {
-    fn from_offset(id: u64) -> Self {
+    pub(crate) fn from_offset(id: u64) -> Self {
         NonZeroU64::new(id).map(Self::Child).unwrap_or(Self::Root)
     }
 }
@@ -123,6 +123,32 @@ impl From<crate::get::fsm::ConnectedNextError> for GetError {
     }
 }
 
+impl From<crate::get::fsm::AtConnectedCustomNextError> for GetError {
+    fn from(value: crate::get::fsm::AtConnectedCustomNextError) -> Self {
+        use crate::get::fsm::AtConnectedCustomNextError::*;
+        match value {
+            Send(e) => e.into(),
+            e @ NotFound => {
+                // > This indicates that the provider could not answer the query.
+                GetError::NotFound(e.into())
+            }
+            e @ RequestTooBig => {
+                // the provider sent something we will never accept
+                GetError::NoncompliantNode(e.into())
+            }
+            e @ PostcardDe(_) => {
+                // the provider sent something that is not a request
+                GetError::NoncompliantNode(e.into())
+            }
+            Read(e) => e.into(),
+            e @ Io(_) => {
+                // io errors are likely recoverable
+                GetError::Io(e.into())
+            }
+        }
+    }
+}
+
 impl From<crate::get::fsm::AtBlobHeaderNextError> for GetError {
     fn from(value: crate::get::fsm::AtBlobHeaderNextError) -> Self {
         use crate::get::fsm::AtBlobHeaderNextError::*;
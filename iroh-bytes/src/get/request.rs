@@ -3,7 +3,10 @@ use std::sync::Arc;
 
 use crate::{
     hashseq::HashSeq,
-    protocol::{GetRequest, RangeSpecSeq},
+    protocol::{
+        AvailabilityRequest, AvailabilityResponse, GetRequest, RangeSpecSeq, Request,
+        MAX_MESSAGE_SIZE,
+    },
     Hash, HashAndFormat,
 };
 use bao_tree::{ChunkNum, ChunkRanges};
@@ -35,6 +38,24 @@ pub async fn get_unverified_size(
     Ok((size, stats))
 }
 
+/// Ask a peer which ranges of a blob, or of a hash seq and its children, it has.
+///
+/// Note that the response is not verified. It only tells what the peer claims to have,
+/// so a subsequent get request can still fail.
+pub async fn get_availability(
+    connection: &quinn::Connection,
+    request: AvailabilityRequest,
+) -> anyhow::Result<AvailabilityResponse> {
+    tracing::trace!("Getting availability of {}", request.hash.to_hex());
+    let request_bytes = postcard::to_stdvec(&Request::from(request))?;
+    let (mut writer, mut reader) = connection.open_bi().await?;
+    writer.write_all(&request_bytes).await?;
+    writer.finish().await?;
+    let response_bytes = reader.read_to_end(MAX_MESSAGE_SIZE).await?;
+    let response = postcard::from_bytes(&response_bytes)?;
+    Ok(response)
+}
+
 /// Get the verified size of a blob from a peer.
 ///
 /// This asks for the last chunk of the blob and validates the response.
@@ -3,6 +3,7 @@
 #![deny(missing_docs, rustdoc::broken_intra_doc_links)]
 #![recursion_limit = "256"]
 
+pub mod dag;
 #[cfg(feature = "downloader")]
 pub mod downloader;
 pub mod export;
@@ -13,6 +14,7 @@ pub mod hashseq;
 pub mod metrics;
 pub mod protocol;
 pub mod provider;
+pub mod push;
 pub mod store;
 pub mod util;
 
@@ -8,6 +8,9 @@
 //! The protocol is a request/response protocol with two parties, a *provider* that
 //! serves blobs and a *getter* that requests blobs.
 //!
+//! A node can also offer data to another node using a push request, see below. In
+//! that case the roles are reversed once the other node accepts the offer.
+//!
 //! # Goals
 //!
 //! - Be paranoid about data integrity.
@@ -55,14 +58,19 @@
 //! deeply nested graph data, you will need to either do multiple requests or flatten
 //! the graph into a single temporary collection.
 //!
+//!   The [DAG format](crate::dag) does the multiple requests for you: a getter requests
+//! one node at a time, with a normal request for the node and its direct children, and
+//! then requests the child nodes it found. The provider does not need to know about
+//! the format.
+//!
 //! - Do not support discovery.
 //!
-//!   The protocol does not yet have a discovery mechanism for asking the provider
-//! what ranges are available for a given blob. Currently you have to have some
-//! out-of-band knowledge about what node has data for a given hash, or you can
-//! just try to retrieve the data and see if it is available.
+//!   The protocol does not have a discovery mechanism for finding nodes that
+//! have data for a given hash. You have to have some out-of-band knowledge about
+//! what node has data for a given hash.
 //!
-//! A discovery protocol is planned in the future though.
+//! Once you know a node, you can ask it what ranges it has available for a given
+//! blob using an [`AvailabilityRequest`], see below.
 //!
 //! # Requests
 //!
@@ -87,6 +95,20 @@
 //! the same format as the getter defined requests, followed by the bao encoded
 //! data. From then on the protocol is the same as for getter defined requests.
 //!
+//! The serialized request is prefixed with its length as a little endian `u64`,
+//! so the getter knows where the request ends and the data begins. If the provider
+//! can not answer the query, it closes the stream without sending a request.
+//!
+//! A provider defined request is sent as a [`CustomGetRequest`]. How the query is
+//! evaluated is up to the [`CustomGetHandler`] configured on the provider.
+//!
+//! ```rust
+//! # use iroh_bytes::protocol::CustomGetRequest;
+//! let request = CustomGetRequest::new(b"latest build for branch main".to_vec());
+//! ```
+//!
+//! [`CustomGetHandler`]: crate::provider::CustomGetHandler
+//!
 //! ## Specifying the required data
 //!
 //! A [`GetRequest`] contains a hash and a specification of what data related to
@@ -291,6 +313,47 @@
 //! [`RangeSpecSeq`] should be efficient even in case of very fragmented availability
 //! of chunks, like a download from multiple providers that was frequently interrupted.
 //!
+//! ## Availability requests
+//!
+//! Before downloading, a getter can ask the provider which parts of a blob it
+//! has, using an [`AvailabilityRequest`]. This is useful to pick providers that
+//! actually hold (partial) data, instead of trying to download and failing.
+//!
+//! The provider responds with a single postcard encoded [`AvailabilityResponse`]
+//! and then closes the stream. The response contains the ranges the provider
+//! has verified, and the size of the blob if known.
+//!
+//! For a hash sequence, the response also contains the availability of each
+//! child, in the order in which the children appear in the sequence. This
+//! is only possible if the provider has the complete hash sequence itself.
+//!
+//! ```rust
+//! # use iroh_bytes::protocol::AvailabilityRequest;
+//! # let hash: iroh_bytes::Hash = [0; 32].into();
+//! let request = AvailabilityRequest::hash_seq(hash);
+//! ```
+//!
+//! ## Push requests
+//!
+//! A node can offer a blob or a hash sequence to a provider with a [`PushRequest`].
+//! The provider decides whether it wants to store the data, see
+//! [`PushHandler`]. If it does, it becomes the getter: it opens a new stream on the
+//! same connection and sends a normal [`GetRequest`] for the data it does not have
+//! yet, and the pushing node answers it like a provider would. So the provider never
+//! has to dial back, and all data is verified as usual.
+//!
+//! Once the provider is done, it responds to the push request with a single postcard
+//! encoded [`PushResponse`] and closes the stream. The pushing node only answers get
+//! requests for the data it offered.
+//!
+//! ```rust
+//! # use iroh_bytes::protocol::PushRequest;
+//! # let hash: iroh_bytes::Hash = [0; 32].into();
+//! let request = PushRequest::hash_seq(hash, 1024);
+//! ```
+//!
+//! [`PushHandler`]: crate::provider::PushHandler
+//!
 //! # Responses
 //!
 //! The response stream contains the bao encoded bytes for the requested data.
@@ -339,13 +402,14 @@
 //! In case nodes are permanently exchanging data, it is probably valuable to
 //! keep a connection open and reuse it for multiple requests.
 use bao_tree::{ChunkNum, ChunkRanges};
+use bytes::Bytes;
 use derive_more::From;
 use quinn::VarInt;
 use serde::{Deserialize, Serialize};
 mod range_spec;
 pub use range_spec::{NonEmptyRequestRangeSpecIter, RangeSpec, RangeSpecSeq};
 
-use crate::Hash;
+use crate::{store::BaoBlobSize, util::Tag, BlobFormat, Hash, HashAndFormat};
 
 /// Maximum message size is limited to 100MiB for now.
 pub const MAX_MESSAGE_SIZE: usize = 1024 * 1024 * 100;
@@ -358,6 +422,12 @@ pub const ALPN: &[u8] = b"/iroh-bytes/4";
 pub enum Request {
     /// A get request for a blob or collection
     Get(GetRequest),
+    /// A request for the ranges of a blob or collection that are available
+    Availability(AvailabilityRequest),
+    /// A get request that is defined by the provider, based on a query
+    CustomGet(CustomGetRequest),
+    /// An offer to send a blob or collection to the provider
+    Push(PushRequest),
 }
 
 /// A request
@@ -417,6 +487,161 @@ impl GetRequest {
     }
 }
 
+/// A provider defined request
+///
+/// The provider evaluates the query in `data` and responds with the [`GetRequest`]
+/// it chose, followed by the data for it.
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone)]
+pub struct CustomGetRequest {
+    /// The opaque query data, interpreted by the provider
+    pub data: Bytes,
+}
+
+impl CustomGetRequest {
+    /// Create a new provider defined request from the query data
+    pub fn new(data: impl Into<Bytes>) -> Self {
+        Self { data: data.into() }
+    }
+}
+
+/// A request for the available ranges of a blob, or of a hash sequence and its children
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone)]
+pub struct AvailabilityRequest {
+    /// blake3 hash
+    pub hash: Hash,
+    /// The format of the data
+    ///
+    /// For [`BlobFormat::HashSeq`], the availability of all children is requested as well.
+    /// For [`BlobFormat::Dag`], this includes the direct children of the node only.
+    pub format: BlobFormat,
+}
+
+impl AvailabilityRequest {
+    /// Request the availability of a single blob
+    pub fn single(hash: Hash) -> Self {
+        Self {
+            hash,
+            format: BlobFormat::Raw,
+        }
+    }
+
+    /// Request the availability of a hash sequence and all its children
+    pub fn hash_seq(hash: Hash) -> Self {
+        Self {
+            hash,
+            format: BlobFormat::HashSeq,
+        }
+    }
+}
+
+impl From<HashAndFormat> for AvailabilityRequest {
+    fn from(value: HashAndFormat) -> Self {
+        Self {
+            hash: value.hash,
+            format: value.format,
+        }
+    }
+}
+
+/// The response to an [`AvailabilityRequest`]
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone)]
+pub struct AvailabilityResponse {
+    /// The availability of the requested blobs
+    ///
+    /// The first element is the root, all subsequent elements are children, in the
+    /// order in which they appear in the hash sequence. Children are only present if
+    /// the provider has the complete hash sequence.
+    pub blobs: Vec<BlobAvailability>,
+}
+
+/// The availability of a single blob on the provider
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone)]
+pub struct BlobAvailability {
+    /// blake3 hash
+    pub hash: Hash,
+    /// The size of the blob, if the provider has an entry for it
+    pub size: Option<BaoBlobSize>,
+    /// The verified ranges the provider can send
+    ///
+    /// This is [`ChunkRanges::all()`] for complete blobs, and empty if the
+    /// provider has no data for the blob at all.
+    pub ranges: RangeSpec,
+}
+
+impl BlobAvailability {
+    /// Availability of a blob the provider has no data for
+    pub fn missing(hash: Hash) -> Self {
+        Self {
+            hash,
+            size: None,
+            ranges: RangeSpec::EMPTY,
+        }
+    }
+
+    /// Returns `true` if the provider has the complete blob
+    pub fn is_complete(&self) -> bool {
+        self.ranges.is_all()
+    }
+}
+
+/// An offer to send a blob or hash sequence to the provider
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone)]
+pub struct PushRequest {
+    /// blake3 hash
+    pub hash: Hash,
+    /// The format of the data
+    ///
+    /// For [`BlobFormat::HashSeq`], all children are offered as well. For
+    /// [`BlobFormat::Dag`], the whole DAG is offered.
+    pub format: BlobFormat,
+    /// The total size of the offered data in bytes, including all children
+    ///
+    /// This is claimed by the sender and not verified before the transfer.
+    pub size: u64,
+}
+
+impl PushRequest {
+    /// Offer a single blob
+    pub fn single(hash: Hash, size: u64) -> Self {
+        Self {
+            hash,
+            format: BlobFormat::Raw,
+            size,
+        }
+    }
+
+    /// Offer a hash sequence and all its children
+    pub fn hash_seq(hash: Hash, size: u64) -> Self {
+        Self {
+            hash,
+            format: BlobFormat::HashSeq,
+            size,
+        }
+    }
+
+    /// The offered content
+    pub fn content(&self) -> HashAndFormat {
+        HashAndFormat {
+            hash: self.hash,
+            format: self.format,
+        }
+    }
+}
+
+/// The response to a [`PushRequest`]
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone)]
+pub enum PushResponse {
+    /// The provider did not want the data
+    Rejected,
+    /// The provider has stored the data
+    Stored {
+        /// The tag under which the data was stored, if any
+        tag: Option<Tag>,
+    },
+    /// The provider wanted the data, but getting it failed
+    Failed,
+}
+
 /// Reasons to close connections or stop streams.
 ///
 /// A QUIC **connection** can be *closed* and a **stream** can request the other side to
@@ -483,7 +708,7 @@ impl TryFrom<VarInt> for Closed {
 mod tests {
     use iroh_test::{assert_eq_hex, hexdump::parse_hexdump};
 
-    use super::{GetRequest, Request};
+    use super::{AvailabilityRequest, CustomGetRequest, GetRequest, PushRequest, Request};
 
     #[test]
     fn request_wire_format() {
@@ -505,6 +730,31 @@ mod tests {
                     01000100 # the RangeSpecSeq
             ",
             ),
+            (
+                Request::from(AvailabilityRequest::hash_seq(hash)),
+                r"
+                    01 # enum variant for AvailabilityRequest
+                    dadadadadadadadadadadadadadadadadadadadadadadadadadadadadadadada # the hash
+                    01 # the format
+            ",
+            ),
+            (
+                Request::from(CustomGetRequest::new(&b"query"[..])),
+                r"
+                    02 # enum variant for CustomGetRequest
+                    05 # the length of the query
+                    7175657279 # the query
+            ",
+            ),
+            (
+                Request::from(PushRequest::single(hash, 1024)),
+                r"
+                    03 # enum variant for PushRequest
+                    dadadadadadadadadadadadadadadadadadadadadadadadadadadadadadadada # the hash
+                    00 # the format
+                    8008 # the size
+            ",
+            ),
         ];
         for (case, expected_hex) in cases {
             let expected = parse_hexdump(expected_hex).unwrap();
@@ -11,6 +11,8 @@ use bao_tree::{ChunkNum, ChunkRanges, ChunkRangesRef};
 use serde::{Deserialize, Serialize};
 use smallvec::{smallvec, SmallVec};
 
+use crate::IROH_BLOCK_SIZE;
+
 /// A chunk range specification as a sequence of chunk offsets.
 ///
 /// Offsets encode alternating spans starting on 0, where the first span is always
@@ -224,6 +226,79 @@ impl RangeSpecSeq {
         Self(res)
     }
 
+    /// Restrict this sequence to the part that is also selected by `allowed`.
+    ///
+    /// The result is the longest prefix of this sequence, in the order in which the
+    /// data is sent, that is selected by `allowed`. Everything from the first chunk that
+    /// is not allowed onwards is removed. Since data is sent in chunk groups, the cut is
+    /// rounded down to a chunk group boundary.
+    ///
+    /// Truncating instead of intersecting makes sure that the response to the result is
+    /// a prefix of the response to the original request. A getter that sent the original
+    /// request receives valid data, and then sees the stream end as if the provider did
+    /// not have the rest.
+    pub fn truncate_to(&self, allowed: &RangeSpecSeq) -> RangeSpecSeq {
+        let requested = self.starts();
+        let allowed = allowed.starts();
+        let mut boundaries = requested
+            .iter()
+            .chain(allowed.iter())
+            .map(|(start, _)| *start)
+            .chain(std::iter::once(0))
+            .collect::<Vec<_>>();
+        boundaries.sort_unstable();
+        boundaries.dedup();
+        let mut res = Vec::new();
+        for start in boundaries {
+            let ranges = spec_at(&requested, start).to_chunk_ranges();
+            let allowed = spec_at(&allowed, start).to_chunk_ranges();
+            let not_allowed: ChunkRanges = ranges.difference(&allowed);
+            let Some(first) = not_allowed.boundaries().first() else {
+                res.push((start, RangeSpec::new(ranges)));
+                continue;
+            };
+            let group = 1u64 << IROH_BLOCK_SIZE.chunk_log();
+            let cut = ChunkNum(first.0 / group * group);
+            let truncated: ChunkRanges = ranges.intersection(&ChunkRanges::from(..cut));
+            res.push((start, RangeSpec::new(truncated)));
+            res.push((start.saturating_add(1), RangeSpec::EMPTY));
+            break;
+        }
+        Self::from_starts(res)
+    }
+
+    /// The range specs of this sequence, together with the offset at which they start.
+    fn starts(&self) -> Vec<(u64, &RangeSpec)> {
+        let mut offset = 0u64;
+        self.0
+            .iter()
+            .map(|(count, spec)| {
+                offset = offset.saturating_add(*count);
+                (offset, spec)
+            })
+            .collect()
+    }
+
+    /// Creates a new range spec sequence from range specs and the offset at which they start.
+    ///
+    /// The offsets must be sorted.
+    fn from_starts(starts: impl IntoIterator<Item = (u64, RangeSpec)>) -> Self {
+        let mut res: SmallVec<[(u64, RangeSpec); 2]> = SmallVec::new();
+        let mut prev_start = 0;
+        for (start, spec) in starts {
+            let prev = res
+                .last()
+                .map(|(_, spec)| spec)
+                .unwrap_or(&EMPTY_RANGE_SPEC);
+            if &spec == prev {
+                continue;
+            }
+            res.push((start - prev_start, spec));
+            prev_start = start;
+        }
+        Self(res)
+    }
+
     /// An infinite iterator of range specs for blobs in the sequence.
     ///
     /// Each item yielded by the iterator is the [`RangeSpec`] for a blob in the sequence.
@@ -252,6 +327,16 @@ impl RangeSpecSeq {
 
 static EMPTY_RANGE_SPEC: RangeSpec = RangeSpec::EMPTY;
 
+/// The range spec that is in effect at `offset`, given range specs and their start offsets.
+fn spec_at<'a>(starts: &[(u64, &'a RangeSpec)], offset: u64) -> &'a RangeSpec {
+    starts
+        .iter()
+        .take_while(|(start, _)| *start <= offset)
+        .last()
+        .map(|(_, spec)| *spec)
+        .unwrap_or(&EMPTY_RANGE_SPEC)
+}
+
 /// An infinite iterator yielding [`RangeSpec`]s for each blob in a sequence.
 ///
 /// The first item yielded is the [`RangeSpec`] for the first blob in the sequence, the
@@ -505,6 +590,63 @@ mod tests {
         }
     }
 
+    #[test]
+    fn range_spec_seq_truncate_to() {
+        let all = RangeSpecSeq::all();
+        let cases = [
+            // everything allowed
+            (all.clone(), all.clone(), all.clone()),
+            // nothing allowed
+            (
+                all.clone(),
+                RangeSpecSeq::empty(),
+                RangeSpecSeq::from_ranges([ChunkRanges::empty()]),
+            ),
+            // the cut is rounded down to a chunk group boundary
+            (
+                RangeSpecSeq::from_ranges([ChunkRanges::from(..ChunkNum(64))]),
+                RangeSpecSeq::from_ranges([ChunkRanges::from(..ChunkNum(40))]),
+                RangeSpecSeq::from_ranges([ChunkRanges::from(..ChunkNum(32))]),
+            ),
+            // everything after a child that is not allowed is removed
+            (
+                all.clone(),
+                RangeSpecSeq::from_ranges_infinite([
+                    ChunkRanges::all(),
+                    ChunkRanges::all(),
+                    ChunkRanges::empty(),
+                    ChunkRanges::all(),
+                ]),
+                RangeSpecSeq::from_ranges([ChunkRanges::all(), ChunkRanges::all()]),
+            ),
+            // children that are not requested do not need to be allowed
+            (
+                RangeSpecSeq::from_ranges([
+                    ChunkRanges::all(),
+                    ChunkRanges::empty(),
+                    ChunkRanges::all(),
+                ]),
+                RangeSpecSeq::from_ranges_infinite([
+                    ChunkRanges::all(),
+                    ChunkRanges::empty(),
+                    ChunkRanges::all(),
+                ]),
+                RangeSpecSeq::from_ranges([
+                    ChunkRanges::all(),
+                    ChunkRanges::empty(),
+                    ChunkRanges::all(),
+                ]),
+            ),
+        ];
+        for (requested, allowed, expected) in cases {
+            let actual = requested.truncate_to(&allowed);
+            assert_eq!(
+                actual.iter().take(8).collect::<Vec<_>>(),
+                expected.iter().take(8).collect::<Vec<_>>()
+            );
+        }
+    }
+
     /// Test that the roundtrip from [`Vec<ChunkRanges>`] via [`RangeSpec`] to [`RangeSpecSeq`]  and back works.
     #[test]
     fn range_spec_seq_roundtrip_cases() {
@@ -1,11 +1,18 @@
 //! The server side API
+use std::collections::BTreeSet;
 use std::fmt::Debug;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 use std::time::Duration;
 
 use anyhow::{Context, Result};
 use bao_tree::io::fsm::{encode_ranges_validated, Outboard};
 use bao_tree::io::EncodeError;
+use bao_tree::ChunkRanges;
+use bytes::Bytes;
 use futures::future::BoxFuture;
+use futures::FutureExt;
+use iroh_base::key::NodeId;
 use iroh_base::rpc::RpcError;
 use iroh_io::stats::{
     SliceReaderStats, StreamWriterStats, TrackingSliceReader, TrackingStreamWriter,
@@ -16,11 +23,21 @@ use tokio_util::task::LocalPoolHandle;
 use tracing::{debug, debug_span, info, trace, warn};
 use tracing_futures::Instrument;
 
+use crate::get::db::{entry_valid_ranges, get_to_db, DownloadProgress};
 use crate::hashseq::parse_hash_seq;
-use crate::protocol::{GetRequest, RangeSpec, Request};
+use crate::protocol::{
+    AvailabilityRequest, AvailabilityResponse, BlobAvailability, CustomGetRequest, GetRequest,
+    PushRequest, PushResponse, RangeSpec, RangeSpecSeq, Request,
+};
 use crate::store::*;
-use crate::util::Tag;
-use crate::{BlobFormat, Hash};
+use crate::util::progress::{IdGenerator, ProgressSendError, ProgressSendResult, ProgressSender};
+use crate::util::{SetTagOption, Tag};
+use crate::{BlobFormat, Hash, HashAndFormat};
+
+mod limits;
+
+use self::limits::NodeLimiter;
+pub use self::limits::{UploadLimiter, UploadLimits};
 
 /// Events emitted by the provider informing about the current status.
 #[derive(Debug, Clone)]
@@ -48,6 +65,15 @@ pub enum Event {
         /// The hash for which the client wants to receive data.
         hash: Hash,
     },
+    /// An availability request was received from a client.
+    AvailabilityRequestReceived {
+        /// An unique connection id.
+        connection_id: u64,
+        /// An identifier uniquely identifying this request.
+        request_id: u64,
+        /// The hash for which the client wants to know the available ranges.
+        hash: Hash,
+    },
     /// A request was received from a client.
     CustomGetRequestReceived {
         /// An unique connection id.
@@ -57,6 +83,52 @@ pub enum Event {
         /// The size of the custom get request.
         len: usize,
     },
+    /// A request was denied by the [`RequestAuthorizationHandler`].
+    GetRequestDenied {
+        /// An unique connection id.
+        connection_id: u64,
+        /// An identifier uniquely identifying this transfer request.
+        request_id: u64,
+        /// The hash for which the client wanted to receive data.
+        hash: Hash,
+    },
+    /// A push request was received from a client.
+    PushRequestReceived {
+        /// An unique connection id.
+        connection_id: u64,
+        /// An identifier uniquely identifying this request.
+        request_id: u64,
+        /// The hash of the offered data.
+        hash: Hash,
+    },
+    /// A push request was answered.
+    PushFinished {
+        /// An unique connection id.
+        connection_id: u64,
+        /// An identifier uniquely identifying this request.
+        request_id: u64,
+        /// The hash of the offered data.
+        hash: Hash,
+        /// The response sent to the client.
+        response: PushResponse,
+    },
+    /// A request has to wait because the client already has the maximum number of
+    /// concurrent requests, see [`UploadLimits::max_concurrent_requests_per_node`].
+    TransferQueued {
+        /// An unique connection id.
+        connection_id: u64,
+        /// An identifier uniquely identifying this transfer request.
+        request_id: u64,
+    },
+    /// A transfer is slowed down by the upload bandwidth limits.
+    ///
+    /// This is sent at most once per transfer, when it is delayed for the first time.
+    TransferThrottled {
+        /// An unique connection id.
+        connection_id: u64,
+        /// An identifier uniquely identifying this transfer request.
+        request_id: u64,
+    },
     /// A sequence of hashes has been found and is being transferred.
     TransferHashSeqStarted {
         /// An unique connection id.
@@ -109,6 +181,8 @@ pub struct TransferStats {
     pub read: SliceReaderStats,
     /// The total duration of the transfer.
     pub duration: Duration,
+    /// The time spent waiting for the upload bandwidth limits.
+    pub throttled: Duration,
 }
 
 /// Progress updates for the add operation.
@@ -278,13 +352,203 @@ pub trait EventSender: Clone + Sync + Send + 'static {
     fn send(&self, event: Event) -> BoxFuture<()>;
 }
 
+/// Handler for provider defined requests.
+///
+/// The handler evaluates the query of a [`CustomGetRequest`] and returns the
+/// [`GetRequest`] that will be answered. The request is sent to the getter before
+/// the data, so the getter knows what it receives.
+pub trait CustomGetHandler: Send + Sync + Debug + 'static {
+    /// Handle the query data of a custom get request.
+    fn handle(&self, data: Bytes) -> BoxFuture<'static, anyhow::Result<GetRequest>>;
+}
+
+/// A custom get handler that rejects all custom get requests.
+impl CustomGetHandler for () {
+    fn handle(&self, _data: Bytes) -> BoxFuture<'static, anyhow::Result<GetRequest>> {
+        async move { Err(anyhow::anyhow!("no custom get handler defined")) }.boxed()
+    }
+}
+
+/// The decision of a [`PushHandler`] about a push request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PushDecision {
+    /// Do not store the data.
+    Reject,
+    /// Get the data from the pushing node and store it.
+    Accept {
+        /// How to tag the data once it is complete.
+        ///
+        /// If this is `None`, the data is not protected from garbage collection.
+        tag: Option<SetTagOption>,
+    },
+}
+
+/// Handler that decides whether to store data that other nodes push.
+///
+/// This is the place to implement quotas or to only accept data from trusted nodes.
+///
+/// The id of the pushing node is only known with the `iroh-net` feature, see
+/// [`handle_connection`]. Without it, all push requests are rejected.
+pub trait PushHandler: Send + Sync + Debug + 'static {
+    /// Decide whether to store the data offered by `node_id`.
+    ///
+    /// An error is treated like [`PushDecision::Reject`].
+    fn accept(
+        &self,
+        node_id: NodeId,
+        request: PushRequest,
+    ) -> BoxFuture<'static, anyhow::Result<PushDecision>>;
+}
+
+/// A push handler that rejects all push requests.
+impl PushHandler for () {
+    fn accept(
+        &self,
+        _node_id: NodeId,
+        _request: PushRequest,
+    ) -> BoxFuture<'static, anyhow::Result<PushDecision>> {
+        async move { Ok(PushDecision::Reject) }.boxed()
+    }
+}
+
+/// The decision of a [`RequestAuthorizationHandler`] about a request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Authorization {
+    /// Serve the request as it is.
+    Allow,
+    /// Do not serve anything, as if the provider did not have the data.
+    Deny,
+    /// Serve only the part of the request that is within the given ranges.
+    ///
+    /// The request is answered up to the first chunk group that is not allowed, see
+    /// [`RangeSpecSeq::truncate_to`].
+    Narrow(RangeSpecSeq),
+}
+
+/// Handler that decides which requests are served to which nodes.
+///
+/// The handler is called for every get request, every provider defined get request
+/// once it has been evaluated, and every availability request, before any data
+/// is sent.
+///
+/// The id of the requesting node is only known with the `iroh-net` feature, see
+/// [`handle_connection`]. Without it, all requests are denied.
+pub trait RequestAuthorizationHandler: Send + Sync + Debug + 'static {
+    /// Decide whether the node `node_id` may receive the data of `request`.
+    ///
+    /// An error is treated like [`Authorization::Deny`].
+    fn authorize(
+        &self,
+        node_id: NodeId,
+        request: GetRequest,
+    ) -> BoxFuture<'static, anyhow::Result<Authorization>>;
+}
+
+/// The authorization state of a single connection.
+#[derive(Debug, Clone)]
+struct ConnectionAuthorization {
+    handler: Arc<dyn RequestAuthorizationHandler>,
+    /// The remote node, or `None` if it could not be determined.
+    node_id: Option<NodeId>,
+}
+
+impl ConnectionAuthorization {
+    /// Returns the part of the request that may be served, or `None` if it is denied.
+    async fn authorize(&self, request: GetRequest) -> Option<GetRequest> {
+        let Some(node_id) = self.node_id else {
+            debug!("denying request from unknown node");
+            return None;
+        };
+        match self.handler.authorize(node_id, request.clone()).await {
+            Ok(Authorization::Allow) => Some(request),
+            Ok(Authorization::Deny) => None,
+            Ok(Authorization::Narrow(allowed)) => Some(GetRequest::new(
+                request.hash,
+                request.ranges.truncate_to(&allowed),
+            )),
+            Err(err) => {
+                warn!(node = %node_id.fmt_short(), "request authorization failed: {err:#}");
+                None
+            }
+        }
+    }
+}
+
+/// Returns the node id of the remote end of the connection.
+///
+/// The node id is taken from the certificate of the connection, which requires the `iroh-net`
+/// feature. Without it, the node id is never known.
+fn remote_node_id(connection: &quinn::Connection) -> Option<NodeId> {
+    #[cfg(feature = "iroh-net")]
+    {
+        iroh_net::magic_endpoint::get_remote_node_id(connection)
+            .map_err(|err| {
+                let remote_addr = connection.remote_address();
+                warn!(%remote_addr, "unable to get remote node id: {err:#}")
+            })
+            .ok()
+    }
+    #[cfg(not(feature = "iroh-net"))]
+    {
+        let _ = connection;
+        None
+    }
+}
+
+/// Default for [`ProviderOptions::max_push_size`], 1 GiB.
+pub const DEFAULT_MAX_PUSH_SIZE: u64 = 1024 * 1024 * 1024;
+
+/// Options for serving the requests of connections, see [`handle_connection`].
+///
+/// The default rejects all provider defined get requests and push requests, serves all
+/// other requests and does not limit uploads.
+#[derive(Debug, Clone)]
+pub struct ProviderOptions {
+    /// Handler for provider defined get requests.
+    pub custom_get_handler: Arc<dyn CustomGetHandler>,
+    /// Handler for push requests.
+    pub push_handler: Arc<dyn PushHandler>,
+    /// Handler which checks all requests before they are served.
+    ///
+    /// If `None`, all requests are served.
+    pub authorization_handler: Option<Arc<dyn RequestAuthorizationHandler>>,
+    /// Upload limits.
+    ///
+    /// The limiter should be shared by all connections, since its limits apply to all of them.
+    pub limiter: UploadLimiter,
+    /// Maximum size in bytes of the data of a single push request.
+    ///
+    /// Larger push requests are rejected without asking the [`PushHandler`].
+    pub max_push_size: u64,
+}
+
+impl Default for ProviderOptions {
+    fn default() -> Self {
+        Self {
+            custom_get_handler: Arc::new(()),
+            push_handler: Arc::new(()),
+            authorization_handler: None,
+            limiter: UploadLimiter::default(),
+            max_push_size: DEFAULT_MAX_PUSH_SIZE,
+        }
+    }
+}
+
 /// Handle a single connection.
-pub async fn handle_connection<D: Map, E: EventSender>(
+pub async fn handle_connection<D: Store, E: EventSender>(
     connecting: quinn::Connecting,
     db: D,
     events: E,
+    options: ProviderOptions,
     rt: LocalPoolHandle,
 ) {
+    let ProviderOptions {
+        custom_get_handler,
+        push_handler,
+        authorization_handler,
+        limiter,
+        max_push_size,
+    } = options;
     let remote_addr = connecting.remote_address();
     let connection = match connecting.await {
         Ok(conn) => conn,
@@ -294,6 +558,10 @@ pub async fn handle_connection<D: Map, E: EventSender>(
         }
     };
     let connection_id = connection.stable_id() as u64;
+    let node_id = remote_node_id(&connection);
+    let authorization =
+        authorization_handler.map(|handler| ConnectionAuthorization { handler, node_id });
+    let limiter = limiter.node(node_id);
     let span = debug_span!("connection", connection_id, %remote_addr);
     async move {
         while let Ok((writer, reader)) = connection.accept_bi().await {
@@ -305,12 +573,22 @@ pub async fn handle_connection<D: Map, E: EventSender>(
                 connection_id,
                 events: events.clone(),
                 inner: writer,
+                limiter: limiter.clone(),
+                throttled: Duration::ZERO,
             };
             events.send(Event::ClientConnected { connection_id }).await;
             let db = db.clone();
+            let handlers = StreamHandlers {
+                connection: connection.clone(),
+                node_id,
+                custom_get_handler: custom_get_handler.clone(),
+                push_handler: push_handler.clone(),
+                authorization: authorization.clone(),
+                max_push_size,
+            };
             rt.spawn_pinned(|| {
                 async move {
-                    if let Err(err) = handle_stream(db, reader, writer).await {
+                    if let Err(err) = handle_stream(db, reader, writer, handlers).await {
                         warn!("error: {err:#?}",);
                     }
                 }
@@ -322,11 +600,32 @@ pub async fn handle_connection<D: Map, E: EventSender>(
     .await
 }
 
-async fn handle_stream<D: Map, E: EventSender>(
+/// Everything besides the store that is needed to handle the requests of a connection.
+#[derive(Debug)]
+struct StreamHandlers {
+    connection: quinn::Connection,
+    /// The remote node, or `None` if it could not be determined.
+    node_id: Option<NodeId>,
+    custom_get_handler: Arc<dyn CustomGetHandler>,
+    push_handler: Arc<dyn PushHandler>,
+    authorization: Option<ConnectionAuthorization>,
+    max_push_size: u64,
+}
+
+async fn handle_stream<D: Store, E: EventSender>(
     db: D,
     reader: quinn::RecvStream,
     writer: ResponseWriter<E>,
+    handlers: StreamHandlers,
 ) -> Result<()> {
+    let StreamHandlers {
+        connection,
+        node_id,
+        custom_get_handler,
+        push_handler,
+        authorization,
+        max_push_size,
+    } = handlers;
     // 1. Decode the request.
     debug!("reading request");
     let request = match read_request(reader).await {
@@ -337,9 +636,358 @@ async fn handle_stream<D: Map, E: EventSender>(
         }
     };
 
+    // wait until the client may have another request served
+    let _permit = match writer.limiter.clone() {
+        Some(limiter) => match limiter.try_acquire_request() {
+            Some(permit) => permit,
+            None => {
+                debug!("too many concurrent requests, queueing");
+                writer
+                    .events
+                    .send(Event::TransferQueued {
+                        connection_id: writer.connection_id(),
+                        request_id: writer.request_id(),
+                    })
+                    .await;
+                limiter.acquire_request().await
+            }
+        },
+        None => None,
+    };
+
     match request {
-        Request::Get(request) => handle_get(db, request, writer).await,
+        Request::Get(request) => {
+            let Some(request) = authorize(authorization.as_ref(), request, &writer).await else {
+                return deny(writer).await;
+            };
+            handle_get(db, request, writer).await
+        }
+        Request::Availability(request) => {
+            let mut response = availability(&db, request.clone()).await?;
+            if let Some(authorization) = authorization.as_ref() {
+                // only report what the node would be allowed to get
+                let get = GetRequest::new(request.hash, RangeSpecSeq::all());
+                match authorization.authorize(get).await {
+                    Some(allowed) => {
+                        for (blob, allowed) in response.blobs.iter_mut().zip(allowed.ranges.iter())
+                        {
+                            let ranges = blob.ranges.to_chunk_ranges();
+                            blob.ranges = RangeSpec::new(ranges & allowed.to_chunk_ranges());
+                        }
+                    }
+                    None => {
+                        response = AvailabilityResponse {
+                            blobs: vec![BlobAvailability::missing(request.hash)],
+                        };
+                    }
+                }
+            }
+            handle_availability(request, response, writer).await
+        }
+        Request::CustomGet(request) => {
+            handle_custom_get(db, request, writer, custom_get_handler, authorization).await
+        }
+        Request::Push(request) => {
+            let push = PushContext {
+                connection,
+                node_id,
+                handler: push_handler,
+                max_size: max_push_size,
+            };
+            handle_push(db, request, writer, push).await
+        }
+    }
+}
+
+/// Apply the authorization of the connection, if any, to a request.
+async fn authorize<E: EventSender>(
+    authorization: Option<&ConnectionAuthorization>,
+    request: GetRequest,
+    writer: &ResponseWriter<E>,
+) -> Option<GetRequest> {
+    let Some(authorization) = authorization else {
+        return Some(request);
+    };
+    let hash = request.hash;
+    let res = authorization.authorize(request).await;
+    if res.is_none() {
+        debug!(%hash, "request denied");
+        writer
+            .events
+            .send(Event::GetRequestDenied {
+                hash,
+                connection_id: writer.connection_id(),
+                request_id: writer.request_id(),
+            })
+            .await;
     }
+    res
+}
+
+/// Answer a denied request by closing the stream without sending any data.
+async fn deny<E: EventSender>(mut writer: ResponseWriter<E>) -> Result<()> {
+    writer.notify_transfer_aborted(None).await;
+    writer.inner.finish().await?;
+    Ok(())
+}
+
+/// Handle a single provider defined get request.
+///
+/// The request is evaluated by the [`CustomGetHandler`]. The resulting [`GetRequest`] is
+/// sent to the getter, and then answered like a normal get request.
+async fn handle_custom_get<D: Map, E: EventSender>(
+    db: D,
+    request: CustomGetRequest,
+    mut writer: ResponseWriter<E>,
+    custom_get_handler: Arc<dyn CustomGetHandler>,
+    authorization: Option<ConnectionAuthorization>,
+) -> Result<()> {
+    debug!(len = request.data.len(), "received custom get request");
+    writer
+        .events
+        .send(Event::CustomGetRequestReceived {
+            len: request.data.len(),
+            connection_id: writer.connection_id(),
+            request_id: writer.request_id(),
+        })
+        .await;
+
+    // 2. Evaluate the query
+    let request = match custom_get_handler.handle(request.data).await {
+        Ok(request) => request,
+        Err(e) => {
+            writer.notify_transfer_aborted(None).await;
+            writer.inner.finish().await?;
+            return Err(e.context("custom get handler failed"));
+        }
+    };
+    let Some(request) = authorize(authorization.as_ref(), request, &writer).await else {
+        return deny(writer).await;
+    };
+
+    // 3. Tell the getter which request we are answering
+    let request_bytes = postcard::to_stdvec(&request)?;
+    writer
+        .inner
+        .write_all(&(request_bytes.len() as u64).to_le_bytes())
+        .await?;
+    writer.inner.write_all(&request_bytes).await?;
+
+    handle_get(db, request, writer).await
+}
+
+/// Everything needed to answer a push request besides the store.
+#[derive(Debug)]
+struct PushContext {
+    connection: quinn::Connection,
+    /// The pushing node, or `None` if it could not be determined.
+    node_id: Option<NodeId>,
+    handler: Arc<dyn PushHandler>,
+    max_size: u64,
+}
+
+/// Handle a single push request.
+///
+/// If the [`PushHandler`] accepts the offer, the data is requested from the pushing
+/// node over the same connection and stored in `db`. The transfer fails once the
+/// pushed blobs are larger than the size claimed in the request.
+async fn handle_push<D: Store, E: EventSender>(
+    db: D,
+    request: PushRequest,
+    mut writer: ResponseWriter<E>,
+    push: PushContext,
+) -> Result<()> {
+    let hash = request.hash;
+    debug!(%hash, size = request.size, "received push request");
+    writer
+        .events
+        .send(Event::PushRequestReceived {
+            hash,
+            connection_id: writer.connection_id(),
+            request_id: writer.request_id(),
+        })
+        .await;
+
+    // 2. Decide whether we want the data
+    let decision = match push.node_id {
+        _ if request.size > push.max_size => {
+            debug!(%hash, size = request.size, "push request too large");
+            PushDecision::Reject
+        }
+        Some(node_id) => push
+            .handler
+            .accept(node_id, request.clone())
+            .await
+            .unwrap_or_else(|err| {
+                warn!("push handler failed: {err:#}");
+                PushDecision::Reject
+            }),
+        None => PushDecision::Reject,
+    };
+
+    // 3. Get the data from the pushing node
+    let response = match decision {
+        PushDecision::Reject => PushResponse::Rejected,
+        PushDecision::Accept { tag } => {
+            let limit = PushSizeLimit::new(request.size);
+            match receive_push(&db, push.connection, request.content(), tag, limit).await {
+                Ok(tag) => PushResponse::Stored { tag },
+                Err(err) => {
+                    warn!(%hash, "failed to get pushed data: {err:#}");
+                    PushResponse::Failed
+                }
+            }
+        }
+    };
+
+    // 4. Tell the pushing node how it went
+    writer
+        .events
+        .send(Event::PushFinished {
+            hash,
+            connection_id: writer.connection_id(),
+            request_id: writer.request_id(),
+            response: response.clone(),
+        })
+        .await;
+    let response_bytes = postcard::to_stdvec(&response)?;
+    writer.inner.write_all(&response_bytes).await?;
+    writer.inner.finish().await?;
+
+    debug!("finished push response");
+    Ok(())
+}
+
+/// Get pushed data into the store, and tag it if requested.
+async fn receive_push<D: Store>(
+    db: &D,
+    connection: quinn::Connection,
+    content: HashAndFormat,
+    tag: Option<SetTagOption>,
+    limit: PushSizeLimit,
+) -> Result<Option<Tag>> {
+    // protect the data from gc while it is incomplete
+    let _temp_tag = db.temp_tag(content);
+    get_to_db(
+        db,
+        || async move { anyhow::Ok(connection) },
+        &content,
+        limit.clone(),
+    )
+    .await
+    .with_context(|| match limit.exceeded() {
+        true => format!("pushed data is larger than the claimed {} bytes", limit.max),
+        false => "failed to get pushed data".to_string(),
+    })?;
+    let tag = match tag {
+        None => None,
+        Some(SetTagOption::Auto) => Some(db.create_tag(content).await?),
+        Some(SetTagOption::Named(tag)) => {
+            db.set_tag(tag.clone(), Some(content)).await?;
+            Some(tag)
+        }
+    };
+    Ok(tag)
+}
+
+/// Progress sender that aborts receiving pushed data once it exceeds the claimed size.
+///
+/// The size of every blob is known before its data is received, and verified together with
+/// the data, so summing up the sizes stops the transfer before any excess data is read.
+#[derive(Debug, Clone)]
+struct PushSizeLimit {
+    max: u64,
+    total: Arc<AtomicU64>,
+}
+
+impl PushSizeLimit {
+    fn new(max: u64) -> Self {
+        Self {
+            max,
+            total: Default::default(),
+        }
+    }
+
+    fn exceeded(&self) -> bool {
+        self.total.load(Ordering::Relaxed) > self.max
+    }
+
+    fn check(&self, msg: DownloadProgress) -> ProgressSendResult<()> {
+        if let DownloadProgress::Found { size, .. } = msg {
+            let total = self.total.fetch_add(size, Ordering::Relaxed);
+            if total.saturating_add(size) > self.max {
+                // make sure the total stays above the limit even if it overflowed
+                self.total.store(u64::MAX, Ordering::Relaxed);
+                return Err(ProgressSendError::ReceiverDropped);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl ProgressSender for PushSizeLimit {
+    type Msg = DownloadProgress;
+
+    async fn send(&self, msg: DownloadProgress) -> ProgressSendResult<()> {
+        self.check(msg)
+    }
+
+    fn try_send(&self, msg: DownloadProgress) -> ProgressSendResult<()> {
+        self.check(msg)
+    }
+
+    fn blocking_send(&self, msg: DownloadProgress) -> ProgressSendResult<()> {
+        self.check(msg)
+    }
+}
+
+impl IdGenerator for PushSizeLimit {
+    fn new_id(&self) -> u64 {
+        0
+    }
+}
+
+/// Answer a get request of a node we offered `content` to with a [`PushRequest`].
+///
+/// `nodes` are the hashes that may be requested together with their children, which
+/// are the nodes of a DAG or the root of a hash seq. Requests for anything else but
+/// the offered content are answered as if we did not have the data.
+pub(crate) async fn handle_push_get<D: Map, E: EventSender>(
+    db: D,
+    content: HashAndFormat,
+    nodes: Arc<BTreeSet<Hash>>,
+    events: E,
+    reader: quinn::RecvStream,
+    writer: quinn::SendStream,
+    connection_id: u64,
+) -> Result<()> {
+    let mut writer = ResponseWriter {
+        connection_id,
+        events,
+        inner: writer,
+        limiter: None,
+        throttled: Duration::ZERO,
+    };
+    let request = match read_request(reader).await {
+        Ok(Request::Get(request)) => request,
+        Ok(request) => {
+            writer.notify_transfer_aborted(None).await;
+            writer.inner.finish().await?;
+            anyhow::bail!("unexpected request while pushing: {request:?}");
+        }
+        Err(e) => {
+            writer.notify_transfer_aborted(None).await;
+            return Err(e);
+        }
+    };
+    // for a single blob, only the blob itself may be requested
+    let offered = nodes.contains(&request.hash)
+        || (request.hash == content.hash && matches!(request.ranges.as_single(), Some((0, _))));
+    if !offered {
+        debug!(hash = %request.hash, "request for data that was not offered");
+        return deny(writer).await;
+    }
+    handle_get(db, request, writer).await
 }
 
 /// Handle a single standard get request.
@@ -376,6 +1024,7 @@ pub async fn handle_get<D: Map, E: EventSender>(
             )
             .await;
             stats.duration = t0.elapsed();
+            stats.throttled = writer.throttled;
             match res {
                 Ok(SentStatus::Sent) => {
                     writer.notify_transfer_completed(&hash, stats).await;
@@ -401,19 +1050,94 @@ pub async fn handle_get<D: Map, E: EventSender>(
     Ok(())
 }
 
+/// Handle a single availability request, given the computed response.
+async fn handle_availability<E: EventSender>(
+    request: AvailabilityRequest,
+    response: AvailabilityResponse,
+    mut writer: ResponseWriter<E>,
+) -> Result<()> {
+    let hash = request.hash;
+    debug!(%hash, "received availability request");
+    writer
+        .events
+        .send(Event::AvailabilityRequestReceived {
+            hash,
+            connection_id: writer.connection_id(),
+            request_id: writer.request_id(),
+        })
+        .await;
+
+    let response_bytes = postcard::to_stdvec(&response)?;
+    writer.inner.write_all(&response_bytes).await?;
+    writer.inner.finish().await?;
+
+    debug!("finished availability response");
+    Ok(())
+}
+
+/// Compute the ranges that are available for a blob, or for a hash seq and its children.
+///
+/// Children are only included if the hash seq itself is complete. For a DAG node, this
+/// lists the metadata blob and the direct children of the node.
+pub async fn availability<D: Map>(
+    db: &D,
+    request: AvailabilityRequest,
+) -> Result<AvailabilityResponse> {
+    let Some(entry) = db.get(&request.hash).await? else {
+        return Ok(AvailabilityResponse {
+            blobs: vec![BlobAvailability::missing(request.hash)],
+        });
+    };
+    let mut blobs = vec![blob_availability(&entry).await?];
+    if !request.format.is_raw() && entry.is_complete() {
+        let (mut children, _) = parse_hash_seq(entry.data_reader().await?).await?;
+        while let Some(hash) = children.next().await? {
+            let child = match db.get(&hash).await? {
+                Some(entry) => blob_availability(&entry).await?,
+                None => BlobAvailability::missing(hash),
+            };
+            blobs.push(child);
+        }
+    }
+    Ok(AvailabilityResponse { blobs })
+}
+
+async fn blob_availability(entry: &impl MapEntry) -> Result<BlobAvailability> {
+    let ranges = if entry.is_complete() {
+        ChunkRanges::all()
+    } else {
+        entry_valid_ranges(entry).await?
+    };
+    Ok(BlobAvailability {
+        hash: entry.hash(),
+        size: Some(entry.size()),
+        ranges: RangeSpec::new(ranges),
+    })
+}
+
 /// A helper struct that combines a quinn::SendStream with auxiliary information
 #[derive(Debug)]
 pub struct ResponseWriter<E> {
     inner: quinn::SendStream,
     events: E,
     connection_id: u64,
+    /// The upload limits for the client, if there are any.
+    limiter: Option<NodeLimiter>,
+    /// The time spent waiting for the upload bandwidth limits.
+    throttled: Duration,
 }
 
 impl<E: EventSender> ResponseWriter<E> {
-    fn tracking_writer(
-        &mut self,
-    ) -> TrackingStreamWriter<TokioStreamWriter<&mut quinn::SendStream>> {
-        TrackingStreamWriter::new(TokioStreamWriter(&mut self.inner))
+    fn tracking_writer(&mut self) -> TrackingStreamWriter<ThrottledWriter<'_, E>> {
+        let request_id = self.request_id();
+        TrackingStreamWriter::new(ThrottledWriter {
+            inner: TokioStreamWriter(&mut self.inner),
+            limiter: self.limiter.as_ref(),
+            events: &self.events,
+            connection_id: self.connection_id,
+            request_id,
+            throttled: &mut self.throttled,
+        })
     }
 
     fn connection_id(&self) -> u64 {
@@ -479,6 +1203,54 @@ impl<E: EventSender> ResponseWriter<E> {
     }
 }
 
+/// A writer that waits for the upload bandwidth limits before each write.
+#[derive(Debug)]
+struct ThrottledWriter<'a, E> {
+    inner: TokioStreamWriter<&'a mut quinn::SendStream>,
+    limiter: Option<&'a NodeLimiter>,
+    events: &'a E,
+    connection_id: u64,
+    request_id: u64,
+    throttled: &'a mut Duration,
+}
+
+impl<E: EventSender> ThrottledWriter<'_, E> {
+    async fn throttle(&mut self, len: usize) {
+        let Some(limiter) = self.limiter else {
+            return;
+        };
+        let delay = limiter.acquire_bytes(len).await;
+        if delay.is_zero() {
+            return;
+        }
+        if self.throttled.is_zero() {
+            self.events
+                .send(Event::TransferThrottled {
+                    connection_id: self.connection_id,
+                    request_id: self.request_id,
+                })
+                .await;
+        }
+        *self.throttled += delay;
+    }
+}
+
+impl<E: EventSender> AsyncStreamWriter for ThrottledWriter<'_, E> {
+    async fn write(&mut self, data: &[u8]) -> std::io::Result<()> {
+        self.throttle(data.len()).await;
+        self.inner.write(data).await
+    }
+
+    async fn write_bytes(&mut self, data: Bytes) -> std::io::Result<()> {
+        self.throttle(data.len()).await;
+        self.inner.write_bytes(data).await
+    }
+
+    async fn sync(&mut self) -> std::io::Result<()> {
+        self.inner.sync().await
+    }
+}
+
 /// Status  of a send operation
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum SentStatus {
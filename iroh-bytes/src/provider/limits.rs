@@ -0,0 +1,331 @@
+//! Upload limits for the provider.
+//!
+//! All limits are shared by the connections of a node. The bandwidth limits are token
+//! buckets: sending more than the bucket holds is allowed, but the writer then has to wait
+//! until the debt is repaid before the next write.
+//!
+//! Fairness between nodes is achieved by queueing. Each node has a FIFO queue for its own
+//! streams, and only the stream at the front of that queue waits in the global FIFO queue.
+//! So if several nodes compete for the total bandwidth, they are served round robin, no
+//! matter how many streams each of them has open.
+use std::collections::HashMap;
+use std::num::{NonZeroU64, NonZeroUsize};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+
+use iroh_base::key::NodeId;
+use tokio::sync::{Mutex, MutexGuard, OwnedSemaphorePermit, Semaphore, TryAcquireError};
+use tokio::time::Instant;
+
+/// Limits for serving data to other nodes.
+///
+/// The default has no limits.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct UploadLimits {
+    /// Maximum number of bytes per second sent to all nodes together.
+    pub total_bytes_per_sec: Option<NonZeroU64>,
+    /// Maximum number of bytes per second sent to a single node.
+    pub node_bytes_per_sec: Option<NonZeroU64>,
+    /// Maximum number of requests of a single node that are served at the same time.
+    ///
+    /// Further requests wait until one of the running requests is done.
+    pub max_concurrent_requests_per_node: Option<NonZeroUsize>,
+}
+
+impl UploadLimits {
+    /// Checks whether any limit is set.
+    pub fn is_unlimited(&self) -> bool {
+        self == &Self::default()
+    }
+}
+
+/// Enforces [`UploadLimits`] for all connections of a provider.
+#[derive(Debug, Clone, Default)]
+pub struct UploadLimiter(Arc<LimiterInner>);
+
+#[derive(Debug, Default)]
+struct LimiterInner {
+    limits: UploadLimits,
+    /// The global token bucket, locked by the node whose turn it is.
+    total: Option<Mutex<TokenBucket>>,
+    /// State per node. Nodes without connections are dropped.
+    ///
+    /// Connections for which the node id is not known share the `None` entry.
+    nodes: std::sync::Mutex<HashMap<Option<NodeId>, Weak<NodeState>>>,
+}
+
+#[derive(Debug)]
+struct NodeState {
+    /// The token bucket of the node, locked by the stream whose turn it is.
+    ///
+    /// This is a mutex even if there is no per node bandwidth limit, to make sure only one
+    /// stream per node waits in the global queue.
+    bucket: Mutex<Option<TokenBucket>>,
+    /// Permits for concurrent requests.
+    requests: Option<Arc<Semaphore>>,
+}
+
+impl UploadLimiter {
+    /// Creates a new limiter.
+    pub fn new(limits: UploadLimits) -> Self {
+        Self(Arc::new(LimiterInner {
+            limits,
+            total: limits
+                .total_bytes_per_sec
+                .map(|rate| Mutex::new(TokenBucket::new(rate))),
+            nodes: Default::default(),
+        }))
+    }
+
+    /// The limits that are enforced.
+    pub fn limits(&self) -> &UploadLimits {
+        &self.0.limits
+    }
+
+    /// Returns the limiter for a single node, or `None` if there are no limits.
+    pub(crate) fn node(&self, node_id: Option<NodeId>) -> Option<NodeLimiter> {
+        if self.0.limits.is_unlimited() {
+            return None;
+        }
+        let mut nodes = self.0.nodes.lock().unwrap();
+        if let Some(node) = nodes.get(&node_id).and_then(Weak::upgrade) {
+            return Some(NodeLimiter {
+                limiter: self.clone(),
+                node,
+            });
+        }
+        let limits = &self.0.limits;
+        let node = Arc::new(NodeState {
+            bucket: Mutex::new(limits.node_bytes_per_sec.map(TokenBucket::new)),
+            requests: limits
+                .max_concurrent_requests_per_node
+                .map(|n| Arc::new(Semaphore::new(n.get()))),
+        });
+        nodes.retain(|_, node| node.strong_count() > 0);
+        nodes.insert(node_id, Arc::downgrade(&node));
+        Some(NodeLimiter {
+            limiter: self.clone(),
+            node,
+        })
+    }
+}
+
+/// The limiter for all connections of a single node.
+#[derive(Debug, Clone)]
+pub(crate) struct NodeLimiter {
+    limiter: UploadLimiter,
+    node: Arc<NodeState>,
+}
+
+/// Permit to serve a request, see [`NodeLimiter::try_acquire_request`].
+pub(crate) type RequestPermit = Option<OwnedSemaphorePermit>;
+
+impl NodeLimiter {
+    /// Tries to get a permit to serve a request without waiting.
+    ///
+    /// Returns `None` if the node has too many requests in flight.
+    pub(crate) fn try_acquire_request(&self) -> Option<RequestPermit> {
+        match &self.node.requests {
+            Some(requests) => match requests.clone().try_acquire_owned() {
+                Ok(permit) => Some(Some(permit)),
+                Err(TryAcquireError::NoPermits) | Err(TryAcquireError::Closed) => None,
+            },
+            None => Some(None),
+        }
+    }
+
+    /// Waits for a permit to serve a request.
+    pub(crate) async fn acquire_request(&self) -> RequestPermit {
+        match &self.node.requests {
+            // the semaphore is never closed
+            Some(requests) => requests.clone().acquire_owned().await.ok(),
+            None => None,
+        }
+    }
+
+    /// Waits until `len` bytes may be sent to the node.
+    ///
+    /// Returns the time spent waiting, or zero if the bytes could be sent right away.
+    pub(crate) async fn acquire_bytes(&self, len: usize) -> Duration {
+        let limits = &self.limiter.0.limits;
+        if limits.node_bytes_per_sec.is_none() && limits.total_bytes_per_sec.is_none() {
+            return Duration::ZERO;
+        }
+        let t0 = Instant::now();
+        let len = len as u64;
+        // wait for our turn among the streams of this node
+        let (mut node_bucket, mut waited) = lock(&self.node.bucket).await;
+        if let Some(bucket) = node_bucket.as_mut() {
+            waited |= wait(bucket.take(len)).await;
+        }
+        if let Some(total) = &self.limiter.0.total {
+            // wait for our turn among the nodes
+            let (mut bucket, waited_for_turn) = lock(total).await;
+            waited |= waited_for_turn;
+            waited |= wait(bucket.take(len)).await;
+        }
+        drop(node_bucket);
+        if waited {
+            t0.elapsed()
+        } else {
+            Duration::ZERO
+        }
+    }
+}
+
+/// Locks the mutex, and returns whether we had to wait for it.
+async fn lock<T>(mutex: &Mutex<T>) -> (MutexGuard<'_, T>, bool) {
+    match mutex.try_lock() {
+        Ok(guard) => (guard, false),
+        Err(_) => (mutex.lock().await, true),
+    }
+}
+
+/// Sleeps for `delay`, and returns whether we had to wait.
+async fn wait(delay: Duration) -> bool {
+    if delay.is_zero() {
+        return false;
+    }
+    tokio::time::sleep(delay).await;
+    true
+}
+
+/// A token bucket that allows going into debt.
+#[derive(Debug)]
+struct TokenBucket {
+    /// Bytes per second.
+    rate: f64,
+    /// Available bytes. Negative if in debt.
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: NonZeroU64) -> Self {
+        let rate = rate.get() as f64;
+        Self {
+            rate,
+            tokens: rate,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Takes `len` bytes from the bucket, and returns how long to wait until the debt is
+    /// repaid.
+    fn take(&mut self, len: u64) -> Duration {
+        self.take_at(len, Instant::now())
+    }
+
+    fn take_at(&mut self, len: u64, now: Instant) -> Duration {
+        // refill, at most to one second worth of data
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * self.rate).min(self.rate);
+        self.last_refill = now;
+        self.tokens -= len as f64;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iroh_base::key::SecretKey;
+
+    use super::*;
+
+    fn rate(n: u64) -> Option<NonZeroU64> {
+        NonZeroU64::new(n)
+    }
+
+    #[test]
+    fn token_bucket() {
+        let mut bucket = TokenBucket::new(NonZeroU64::new(1000).unwrap());
+        let t0 = bucket.last_refill;
+        // the first second worth of data is sent without waiting
+        assert_eq!(bucket.take_at(1000, t0), Duration::ZERO);
+        // then we have to wait for the debt to be repaid
+        assert_eq!(bucket.take_at(500, t0), Duration::from_millis(500));
+        // after the debt is repaid we are at zero
+        let t1 = t0 + Duration::from_millis(500);
+        assert_eq!(bucket.take_at(100, t1), Duration::from_millis(100));
+        // the bucket does not fill up beyond its capacity
+        let t2 = t1 + Duration::from_secs(10);
+        assert_eq!(bucket.take_at(1000, t2), Duration::ZERO);
+        assert_eq!(bucket.take_at(1, t2), Duration::from_millis(1));
+    }
+
+    #[tokio::test]
+    async fn concurrent_requests_per_node() {
+        let limiter = UploadLimiter::new(UploadLimits {
+            max_concurrent_requests_per_node: NonZeroUsize::new(1),
+            ..Default::default()
+        });
+        let a = SecretKey::generate().public();
+        let b = SecretKey::generate().public();
+        let node_a = limiter.node(Some(a)).unwrap();
+        let permit = node_a.try_acquire_request().unwrap();
+        // a second connection of the same node shares the limit
+        assert!(limiter
+            .node(Some(a))
+            .unwrap()
+            .try_acquire_request()
+            .is_none());
+        // other nodes are not affected
+        assert!(limiter
+            .node(Some(b))
+            .unwrap()
+            .try_acquire_request()
+            .is_some());
+        drop(permit);
+        assert!(node_a.try_acquire_request().is_some());
+    }
+
+    #[test]
+    fn unlimited() {
+        let limiter = UploadLimiter::new(UploadLimits::default());
+        assert!(limiter.node(None).is_none());
+        let limiter = UploadLimiter::new(UploadLimits {
+            node_bytes_per_sec: rate(1),
+            ..Default::default()
+        });
+        assert!(limiter.node(None).is_some());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn fair_between_nodes() {
+        let limiter = UploadLimiter::new(UploadLimits {
+            total_bytes_per_sec: rate(1000),
+            ..Default::default()
+        });
+        let a = limiter.node(Some(SecretKey::generate().public())).unwrap();
+        let b = limiter.node(Some(SecretKey::generate().public())).unwrap();
+        let (send, recv) = flume::unbounded();
+        let mut tasks = Vec::new();
+        // node a has many streams, node b only one
+        for _ in 0..4 {
+            let (a, send) = (a.clone(), send.clone());
+            tasks.push(tokio::spawn(async move {
+                for _ in 0..4 {
+                    a.acquire_bytes(1000).await;
+                    send.send('a').unwrap();
+                }
+            }));
+        }
+        tasks.push(tokio::spawn(async move {
+            for _ in 0..4 {
+                b.acquire_bytes(1000).await;
+                send.send('b').unwrap();
+            }
+        }));
+        for task in tasks {
+            task.await.unwrap();
+        }
+        let order = recv.drain().collect::<String>();
+        // b gets every other turn instead of every fifth
+        let b_turns = order[..8].chars().filter(|c| *c == 'b').count();
+        assert!(b_turns >= 3, "unfair order {order}");
+    }
+}
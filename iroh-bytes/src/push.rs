@@ -0,0 +1,91 @@
+//! Pushing data to another node.
+//!
+//! The node that pushes offers data with a [`PushRequest`]. If the other node accepts,
+//! it gets the data over the same connection, see the
+//! [protocol documentation](crate::protocol#push-requests) for details.
+use std::collections::BTreeSet;
+use std::sync::Arc;
+
+use anyhow::Result;
+use futures::stream::FuturesUnordered;
+use futures::StreamExt;
+use tracing::{debug, warn};
+
+use crate::dag::DagNode;
+use crate::protocol::{PushRequest, PushResponse, Request, MAX_MESSAGE_SIZE};
+use crate::provider::{handle_push_get, EventSender};
+use crate::store::Map;
+use crate::{Hash, HashAndFormat};
+
+/// Offer data from `db` to the node on the other side of `connection`.
+///
+/// While waiting for the response, this answers the get requests of the other node
+/// for the offered data. Requests for anything else are rejected, so only one push
+/// should be in progress per connection at any time.
+///
+/// When pushing a DAG, all its nodes have to be stored in `db`.
+///
+/// Returns the response of the other node, which tells whether the data was stored.
+pub async fn push<D: Map, E: EventSender>(
+    connection: quinn::Connection,
+    db: D,
+    events: E,
+    request: PushRequest,
+) -> Result<PushResponse> {
+    let connection_id = connection.stable_id() as u64;
+    let content = request.content();
+    debug!(hash = %content.hash, "offering data");
+    let nodes = Arc::new(offered_nodes(&db, content).await?);
+    let (mut writer, mut reader) = connection.open_bi().await?;
+    let request_bytes = postcard::to_stdvec(&Request::Push(request))?;
+    writer.write_all(&request_bytes).await?;
+    writer.finish().await?;
+
+    let response = reader.read_to_end(MAX_MESSAGE_SIZE);
+    tokio::pin!(response);
+    let mut serving = FuturesUnordered::new();
+    loop {
+        tokio::select! {
+            response = &mut response => {
+                let response: PushResponse = postcard::from_bytes(&response?)?;
+                debug!(?response, "push finished");
+                return Ok(response);
+            }
+            stream = connection.accept_bi() => {
+                let (writer, reader) = stream?;
+                serving.push(handle_push_get(
+                    db.clone(),
+                    content,
+                    nodes.clone(),
+                    events.clone(),
+                    reader,
+                    writer,
+                    connection_id,
+                ));
+            }
+            Some(res) = serving.next(), if !serving.is_empty() => {
+                if let Err(err) = res {
+                    warn!("error answering get request for pushed data: {err:#}");
+                }
+            }
+        }
+    }
+}
+
+/// The hashes that the other node may request together with their children.
+///
+/// For a DAG these are all nodes, and the roots of all hash seqs in it.
+async fn offered_nodes<D: Map>(db: &D, content: HashAndFormat) -> Result<BTreeSet<Hash>> {
+    let mut nodes = BTreeSet::new();
+    let mut stack = vec![content];
+    while let Some(HashAndFormat { hash, format }) = stack.pop() {
+        if format.is_raw() || !nodes.insert(hash) {
+            continue;
+        }
+        if format.is_dag() {
+            let node = DagNode::load(db, &hash).await?;
+            stack.extend(node.children().iter().copied());
+        }
+    }
+    Ok(nodes)
+}
@@ -3,6 +3,8 @@ use crate::{BlobFormat, Hash, HashAndFormat};
 
 #[cfg(feature = "fs-store")]
 mod bao_file;
+#[cfg(feature = "fs-store")]
+mod encryption;
 pub mod mem;
 mod mutable_mem_storage;
 pub mod readonly_mem;
@@ -30,6 +32,8 @@ struct TempCounters {
     raw: u64,
     /// number of hash seq temp tags for a hash
     hash_seq: u64,
+    /// number of dag temp tags for a hash
+    dag: u64,
 }
 
 impl TempCounters {
@@ -37,6 +41,7 @@ impl TempCounters {
         match format {
             BlobFormat::Raw => &mut self.raw,
             BlobFormat::HashSeq => &mut self.hash_seq,
+            BlobFormat::Dag => &mut self.dag,
         }
     }
 
@@ -51,7 +56,7 @@ impl TempCounters {
     }
 
     fn is_empty(&self) -> bool {
-        self.raw == 0 && self.hash_seq == 0
+        self.raw == 0 && self.hash_seq == 0 && self.dag == 0
     }
 }
 
@@ -86,6 +91,9 @@ impl TempCounterMap {
             if v.hash_seq > 0 {
                 res.push(HashAndFormat::hash_seq(*k));
             }
+            if v.dag > 0 {
+                res.push(HashAndFormat::dag(*k));
+            }
         }
         res.into_iter()
     }
@@ -36,7 +36,10 @@ use crate::{
 };
 use iroh_base::hash::Hash;
 
-use super::mutable_mem_storage::{MutableMemStorage, SizeInfo};
+use super::{
+    encryption::{BlobCipher, CipherIo, EncryptionKey},
+    mutable_mem_storage::{MutableMemStorage, SizeInfo},
+};
 
 /// Data files are stored in 3 files. The data file, the outboard file,
 /// and a sizes file. The sizes file contains the size that the remote side told us
@@ -83,6 +86,9 @@ struct DataPaths {
 ///
 /// For the memory variant, it does reading in a zero copy way, since storage
 /// is already a `Bytes`.
+///
+/// If the store is encrypted, the file variants contain ciphertext and are
+/// decrypted when reading. The memory variants always contain plaintext.
 #[derive(Default, derive_more::Debug)]
 pub struct CompleteStorage {
     /// data part, which can be in memory or on disk.
@@ -91,6 +97,8 @@ pub struct CompleteStorage {
     /// outboard part, which can be in memory or on disk.
     #[debug("{:?}", outboard.as_ref().map_mem(|x| x.len()))]
     pub outboard: MemOrFile<Bytes, (File, u64)>,
+    /// cipher for the parts that are on disk, if the store is encrypted.
+    pub cipher: Option<BlobCipher>,
 }
 
 impl CompleteStorage {
@@ -98,7 +106,10 @@ impl CompleteStorage {
     pub fn read_data_at(&self, offset: u64, len: usize) -> Bytes {
         match &self.data {
             MemOrFile::Mem(mem) => get_limited_slice(mem, offset, len),
-            MemOrFile::File((file, _size)) => read_to_end(file, offset, len).unwrap(),
+            MemOrFile::File((file, _size)) => {
+                let cipher = self.cipher.as_ref().map(BlobCipher::data);
+                read_to_end(CipherIo::new(file, cipher), offset, len).unwrap()
+            }
         }
     }
 
@@ -106,7 +117,10 @@ impl CompleteStorage {
     pub fn read_outboard_at(&self, offset: u64, len: usize) -> Bytes {
         match &self.outboard {
             MemOrFile::Mem(mem) => get_limited_slice(mem, offset, len),
-            MemOrFile::File((file, _size)) => read_to_end(file, offset, len).unwrap(),
+            MemOrFile::File((file, _size)) => {
+                let cipher = self.cipher.as_ref().map(BlobCipher::outboard);
+                read_to_end(CipherIo::new(file, cipher), offset, len).unwrap()
+            }
         }
     }
 
@@ -177,11 +191,15 @@ fn max_offset(batch: &[BaoContentItem]) -> u64 {
 }
 
 /// A file storage for an incomplete bao file.
+///
+/// If the store is encrypted, data and outboard are encrypted when writing and
+/// decrypted when reading. The sizes file is not encrypted.
 #[derive(Debug)]
 pub struct FileStorage {
     data: std::fs::File,
     outboard: std::fs::File,
     sizes: std::fs::File,
+    cipher: Option<BlobCipher>,
 }
 
 impl FileStorage {
@@ -205,28 +223,26 @@ impl FileStorage {
 
     fn write_batch(&mut self, size: u64, batch: &[BaoContentItem]) -> io::Result<()> {
         let tree = BaoTree::new(size, IROH_BLOCK_SIZE);
+        let mut data = CipherIo::new(&mut self.data, self.cipher.as_ref().map(BlobCipher::data));
+        let mut outboard = CipherIo::new(
+            &mut self.outboard,
+            self.cipher.as_ref().map(BlobCipher::outboard),
+        );
         for item in batch {
             match item {
                 BaoContentItem::Parent(parent) => {
                     if let Some(offset) = tree.pre_order_offset(parent.node) {
                         let o0 = offset * 64;
-                        self.outboard
-                            .write_all_at(o0, parent.pair.0.as_bytes().as_slice())?;
-                        self.outboard
-                            .write_all_at(o0 + 32, parent.pair.1.as_bytes().as_slice())?;
+                        outboard.write_all_at(o0, parent.pair.0.as_bytes().as_slice())?;
+                        outboard.write_all_at(o0 + 32, parent.pair.1.as_bytes().as_slice())?;
                     }
                 }
                 BaoContentItem::Leaf(leaf) => {
                     let o0 = leaf.offset;
                     // divide by chunk size, multiply by 8
                     let index = (leaf.offset >> (tree.block_size().chunk_log() + 10)) << 3;
-                    tracing::trace!(
-                        "write_batch f={:?} o={} l={}",
-                        self.data,
-                        o0,
-                        leaf.data.len()
-                    );
-                    self.data.write_all_at(o0, leaf.data.as_ref())?;
+                    tracing::trace!("write_batch f={:?} o={} l={}", data, o0, leaf.data.len());
+                    data.write_all_at(o0, leaf.data.as_ref())?;
                     let size = tree.size();
                     self.sizes.write_all_at(index, &size.to_le_bytes())?;
                 }
@@ -236,11 +252,13 @@ impl FileStorage {
     }
 
     fn read_data_at(&self, offset: u64, len: usize) -> io::Result<Bytes> {
-        read_to_end(&self.data, offset, len)
+        let cipher = self.cipher.as_ref().map(BlobCipher::data);
+        read_to_end(CipherIo::new(&self.data, cipher), offset, len)
     }
 
     fn read_outboard_at(&self, offset: u64, len: usize) -> io::Result<Bytes> {
-        read_to_end(&self.outboard, offset, len)
+        let cipher = self.cipher.as_ref().map(BlobCipher::outboard);
+        read_to_end(CipherIo::new(&self.outboard, cipher), offset, len)
     }
 }
 
@@ -353,6 +371,8 @@ pub struct BaoFileConfig {
     /// Todo: make this async.
     #[debug("{:?}", on_file_create.as_ref().map(|_| ()))]
     on_file_create: Option<CreateCb>,
+    /// Key to encrypt files with, if the store is encrypted.
+    encryption: Option<EncryptionKey>,
 }
 
 impl BaoFileConfig {
@@ -362,9 +382,21 @@ impl BaoFileConfig {
             dir,
             max_mem,
             on_file_create,
+            encryption: None,
         }
     }
 
+    /// Encrypt all files that are created with this configuration.
+    pub fn with_encryption(mut self, encryption: Option<EncryptionKey>) -> Self {
+        self.encryption = encryption;
+        self
+    }
+
+    /// Get the cipher for a hash, if files are encrypted.
+    pub(crate) fn cipher(&self, hash: &Hash) -> Option<BlobCipher> {
+        self.encryption.as_ref().map(|key| key.blob_cipher(hash))
+    }
+
     /// Get the paths for a hash.
     fn paths(&self, hash: &Hash) -> DataPaths {
         DataPaths {
@@ -499,6 +531,7 @@ impl BaoFileHandle {
             data: create_read_write(&paths.data)?,
             outboard: create_read_write(&paths.outboard)?,
             sizes: create_read_write(&paths.sizes)?,
+            cipher: config.cipher(&hash),
         });
         Ok(Self(Arc::new(BaoFileHandleInner {
             storage: RwLock::new(storage),
@@ -514,7 +547,12 @@ impl BaoFileHandle {
         data: MemOrFile<Bytes, (File, u64)>,
         outboard: MemOrFile<Bytes, (File, u64)>,
     ) -> Self {
-        let storage = BaoFileStorage::Complete(CompleteStorage { data, outboard });
+        let cipher = config.cipher(&hash);
+        let storage = BaoFileStorage::Complete(CompleteStorage {
+            data,
+            outboard,
+            cipher,
+        });
         Self(Arc::new(BaoFileHandleInner {
             storage: RwLock::new(storage),
             config,
@@ -607,7 +645,7 @@ impl BaoFileHandle {
                     //
                     // otherwise we might allocate a lot of memory if we get
                     // a write at the end of a very large file.
-                    let mut file_batch = mem.persist(paths)?;
+                    let mut file_batch = mem.persist(paths, self.config.cipher(&self.hash))?;
                     file_batch.write_batch(size, batch)?;
                     *storage = BaoFileStorage::IncompleteFile(file_batch);
                     Ok(HandleChange::MemToFile)
@@ -650,12 +688,18 @@ impl SizeInfo {
 
 impl MutableMemStorage {
     /// Persist the batch to disk, creating a FileBatch.
-    fn persist(&self, paths: DataPaths) -> io::Result<FileStorage> {
+    fn persist(&self, paths: DataPaths, cipher: Option<BlobCipher>) -> io::Result<FileStorage> {
         let mut data = create_read_write(&paths.data)?;
         let mut outboard = create_read_write(&paths.outboard)?;
         let mut sizes = create_read_write(&paths.sizes)?;
-        self.data.persist(&mut data)?;
-        self.outboard.persist(&mut outboard)?;
+        self.data.persist(CipherIo::new(
+            &mut data,
+            cipher.as_ref().map(BlobCipher::data),
+        ))?;
+        self.outboard.persist(CipherIo::new(
+            &mut outboard,
+            cipher.as_ref().map(BlobCipher::outboard),
+        ))?;
         self.sizes.persist(&mut sizes)?;
         data.sync_all()?;
         outboard.sync_all()?;
@@ -664,6 +708,7 @@ impl MutableMemStorage {
             data,
             outboard,
             sizes,
+            cipher,
         })
     }
 
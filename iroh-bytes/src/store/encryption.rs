@@ -0,0 +1,342 @@
+//! At rest encryption for the file system store.
+//!
+//! Data and outboard of every blob are encrypted with a stream cipher, using
+//! a key that is derived from the store key and the hash of the blob. Since
+//! the keystream only depends on the position, ciphertext can be read and
+//! written at arbitrary offsets, and the encrypted files have exactly the same
+//! size as the plaintext. This is what allows partial blobs to be written in
+//! any order, and complete blobs to be read in random access.
+//!
+//! The keystream is deterministic, so two stores using the same key will
+//! produce the same ciphertext for the same blob. This leaks nothing beyond
+//! the hash, which is stored in the clear anyway.
+//!
+//! The hash of imported data is only known once all of it has been read, so
+//! imports are written to disk with a random temporary cipher, and re-encrypted
+//! in place once the hash is known. Plaintext never reaches the disk.
+use std::{
+    fs::{File, OpenOptions},
+    io,
+    path::Path,
+};
+
+use bao_tree::{
+    blake3,
+    io::sync::{ReadAt, WriteAt},
+};
+use bytes::{Bytes, BytesMut};
+use chacha20::{
+    cipher::{KeyIvInit, StreamCipher, StreamCipherSeek},
+    ChaCha20, Nonce,
+};
+use iroh_base::hash::Hash;
+
+/// Log2 of the size of a keystream segment.
+///
+/// The block counter of the cipher is only 32 bits, so the keystream of a
+/// blob part is split into segments which use the segment index as nonce.
+const SEGMENT_LOG: u32 = 32;
+
+/// Size of a keystream segment in bytes.
+const SEGMENT_SIZE: u64 = 1 << SEGMENT_LOG;
+
+/// Buffer size for encrypting or decrypting entire files.
+const FILE_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// The key used to encrypt the content of a file system store at rest.
+///
+/// The key is supplied by the application, and must be the same every time the
+/// store is opened. Opening an encrypted store with a different key, or without
+/// a key, fails.
+#[derive(Clone, PartialEq, Eq)]
+pub struct EncryptionKey([u8; 32]);
+
+impl std::fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("EncryptionKey(..)")
+    }
+}
+
+impl From<[u8; 32]> for EncryptionKey {
+    fn from(value: [u8; 32]) -> Self {
+        Self(value)
+    }
+}
+
+impl EncryptionKey {
+    /// Create a key from 32 bytes of key material.
+    pub fn new(bytes: [u8; 32]) -> Self {
+        Self(bytes)
+    }
+
+    /// Generate a new random key.
+    pub fn generate() -> Self {
+        use rand::Rng;
+        Self(rand::thread_rng().gen())
+    }
+
+    /// The raw key material.
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+
+    /// A value that can be stored in the clear to check if a key is correct.
+    pub(crate) fn check_value(&self) -> [u8; 32] {
+        *blake3::keyed_hash(&self.0, b"iroh-bytes fs store key check").as_bytes()
+    }
+
+    /// Get the cipher for the data and outboard of a blob.
+    pub(crate) fn blob_cipher(&self, hash: &Hash) -> BlobCipher {
+        BlobCipher {
+            data: self.part_cipher(b"data", hash),
+            outboard: self.part_cipher(b"outboard", hash),
+        }
+    }
+
+    fn part_cipher(&self, label: &[u8], hash: &Hash) -> PartCipher {
+        let key = blake3::Hasher::new_keyed(&self.0)
+            .update(label)
+            .update(hash.as_bytes())
+            .finalize();
+        PartCipher(*key.as_bytes())
+    }
+}
+
+/// The ciphers for the data and outboard of a single blob.
+#[derive(Clone)]
+pub(crate) struct BlobCipher {
+    data: PartCipher,
+    outboard: PartCipher,
+}
+
+impl std::fmt::Debug for BlobCipher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("BlobCipher(..)")
+    }
+}
+
+impl BlobCipher {
+    /// The cipher for the data part.
+    pub fn data(&self) -> &PartCipher {
+        &self.data
+    }
+
+    /// The cipher for the outboard part.
+    pub fn outboard(&self) -> &PartCipher {
+        &self.outboard
+    }
+}
+
+/// The cipher for one part of a blob.
+///
+/// Encryption and decryption are the same operation.
+#[derive(Clone)]
+pub(crate) struct PartCipher([u8; 32]);
+
+impl std::fmt::Debug for PartCipher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("PartCipher(..)")
+    }
+}
+
+impl PartCipher {
+    /// Apply the keystream to `buf`, which is at `offset` in the part.
+    pub fn apply(&self, mut offset: u64, mut buf: &mut [u8]) {
+        while !buf.is_empty() {
+            let segment = offset >> SEGMENT_LOG;
+            let within = offset & (SEGMENT_SIZE - 1);
+            let n = usize::try_from(SEGMENT_SIZE - within)
+                .unwrap_or(usize::MAX)
+                .min(buf.len());
+            let mut nonce = Nonce::default();
+            nonce[..8].copy_from_slice(&segment.to_le_bytes());
+            let mut cipher = ChaCha20::new(&self.0.into(), &nonce);
+            cipher.seek(within);
+            let (head, tail) = buf.split_at_mut(n);
+            cipher.apply_keystream(head);
+            buf = tail;
+            offset += n as u64;
+        }
+    }
+
+    /// Generate a random cipher, used to encrypt temp files of imports whose
+    /// hash is not yet known.
+    pub fn generate() -> Self {
+        use rand::Rng;
+        Self(rand::thread_rng().gen())
+    }
+
+    /// Apply the keystream to a copy of an entire part.
+    pub fn apply_bytes(&self, data: &[u8]) -> Bytes {
+        let mut buf = BytesMut::from(data);
+        self.apply(0, &mut buf);
+        buf.freeze()
+    }
+
+    /// Re-encrypt an entire file in place, from the keystream of `from` to the
+    /// keystream of this cipher.
+    ///
+    /// The plaintext only ever exists in memory.
+    pub fn reencrypt_file(&self, from: &PartCipher, path: &Path) -> io::Result<()> {
+        map_file(path, |offset, buf| {
+            from.apply(offset, buf);
+            self.apply(offset, buf);
+        })
+    }
+
+    /// Copy an entire file, applying the keystream to the copy.
+    pub fn copy_file(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let source = File::open(from)?;
+        let mut target = File::create(to)?;
+        let mut buf = vec![0u8; FILE_BUFFER_SIZE];
+        let mut offset = 0;
+        loop {
+            let n = source.read_at(offset, &mut buf)?;
+            if n == 0 {
+                break;
+            }
+            let buf = &mut buf[..n];
+            self.apply(offset, buf);
+            target.write_all_at(offset, buf)?;
+            offset += n as u64;
+        }
+        target.sync_all()
+    }
+}
+
+/// Transform an entire file in place, one buffer at a time.
+fn map_file(path: &Path, f: impl Fn(u64, &mut [u8])) -> io::Result<()> {
+    let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+    let size = file.metadata()?.len();
+    let mut buf = vec![0u8; FILE_BUFFER_SIZE];
+    let mut offset = 0;
+    while offset < size {
+        let n = usize::try_from(size - offset)
+            .unwrap_or(usize::MAX)
+            .min(buf.len());
+        let buf = &mut buf[..n];
+        file.read_exact_at(offset, buf)?;
+        f(offset, buf);
+        file.write_all_at(offset, buf)?;
+        offset += n as u64;
+    }
+    file.sync_all()
+}
+
+/// Apply the keystream of an optional cipher to an entire part.
+///
+/// Without a cipher, the data is returned unchanged.
+pub(crate) fn apply_opt(cipher: Option<&PartCipher>, data: Bytes) -> Bytes {
+    match cipher {
+        Some(cipher) => cipher.apply_bytes(&data),
+        None => data,
+    }
+}
+
+/// Wrapper that transparently applies the keystream of an optional cipher when
+/// reading from or writing to the inner value.
+#[derive(Debug)]
+pub(crate) struct CipherIo<'a, I> {
+    inner: I,
+    cipher: Option<&'a PartCipher>,
+}
+
+impl<'a, I> CipherIo<'a, I> {
+    /// Wrap `inner`, which contains the part for `cipher`.
+    pub fn new(inner: I, cipher: Option<&'a PartCipher>) -> Self {
+        Self { inner, cipher }
+    }
+}
+
+impl<I: ReadAt> ReadAt for CipherIo<'_, I> {
+    fn read_at(&self, pos: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read_at(pos, buf)?;
+        if let Some(cipher) = self.cipher {
+            cipher.apply(pos, &mut buf[..n]);
+        }
+        Ok(n)
+    }
+}
+
+impl<I: WriteAt> WriteAt for CipherIo<'_, I> {
+    fn write_at(&mut self, pos: u64, buf: &[u8]) -> io::Result<usize> {
+        match self.cipher {
+            Some(cipher) => {
+                let mut buf = buf.to_vec();
+                cipher.apply(pos, &mut buf);
+                self.inner.write_all_at(pos, &buf)?;
+                Ok(buf.len())
+            }
+            None => self.inner.write_at(pos, buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Reader that applies the keystream of an optional cipher to the data read
+/// from a part, starting at offset 0.
+#[derive(Debug)]
+pub(crate) struct CipherReader<'a, R> {
+    inner: R,
+    cipher: Option<&'a PartCipher>,
+    offset: u64,
+}
+
+impl<'a, R> CipherReader<'a, R> {
+    /// Wrap `inner`, which reads the part for `cipher` from the start.
+    pub fn new(inner: R, cipher: Option<&'a PartCipher>) -> Self {
+        Self {
+            inner,
+            cipher,
+            offset: 0,
+        }
+    }
+}
+
+impl<R: io::Read> io::Read for CipherReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if let Some(cipher) = self.cipher {
+            cipher.apply(self.offset, &mut buf[..n]);
+        }
+        self.offset += n as u64;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn apply_at_offset() {
+        let key = EncryptionKey::generate();
+        let cipher = key.blob_cipher(&Hash::new("test"));
+        let data = (0..10000u32).map(|x| x as u8).collect::<Vec<_>>();
+        let encrypted = cipher.data().apply_bytes(&data);
+        assert_ne!(encrypted.as_ref(), data.as_slice());
+        // decrypting a slice in the middle gives the plaintext of that slice
+        let mut part = encrypted[1234..5678].to_vec();
+        cipher.data().apply(1234, &mut part);
+        assert_eq!(part, &data[1234..5678]);
+        // data and outboard use a different keystream
+        assert_ne!(cipher.outboard().apply_bytes(&data), encrypted);
+    }
+
+    #[test]
+    fn apply_across_segments() {
+        let cipher = EncryptionKey::generate().blob_cipher(&Hash::new("test"));
+        let offset = SEGMENT_SIZE - 100;
+        let mut whole = vec![0u8; 200];
+        cipher.data().apply(offset, &mut whole);
+        let mut first = vec![0u8; 100];
+        let mut second = vec![0u8; 100];
+        cipher.data().apply(offset, &mut first);
+        cipher.data().apply(SEGMENT_SIZE, &mut second);
+        assert_eq!(&whole[..100], first.as_slice());
+        assert_eq!(&whole[100..], second.as_slice());
+    }
+}
@@ -49,6 +49,8 @@
 //! The inline_data table contains the actual data for complete entries.
 //! The inline_outboard table contains the actual outboard for complete entries.
 //! The tags table contains a mapping from tag to hash.
+//! The access_time table contains the last access time for complete entries,
+//! if the store has a size budget.
 //!
 //! Design:
 //!
@@ -64,6 +66,23 @@
 //!
 //! OuterError is an enum containing all the actor errors and in addition
 //! errors when communicating with the actor.
+//!
+//! Encryption:
+//!
+//! If [`Options::encryption`] is set, data and outboard are encrypted at rest,
+//! both in files and in the inline tables. Hashes and outboards are always
+//! computed on the plaintext, so verified streaming works as usual. In memory
+//! data is plaintext. Sizes files and the blobs and tags tables are not
+//! encrypted. Temp files of imports are encrypted with a random cipher while
+//! they are written, and re-encrypted once the hash is known.
+//!
+//! Quota:
+//!
+//! If [`QuotaOptions::max_size`] is set, the actor records when entries are
+//! accessed. Access times are kept in memory and written with the next write
+//! transaction. At the end of every gc sweep, the least recently accessed
+//! entries that are not pinned by a normal tag or a temp tag are evicted until
+//! the store is below its size budget.
 use std::{
     collections::{BTreeMap, BTreeSet},
     io::{self, BufReader, Read},
@@ -79,6 +98,7 @@ use bao_tree::io::{
 };
 use bytes::Bytes;
 use futures::{channel::oneshot, Stream, StreamExt};
+use genawaiter::rc::{Co, Gen};
 
 use iroh_base::hash::{BlobFormat, Hash, HashAndFormat};
 use iroh_io::AsyncSliceReader;
@@ -99,8 +119,10 @@ mod util;
 mod validate;
 
 use crate::{
+    format::chunked::{ChunkedBlob, Chunker, ChunkerConfig},
     store::{
         bao_file::{BaoFileStorage, CompleteStorage},
+        encryption::{apply_opt, BlobCipher, CipherReader, PartCipher},
         fs::{
             tables::BaoFilePart,
             util::{overwrite_and_sync, read_and_remove, ProgressReader},
@@ -115,16 +137,20 @@ use crate::{
     },
     Tag, TempTag, IROH_BLOCK_SIZE,
 };
-use tables::{ReadOnlyTables, ReadableTables, Tables};
+use tables::{ReadOnlyTables, ReadableTables, Tables, BLOBS_TABLE, ENCRYPTION_TABLE, KEY_CHECK};
 
 use self::{tables::DeleteSet, util::PeekableFlumeReceiver};
 
 use self::test_support::EntryData;
 
+pub use super::encryption::EncryptionKey;
+
 use super::{
     bao_file::{BaoFileConfig, BaoFileHandle, BaoFileHandleWeak, CreateCb},
-    temp_name, BaoBatchWriter, BaoBlobSize, ConsistencyCheckProgress, EntryStatus, ExportMode,
-    ExportProgressCb, ImportMode, ImportProgress, Map, TempCounterMap,
+    temp_name,
+    traits::mark_reachable,
+    BaoBatchWriter, BaoBlobSize, ConsistencyCheckProgress, EntryStatus, ExportMode,
+    ExportProgressCb, GcSweepEvent, ImportMode, ImportProgress, Map, TempCounterMap,
 };
 
 /// Location of the data.
@@ -428,6 +454,40 @@ impl Default for BatchOptions {
     }
 }
 
+/// Options for limiting the size of the store.
+///
+/// When the store is over its size budget, the gc sweep evicts the least
+/// recently accessed blobs that are not protected by a tag or a temp tag.
+/// Blobs that are only reachable from cache tags are not protected.
+#[derive(Debug, Clone)]
+pub struct QuotaOptions {
+    /// Maximum total size of data and outboards of complete blobs, or `None` for
+    /// no limit.
+    ///
+    /// Partial blobs and data of external files do not count towards the limit.
+    pub max_size: Option<u64>,
+    /// Tags starting with this prefix are cache tags.
+    ///
+    /// Cache tags keep blobs alive during gc, but do not protect them from
+    /// eviction. Cache tags pointing to evicted blobs are removed.
+    pub cache_tag_prefix: Bytes,
+}
+
+impl Default for QuotaOptions {
+    fn default() -> Self {
+        Self {
+            max_size: None,
+            cache_tag_prefix: Bytes::from_static(b"cache"),
+        }
+    }
+}
+
+impl QuotaOptions {
+    fn is_cache_tag(&self, tag: &Tag) -> bool {
+        tag.0.starts_with(&self.cache_tag_prefix)
+    }
+}
+
 /// Options for the file store.
 #[derive(Debug, Clone)]
 pub struct Options {
@@ -437,11 +497,21 @@ pub struct Options {
     pub inline: InlineOptions,
     /// Transaction batching options.
     pub batch: BatchOptions,
+    /// Key to encrypt data and outboards at rest, or `None` to store them
+    /// unencrypted.
+    ///
+    /// Encryption can only be enabled when the store is created. Files can not
+    /// be referenced in an encrypted store, so imports always copy.
+    pub encryption: Option<EncryptionKey>,
+    /// Size budget and eviction options.
+    pub quota: QuotaOptions,
 }
 
 #[derive(derive_more::Debug)]
 pub(crate) enum ImportSource {
-    TempFile(PathBuf),
+    /// A temp file owned by the store, encrypted with the given cipher if the
+    /// store is encrypted.
+    TempFile(PathBuf, Option<PartCipher>),
     External(PathBuf),
     Memory(#[debug(skip)] Bytes),
 }
@@ -449,7 +519,7 @@ pub(crate) enum ImportSource {
 impl ImportSource {
     fn content(&self) -> MemOrFile<&[u8], &Path> {
         match self {
-            Self::TempFile(path) => MemOrFile::File(path.as_path()),
+            Self::TempFile(path, _) => MemOrFile::File(path.as_path()),
             Self::External(path) => MemOrFile::File(path.as_path()),
             Self::Memory(data) => MemOrFile::Mem(data.as_ref()),
         }
@@ -457,7 +527,7 @@ impl ImportSource {
 
     fn len(&self) -> io::Result<u64> {
         match self {
-            Self::TempFile(path) => std::fs::metadata(path).map(|m| m.len()),
+            Self::TempFile(path, _) => std::fs::metadata(path).map(|m| m.len()),
             Self::External(path) => std::fs::metadata(path).map(|m| m.len()),
             Self::Memory(data) => Ok(data.len() as u64),
         }
@@ -634,6 +704,12 @@ pub(crate) enum ActorMessage {
         hashes: Vec<Hash>,
         tx: oneshot::Sender<ActorResult<()>>,
     },
+    /// Modification method: evict the least recently accessed blobs that are not
+    /// pinned until the store is below its size budget.
+    Evict {
+        pinned: BTreeSet<Hash>,
+        tx: oneshot::Sender<ActorResult<EvictStats>>,
+    },
     /// Sync the entire database to disk.
     ///
     /// This just makes sure that there is no write transaction open.
@@ -677,6 +753,7 @@ impl ActorMessage {
             | Self::SetTag { .. }
             | Self::CreateTag { .. }
             | Self::SetFullEntryState { .. }
+            | Self::Evict { .. }
             | Self::Delete { .. } => MessageCategory::ReadWrite,
             Self::UpdateInlineOptions { .. }
             | Self::Sync { .. }
@@ -724,6 +801,8 @@ impl Store {
             path: PathOptions::new(path),
             inline: Default::default(),
             batch: Default::default(),
+            encryption: None,
+            quota: Default::default(),
         };
         Self::new(db_path, options).await
     }
@@ -769,6 +848,53 @@ impl Store {
     pub async fn import_flat_store(&self, paths: FlatStorePaths) -> io::Result<bool> {
         Ok(self.0.import_flat_store(paths).await?)
     }
+
+    /// Import a file split into content defined chunks.
+    ///
+    /// Every chunk is stored as a separate blob, and chunks that are already in the
+    /// store are not written again. Returns the root of the [`ChunkedBlob`] manifest,
+    /// in [`BlobFormat::HashSeq`], and the size of the file.
+    ///
+    /// The file is always copied, since a chunk can not reference a part of a file.
+    pub async fn import_file_chunked(
+        &self,
+        path: PathBuf,
+        config: ChunkerConfig,
+        progress: impl ProgressSender<Msg = ImportProgress> + IdGenerator,
+    ) -> io::Result<(TempTag, u64)> {
+        let this = self.0.clone();
+        Ok(tokio::task::spawn_blocking(move || {
+            this.import_file_chunked_sync(path, config, progress)
+        })
+        .await??)
+    }
+
+    /// Evict blobs that are not pinned if the store is over its size budget.
+    ///
+    /// Blobs are pinned if they are reachable from a tag that is not a cache
+    /// tag, or from a temp tag.
+    async fn gc_evict_task(&self, co: &Co<GcSweepEvent>) -> anyhow::Result<()> {
+        if self.0.quota.max_size.is_none() {
+            return Ok(());
+        }
+        let mut roots = BTreeSet::new();
+        for item in self.0.tags().await? {
+            let (name, haf) = item?;
+            if !self.0.quota.is_cache_tag(&name) {
+                roots.insert(haf);
+            }
+        }
+        roots.extend(self.0.temp.read().unwrap().keys());
+        let mut pinned = BTreeSet::new();
+        mark_reachable(self, roots, &mut pinned, co).await?;
+        let stats = self.0.evict(pinned).await?;
+        co.yield_(GcSweepEvent::CustomDebug(format!(
+            "evicted {} blobs, {} bytes",
+            stats.count, stats.size
+        )))
+        .await;
+        Ok(())
+    }
 }
 
 #[derive(Debug)]
@@ -777,6 +903,8 @@ struct StoreInner {
     temp: Arc<RwLock<TempCounterMap>>,
     handle: Option<std::thread::JoinHandle<()>>,
     path_options: Arc<PathOptions>,
+    encryption: Option<EncryptionKey>,
+    quota: QuotaOptions,
 }
 
 impl LivenessTracker for RwLock<TempCounterMap> {
@@ -807,6 +935,8 @@ impl StoreInner {
         );
         std::fs::create_dir_all(path.parent().unwrap())?;
         let temp: Arc<RwLock<TempCounterMap>> = Default::default();
+        let encryption = options.encryption.clone();
+        let quota = options.quota.clone();
         let (actor, tx) = Actor::new(&path, options.clone(), temp.clone(), rt)?;
         let handle = std::thread::Builder::new()
             .name("redb-actor".to_string())
@@ -821,6 +951,8 @@ impl StoreInner {
             temp,
             handle: Some(handle),
             path_options: Arc::new(options.path),
+            encryption,
+            quota,
         })
     }
 
@@ -932,6 +1064,14 @@ impl StoreInner {
         Ok(rx.await?)
     }
 
+    async fn evict(&self, pinned: BTreeSet<Hash>) -> OuterResult<EvictStats> {
+        let (tx, rx) = oneshot::channel();
+        self.tx
+            .send_async(ActorMessage::Evict { pinned, tx })
+            .await?;
+        Ok(rx.await??)
+    }
+
     async fn entry_status(&self, hash: &Hash) -> OuterResult<EntryStatus> {
         let (tx, rx) = flume::bounded(1);
         self.tx
@@ -1076,6 +1216,12 @@ impl StoreInner {
             id,
             name: path.to_string_lossy().to_string(),
         })?;
+        // an external file can not be encrypted, so always copy if the store is encrypted
+        let mode = if self.encryption.is_some() {
+            ImportMode::Copy
+        } else {
+            mode
+        };
         let file = match mode {
             ImportMode::TryReference => ImportSource::External(path),
             ImportMode::Copy => {
@@ -1087,15 +1233,20 @@ impl StoreInner {
                     ImportSource::Memory(data.into())
                 } else {
                     let temp_path = self.temp_file_name();
+                    let temp_cipher = self.temp_cipher();
                     // copy the data, since it is not stable
                     progress.try_send(ImportProgress::CopyProgress { id, offset: 0 })?;
-                    if reflink_copy::reflink_or_copy(&path, &temp_path)?.is_none() {
+                    if let Some(cipher) = &temp_cipher {
+                        // never write the plaintext to the store
+                        cipher.copy_file(&path, &temp_path)?;
+                        tracing::debug!("encrypted {} to {}", path.display(), temp_path.display());
+                    } else if reflink_copy::reflink_or_copy(&path, &temp_path)?.is_none() {
                         tracing::debug!("reflinked {} to {}", path.display(), temp_path.display());
                     } else {
                         tracing::debug!("copied {} to {}", path.display(), temp_path.display());
                     }
                     // copy progress for size will be called in finalize_import_sync
-                    ImportSource::TempFile(temp_path)
+                    ImportSource::TempFile(temp_path, temp_cipher)
                 }
             }
         };
@@ -1103,6 +1254,53 @@ impl StoreInner {
         Ok((tag, size))
     }
 
+    fn import_file_chunked_sync(
+        &self,
+        path: PathBuf,
+        config: ChunkerConfig,
+        progress: impl ProgressSender<Msg = ImportProgress> + IdGenerator,
+    ) -> OuterResult<(TempTag, u64)> {
+        if !path.is_absolute() {
+            return Err(
+                io::Error::new(io::ErrorKind::InvalidInput, "path must be absolute").into(),
+            );
+        }
+        let file = std::fs::File::open(&path)?;
+        let id = progress.new_id();
+        progress.blocking_send(ImportProgress::Found {
+            id,
+            name: path.to_string_lossy().to_string(),
+        })?;
+        progress.blocking_send(ImportProgress::Size {
+            id,
+            size: file.metadata()?.len(),
+        })?;
+        let mut blob = ChunkedBlob::default();
+        // keep the chunks alive until the manifest protects them
+        let mut chunk_tags = Vec::new();
+        let mut offset = 0;
+        for chunk in Chunker::new(file, config) {
+            let chunk = chunk?;
+            let size = chunk.len() as u64;
+            let tag = self.temp_tag(HashAndFormat::raw(Hash::new(&chunk)));
+            if self.entry_status_sync(tag.hash())? != EntryStatus::Complete {
+                self.import_bytes_sync(chunk, BlobFormat::Raw)?;
+            }
+            blob.push(*tag.hash(), size);
+            chunk_tags.push(tag);
+            offset += size;
+            progress.try_send(ImportProgress::CopyProgress { id, offset })?;
+        }
+        let (meta, links) = blob.to_meta_and_links();
+        let _meta_tag = self.import_bytes_sync(meta, BlobFormat::Raw)?;
+        let tag = self.import_bytes_sync(links, BlobFormat::HashSeq)?;
+        progress.blocking_send(ImportProgress::OutboardDone {
+            id,
+            hash: *tag.hash(),
+        })?;
+        Ok((tag, offset))
+    }
+
     fn import_bytes_sync(&self, data: Bytes, format: BlobFormat) -> OuterResult<TempTag> {
         let id = 0;
         let file = ImportSource::Memory(data);
@@ -1129,7 +1327,11 @@ impl StoreInner {
             MemOrFile::File(path) => {
                 let span = trace_span!("outboard.compute", path = %path.display());
                 let _guard = span.enter();
-                let file = std::fs::File::open(path)?;
+                let temp_cipher = match &file {
+                    ImportSource::TempFile(_, cipher) => cipher.as_ref(),
+                    _ => None,
+                };
+                let file = CipherReader::new(std::fs::File::open(path)?, temp_cipher);
                 compute_outboard(file, data_size, move |offset| {
                     Ok(progress2.try_send(ImportProgress::OutboardProgress { id, offset })?)
                 })?
@@ -1143,6 +1345,38 @@ impl StoreInner {
         // from here on, everything related to the hash is protected by the temp tag
         let tag = self.temp_tag(HashAndFormat { hash, format });
         let hash = *tag.hash();
+        // now that the hash is known, encrypt the data and outboard if needed.
+        // the actor stores them as is.
+        let (file, outboard) = match &self.encryption {
+            Some(key) => {
+                let cipher = key.blob_cipher(&hash);
+                let file = match file {
+                    ImportSource::TempFile(path, temp_cipher) => {
+                        let temp_cipher = temp_cipher.ok_or_else(|| {
+                            io::Error::new(
+                                io::ErrorKind::InvalidInput,
+                                "temp file of an encrypted store is not encrypted",
+                            )
+                        })?;
+                        cipher.data().reencrypt_file(&temp_cipher, &path)?;
+                        ImportSource::TempFile(path, None)
+                    }
+                    ImportSource::Memory(data) => {
+                        ImportSource::Memory(cipher.data().apply_bytes(&data))
+                    }
+                    ImportSource::External(_) => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::Unsupported,
+                            "can not reference external files in an encrypted store",
+                        )
+                        .into());
+                    }
+                };
+                let outboard = outboard.map(|x| cipher.outboard().apply_bytes(&x).to_vec());
+                (file, outboard)
+            }
+            None => (file, outboard),
+        };
         // blocking send for the import
         let (tx, rx) = flume::bounded(1);
         self.tx.send(ActorMessage::Import {
@@ -1161,6 +1395,11 @@ impl StoreInner {
         self.path_options.temp_file_name()
     }
 
+    /// A fresh cipher for a temp file, if the store is encrypted.
+    fn temp_cipher(&self) -> Option<PartCipher> {
+        self.encryption.as_ref().map(|_| PartCipher::generate())
+    }
+
     async fn shutdown(&self) {
         let (tx, rx) = oneshot::channel();
         self.tx
@@ -1180,9 +1419,20 @@ impl Drop for StoreInner {
     }
 }
 
+/// Result of an eviction pass.
+#[derive(Debug, Default)]
+pub(crate) struct EvictStats {
+    /// Number of evicted blobs.
+    count: u64,
+    /// Number of bytes of data and outboard that were freed.
+    size: u64,
+}
+
 struct ActorState {
     handles: BTreeMap<Hash, BaoFileHandleWeak>,
     protected: BTreeSet<Hash>,
+    /// Access times that have not yet been written to the access time table.
+    access_times: BTreeMap<Hash, u64>,
     temp: Arc<RwLock<TempCounterMap>>,
     msgs: flume::Receiver<ActorMessage>,
     create_options: Arc<BaoFileConfig>,
@@ -1221,6 +1471,8 @@ pub(crate) enum ActorError {
     Inconsistent(String),
     #[error("error during database migration: {0}")]
     Migration(#[source] anyhow::Error),
+    #[error("encryption error: {0}")]
+    Encryption(String),
 }
 
 impl From<ActorError> for io::Error {
@@ -1388,17 +1640,25 @@ impl super::Store for Store {
             .to_string_lossy()
             .to_string();
         progress.send(ImportProgress::Found { id, name }).await?;
+        let temp_cipher = this.0.temp_cipher();
         let mut writer = tokio::fs::File::create(&temp_data_path).await?;
         let mut offset = 0;
         while let Some(chunk) = data.next().await {
             let chunk = chunk?;
-            writer.write_all(&chunk).await?;
+            match &temp_cipher {
+                Some(cipher) => {
+                    let mut chunk = chunk.to_vec();
+                    cipher.apply(offset, &mut chunk);
+                    writer.write_all(&chunk).await?;
+                }
+                None => writer.write_all(&chunk).await?,
+            }
             offset += chunk.len() as u64;
             progress.try_send(ImportProgress::CopyProgress { id, offset })?;
         }
         writer.flush().await?;
         drop(writer);
-        let file = ImportSource::TempFile(temp_data_path);
+        let file = ImportSource::TempFile(temp_data_path, temp_cipher);
         Ok(tokio::task::spawn_blocking(move || {
             this.0.finalize_import_sync(file, format, id, progress)
         })
@@ -1422,6 +1682,14 @@ impl super::Store for Store {
         Ok(())
     }
 
+    fn gc_evict(&self) -> impl Stream<Item = GcSweepEvent> + Unpin {
+        Gen::new(|co| async move {
+            if let Err(e) = self.gc_evict_task(&co).await {
+                co.yield_(GcSweepEvent::Error(e)).await;
+            }
+        })
+    }
+
     fn temp_tag(&self, value: HashAndFormat) -> TempTag {
         self.0.temp_tag(value)
     }
@@ -1451,6 +1719,7 @@ impl Actor {
         let mut t = Default::default();
         let tables = Tables::new(&txn, &mut t)?;
         drop(tables);
+        check_encryption_key(&txn, options.encryption.as_ref())?;
         txn.commit()?;
         // make the channel relatively large. there are some messages that don't
         // require a response, it's fine if they pile up a bit.
@@ -1466,7 +1735,8 @@ impl Actor {
             Arc::new(options.path.data_path.clone()),
             16 * 1024,
             Some(on_file_create),
-        );
+        )
+        .with_encryption(options.encryption.clone());
         Ok((
             Self {
                 db,
@@ -1474,6 +1744,7 @@ impl Actor {
                     temp,
                     handles: BTreeMap::new(),
                     protected: BTreeSet::new(),
+                    access_times: BTreeMap::new(),
                     msgs: rx,
                     options,
                     create_options: Arc::new(create_options),
@@ -1526,6 +1797,7 @@ impl Actor {
                             break;
                         }
                     }
+                    self.state.flush_access_times(&mut tables)?;
                     drop(tables);
                     txn.commit()?;
                     delete_after_commit.apply_and_clear(&self.state.options.path);
@@ -1560,11 +1832,13 @@ impl ActorState {
         hash: Hash,
     ) -> ActorResult<Option<BaoFileHandle>> {
         if let Some(handle) = self.handles.get(&hash).and_then(|weak| weak.upgrade()) {
+            self.touch(hash);
             return Ok(Some(handle));
         }
         let Some(entry) = tables.blobs().get(hash)? else {
             return Ok(None);
         };
+        self.touch(hash);
         // todo: if complete, load inline data and/or outboard into memory if needed,
         // and return a complete entry.
         let entry = entry.value();
@@ -1574,13 +1848,21 @@ impl ActorState {
                 data_location,
                 outboard_location,
             } => {
-                let data = load_data(tables, &self.options.path, data_location, &hash)?;
+                let cipher = config.cipher(&hash);
+                let data = load_data(
+                    tables,
+                    &self.options.path,
+                    data_location,
+                    &hash,
+                    cipher.as_ref(),
+                )?;
                 let outboard = load_outboard(
                     tables,
                     &self.options.path,
                     outboard_location,
                     data.size(),
                     &hash,
+                    cipher.as_ref(),
                 )?;
                 BaoFileHandle::new_complete(config, hash, data, outboard)
             }
@@ -1607,6 +1889,7 @@ impl ActorState {
             .get(temp_tag.hash())?
             .ok_or_else(|| ActorError::Inconsistent("entry not found".to_owned()))?;
         let entry = guard.value();
+        let cipher = self.create_options.cipher(temp_tag.hash());
         match entry {
             EntryState::Complete {
                 data_location,
@@ -1618,17 +1901,22 @@ impl ActorState {
                         let data = tables.inline_data.get(temp_tag.hash())?.ok_or_else(|| {
                             ActorError::Inconsistent("inline data not found".to_owned())
                         })?;
+                        let data = Bytes::copy_from_slice(data.value());
+                        let data = apply_opt(cipher.as_ref().map(BlobCipher::data), data);
                         tracing::trace!("exporting inline data to {}", target.display());
-                        tx.send(std::fs::write(&target, data.value()).map_err(|e| e.into()))
+                        tx.send(std::fs::write(&target, data).map_err(|e| e.into()))
                             .ok();
                     }
                     DataLocation::Owned(size) => {
                         let path = self.options.path.owned_data_path(temp_tag.hash());
-                        if mode == ExportMode::Copy {
+                        // an encrypted file can not be moved out of the store, so copy it
+                        if mode == ExportMode::Copy || cipher.is_some() {
                             // copy in an external thread
                             self.rt.spawn_blocking(move || {
-                                tx.send(export_file_copy(temp_tag, path, size, target, progress))
-                                    .ok();
+                                tx.send(export_file_copy(
+                                    temp_tag, path, size, target, cipher, progress,
+                                ))
+                                .ok();
                             });
                         } else {
                             match std::fs::rename(&path, &target) {
@@ -1663,8 +1951,10 @@ impl ActorState {
                         } else {
                             // copy in an external thread
                             self.rt.spawn_blocking(move || {
-                                tx.send(export_file_copy(temp_tag, path, size, target, progress))
-                                    .ok();
+                                tx.send(export_file_copy(
+                                    temp_tag, path, size, target, None, progress,
+                                ))
+                                .ok();
                             });
                         }
                     }
@@ -1692,6 +1982,7 @@ impl ActorState {
         let tag = TempTag::new(content_id, Some(self.temp.clone()));
         let hash = *tag.hash();
         self.protected.insert(hash);
+        self.touch(hash);
         // move the data file into place, or create a reference to it
         let data_location = match file {
             ImportSource::External(external_path) => {
@@ -1707,7 +1998,7 @@ impl ActorState {
                     DataLocation::External(vec![external_path], data_size)
                 }
             }
-            ImportSource::TempFile(temp_data_path) => {
+            ImportSource::TempFile(temp_data_path, _) => {
                 if inline_data {
                     tracing::debug!(
                         "reading and deleting temp file to inline it: {}",
@@ -1789,13 +2080,21 @@ impl ActorState {
                     outboard_location,
                     ..
                 } => {
-                    let data = load_data(tables, &self.options.path, data_location, &hash)?;
+                    let cipher = self.create_options.cipher(&hash);
+                    let data = load_data(
+                        tables,
+                        &self.options.path,
+                        data_location,
+                        &hash,
+                        cipher.as_ref(),
+                    )?;
                     let outboard = load_outboard(
                         tables,
                         &self.options.path,
                         outboard_location,
                         data.size(),
                         &hash,
+                        cipher.as_ref(),
                     )?;
                     println!("creating complete entry for {}", hash.to_hex());
                     BaoFileHandle::new_complete(self.create_options.clone(), hash, data, outboard)
@@ -2027,6 +2326,8 @@ impl ActorState {
             }
             tracing::debug!("deleting {}", &hash.to_hex()[..8]);
             self.handles.remove(&hash);
+            self.access_times.remove(&hash);
+            tables.access_time.remove(hash)?;
             if let Some(entry) = tables.blobs.remove(hash)? {
                 match entry.value() {
                     EntryState::Complete {
@@ -2069,10 +2370,143 @@ impl ActorState {
         Ok(())
     }
 
+    /// Record that a blob was accessed, if the store has a size budget.
+    fn touch(&mut self, hash: Hash) {
+        if self.options.quota.max_size.is_none() {
+            return;
+        }
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|x| x.as_micros() as u64)
+            .unwrap_or_default();
+        self.access_times.insert(hash, now);
+    }
+
+    /// Write the access times that were recorded since the last write transaction.
+    fn flush_access_times(&mut self, tables: &mut Tables) -> ActorResult<()> {
+        for (hash, time) in std::mem::take(&mut self.access_times) {
+            // the blob might have been deleted in the meantime
+            if tables.blobs.get(hash)?.is_some() {
+                tables.access_time.insert(hash, time)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Evict the least recently accessed complete blobs until the store is
+    /// below its size budget.
+    ///
+    /// Blobs in `pinned`, blobs protected by a temp tag, blobs that are
+    /// currently being written and blobs with a live handle are never evicted.
+    /// If the pinned blobs alone exceed the budget, the store stays over it.
+    fn evict(&mut self, tables: &mut Tables, pinned: BTreeSet<Hash>) -> ActorResult<EvictStats> {
+        let mut stats = EvictStats::default();
+        let Some(max_size) = self.options.quota.max_size else {
+            return Ok(stats);
+        };
+        self.flush_access_times(tables)?;
+        let mut total = 0;
+        let mut candidates = Vec::new();
+        for item in tables.blobs.iter()? {
+            let (hash, entry) = item?;
+            let hash = hash.value();
+            let EntryState::Complete {
+                data_location,
+                outboard_location,
+            } = entry.value()
+            else {
+                continue;
+            };
+            let (data_size, stored_data_size) = match data_location {
+                DataLocation::Inline(()) => {
+                    let size = tables
+                        .inline_data
+                        .get(hash)?
+                        .map(|x| x.value().len() as u64)
+                        .unwrap_or_default();
+                    (size, size)
+                }
+                DataLocation::Owned(size) => (size, size),
+                DataLocation::External(_, size) => (size, 0),
+            };
+            let outboard_size = match outboard_location {
+                OutboardLocation::NotNeeded => 0,
+                _ => raw_outboard_size(data_size),
+            };
+            let size = stored_data_size + outboard_size;
+            total += size;
+            if size > 0 && self.is_evictable(&hash, &pinned) {
+                candidates.push((hash, size));
+            }
+        }
+        if total <= max_size {
+            return Ok(stats);
+        }
+
+        let mut candidates = candidates
+            .into_iter()
+            .map(|(hash, size)| {
+                let time = tables.access_time.get(hash)?.map(|x| x.value());
+                Ok((time.unwrap_or_default(), hash, size))
+            })
+            .collect::<ActorResult<Vec<_>>>()?;
+        candidates.sort();
+        let mut evicted = BTreeSet::new();
+        for (_, hash, size) in candidates {
+            if total <= max_size {
+                break;
+            }
+            tracing::debug!("evicting {}, {} bytes", &hash.to_hex()[..8], size);
+            self.delete(tables, vec![hash])?;
+            evicted.insert(hash);
+            total -= size;
+            stats.count += 1;
+            stats.size += size;
+        }
+        if total > max_size {
+            // everything that is left is pinned, so there is nothing more to do
+            tracing::warn!(
+                "pinned blobs use {} bytes, which is over the size budget of {} bytes",
+                total,
+                max_size
+            );
+        }
+        // remove cache tags that point to evicted blobs
+        let mut dangling = Vec::new();
+        for item in tables.tags.iter()? {
+            let (tag, value) = item?;
+            let tag = tag.value();
+            if self.options.quota.is_cache_tag(&tag) && evicted.contains(&value.value().hash) {
+                dangling.push(tag);
+            }
+        }
+        for tag in dangling {
+            tables.tags.remove(tag)?;
+        }
+        Ok(stats)
+    }
+
+    /// True if a complete blob may be evicted.
+    fn is_evictable(&self, hash: &Hash, pinned: &BTreeSet<Hash>) -> bool {
+        if pinned.contains(hash) || self.protected.contains(hash) {
+            return false;
+        }
+        if self.temp.as_ref().read().unwrap().contains(hash) {
+            return false;
+        }
+        // someone is reading or writing the blob
+        !self
+            .handles
+            .get(hash)
+            .map(|weak| weak.is_live())
+            .unwrap_or_default()
+    }
+
     fn on_complete(&mut self, tables: &mut Tables, entry: BaoFileHandle) -> ActorResult<()> {
         let hash = entry.hash();
         let mut info = None;
         tracing::trace!("on_complete({})", hash.to_hex());
+        self.touch(hash);
         entry.transform(|state| {
             tracing::trace!("on_complete transform {:?}", state);
             let entry = match complete_storage(
@@ -2080,6 +2514,7 @@ impl ActorState {
                 &hash,
                 &self.options.path,
                 &self.options.inline,
+                self.create_options.cipher(&hash),
                 tables.delete_after_commit,
             )? {
                 Ok(entry) => {
@@ -2128,10 +2563,14 @@ impl ActorState {
                     outboard_location,
                 })?;
                 tables.blobs.insert(hash, entry)?;
+                // in memory data is plaintext, so encrypt it before inlining it
+                let cipher = self.create_options.cipher(&hash);
                 if let Some(data) = data {
+                    let data = apply_opt(cipher.as_ref().map(BlobCipher::data), data);
                     tables.inline_data.insert(hash, data.as_ref())?;
                 }
                 if let Some(outboard) = outboard {
+                    let outboard = apply_opt(cipher.as_ref().map(BlobCipher::outboard), outboard);
                     tables.inline_outboard.insert(hash, outboard.as_ref())?;
                 }
             }
@@ -2262,6 +2701,10 @@ impl ActorState {
                 let res = self.set_full_entry_state(tables, hash, entry);
                 tx.send(res).ok();
             }
+            ActorMessage::Evict { pinned, tx } => {
+                let res = self.evict(tables, pinned);
+                tx.send(res).ok();
+            }
             msg => {
                 // try to handle it as readonly
                 if let Err(msg) = self.handle_readonly(tables, msg)? {
@@ -2273,17 +2716,60 @@ impl ActorState {
     }
 }
 
+/// Make sure that an encrypted store is opened with the key it was created with.
+///
+/// When an empty store is opened with a key for the first time, a check value
+/// for the key is stored.
+fn check_encryption_key(
+    txn: &redb::WriteTransaction,
+    key: Option<&EncryptionKey>,
+) -> ActorResult<()> {
+    let mut table = txn.open_table(ENCRYPTION_TABLE)?;
+    let check = table.get(KEY_CHECK)?.map(|x| x.value().to_vec());
+    match (check, key) {
+        (Some(check), Some(key)) => {
+            if check != key.check_value() {
+                return Err(ActorError::Encryption("wrong encryption key".to_owned()));
+            }
+        }
+        (Some(_), None) => {
+            return Err(ActorError::Encryption(
+                "store is encrypted, but no encryption key was given".to_owned(),
+            ));
+        }
+        (None, Some(key)) => {
+            if txn.open_table(BLOBS_TABLE)?.iter()?.next().is_some() {
+                return Err(ActorError::Encryption(
+                    "can not enable encryption for a store that contains unencrypted data"
+                        .to_owned(),
+                ));
+            }
+            table.insert(KEY_CHECK, key.check_value().as_slice())?;
+        }
+        (None, None) => {}
+    }
+    Ok(())
+}
+
 /// Export a file by copyign out its content to a new location
+///
+/// If there is a cipher, the content is decrypted while copying.
 fn export_file_copy(
     temp_tag: TempTag,
     path: PathBuf,
     size: u64,
     target: PathBuf,
+    cipher: Option<BlobCipher>,
     progress: ExportProgressCb,
 ) -> ActorResult<()> {
     progress(0)?;
     // todo: fine grained copy progress
-    reflink_copy::reflink_or_copy(path, target)?;
+    match cipher {
+        Some(cipher) => cipher.data().copy_file(&path, &target)?,
+        None => {
+            reflink_copy::reflink_or_copy(path, target)?;
+        }
+    }
     progress(size)?;
     drop(temp_tag);
     Ok(())
@@ -2350,11 +2836,15 @@ fn dump(tables: &impl ReadableTables) -> ActorResult<()> {
     Ok(())
 }
 
+/// Load the data of a complete entry.
+///
+/// Inline data is decrypted if there is a cipher, files are returned as is.
 fn load_data(
     tables: &impl ReadableTables,
     options: &PathOptions,
     location: DataLocation<(), u64>,
     hash: &Hash,
+    cipher: Option<&BlobCipher>,
 ) -> ActorResult<MemOrFile<Bytes, (std::fs::File, u64)>> {
     Ok(match location {
         DataLocation::Inline(()) => {
@@ -2364,7 +2854,8 @@ fn load_data(
                     hash.to_hex()
                 )));
             };
-            MemOrFile::Mem(Bytes::copy_from_slice(data.value()))
+            let data = Bytes::copy_from_slice(data.value());
+            MemOrFile::Mem(apply_opt(cipher.map(BlobCipher::data), data))
         }
         DataLocation::Owned(data_size) => {
             let path = options.owned_data_path(hash);
@@ -2396,12 +2887,16 @@ fn load_data(
     })
 }
 
+/// Load the outboard of a complete entry.
+///
+/// An inline outboard is decrypted if there is a cipher, files are returned as is.
 fn load_outboard(
     tables: &impl ReadableTables,
     options: &PathOptions,
     location: OutboardLocation,
     size: u64,
     hash: &Hash,
+    cipher: Option<&BlobCipher>,
 ) -> ActorResult<MemOrFile<Bytes, (std::fs::File, u64)>> {
     Ok(match location {
         OutboardLocation::NotNeeded => MemOrFile::Mem(Bytes::new()),
@@ -2412,7 +2907,8 @@ fn load_outboard(
                     hash.to_hex()
                 )));
             };
-            MemOrFile::Mem(Bytes::copy_from_slice(outboard.value()))
+            let outboard = Bytes::copy_from_slice(outboard.value());
+            MemOrFile::Mem(apply_opt(cipher.map(BlobCipher::outboard), outboard))
         }
         OutboardLocation::Owned => {
             let outboard_size = raw_outboard_size(size);
@@ -2430,11 +2926,15 @@ fn load_outboard(
 }
 
 /// Take a possibly incomplete storage and turn it into complete
+///
+/// If there is a cipher, data and outboard are encrypted when moving them from
+/// memory to a file, and decrypted when moving them from a file to memory.
 fn complete_storage(
     storage: BaoFileStorage,
     hash: &Hash,
     path_options: &PathOptions,
     inline_options: &InlineOptions,
+    cipher: Option<BlobCipher>,
     delete_after_commit: &mut DeleteSet,
 ) -> ActorResult<std::result::Result<CompleteStorage, CompleteStorage>> {
     let (data, outboard, _sizes) = match storage {
@@ -2466,6 +2966,9 @@ fn complete_storage(
             MemOrFile::File(data) => {
                 let mut buf = vec![0; data_size as usize];
                 data.read_at(0, &mut buf)?;
+                if let Some(cipher) = &cipher {
+                    cipher.data().apply(0, &mut buf);
+                }
                 // mark data for deletion after commit
                 delete_after_commit.insert(*hash, [BaoFilePart::Data]);
                 MemOrFile::Mem(Bytes::from(buf))
@@ -2478,6 +2981,7 @@ fn complete_storage(
         match data {
             MemOrFile::Mem(data) => {
                 let path = path_options.owned_data_path(hash);
+                let data = apply_opt(cipher.as_ref().map(BlobCipher::data), data);
                 let file = overwrite_and_sync(&path, &data)?;
                 MemOrFile::File((file, data_size))
             }
@@ -2493,6 +2997,9 @@ fn complete_storage(
                 let mut buf = vec![0; outboard_size as usize];
                 outboard.read_at(0, &mut buf)?;
                 drop(outboard);
+                if let Some(cipher) = &cipher {
+                    cipher.outboard().apply(0, &mut buf);
+                }
                 // mark outboard for deletion after commit
                 delete_after_commit.insert(*hash, [BaoFilePart::Outboard]);
                 MemOrFile::Mem(Bytes::from(buf))
@@ -2505,6 +3012,7 @@ fn complete_storage(
         match outboard {
             MemOrFile::Mem(outboard) => {
                 let path = path_options.owned_outboard_path(hash);
+                let outboard = apply_opt(cipher.as_ref().map(BlobCipher::outboard), outboard);
                 let file = overwrite_and_sync(&path, &outboard)?;
                 MemOrFile::File((file, outboard_size))
             }
@@ -2514,5 +3022,9 @@ fn complete_storage(
     // mark sizes for deletion after commit in any case - a complete entry
     // does not need sizes.
     delete_after_commit.insert(*hash, [BaoFilePart::Sizes]);
-    Ok(Ok(CompleteStorage { data, outboard }))
+    Ok(Ok(CompleteStorage {
+        data,
+        outboard,
+        cipher,
+    }))
 }
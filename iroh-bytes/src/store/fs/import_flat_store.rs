@@ -14,7 +14,7 @@ use crate::{
     IROH_BLOCK_SIZE,
 };
 
-use super::{ActorResult, ActorState, FlatStorePaths};
+use super::{ActorError, ActorResult, ActorState, FlatStorePaths};
 use iroh_base::hash::{Hash, HashAndFormat};
 use redb::ReadableTable;
 use std::str::FromStr;
@@ -124,6 +124,13 @@ impl ActorState {
             partial: partial_path,
             meta: meta_path,
         } = &paths;
+        if self.options.encryption.is_some()
+            && (complete_path.exists() || partial_path.exists() || meta_path.exists())
+        {
+            return Err(ActorError::Encryption(
+                "can not import an unencrypted flat store into an encrypted store".to_owned(),
+            ));
+        }
         let mut index = BTreeMap::<Hash, EntryPaths>::new();
         let mut have_partial = false;
         let mut have_complete = false;
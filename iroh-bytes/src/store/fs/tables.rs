@@ -18,6 +18,18 @@ pub(super) const INLINE_DATA_TABLE: TableDefinition<Hash, &[u8]> =
 pub(super) const INLINE_OUTBOARD_TABLE: TableDefinition<Hash, &[u8]> =
     TableDefinition::new("inline-outboard-0");
 
+/// Contains the last time a complete blob was accessed, in microseconds since
+/// the unix epoch. Only maintained if the store has a size budget.
+pub(super) const ACCESS_TIME_TABLE: TableDefinition<Hash, u64> =
+    TableDefinition::new("access-time-0");
+
+/// Contains a check value for the encryption key if the store is encrypted.
+pub(super) const ENCRYPTION_TABLE: TableDefinition<&str, &[u8]> =
+    TableDefinition::new("encryption-0");
+
+/// Key of the encryption key check value in the encryption table.
+pub(super) const KEY_CHECK: &str = "key-check";
+
 /// A trait similar to [`redb::ReadableTable`] but for all tables that make up
 /// the blob store. This can be used in places where either a readonly or
 /// mutable table is needed.
@@ -35,6 +47,7 @@ pub(super) struct Tables<'a> {
     pub tags: redb::Table<'a, Tag, HashAndFormat>,
     pub inline_data: redb::Table<'a, Hash, &'static [u8]>,
     pub inline_outboard: redb::Table<'a, Hash, &'static [u8]>,
+    pub access_time: redb::Table<'a, Hash, u64>,
     pub delete_after_commit: &'a mut DeleteSet,
 }
 
@@ -55,6 +68,7 @@ impl<'txn> Tables<'txn> {
             tags: tx.open_table(TAGS_TABLE)?,
             inline_data: tx.open_table(INLINE_DATA_TABLE)?,
             inline_outboard: tx.open_table(INLINE_OUTBOARD_TABLE)?,
+            access_time: tx.open_table(ACCESS_TIME_TABLE)?,
             delete_after_commit,
         })
     }
@@ -2,6 +2,7 @@ use bao_tree::ChunkRanges;
 use iroh_io::AsyncSliceReaderExt;
 use std::io::Cursor;
 
+use crate::format::chunked::{ChunkedBlob, ChunkedReader, ChunkerConfig};
 use crate::store::bao_file::test_support::{
     decode_response_into_batch, make_wire_data, random_test_data, simulate_remote, validate,
 };
@@ -44,6 +45,19 @@ pub fn to_stream(
         .boxed()
 }
 
+async fn create_encrypted_test_db(testdir: &Path, key: Option<EncryptionKey>) -> io::Result<Store> {
+    let _ = tracing_subscriber::fmt::try_init();
+    let db_path = testdir.join("db.redb");
+    let options = Options {
+        path: PathOptions::new(testdir),
+        batch: Default::default(),
+        inline: Default::default(),
+        encryption: key,
+        quota: Default::default(),
+    };
+    Store::new(db_path, options).await
+}
+
 async fn create_test_db() -> (tempfile::TempDir, Store) {
     let _ = tracing_subscriber::fmt::try_init();
     let testdir = tempfile::tempdir().unwrap();
@@ -52,6 +66,8 @@ async fn create_test_db() -> (tempfile::TempDir, Store) {
         path: PathOptions::new(testdir.path()),
         batch: Default::default(),
         inline: Default::default(),
+        encryption: None,
+        quota: Default::default(),
     };
     let db = Store::new(db_path, options).await.unwrap();
     (testdir, db)
@@ -420,6 +436,49 @@ async fn import_file_cases() {
     }
 }
 
+#[tokio::test]
+async fn import_file_chunked_cases() {
+    let np = IgnoreProgressSender::<ImportProgress>::default;
+    let (tempdir, db) = create_test_db().await;
+    let config = ChunkerConfig::new(1024, 4096, 16384).unwrap();
+    let v1 = random_test_data(LARGE_SIZE as usize);
+    let mut v2 = v1.clone();
+    v2[LARGE_SIZE as usize / 2] ^= 1;
+    let path = tempdir.path().join("v1.data");
+    std::fs::write(&path, &v1).unwrap();
+    let (tt1, size) = db.import_file_chunked(path, config, np()).await.unwrap();
+    assert_eq!(size, LARGE_SIZE);
+    let path = tempdir.path().join("v2.data");
+    std::fs::write(&path, &v2).unwrap();
+    let (tt2, _) = db.import_file_chunked(path, config, np()).await.unwrap();
+    assert_eq!(tt1.format(), BlobFormat::HashSeq);
+    // only the chunks around the changed byte differ
+    let m1 = ChunkedBlob::load(&db, tt1.hash()).await.unwrap();
+    let m2 = ChunkedBlob::load(&db, tt2.hash()).await.unwrap();
+    let changed = m2
+        .iter()
+        .filter(|chunk| !m1.iter().any(|c| c == *chunk))
+        .count();
+    assert!(
+        (1..=2).contains(&changed),
+        "{changed} of {} changed",
+        m2.len()
+    );
+    // reassemble
+    let mut reader = ChunkedReader::load(&db, tt2.hash()).await.unwrap();
+    assert_eq!(reader.read_to_end().await.unwrap(), v2);
+    let out = tempdir.path().join("v2.out");
+    crate::export::export_chunked(
+        &db,
+        *tt2.hash(),
+        out.clone(),
+        IgnoreProgressSender::default(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(std::fs::read(&out).unwrap(), v2);
+}
+
 #[tokio::test]
 async fn import_file_reference_cases() {
     let np = IgnoreProgressSender::<ImportProgress>::default;
@@ -781,6 +840,8 @@ async fn actor_store_smoke() {
         path: PathOptions::new(testdir.path()),
         batch: Default::default(),
         inline: Default::default(),
+        encryption: None,
+        quota: Default::default(),
     };
     let db = Store::new(db_path, options).await.unwrap();
     db.dump().await.unwrap();
@@ -803,3 +864,252 @@ async fn actor_store_smoke() {
     db.sync().await.unwrap();
     db.dump().await.unwrap();
 }
+
+/// Check that neither data nor outboard of a complete entry are stored in the clear.
+async fn assert_encrypted(db: &Store, hash: Hash, data: &[u8]) {
+    let (outboard, _) = raw_outboard(data);
+    let state = db.entry_state(hash).await.unwrap();
+    let Some(EntryState::Complete {
+        data_location,
+        outboard_location,
+    }) = state.db
+    else {
+        panic!("entry not complete");
+    };
+    let stored_data = match data_location {
+        DataLocation::Inline(stored) => stored,
+        DataLocation::Owned(_) => std::fs::read(db.owned_data_path(&hash)).unwrap(),
+        DataLocation::External(..) => panic!("external data in an encrypted store"),
+    };
+    assert_eq!(stored_data.len(), data.len());
+    assert_ne!(stored_data, data);
+    let stored_outboard = match outboard_location {
+        OutboardLocation::Inline(stored) => stored,
+        OutboardLocation::Owned => std::fs::read(db.owned_outboard_path(&hash)).unwrap(),
+        OutboardLocation::NotNeeded => Vec::new(),
+    };
+    assert_eq!(stored_outboard.len(), outboard.len());
+    if !outboard.is_empty() {
+        assert_ne!(stored_outboard, outboard);
+    }
+}
+
+#[tokio::test]
+async fn encrypted_store_cases() {
+    let np = IgnoreProgressSender::<ImportProgress>::default;
+    let testdir = tempfile::tempdir().unwrap();
+    let key = EncryptionKey::generate();
+    let db = create_encrypted_test_db(testdir.path(), Some(key.clone()))
+        .await
+        .unwrap();
+    let mut expected = Vec::new();
+    // import from memory and from a file, which is copied even though a reference is requested
+    for size in [SMALL_SIZE, MID_SIZE, LARGE_SIZE] {
+        let data = Bytes::from(random_test_data(size as usize));
+        let tt = db
+            .import_bytes(data.clone(), BlobFormat::Raw)
+            .await
+            .unwrap();
+        assert_encrypted(&db, *tt.hash(), &data).await;
+        expected.push((tt, data));
+        let data = Bytes::from(random_test_data(size as usize));
+        let path = testdir.path().join(format!("import-{size}"));
+        std::fs::write(&path, &data).unwrap();
+        let (tt, _) = db
+            .import_file(path, ImportMode::TryReference, BlobFormat::Raw, np())
+            .await
+            .unwrap();
+        assert_encrypted(&db, *tt.hash(), &data).await;
+        expected.push((tt, data));
+    }
+    // sync from a remote, going through the in memory and the file storage
+    for size in [SMALL_SIZE, MID_SIZE, LARGE_SIZE] {
+        let data = Bytes::from(random_test_data(size as usize));
+        let (hash, reader) = simulate_remote(&data);
+        let entry = db.get_or_create(hash, 0).await.unwrap();
+        let writer = entry.batch_writer().await.unwrap();
+        decode_response_into_batch(hash, IROH_BLOCK_SIZE, ChunkRanges::all(), reader, writer)
+            .await
+            .unwrap();
+        if size > SMALL_SIZE {
+            let stored = std::fs::read(db.owned_data_path(&hash)).unwrap();
+            assert_ne!(stored, data);
+        }
+        db.insert_complete(entry.clone()).await.unwrap();
+        drop(entry);
+        assert_encrypted(&db, hash, &data).await;
+        expected.push((db.temp_tag(HashAndFormat::raw(hash)), data));
+    }
+    db.sync().await.unwrap();
+    // reading and exporting gives the plaintext
+    for (tt, data) in &expected {
+        let entry = db.get(tt.hash()).await.unwrap().expect("entry not found");
+        let actual = entry.data_reader().read_to_end().await.unwrap();
+        assert_eq!(&actual, data);
+        let target = testdir
+            .path()
+            .join(format!("export-{}", tt.hash().to_hex()));
+        db.export(
+            *tt.hash(),
+            target.clone(),
+            ExportMode::TryReference,
+            Box::new(|_| Ok(())),
+        )
+        .await
+        .unwrap();
+        assert_eq!(&std::fs::read(&target).unwrap(), data);
+    }
+    drop(db);
+    // the store can only be opened with the right key
+    assert!(create_encrypted_test_db(testdir.path(), None)
+        .await
+        .is_err());
+    let wrong_key = EncryptionKey::generate();
+    assert!(create_encrypted_test_db(testdir.path(), Some(wrong_key))
+        .await
+        .is_err());
+    let db = create_encrypted_test_db(testdir.path(), Some(key))
+        .await
+        .unwrap();
+    for (tt, data) in &expected {
+        let entry = db.get(tt.hash()).await.unwrap().expect("entry not found");
+        let actual = entry.data_reader().read_to_end().await.unwrap();
+        assert_eq!(&actual, data);
+    }
+}
+
+#[tokio::test]
+async fn encrypted_stream_import_temp_file() {
+    let testdir = tempfile::tempdir().unwrap();
+    let db = create_encrypted_test_db(testdir.path(), Some(EncryptionKey::generate()))
+        .await
+        .unwrap();
+    // an import that fails midway leaves its temp file behind, like a crash would
+    let data = Bytes::from(random_test_data(MID_SIZE as usize));
+    let stream = futures::stream::iter([Ok(data.clone()), Err(io::Error::other("aborted"))]);
+    assert!(db
+        .import_stream(stream, BlobFormat::Raw, IgnoreProgressSender::default())
+        .await
+        .is_err());
+    let temp_files = std::fs::read_dir(testdir.path().join("temp"))
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect::<Vec<_>>();
+    assert_eq!(temp_files.len(), 1);
+    let stored = loop {
+        let stored = std::fs::read(&temp_files[0]).unwrap();
+        if stored.len() == data.len() {
+            break stored;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    };
+    assert_ne!(stored, data);
+    // a complete stream import is readable as usual
+    let data = Bytes::from(random_test_data(LARGE_SIZE as usize));
+    let stream = to_stream(&data, MID_SIZE as usize, Duration::ZERO);
+    let (tt, size) = db
+        .import_stream(stream, BlobFormat::Raw, IgnoreProgressSender::default())
+        .await
+        .unwrap();
+    assert_eq!(size, data.len() as u64);
+    assert_encrypted(&db, *tt.hash(), &data).await;
+    let entry = db.get(tt.hash()).await.unwrap().expect("entry not found");
+    assert_eq!(entry.data_reader().read_to_end().await.unwrap(), data);
+}
+
+#[tokio::test]
+async fn encryption_requires_empty_store() {
+    let testdir = tempfile::tempdir().unwrap();
+    let db = create_encrypted_test_db(testdir.path(), None)
+        .await
+        .unwrap();
+    let _tt = db
+        .import_bytes(Bytes::from_static(b"hello"), BlobFormat::Raw)
+        .await
+        .unwrap();
+    db.sync().await.unwrap();
+    drop(db);
+    let key = EncryptionKey::generate();
+    assert!(create_encrypted_test_db(testdir.path(), Some(key))
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn evict_lru_cases() {
+    let _ = tracing_subscriber::fmt::try_init();
+    let testdir = tempfile::tempdir().unwrap();
+    let blob_size = MID_SIZE + raw_outboard_size(MID_SIZE);
+    let options = Options {
+        path: PathOptions::new(testdir.path()),
+        batch: Default::default(),
+        inline: Default::default(),
+        encryption: None,
+        quota: QuotaOptions {
+            max_size: Some(blob_size * 3),
+            ..Default::default()
+        },
+    };
+    let db = Store::new(testdir.path().join("db.redb"), options)
+        .await
+        .unwrap();
+    // blob 0 is protected by a normal tag, the others only by cache tags
+    let mut hashes = Vec::new();
+    for i in 0..4 {
+        let data = Bytes::from(random_test_data(MID_SIZE as usize));
+        let tt = db.import_bytes(data, BlobFormat::Raw).await.unwrap();
+        let tag = if i == 0 {
+            Tag::from("pinned")
+        } else {
+            Tag::from(format!("cache-{i}"))
+        };
+        db.set_tag(tag, Some(*tt.inner())).await.unwrap();
+        hashes.push(*tt.hash());
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    // serving blob 1 makes blob 2 the least recently accessed one
+    db.get(&hashes[1]).await.unwrap().expect("entry not found");
+    let gc = || async {
+        db.gc_start().await.unwrap();
+        let mut live = BTreeSet::new();
+        db.gc_mark(&mut live).count().await;
+        let events = db.gc_sweep(&live).collect::<Vec<_>>().await;
+        assert!(!events
+            .iter()
+            .any(|e| matches!(e, crate::store::GcSweepEvent::Error(_))));
+    };
+    gc().await;
+    let status = |i: usize| db.entry_status(&hashes[i]);
+    assert_eq!(status(0).await.unwrap(), EntryStatus::Complete);
+    assert_eq!(status(1).await.unwrap(), EntryStatus::Complete);
+    assert_eq!(status(2).await.unwrap(), EntryStatus::NotFound);
+    assert_eq!(status(3).await.unwrap(), EntryStatus::Complete);
+    let tags = db
+        .tags()
+        .await
+        .unwrap()
+        .map(|x| x.unwrap().0)
+        .collect::<Vec<_>>();
+    assert!(!tags.contains(&Tag::from("cache-2")));
+    assert!(tags.contains(&Tag::from("cache-3")));
+    // once the store is below its budget, nothing else is evicted
+    gc().await;
+    assert_eq!(status(1).await.unwrap(), EntryStatus::Complete);
+    assert_eq!(status(3).await.unwrap(), EntryStatus::Complete);
+    // a blob that is in use is not evicted, even if it is the least recently accessed one
+    let entry = db.get(&hashes[3]).await.unwrap().expect("entry not found");
+    db.get(&hashes[1]).await.unwrap().expect("entry not found");
+    let data = Bytes::from(random_test_data(MID_SIZE as usize));
+    let tt = db.import_bytes(data, BlobFormat::Raw).await.unwrap();
+    db.set_tag(Tag::from("cache-4"), Some(*tt.inner()))
+        .await
+        .unwrap();
+    let hash4 = *tt.hash();
+    drop(tt);
+    gc().await;
+    assert_eq!(status(0).await.unwrap(), EntryStatus::Complete);
+    assert_eq!(status(1).await.unwrap(), EntryStatus::NotFound);
+    assert_eq!(status(3).await.unwrap(), EntryStatus::Complete);
+    assert_eq!(db.entry_status(&hash4).await.unwrap(), EntryStatus::Complete);
+    drop(entry);
+}
@@ -15,6 +15,7 @@ use tokio::io::AsyncRead;
 use tokio_util::task::LocalPoolHandle;
 
 use crate::{
+    dag::DagNode,
     hashseq::parse_hash_seq,
     protocol::RangeSpec,
     util::{
@@ -394,6 +395,14 @@ pub trait Store: ReadableStore + MapMut {
         })
     }
 
+    /// Evict blobs to bring the store below its size budget.
+    ///
+    /// This is called at the end of every gc sweep, after the garbage has been
+    /// deleted. Stores that don't have a size budget don't need to do anything.
+    fn gc_evict(&self) -> impl Stream<Item = GcSweepEvent> + Unpin {
+        futures::stream::empty()
+    }
+
     /// physically delete the given hashes from the store.
     fn delete(&self, hashes: Vec<Hash>) -> impl Future<Output = io::Result<()>> + Send;
 
@@ -554,11 +563,6 @@ async fn gc_mark_task<'a>(
             co.yield_(GcMarkEvent::CustomDebug(format!($($arg)*))).await;
         };
     }
-    macro_rules! warn {
-        ($($arg:tt)*) => {
-            co.yield_(GcMarkEvent::CustomWarning(format!($($arg)*), None)).await;
-        };
-    }
     let mut roots = BTreeSet::new();
     debug!("traversing tags");
     for item in store.tags().await? {
@@ -571,9 +575,63 @@ async fn gc_mark_task<'a>(
         debug!("adding temp pin {:?}", haf);
         roots.insert(haf);
     }
-    for HashAndFormat { hash, format } in roots {
+    mark_reachable(store, roots, live, co).await?;
+    debug!("gc mark done. found {} live blobs", live.len());
+    Ok(())
+}
+
+/// An event that can be emitted while traversing the blobs reachable from a set of roots.
+pub(crate) trait GcEvent {
+    /// A custom event (debug)
+    fn debug(text: String) -> Self;
+    /// A custom non critical error
+    fn warning(text: String) -> Self;
+}
+
+impl GcEvent for GcMarkEvent {
+    fn debug(text: String) -> Self {
+        Self::CustomDebug(text)
+    }
+
+    fn warning(text: String) -> Self {
+        Self::CustomWarning(text, None)
+    }
+}
+
+impl GcEvent for GcSweepEvent {
+    fn debug(text: String) -> Self {
+        Self::CustomDebug(text)
+    }
+
+    fn warning(text: String) -> Self {
+        Self::CustomWarning(text, None)
+    }
+}
+
+/// Add all blobs that are reachable from `roots` to `live`.
+pub(crate) async fn mark_reachable<E: GcEvent>(
+    store: &impl Store,
+    roots: BTreeSet<HashAndFormat>,
+    live: &mut BTreeSet<Hash>,
+    co: &Co<E>,
+) -> anyhow::Result<()> {
+    macro_rules! debug {
+        ($($arg:tt)*) => {
+            co.yield_(E::debug(format!($($arg)*))).await;
+        };
+    }
+    macro_rules! warn {
+        ($($arg:tt)*) => {
+            co.yield_(E::warning(format!($($arg)*))).await;
+        };
+    }
+    // hashes of non raw blobs whose children have been marked
+    let mut visited = BTreeSet::new();
+    let mut stack = roots.into_iter().collect::<Vec<_>>();
+    while let Some(HashAndFormat { hash, format }) = stack.pop() {
+        live.insert(hash);
         // we need to do this for all formats except raw
-        if live.insert(hash) && !format.is_raw() {
+        if !format.is_raw() && visited.insert(hash) {
             let Some(entry) = store.get(&hash).await? else {
                 warn!("gc: {} not found", hash);
                 continue;
@@ -600,12 +658,23 @@ async fn gc_mark_task<'a>(
                         break;
                     }
                 };
-                // if format != raw we would have to recurse here by adding this to current
                 live.insert(item);
             }
+            // for dags, the children that are not raw have to be traversed as well
+            if format.is_dag() {
+                match DagNode::load(store, &hash).await {
+                    Ok(node) => stack.extend(
+                        node.children()
+                            .iter()
+                            .filter(|child| !child.format.is_raw()),
+                    ),
+                    Err(err) => {
+                        warn!("gc: {} dag node load failed: {}", hash, err);
+                    }
+                }
+            }
         }
     }
-    debug!("gc mark done. found {} live blobs", live.len());
     Ok(())
 }
 
@@ -636,6 +705,10 @@ async fn gc_sweep_task<'a>(
         count
     )))
     .await;
+    let mut evict = store.gc_evict();
+    while let Some(event) = evict.next().await {
+        co.yield_(event).await;
+    }
     Ok(())
 }
 
@@ -758,6 +831,20 @@ pub enum ExportFormat {
     ///
     /// If the blob cannot be parsed as a collection, the operation will fail.
     Collection,
+    /// The hash refers to a [`crate::format::directory::Directory`] and the whole
+    /// directory tree shall be exported.
+    ///
+    /// Every subdirectory is exported to a directory of the same name, and every file
+    /// to a file of the same name, relative to the export destination path.
+    ///
+    /// If the blob cannot be parsed as a directory, the operation will fail.
+    Directory,
+    /// The hash refers to a [`crate::format::chunked::ChunkedBlob`] manifest and the
+    /// chunks shall be reassembled into a single file.
+    ///
+    /// If the blob cannot be parsed as a chunked blob, or any chunk is missing, the
+    /// operation will fail.
+    Chunked,
 }
 
 #[allow(missing_docs)]
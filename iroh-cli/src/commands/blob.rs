@@ -279,14 +279,14 @@ impl BlobCommands {
                         if matches!(format, BlobFormat::HashSeq) {
                             ensure!(!absolute.is_dir(), "output must not be a directory");
                         }
-                        let recursive = format == BlobFormat::HashSeq;
                         let mode = match stable {
                             true => ExportMode::TryReference,
                             false => ExportMode::Copy,
                         };
-                        let format = match recursive {
-                            true => ExportFormat::Collection,
-                            false => ExportFormat::Blob,
+                        let format = match format {
+                            BlobFormat::Raw => ExportFormat::Blob,
+                            BlobFormat::HashSeq => ExportFormat::Collection,
+                            BlobFormat::Dag => ExportFormat::Directory,
                         };
                         tracing::info!("exporting to {} -> {}", path.display(), absolute.display());
                         let stream = iroh.blobs.export(hash, absolute, format, mode).await?;
@@ -369,6 +369,10 @@ impl BlobCommands {
                     (BlobStatus::Partial { size }, BlobFormat::HashSeq) => {
                         ("incomplete collection", size)
                     }
+                    (BlobStatus::Complete { size }, BlobFormat::Dag) => ("directory", size),
+                    (BlobStatus::Partial { size }, BlobFormat::Dag) => {
+                        ("incomplete directory", size)
+                    }
                 };
                 println!(
                     "Ticket for {blob_status} {hash} ({})\n{ticket}",
@@ -959,6 +963,7 @@ pub fn print_add_response(hash: Hash, format: BlobFormat, entries: Vec<ProvideRe
     match format {
         BlobFormat::Raw => println!("Blob: {}", hash),
         BlobFormat::HashSeq => println!("Collection: {}", hash),
+        BlobFormat::Dag => println!("Directory: {}", hash),
     }
 }
 
@@ -6,7 +6,7 @@ use clap::Parser;
 use ed25519_dalek::Signature;
 use iroh_base::base32;
 use iroh_gossip::{
-    net::{Gossip, GOSSIP_ALPN},
+    net::{Gossip, GOSSIP_ALPN, GOSSIP_ALPN_V0},
     proto::{Event, TopicId},
 };
 use iroh_net::{
@@ -103,7 +103,7 @@ async fn main() -> anyhow::Result<()> {
     // build our magic endpoint
     let endpoint = MagicEndpoint::builder()
         .secret_key(secret_key)
-        .alpns(vec![GOSSIP_ALPN.to_vec()])
+        .alpns(vec![GOSSIP_ALPN.to_vec(), GOSSIP_ALPN_V0.to_vec()])
         .relay_mode(relay_mode)
         .bind(args.bind_port)
         .await?;
@@ -203,7 +203,7 @@ async fn endpoint_loop(endpoint: MagicEndpoint, gossip: Gossip) {
 async fn handle_connection(conn: quinn::Connecting, gossip: Gossip) -> anyhow::Result<()> {
     let (peer_id, alpn, conn) = accept_conn(conn).await?;
     match alpn.as_bytes() {
-        GOSSIP_ALPN => gossip
+        GOSSIP_ALPN | GOSSIP_ALPN_V0 => gossip
             .handle_connection(conn)
             .await
             .context(format!("connection to {peer_id} with ALPN {alpn} failed"))?,
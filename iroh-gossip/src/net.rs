@@ -5,8 +5,8 @@ use bytes::{Bytes, BytesMut};
 use futures::{stream::Stream, FutureExt};
 use genawaiter::sync::{Co, Gen};
 use iroh_net::{
-    dialer::Dialer, key::PublicKey, magic_endpoint::get_remote_node_id, AddrInfo, MagicEndpoint,
-    NodeAddr,
+    dialer::Dialer, key::PublicKey, magic_endpoint::get_remote_node_id, pool::PooledConnection,
+    AddrInfo, MagicEndpoint, NodeAddr,
 };
 use rand::rngs::StdRng;
 use rand_core::SeedableRng;
@@ -23,7 +23,15 @@ use crate::proto::{self, PeerData, Scope, TopicId};
 pub mod util;
 
 /// ALPN protocol name
-pub const GOSSIP_ALPN: &[u8] = b"/iroh-gossip/0";
+///
+/// Connections with this ALPN carry any number of consecutive sessions with a peer, each on its
+/// own bi-directional stream.
+pub const GOSSIP_ALPN: &[u8] = b"/iroh-gossip/1";
+/// ALPN protocol name of version 0 of the gossip protocol
+///
+/// Connections with this ALPN carry a single session and are closed to disconnect. Only used to
+/// talk to peers which do not support [`GOSSIP_ALPN`] yet.
+pub const GOSSIP_ALPN_V0: &[u8] = b"/iroh-gossip/0";
 /// Maximum message size is limited currently. The limit is more-or-less arbitrary.
 // TODO: Make the limit configurable.
 pub const MAX_MESSAGE_SIZE: usize = 4096;
@@ -65,8 +73,8 @@ type ProtoMessage = proto::Message<PublicKey>;
 ///
 /// Even though the [`Gossip`] is created from a [`MagicEndpoint`], it does not accept connections
 /// itself. You should run an accept loop on the MagicEndpoint yourself, check the ALPN protocol of incoming
-/// connections, and if the ALPN protocol equals [`GOSSIP_ALPN`], forward the connection to the
-/// gossip actor through [Self::handle_connection].
+/// connections, and if the ALPN protocol equals [`GOSSIP_ALPN`] or [`GOSSIP_ALPN_V0`], forward
+/// the connection to the gossip actor through [Self::handle_connection].
 ///
 /// The gossip actor will, however, initiate new connections to other peers by itself.
 #[derive(Debug, Clone)]
@@ -104,7 +112,6 @@ impl Gossip {
             in_event_rx,
             in_event_tx,
             on_endpoints_rx,
-            conns: Default::default(),
             conn_send_tx: Default::default(),
             pending_sends: Default::default(),
             timers: Timers::new(),
@@ -232,10 +239,25 @@ impl Gossip {
     /// Handle an incoming [`quinn::Connection`].
     ///
     /// Make sure to check the ALPN protocol yourself before passing the connection.
+    ///
+    /// Peers keep their connections in a pool and start each session with us on a new
+    /// bi-directional stream, so the streams of the connection are accepted until it is closed.
+    #[allow(clippy::unused_async)]
     pub async fn handle_connection(&self, conn: quinn::Connection) -> anyhow::Result<()> {
         let peer_id = get_remote_node_id(&conn)?;
-        self.send(ToActor::ConnIncoming(peer_id, ConnOrigin::Accept, conn))
-            .await?;
+        let to_actor_tx = self.to_actor_tx.clone();
+        tokio::spawn(
+            async move {
+                while let Ok((send, recv)) = conn.accept_bi().await {
+                    let msg = ToActor::ConnIncoming(peer_id, ConnOrigin::Accept(send, recv));
+                    if to_actor_tx.send(msg).await.is_err() {
+                        break;
+                    }
+                }
+                debug!("connection closed");
+            }
+            .instrument(error_span!("gossip_accept", peer = %peer_id.fmt_short())),
+        );
         Ok(())
     }
 
@@ -285,19 +307,26 @@ impl Future for JoinTopicFut {
     }
 }
 
-/// Whether a connection is initiated by us (Dial) or by the remote peer (Accept)
-#[derive(Debug)]
+/// Whether a session is initiated by us (Dial) or by the remote peer (Accept)
+///
+/// Each session runs on its own bi-directional stream, so that the connection can outlive it.
+#[derive(derive_more::Debug)]
 enum ConnOrigin {
-    Accept,
-    Dial,
+    /// The remote peer opened a stream on its connection to us.
+    Accept(
+        #[debug(skip)] quinn::SendStream,
+        #[debug(skip)] quinn::RecvStream,
+    ),
+    /// We connected to the peer and open a stream on the connection.
+    Dial(#[debug(skip)] PooledConnection),
 }
 
 /// Input messages for the gossip [`Actor`].
 #[derive(derive_more::Debug)]
 enum ToActor {
-    /// Handle a new QUIC connection, either from accept (external to the actor) or from connect
+    /// Handle a new session, either from accept (external to the actor) or from connect
     /// (happens internally in the actor).
-    ConnIncoming(PublicKey, ConnOrigin, #[debug(skip)] quinn::Connection),
+    ConnIncoming(PublicKey, ConnOrigin),
     /// Join a topic with a list of peers. Reply with oneshot once at least one peer joined.
     Join(
         TopicId,
@@ -343,8 +372,6 @@ struct Actor {
     on_endpoints_rx: mpsc::Receiver<Vec<iroh_net::config::Endpoint>>,
     /// Queued timers
     timers: Timers<Timer>,
-    /// Currently opened quinn connections to peers
-    conns: HashMap<PublicKey, quinn::Connection>,
     /// Channels to send outbound messages into the connection loops
     conn_send_tx: HashMap<PublicKey, mpsc::Sender<ProtoMessage>>,
     /// Queued messages that were to be sent before a dial completed
@@ -391,7 +418,7 @@ impl Actor {
                     match res {
                         Ok(conn) => {
                             debug!(peer = ?peer_id, "dial successful");
-                            self.handle_to_actor_msg(ToActor::ConnIncoming(peer_id, ConnOrigin::Dial, conn), Instant::now()).await.context("dialer.next -> conn -> handle_to_actor_msg")?;
+                            self.handle_to_actor_msg(ToActor::ConnIncoming(peer_id, ConnOrigin::Dial(conn)), Instant::now()).await.context("dialer.next -> conn -> handle_to_actor_msg")?;
                         }
                         Err(err) => {
                             warn!(peer = ?peer_id, "dial failed: {err}");
@@ -423,8 +450,7 @@ impl Actor {
     async fn handle_to_actor_msg(&mut self, msg: ToActor, now: Instant) -> anyhow::Result<()> {
         trace!("handle to_actor  {msg:?}");
         match msg {
-            ToActor::ConnIncoming(peer_id, origin, conn) => {
-                self.conns.insert(peer_id, conn.clone());
+            ToActor::ConnIncoming(peer_id, origin) => {
                 self.dialer.abort_dial(&peer_id);
                 let (send_tx, send_rx) = mpsc::channel(SEND_QUEUE_CAP);
                 self.conn_send_tx.insert(peer_id, send_tx.clone());
@@ -434,7 +460,7 @@ impl Actor {
                 tokio::spawn(
                     async move {
                         debug!("connection established");
-                        match connection_loop(peer_id, conn, origin, send_rx, &in_event_tx).await {
+                        match connection_loop(peer_id, origin, send_rx, &in_event_tx).await {
                             Ok(()) => {
                                 debug!("connection closed without error")
                             }
@@ -505,6 +531,15 @@ impl Actor {
             debug!("handle in_event  {event:?}");
         };
         if let InEvent::PeerDisconnected(peer) = &event {
+            // A session which ended after the peer already started a new one is stale.
+            if self
+                .conn_send_tx
+                .get(peer)
+                .is_some_and(|send| !send.is_closed())
+            {
+                debug!(peer = ?peer, "ignore disconnect of replaced session");
+                return Ok(());
+            }
             self.conn_send_tx.remove(peer);
         }
         let out = self.state.handle(event, now);
@@ -523,7 +558,8 @@ impl Actor {
                         }
                     } else {
                         debug!(peer = ?peer_id, "dial");
-                        self.dialer.queue_dial(peer_id, GOSSIP_ALPN);
+                        self.dialer
+                            .queue_dial_with_fallback(peer_id, GOSSIP_ALPN, GOSSIP_ALPN_V0);
                         // TODO: Enforce max length
                         self.pending_sends.entry(peer_id).or_default().push(message);
                     }
@@ -546,9 +582,8 @@ impl Actor {
                     self.timers.insert(now + delay, timer);
                 }
                 OutEvent::DisconnectPeer(peer) => {
-                    if let Some(conn) = self.conns.remove(&peer) {
-                        conn.close(0u8.into(), b"close from disconnect");
-                    }
+                    // Dropping the sender ends the session, which finishes its stream. The
+                    // connection stays open for the next session.
                     self.conn_send_tx.remove(&peer);
                     self.pending_sends.remove(&peer);
                     self.dialer.abort_dial(&peer);
@@ -603,14 +638,18 @@ async fn wait_for_neighbor_up(mut sub: broadcast::Receiver<Event>) -> anyhow::Re
 
 async fn connection_loop(
     from: PublicKey,
-    conn: quinn::Connection,
     origin: ConnOrigin,
     mut send_rx: mpsc::Receiver<ProtoMessage>,
     in_event_tx: &mpsc::Sender<InEvent>,
 ) -> anyhow::Result<()> {
-    let (mut send, mut recv) = match origin {
-        ConnOrigin::Accept => conn.accept_bi().await?,
-        ConnOrigin::Dial => conn.open_bi().await?,
+    // Keep the pooled connection leased while the session runs. Connections dialed with
+    // [`GOSSIP_ALPN_V0`] are not pooled and are closed once the session ends.
+    let (_conn, mut send, mut recv) = match origin {
+        ConnOrigin::Accept(send, recv) => (None, send, recv),
+        ConnOrigin::Dial(conn) => {
+            let (send, recv) = conn.open_bi().await?;
+            (Some(conn), send, recv)
+        }
     };
     let mut send_buf = BytesMut::new();
     let mut recv_buf = BytesMut::new();
@@ -633,6 +672,7 @@ async fn connection_loop(
             }
         }
     }
+    send.finish().await.ok();
     Ok(())
 }
 
@@ -655,7 +695,10 @@ fn decode_peer_data(peer_data: &PeerData) -> anyhow::Result<AddrInfo> {
 mod test {
     use std::time::Duration;
 
-    use iroh_net::relay::{RelayMap, RelayMode};
+    use iroh_net::{
+        pool::PoolEvent,
+        relay::{RelayMap, RelayMode},
+    };
     use tokio::spawn;
     use tokio::time::timeout;
     use tokio_util::sync::CancellationToken;
@@ -819,6 +862,133 @@ mod test {
         drop(cleanup);
     }
 
+    /// Waits for an event on the subscription which matches `f`.
+    async fn wait_for(
+        sub: &mut broadcast::Receiver<Event>,
+        f: impl Fn(&Event) -> bool,
+    ) -> anyhow::Result<()> {
+        timeout(Duration::from_secs(10), async {
+            loop {
+                let event = sub.recv().await?;
+                if f(&event) {
+                    break anyhow::Ok(());
+                }
+            }
+        })
+        .await?
+    }
+
+    #[tokio::test]
+    async fn gossip_net_reconnect_reuses_connection() -> anyhow::Result<()> {
+        let _guard = iroh_test::logging::setup();
+        let mut endpoints = vec![];
+        let mut gossips = vec![];
+        let cancel = CancellationToken::new();
+        let mut tasks = vec![];
+        for _ in 0..2 {
+            let endpoint = MagicEndpoint::builder()
+                .alpns(vec![GOSSIP_ALPN.to_vec()])
+                .relay_mode(RelayMode::Disabled)
+                .bind(0)
+                .await?;
+            let addr = endpoint.my_addr().await?;
+            let gossip = Gossip::from_endpoint(endpoint.clone(), Default::default(), &addr.info);
+            tasks.push(spawn(endpoint_loop(
+                endpoint.clone(),
+                gossip.clone(),
+                cancel.clone(),
+            )));
+            endpoints.push(endpoint);
+            gossips.push(gossip);
+        }
+        let (go1, go2) = (&gossips[0], &gossips[1]);
+        let pi1 = endpoints[0].node_id();
+        endpoints[1].add_node_addr(endpoints[0].my_addr().await?)?;
+        let mut pool_events = endpoints[1].connection_pool().subscribe();
+
+        let topic: TopicId = blake3::hash(b"reconnect").into();
+        let mut sub1 = go1.subscribe(topic).await?;
+        go1.join(topic, vec![]).await?;
+        for message in [&b"first"[..], b"second"] {
+            timeout(Duration::from_secs(10), go2.join(topic, vec![pi1]).await?).await??;
+            go2.broadcast(topic, Bytes::from_static(message)).await?;
+            wait_for(
+                &mut sub1,
+                |event| matches!(event, Event::Received(msg) if msg.content == message),
+            )
+            .await?;
+            // Leaving the topic disconnects the peers, but keeps the connection open.
+            go2.quit(topic).await?;
+            wait_for(&mut sub1, |event| matches!(event, Event::NeighborDown(_))).await?;
+        }
+
+        let mut events = vec![];
+        while let Ok(event) = pool_events.try_recv() {
+            events.push(event);
+        }
+        assert!(
+            matches!(&events[..], [PoolEvent::Connected { node_id, .. }] if *node_id == pi1),
+            "expected a single connection, got {events:?}"
+        );
+
+        cancel.cancel();
+        for t in tasks {
+            timeout(Duration::from_secs(10), t).await???;
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn gossip_net_alpn_v0_fallback() -> anyhow::Result<()> {
+        let _guard = iroh_test::logging::setup();
+        let cancel = CancellationToken::new();
+        // The first peer only accepts version 0 of the protocol.
+        let mut gossips = vec![];
+        let mut endpoints = vec![];
+        let mut tasks = vec![];
+        for alpn in [GOSSIP_ALPN_V0, GOSSIP_ALPN] {
+            let endpoint = MagicEndpoint::builder()
+                .alpns(vec![alpn.to_vec()])
+                .relay_mode(RelayMode::Disabled)
+                .bind(0)
+                .await?;
+            let addr = endpoint.my_addr().await?;
+            let gossip = Gossip::from_endpoint(endpoint.clone(), Default::default(), &addr.info);
+            tasks.push(spawn(endpoint_loop(
+                endpoint.clone(),
+                gossip.clone(),
+                cancel.clone(),
+            )));
+            endpoints.push(endpoint);
+            gossips.push(gossip);
+        }
+        let (go1, go2) = (&gossips[0], &gossips[1]);
+        let pi1 = endpoints[0].node_id();
+        endpoints[1].add_node_addr(endpoints[0].my_addr().await?)?;
+        let mut pool_events = endpoints[1].connection_pool().subscribe();
+
+        let topic: TopicId = blake3::hash(b"fallback").into();
+        let mut sub1 = go1.subscribe(topic).await?;
+        go1.join(topic, vec![]).await?;
+        timeout(Duration::from_secs(10), go2.join(topic, vec![pi1]).await?).await??;
+        go2.broadcast(topic, Bytes::from_static(b"hi")).await?;
+        wait_for(
+            &mut sub1,
+            |event| matches!(event, Event::Received(msg) if &msg.content[..] == b"hi"),
+        )
+        .await?;
+
+        // The fallback connection is not pooled.
+        assert!(pool_events.try_recv().is_err());
+        assert!(endpoints[1].connection_pool().is_empty());
+
+        cancel.cancel();
+        for t in tasks {
+            timeout(Duration::from_secs(10), t).await???;
+        }
+        Ok(())
+    }
+
     // This is copied from iroh-net/src/hp/magicsock/conn.rs
     // TODO: Move into a public test_utils module in iroh-net?
     mod util {
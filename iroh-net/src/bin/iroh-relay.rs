@@ -8,6 +8,7 @@ use std::{
     path::{Path, PathBuf},
     pin::Pin,
     sync::Arc,
+    time::UNIX_EPOCH,
 };
 
 use anyhow::{anyhow, bail, Context as _, Result};
@@ -17,12 +18,15 @@ use http::{response::Builder as ResponseBuilder, HeaderMap};
 use hyper::body::Incoming;
 use hyper::{Method, Request, Response, StatusCode};
 use iroh_metrics::inc;
-use iroh_net::defaults::{DEFAULT_RELAY_STUN_PORT, NA_RELAY_HOSTNAME};
-use iroh_net::key::SecretKey;
+use iroh_net::defaults::{DEFAULT_RELAY_QUIC_PORT, DEFAULT_RELAY_STUN_PORT, NA_RELAY_HOSTNAME};
+use iroh_net::key::{PublicKey, SecretKey};
 use iroh_net::relay::http::{
     ServerBuilder as RelayServerBuilder, TlsAcceptor, TlsConfig as RelayTlsConfig,
 };
-use iroh_net::relay::{self};
+use iroh_net::relay::{
+    self, AccessControl, AdminHandle, ClientRateLimits, ClientStats, FlowStats, QuicServer,
+    RelayUrl,
+};
 use iroh_net::stun;
 use serde::{Deserialize, Serialize};
 use serde_with::{serde_as, DisplayFromStr};
@@ -187,6 +191,17 @@ struct Config {
     ///
     /// Defaults to `true`.
     enable_stun: bool,
+    /// The UDP port on which to serve QUIC address discovery. The listener is bound to the
+    /// same IP (if any) as specified in the `addr` field. Defaults to
+    /// [`DEFAULT_RELAY_QUIC_PORT`].
+    #[serde(default = "default_quic_port")]
+    quic_port: u16,
+    /// Whether to run a QUIC address discovery server, an alternative to STUN for networks
+    /// which block STUN but let QUIC through.
+    ///
+    /// Defaults to `false`.
+    #[serde(default)]
+    enable_quic_addr_discovery: bool,
     /// Whether to run a relay server. The only reason to set this false is if you're decommissioning a
     /// server but want to keep its bootstrap DNS functionality still running.
     ///
@@ -196,6 +211,12 @@ struct Config {
     tls: Option<TlsConfig>,
     /// Rate limiting configuration
     limits: Option<Limits>,
+    /// Access control configuration. If not set, all clients are admitted.
+    access: Option<AccessConfig>,
+    /// Mesh configuration. If not set, the relay server does not mesh with other relay servers.
+    mesh: Option<MeshConfig>,
+    /// Admin API configuration. If not set, the admin API is not served.
+    admin: Option<AdminConfig>,
     #[cfg(feature = "metrics")]
     /// Metrics serve address. If not set, metrics are not served.
     metrics_addr: Option<SocketAddr>,
@@ -234,6 +255,83 @@ struct Limits {
     accept_conn_limit: Option<f64>,
     /// Burst limit for accepting new connection. Unlimited if not set.
     accept_conn_burst: Option<usize>,
+    /// Rate limit for the bytes per second each client may send. Unlimited if not set.
+    client_bytes_per_second: Option<u32>,
+    /// Burst limit for the bytes each client may send. Defaults to `client_bytes_per_second`.
+    client_bytes_burst: Option<u32>,
+    /// Rate limit for the packets per second each client may send. Unlimited if not set.
+    client_packets_per_second: Option<u32>,
+    /// Burst limit for the packets each client may send. Defaults to `client_packets_per_second`.
+    client_packets_burst: Option<u32>,
+}
+
+impl Limits {
+    fn client_rate_limits(&self) -> ClientRateLimits {
+        ClientRateLimits {
+            bytes_per_second: self.client_bytes_per_second,
+            bytes_burst: self.client_bytes_burst,
+            packets_per_second: self.client_packets_per_second,
+            packets_burst: self.client_packets_burst,
+        }
+    }
+}
+
+#[serde_as]
+#[derive(Serialize, Deserialize)]
+struct AccessConfig {
+    /// Node ids which may connect. If empty, and no `tokens` are set, all nodes not on the
+    /// `deny` list may connect.
+    #[serde_as(as = "Vec<DisplayFromStr>")]
+    #[serde(default)]
+    allow: Vec<PublicKey>,
+    /// Node ids which are always rejected.
+    #[serde_as(as = "Vec<DisplayFromStr>")]
+    #[serde(default)]
+    deny: Vec<PublicKey>,
+    /// Bearer tokens which admit any node presenting them in the handshake.
+    #[serde(default)]
+    tokens: Vec<String>,
+}
+
+impl From<AccessConfig> for AccessControl {
+    fn from(config: AccessConfig) -> Self {
+        let access = config
+            .allow
+            .into_iter()
+            .fold(AccessControl::new(), AccessControl::allow);
+        let access = config.deny.into_iter().fold(access, AccessControl::deny);
+        config.tokens.into_iter().fold(access, AccessControl::token)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct MeshConfig {
+    /// Shared secret of the meshed relay servers.
+    key: String,
+    /// Urls of the relay servers to mesh with.
+    ///
+    /// Every relay server of the mesh should list all other relay servers.
+    #[serde(default)]
+    peers: Vec<RelayUrl>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct AdminConfig {
+    /// Listen address of the admin API.
+    ///
+    /// The admin API is served over plain HTTP, this address should not be publicly reachable.
+    addr: SocketAddr,
+    /// Bearer token which must be sent in the `Authorization` header of every admin request.
+    ///
+    /// Must be at least [`MIN_ADMIN_TOKEN_LEN`] bytes long.
+    token: String,
+}
+
+/// Minimum length of the admin API bearer token.
+const MIN_ADMIN_TOKEN_LEN: usize = 16;
+
+fn default_quic_port() -> u16 {
+    DEFAULT_RELAY_QUIC_PORT
 }
 
 impl Default for Config {
@@ -244,9 +342,14 @@ impl Default for Config {
             stun_port: DEFAULT_RELAY_STUN_PORT,
             hostname: NA_RELAY_HOSTNAME.into(),
             enable_stun: true,
+            quic_port: DEFAULT_RELAY_QUIC_PORT,
+            enable_quic_addr_discovery: false,
             enable_relay: true,
             tls: None,
             limits: None,
+            access: None,
+            mesh: None,
+            admin: None,
             #[cfg(feature = "metrics")]
             metrics_addr: None,
         }
@@ -279,6 +382,7 @@ impl Config {
             .await
             .context("unable to read config")?;
         let config: Self = toml::from_str(&config_ser).context("unable to decode config")?;
+        config.validate()?;
         if !config_ser.contains("secret_key") {
             info!("generating new secret key and updating config file");
             config.write_to_file(path).await?;
@@ -287,6 +391,16 @@ impl Config {
         Ok(config)
     }
 
+    /// Rejects configurations which would leave the relay server unprotected.
+    fn validate(&self) -> Result<()> {
+        if let Some(admin) = &self.admin {
+            if admin.token.len() < MIN_ADMIN_TOKEN_LEN {
+                bail!("admin token must be at least {MIN_ADMIN_TOKEN_LEN} bytes long");
+            }
+        }
+        Ok(())
+    }
+
     /// Write the content of this configuration to the provided path.
     async fn write_to_file(&self, path: impl AsRef<Path>) -> Result<()> {
         let p = path
@@ -405,6 +519,16 @@ async fn run(
         None
     };
 
+    // run QUIC address discovery
+    let quic_server = if cfg.enable_quic_addr_discovery {
+        let quic_addr = SocketAddr::new(addr.ip(), cfg.quic_port);
+        let server = QuicServer::spawn(quic_addr)?;
+        info!(addr = %server.local_addr()?, "running QUIC address discovery");
+        Some(server)
+    } else {
+        None
+    };
+
     // set up tls configuration details
     let (tls_config, headers, captive_portal_port) = if let Some(tls_config) = tls_config {
         let contact = tls_config.contact;
@@ -433,8 +557,20 @@ async fn run(
         (None, HeaderMap::new(), 0)
     };
 
+    let access_control = cfg.access.map(AccessControl::from).unwrap_or_default();
+    let client_rate_limits = cfg
+        .limits
+        .as_ref()
+        .map(Limits::client_rate_limits)
+        .unwrap_or_default();
+    if !access_control.is_open() {
+        info!("relay access is restricted");
+    }
+
     let mut builder = RelayServerBuilder::new(addr)
         .secret_key(secret_key.map(Into::into))
+        .access_control(access_control)
+        .client_rate_limits(client_rate_limits)
         .headers(headers)
         .tls_config(tls_config.clone())
         .relay_override(Box::new(relay_disabled_handler))
@@ -451,6 +587,10 @@ async fn run(
             Box::new(serve_no_content_handler),
         );
     }
+    if let Some(mesh) = cfg.mesh {
+        info!("meshing with {} relay servers", mesh.peers.len());
+        builder = builder.mesh(mesh.key, mesh.peers);
+    }
     let relay_server = builder.spawn().await?;
 
     // captive portal detections must be served over HTTP
@@ -462,6 +602,17 @@ async fn run(
         None
     };
 
+    let admin_task = match (cfg.admin, relay_server.admin_handle()) {
+        (Some(admin), Some(handle)) => {
+            Some(serve_admin_service(admin.addr, admin.token, handle).await?)
+        }
+        (Some(_), None) => {
+            warn!("relay server disabled, not serving the admin API");
+            None
+        }
+        (None, _) => None,
+    };
+
     if let Some(addr_sender) = addr_sender {
         if let Err(e) = addr_sender.send(relay_server.addr()) {
             bail!("Unable to send the local SocketAddr, the Sender was dropped - {e:?}");
@@ -473,9 +624,15 @@ async fn run(
     if let Some(task) = stun_task {
         task.abort();
     }
+    if let Some(server) = quic_server {
+        server.shutdown().await;
+    }
     if let Some(task) = captive_portal_task {
         task.abort()
     }
+    if let Some(task) = admin_task {
+        task.abort()
+    }
     relay_server.shutdown().await;
 
     Ok(())
@@ -574,6 +731,208 @@ impl hyper::service::Service<Request<Incoming>> for CaptivePortalService {
     }
 }
 
+async fn serve_admin_service(
+    addr: SocketAddr,
+    token: String,
+    handle: AdminHandle,
+) -> Result<tokio::task::JoinHandle<()>> {
+    let admin_listener = TcpListener::bind(&addr)
+        .await
+        .context("failed to bind admin api")?;
+    let admin_addr = admin_listener.local_addr()?;
+    info!("[AdminService]: serving on {}", admin_addr);
+
+    let service = AdminService {
+        token: Arc::new(token),
+        handle,
+    };
+    let task = tokio::spawn(
+        async move {
+            loop {
+                match admin_listener.accept().await {
+                    Ok((stream, peer_addr)) => {
+                        debug!("[AdminService] Connection opened from {}", peer_addr);
+                        let handler = service.clone();
+
+                        tokio::task::spawn(async move {
+                            let stream = hyper_util::rt::TokioIo::new(stream);
+                            if let Err(err) = hyper::server::conn::http1::Builder::new()
+                                .serve_connection(stream, handler)
+                                .await
+                            {
+                                error!("[AdminService] Failed to serve connection: {:?}", err);
+                            }
+                        });
+                    }
+                    Err(err) => {
+                        error!("[AdminService] failed to accept connection: {:#?}", err);
+                    }
+                }
+            }
+        }
+        .instrument(info_span!("admin.service")),
+    );
+    Ok(task)
+}
+
+/// Serves the admin API of the relay server.
+///
+/// All requests must carry the configured token in an `Authorization: Bearer <token>` header.
+///
+/// * `GET /clients` lists the connected clients.
+/// * `GET /clients/<node_id>` shows a single client.
+/// * `DELETE /clients/<node_id>` disconnects a client.
+/// * `GET /flows` lists the packets sent between clients.
+#[derive(Clone)]
+struct AdminService {
+    token: Arc<String>,
+    handle: AdminHandle,
+}
+
+impl hyper::service::Service<Request<Incoming>> for AdminService {
+    type Response = Response<BytesBody>;
+    type Error = HyperError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn call(&self, req: Request<Incoming>) -> Self::Future {
+        let service = self.clone();
+        Box::pin(async move { service.handle(req).await })
+    }
+}
+
+impl AdminService {
+    async fn handle<B>(&self, req: Request<B>) -> HyperResult<Response<BytesBody>> {
+        if !self.is_authorized(req.headers()) {
+            return Response::builder()
+                .status(StatusCode::UNAUTHORIZED)
+                .header("WWW-Authenticate", "Bearer")
+                .body(body_empty())
+                .map_err(|err| Box::new(err) as HyperError);
+        }
+
+        match (req.method(), req.uri().path()) {
+            (&Method::GET, "/clients") => {
+                let clients = self.handle.clients().await?;
+                json_response(&clients.iter().map(AdminClient::from).collect::<Vec<_>>())
+            }
+            (&Method::GET, "/flows") => {
+                let flows = self.handle.flows().await?;
+                json_response(&flows.iter().map(AdminFlow::from).collect::<Vec<_>>())
+            }
+            (method, path) => {
+                let Some(node_id) = path.strip_prefix("/clients/") else {
+                    return status_response(StatusCode::NOT_FOUND, NOTFOUND);
+                };
+                let Ok(key) = node_id.parse::<PublicKey>() else {
+                    return status_response(StatusCode::BAD_REQUEST, b"invalid node id");
+                };
+                match *method {
+                    Method::GET => match self.handle.client(key).await? {
+                        Some(client) => json_response(&AdminClient::from(&client)),
+                        None => status_response(StatusCode::NOT_FOUND, NOTFOUND),
+                    },
+                    Method::DELETE => {
+                        if self.handle.disconnect(key).await? {
+                            status_response(StatusCode::NO_CONTENT, b"")
+                        } else {
+                            status_response(StatusCode::NOT_FOUND, NOTFOUND)
+                        }
+                    }
+                    _ => status_response(StatusCode::METHOD_NOT_ALLOWED, b""),
+                }
+            }
+        }
+    }
+
+    fn is_authorized(&self, headers: &HeaderMap) -> bool {
+        headers
+            .get(http::header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .is_some_and(|token| {
+                ring::constant_time::verify_slices_are_equal(
+                    token.as_bytes(),
+                    self.token.as_bytes(),
+                )
+                .is_ok()
+            })
+    }
+}
+
+/// A connected client, as reported by the admin API.
+#[serde_as]
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+struct AdminClient {
+    #[serde_as(as = "DisplayFromStr")]
+    node_id: PublicKey,
+    /// Seconds since the unix epoch.
+    connected_at: u64,
+    bytes_sent: u64,
+    packets_sent: u64,
+    bytes_recv: u64,
+    packets_recv: u64,
+    preferred: bool,
+    mesh_peer: bool,
+}
+
+impl From<&ClientStats> for AdminClient {
+    fn from(stats: &ClientStats) -> Self {
+        Self {
+            node_id: stats.key,
+            connected_at: stats
+                .connected_at
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            bytes_sent: stats.bytes_sent,
+            packets_sent: stats.packets_sent,
+            bytes_recv: stats.bytes_recv,
+            packets_recv: stats.packets_recv,
+            preferred: stats.preferred,
+            mesh_peer: stats.mesh_peer,
+        }
+    }
+}
+
+/// Packets sent from one client to another, as reported by the admin API.
+#[serde_as]
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+struct AdminFlow {
+    #[serde_as(as = "DisplayFromStr")]
+    src: PublicKey,
+    #[serde_as(as = "DisplayFromStr")]
+    dst: PublicKey,
+    packets: u64,
+    bytes: u64,
+}
+
+impl From<&FlowStats> for AdminFlow {
+    fn from(stats: &FlowStats) -> Self {
+        Self {
+            src: stats.src,
+            dst: stats.dst,
+            packets: stats.packets,
+            bytes: stats.bytes,
+        }
+    }
+}
+
+fn json_response(value: &impl Serialize) -> HyperResult<Response<BytesBody>> {
+    let body = serde_json::to_vec(value)?;
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/json")
+        .body(body.into())
+        .map_err(|err| Box::new(err) as HyperError)
+}
+
+fn status_response(status: StatusCode, body: &'static [u8]) -> HyperResult<Response<BytesBody>> {
+    Response::builder()
+        .status(status)
+        .body(body.into())
+        .map_err(|err| Box::new(err) as HyperError)
+}
+
 fn relay_disabled_handler(
     _r: Request<Incoming>,
     response: ResponseBuilder,
@@ -905,6 +1264,123 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_admin_token_too_short() {
+        let config = |token: &str| Config {
+            admin: Some(AdminConfig {
+                addr: (Ipv4Addr::LOCALHOST, 0).into(),
+                token: token.to_string(),
+            }),
+            ..Default::default()
+        };
+        assert!(config("").validate().is_err());
+        assert!(config("secret").validate().is_err());
+        assert!(config("0123456789abcdef").validate().is_ok());
+        assert!(Config::default().validate().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_admin_service() -> Result<()> {
+        let relay_server = RelayServerBuilder::new((Ipv4Addr::LOCALHOST, 0).into())
+            .secret_key(Some(SecretKey::generate()))
+            .spawn()
+            .await?;
+        let service = AdminService {
+            token: Arc::new("secret".to_string()),
+            handle: relay_server.admin_handle().unwrap(),
+        };
+        let request = |method: Method, path: &str, token: &str| {
+            Request::builder()
+                .method(method)
+                .uri(path)
+                .header(http::header::AUTHORIZATION, format!("Bearer {token}"))
+                .body(body_empty())
+                .unwrap()
+        };
+
+        let res = service
+            .handle(request(Method::GET, "/clients", "wrong"))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        // connect a client
+        let secret_key = SecretKey::generate();
+        let node_id = secret_key.public();
+        let url: RelayUrl = format!("http://{}", relay_server.addr()).parse()?;
+        let resolver = iroh_net::dns::default_resolver().clone();
+        let (client, mut client_receiver) = ClientBuilder::new(url).build(secret_key, resolver);
+        client.connect().await?;
+
+        // the client is registered with the server shortly after connecting
+        let clients = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let res = service
+                    .handle(request(Method::GET, "/clients", "secret"))
+                    .await
+                    .unwrap();
+                assert_eq!(res.status(), StatusCode::OK);
+                let body = res.into_body().collect().await.unwrap().to_bytes();
+                let clients: Vec<AdminClient> = serde_json::from_slice(&body).unwrap();
+                if !clients.is_empty() {
+                    break clients;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await?;
+        assert_eq!(clients.len(), 1);
+        assert_eq!(clients[0].node_id, node_id);
+
+        let res = service
+            .handle(request(
+                Method::GET,
+                &format!("/clients/{node_id}"),
+                "secret",
+            ))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let res = service
+            .handle(request(Method::GET, "/flows", "secret"))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = res.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&body[..], b"[]");
+
+        let res = service
+            .handle(request(Method::GET, "/clients/foo", "secret"))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        let res = service
+            .handle(request(
+                Method::DELETE,
+                &format!("/clients/{node_id}"),
+                "secret",
+            ))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        assert!(client_receiver.recv().await.unwrap().is_err());
+
+        let res = service
+            .handle(request(
+                Method::GET,
+                &format!("/clients/{node_id}"),
+                "secret",
+            ))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        relay_server.shutdown().await;
+        Ok(())
+    }
+
     struct DropServer {
         server_task: JoinHandle<()>,
     }
@@ -31,6 +31,8 @@ pub enum EndpointType {
     Portmapped,
     /// Hard NAT: STUN'ed IPv4 address + local fixed port.
     Stun4LocalPort,
+    /// Endpoint has a publicly reachable address found via QUIC address discovery.
+    Quic,
 }
 
 impl Display for EndpointType {
@@ -41,6 +43,7 @@ impl Display for EndpointType {
             EndpointType::Stun => write!(f, "stun"),
             EndpointType::Portmapped => write!(f, "portmap"),
             EndpointType::Stun4LocalPort => write!(f, "stun4localport"),
+            EndpointType::Quic => write!(f, "quic"),
         }
     }
 }
@@ -12,6 +12,9 @@ pub const EU_RELAY_HOSTNAME: &str = "euw1-1.relay.iroh.network.";
 /// STUN port as defined by [RFC 8489](<https://www.rfc-editor.org/rfc/rfc8489#section-18.6>)
 pub const DEFAULT_RELAY_STUN_PORT: u16 = 3478;
 
+/// UDP port of the QUIC address discovery server, see [`crate::relay::QuicServer`].
+pub const DEFAULT_RELAY_QUIC_PORT: u16 = 7842;
+
 /// Get the default [`RelayMap`].
 pub fn default_relay_map() -> RelayMap {
     RelayMap::from_nodes([default_na_relay_node(), default_eu_relay_node()])
@@ -28,6 +31,8 @@ pub fn default_na_relay_node() -> RelayNode {
         url: url.into(),
         stun_only: false,
         stun_port: DEFAULT_RELAY_STUN_PORT,
+        auth_token: None,
+        quic_port: None,
     }
 }
 
@@ -41,5 +46,7 @@ pub fn default_eu_relay_node() -> RelayNode {
         url: url.into(),
         stun_only: false,
         stun_port: DEFAULT_RELAY_STUN_PORT,
+        auth_token: None,
+        quic_port: None,
     }
 }
@@ -2,21 +2,27 @@
 
 use std::{collections::HashMap, pin::Pin, task::Poll};
 
-use crate::{key::PublicKey, MagicEndpoint, NodeAddr, NodeId};
+use crate::{
+    key::PublicKey,
+    magic_endpoint::is_alpn_unsupported,
+    pool::{ConnectionPool, PooledConnection},
+    MagicEndpoint, NodeAddr, NodeId,
+};
 use anyhow::anyhow;
 use futures::future::BoxFuture;
 use tokio::task::JoinSet;
 use tokio_util::sync::CancellationToken;
-use tracing::error;
+use tracing::{debug, error};
 
 /// Dial nodes and maintain a queue of pending dials
 ///
-/// This wraps a [`MagicEndpoint`], connects to nodes through the endpoint, stores
-/// the pending connect futures and emits finished connect results.
+/// This wraps a [`MagicEndpoint`], connects to nodes through the endpoint's
+/// [`ConnectionPool`], stores the pending connect futures and emits finished connect results.
 #[derive(Debug)]
 pub struct Dialer {
     endpoint: MagicEndpoint,
-    pending: JoinSet<(PublicKey, anyhow::Result<quinn::Connection>)>,
+    pool: ConnectionPool,
+    pending: JoinSet<(PublicKey, anyhow::Result<PooledConnection>)>,
     pending_dials: HashMap<PublicKey, CancellationToken>,
 }
 
@@ -24,6 +30,7 @@ impl Dialer {
     /// Create a new dialer for a [`MagicEndpoint`]
     pub fn new(endpoint: MagicEndpoint) -> Self {
         Self {
+            pool: endpoint.connection_pool(),
             endpoint,
             pending: Default::default(),
             pending_dials: Default::default(),
@@ -35,17 +42,52 @@ impl Dialer {
     /// Note that the node's addresses and/or relay url must be added to the endpoint's
     /// addressbook for a dial to succeed, see [`MagicEndpoint::add_node_addr`].
     pub fn queue_dial(&mut self, node_id: NodeId, alpn: &'static [u8]) {
+        self.spawn_dial(node_id, alpn, None)
+    }
+
+    /// Start to dial a node, falling back to another ALPN if the node does not support `alpn`.
+    ///
+    /// Connections with the `fallback_alpn` are not pooled, they are closed once the returned
+    /// connection is dropped. This allows to talk to nodes which only speak a previous version
+    /// of a protocol, where a connection carries a single session.
+    pub fn queue_dial_with_fallback(
+        &mut self,
+        node_id: NodeId,
+        alpn: &'static [u8],
+        fallback_alpn: &'static [u8],
+    ) {
+        self.spawn_dial(node_id, alpn, Some(fallback_alpn))
+    }
+
+    fn spawn_dial(
+        &mut self,
+        node_id: NodeId,
+        alpn: &'static [u8],
+        fallback_alpn: Option<&'static [u8]>,
+    ) {
         if self.is_pending(&node_id) {
             return;
         }
         let cancel = CancellationToken::new();
         self.pending_dials.insert(node_id, cancel.clone());
         let endpoint = self.endpoint.clone();
+        let pool = self.pool.clone();
         self.pending.spawn(async move {
+            let connect = async {
+                let node_addr = NodeAddr::new(node_id);
+                match (pool.connect(node_addr.clone(), alpn).await, fallback_alpn) {
+                    (Err(err), Some(fallback_alpn)) if is_alpn_unsupported(&err) => {
+                        debug!(node = %node_id.fmt_short(), "ALPN not supported, using fallback");
+                        let conn = endpoint.connect(node_addr, fallback_alpn).await?;
+                        Ok(conn.into())
+                    }
+                    (res, _) => res,
+                }
+            };
             let res = tokio::select! {
                 biased;
                 _ = cancel.cancelled() => Err(anyhow!("Cancelled")),
-                res = endpoint.connect(NodeAddr::new(node_id), alpn) => res
+                res = connect => res
             };
             (node_id, res)
         });
@@ -64,7 +106,11 @@ impl Dialer {
     }
 
     /// Wait for the next dial operation to complete
-    pub async fn next_conn(&mut self) -> (PublicKey, anyhow::Result<quinn::Connection>) {
+    ///
+    /// The connection is a [`PooledConnection`], which dereferences to the
+    /// [`quinn::Connection`], see [`PooledConnection::connection`]. It is not pooled if it was
+    /// dialed with the fallback ALPN of [`Dialer::queue_dial_with_fallback`].
+    pub async fn next_conn(&mut self) -> (PublicKey, anyhow::Result<PooledConnection>) {
         match self.pending_dials.is_empty() {
             false => {
                 let (node_id, res) = loop {
@@ -96,7 +142,7 @@ impl Dialer {
 }
 
 impl futures::Stream for Dialer {
-    type Item = (PublicKey, anyhow::Result<quinn::Connection>);
+    type Item = (PublicKey, anyhow::Result<PooledConnection>);
 
     fn poll_next(
         mut self: Pin<&mut Self>,
@@ -117,4 +163,4 @@ impl futures::Stream for Dialer {
 }
 
 /// Future for a pending dial operation
-pub type DialFuture = BoxFuture<'static, (PublicKey, anyhow::Result<quinn::Connection>)>;
+pub type DialFuture = BoxFuture<'static, (PublicKey, anyhow::Result<PooledConnection>)>;
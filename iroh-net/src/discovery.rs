@@ -11,6 +11,7 @@ use tracing::{debug, error_span, warn, Instrument};
 use crate::{AddrInfo, MagicEndpoint, NodeId};
 
 pub mod dns;
+pub mod local_swarm;
 pub mod pkarr_publish;
 
 /// Node discovery for [`super::MagicEndpoint`].
@@ -44,11 +45,27 @@ pub trait Discovery: std::fmt::Debug + Send + Sync {
     ) -> Option<BoxStream<'_, Result<DiscoveryItem>>> {
         None
     }
+
+    /// Subscribe to nodes that this service finds on its own.
+    ///
+    /// Services that learn about nodes without being asked, e.g. from announcements on
+    /// the local network, should yield a [`DiscoveryItem`] whenever they find a new node
+    /// or new addressing information for a known node. Every call returns a new stream.
+    ///
+    /// Returns `None` if the service does not find nodes on its own.
+    fn subscribe(&self) -> Option<BoxStream<'static, DiscoveryItem>> {
+        None
+    }
 }
 
-/// The results returned from [`Discovery::resolve`].
+/// The results returned from [`Discovery::resolve`] and [`Discovery::subscribe`].
+///
+/// Discovery services outside of this crate create items with [`DiscoveryItem::new`].
 #[derive(Debug, Clone)]
+#[non_exhaustive]
 pub struct DiscoveryItem {
+    /// The node this item is about.
+    pub node_id: NodeId,
     /// A static string to identify the discovery source.
     ///
     /// Should be uniform per discovery service.
@@ -61,6 +78,23 @@ pub struct DiscoveryItem {
     pub addr_info: AddrInfo,
 }
 
+impl DiscoveryItem {
+    /// Create a new item for the addressing information of `node_id`.
+    pub fn new(
+        node_id: NodeId,
+        provenance: &'static str,
+        last_updated: Option<u64>,
+        addr_info: AddrInfo,
+    ) -> Self {
+        Self {
+            node_id,
+            provenance,
+            last_updated,
+            addr_info,
+        }
+    }
+}
+
 /// A discovery service that combines multiple discovery sources.
 ///
 /// The discovery services will resolve concurrently.
@@ -115,6 +149,19 @@ impl Discovery for ConcurrentDiscovery {
         let streams = futures::stream::select_all(streams);
         Some(Box::pin(streams))
     }
+
+    fn subscribe(&self) -> Option<BoxStream<'static, DiscoveryItem>> {
+        let streams = self
+            .services
+            .iter()
+            .filter_map(|service| service.subscribe())
+            .collect::<Vec<_>>();
+        if streams.is_empty() {
+            return None;
+        }
+        let streams = futures::stream::select_all(streams);
+        Some(Box::pin(streams))
+    }
 }
 
 /// Maximum duration since the last control or data message received from an endpoint to make us
@@ -352,6 +399,7 @@ mod tests {
             let stream = match addr_info {
                 Some((addr_info, ts)) => {
                     let item = DiscoveryItem {
+                        node_id,
                         provenance: "test-disco",
                         last_updated: Some(ts),
                         addr_info,
@@ -388,6 +436,17 @@ mod tests {
         }
     }
 
+    /// A discovery that only reports a fixed set of nodes through [`Discovery::subscribe`].
+    #[derive(Debug)]
+    struct PassiveDiscovery {
+        items: Vec<DiscoveryItem>,
+    }
+    impl Discovery for PassiveDiscovery {
+        fn subscribe(&self) -> Option<BoxStream<'static, DiscoveryItem>> {
+            Some(stream::iter(self.items.clone()).boxed())
+        }
+    }
+
     const TEST_ALPN: &[u8] = b"n0/iroh/test";
 
     /// This is a smoke test for our discovery mechanism.
@@ -521,6 +580,65 @@ mod tests {
         Ok(())
     }
 
+    /// Nodes found by a subscription are added to the endpoint, so they can be dialed
+    /// without resolving them.
+    #[tokio::test]
+    async fn magic_endpoint_discovery_subscribe() -> anyhow::Result<()> {
+        let _guard = iroh_test::logging::setup();
+        let ep1 = new_endpoint(SecretKey::generate(), EmptyDiscovery).await;
+        let item = DiscoveryItem {
+            node_id: ep1.node_id(),
+            provenance: "test-passive",
+            last_updated: None,
+            addr_info: ep1.my_addr().await?.info,
+        };
+        let ep2 = {
+            let mut disco = ConcurrentDiscovery::empty();
+            disco.add(EmptyDiscovery);
+            disco.add(PassiveDiscovery {
+                items: vec![item.clone()],
+            });
+            new_endpoint(SecretKey::generate(), disco).await
+        };
+        let events = ep2.subscribe_discovery().unwrap().collect::<Vec<_>>().await;
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].node_id, ep1.node_id());
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while ep2.connection_info(ep1.node_id()).is_none() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await?;
+        let _conn = ep2.connect(NodeAddr::new(ep1.node_id()), TEST_ALPN).await?;
+        Ok(())
+    }
+
+    /// A flood of unknown nodes from a subscription is added to the endpoint slowly.
+    #[tokio::test]
+    async fn magic_endpoint_discovery_subscribe_throttled() -> anyhow::Result<()> {
+        let _guard = iroh_test::logging::setup();
+        let items = (0..50)
+            .map(|i| {
+                let addr_info = AddrInfo {
+                    relay_url: None,
+                    direct_addresses: BTreeSet::from([SocketAddr::from(([240, 0, 0, 1], i))]),
+                };
+                DiscoveryItem::new(
+                    SecretKey::generate().public(),
+                    "test-passive",
+                    None,
+                    addr_info,
+                )
+            })
+            .collect();
+        let ep = new_endpoint(SecretKey::generate(), PassiveDiscovery { items }).await;
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        let count = ep.connection_infos().len();
+        assert!(count > 0);
+        assert!(count < 20, "{count} nodes added");
+        Ok(())
+    }
+
     async fn new_endpoint(secret: SecretKey, disco: impl Discovery + 'static) -> MagicEndpoint {
         MagicEndpoint::builder()
             .secret_key(secret)
@@ -556,7 +674,11 @@ mod test_dns_pkarr {
     use url::Url;
 
     use crate::{
-        discovery::{dns::DnsDiscovery, pkarr_publish::PkarrPublisher, ConcurrentDiscovery},
+        discovery::{
+            dns::DnsDiscovery,
+            pkarr_publish::{AddrFilter, PkarrPublisher},
+            ConcurrentDiscovery,
+        },
         dns::node_info::{lookup_by_id, NodeInfo},
         relay::{RelayMap, RelayMode},
         test_utils::{
@@ -634,6 +756,49 @@ mod test_dns_pkarr {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn pkarr_publish_filtered_addrs_dns_resolve() -> Result<()> {
+        let _logging_guard = iroh_test::logging::setup();
+
+        let origin = "testdns.example".to_string();
+        let cancel = CancellationToken::new();
+        let timeout = Duration::from_secs(2);
+
+        let (nameserver, pkarr_url, state, task) =
+            run_dns_and_pkarr_servers(origin.clone(), cancel.clone()).await?;
+
+        let secret_key = SecretKey::generate();
+        let node_id = secret_key.public();
+
+        let public_addr: SocketAddr = "203.0.114.1:1234".parse().unwrap();
+        let private_addr: SocketAddr = "192.168.1.2:1234".parse().unwrap();
+        let addr_info = AddrInfo {
+            relay_url: Some("https://relay.example".parse().unwrap()),
+            direct_addresses: [public_addr, private_addr].into_iter().collect(),
+        };
+
+        let resolver = create_dns_resolver(nameserver)?;
+        let publisher =
+            PkarrPublisher::new(secret_key, pkarr_url).with_addr_filter(AddrFilter::Public);
+        publisher.update_addr_info(&addr_info);
+        state.on_node(&node_id, timeout).await?;
+        let resolved = lookup_by_id(&resolver, &node_id, &origin).await?;
+
+        let expected = NodeAddr {
+            info: AddrInfo {
+                relay_url: addr_info.relay_url,
+                direct_addresses: [public_addr].into_iter().collect(),
+            },
+            node_id,
+        };
+
+        assert_eq!(resolved, expected);
+
+        cancel.cancel();
+        task.await??;
+        Ok(())
+    }
+
     const TEST_ALPN: &[u8] = b"TEST";
 
     #[tokio::test]
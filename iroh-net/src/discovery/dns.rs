@@ -59,11 +59,12 @@ impl Discovery for DnsDiscovery {
         let fut = async move {
             let node_addr =
                 dns::node_info::lookup_by_id(&resolver, &node_id, &self.origin_domain).await?;
-            Ok(DiscoveryItem {
-                provenance: "dns",
-                last_updated: None,
-                addr_info: node_addr.info,
-            })
+            Ok(DiscoveryItem::new(
+                node_addr.node_id,
+                "dns",
+                None,
+                node_addr.info,
+            ))
         };
         Some(fut.into_stream().boxed())
     }
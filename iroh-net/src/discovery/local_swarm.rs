@@ -0,0 +1,615 @@
+//! A discovery service which finds nodes on the local network using UDP multicast.
+//!
+//! Every node periodically announces its [`AddrInfo`] to a well known multicast group,
+//! and whenever its address information changes. Announcements are signed with the
+//! secret key of the node, so other nodes on the network can not announce addresses
+//! on behalf of a node they do not control.
+//!
+//! To resolve a node, the service first looks at the announcements it has already
+//! received, and then asks the node to announce itself with a query to the multicast
+//! group.
+//!
+//! Nodes that announce themselves are also reported to [`Discovery::subscribe`], so
+//! applications can learn about nodes on the local network without knowing their
+//! [`NodeId`] in advance.
+//!
+//! This does not need any infrastructure, but only works for nodes on the same link,
+//! e.g. connected to the same Wi-Fi network.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{Context, Result};
+use futures::{stream::BoxStream, StreamExt};
+use iroh_base::key::{PublicKey, SecretKey, Signature};
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::{net::UdpSocket, sync::mpsc, task::JoinHandle, time::Instant};
+use tracing::{debug, error_span, trace, warn, Instrument};
+
+use crate::{
+    discovery::{Discovery, DiscoveryItem},
+    relay::RelayUrl,
+    AddrInfo, MagicEndpoint, NodeId,
+};
+
+/// The multicast group local swarm announcements are sent to.
+pub const MULTICAST_ADDR: Ipv4Addr = Ipv4Addr::new(239, 255, 73, 82);
+
+/// The default port local swarm announcements are sent to.
+pub const DEFAULT_PORT: u16 = 37829;
+
+/// Interval in which we will announce our node info even if unchanged.
+pub const DEFAULT_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Provenance string of the [`DiscoveryItem`]s produced by [`LocalSwarmDiscovery`].
+pub const NAME: &str = "local_swarm";
+
+/// How long a resolve waits for announcements of the node.
+const RESOLVE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Maximum size of a packet.
+///
+/// Announcements of nodes with a lot of direct addresses are larger than this, those
+/// will be truncated to fit.
+const MAX_PACKET_SIZE: usize = 1200;
+
+/// Maximum number of nodes we keep announcements for.
+///
+/// When this is reached, the node we have not heard from for the longest time is
+/// dropped to make room.
+const MAX_NODES: usize = 1024;
+
+/// Number of announce intervals after which we forget a node we have not heard from.
+const EXPIRY_INTERVALS: u32 = 3;
+
+/// Discover nodes on the local network with UDP multicast.
+#[derive(Debug, Clone)]
+pub struct LocalSwarmDiscovery {
+    sender: mpsc::Sender<Command>,
+    join_handle: Arc<JoinHandle<()>>,
+}
+
+impl LocalSwarmDiscovery {
+    /// Create a new service that announces and discovers nodes on the local network.
+    ///
+    /// Will use [`DEFAULT_PORT`] and announce the node info, even if unchanged, every
+    /// [`DEFAULT_ANNOUNCE_INTERVAL`].
+    pub fn new(secret_key: SecretKey) -> Result<Self> {
+        Self::with_options(secret_key, DEFAULT_PORT, DEFAULT_ANNOUNCE_INTERVAL)
+    }
+
+    /// Create a new [`LocalSwarmDiscovery`] with a custom port and announce interval.
+    ///
+    /// All nodes that want to find each other need to use the same port.
+    pub fn with_options(
+        secret_key: SecretKey,
+        port: u16,
+        announce_interval: Duration,
+    ) -> Result<Self> {
+        let node_id = secret_key.public();
+        let socket = bind_multicast(port)?;
+        let (sender, receiver) = mpsc::channel(64);
+        let service = LocalSwarmService {
+            secret_key,
+            socket,
+            group: SocketAddrV4::new(MULTICAST_ADDR, port).into(),
+            announce_interval,
+            receiver,
+            info: None,
+            nodes: HashMap::new(),
+            resolvers: HashMap::new(),
+            subscribers: Vec::new(),
+        };
+        let join_handle = tokio::task::spawn(
+            service
+                .run()
+                .instrument(error_span!("local_swarm", me = %node_id.fmt_short())),
+        );
+        Ok(Self {
+            sender,
+            join_handle: Arc::new(join_handle),
+        })
+    }
+}
+
+impl Discovery for LocalSwarmDiscovery {
+    fn publish(&self, info: &AddrInfo) {
+        self.sender.try_send(Command::Publish(info.clone())).ok();
+    }
+
+    fn resolve(
+        &self,
+        _endpoint: MagicEndpoint,
+        node_id: NodeId,
+    ) -> Option<BoxStream<'_, Result<DiscoveryItem>>> {
+        let (tx, rx) = flume::bounded(8);
+        self.sender
+            .try_send(Command::Resolve { node_id, tx })
+            .ok()?;
+        let stream = rx
+            .into_stream()
+            .map(Ok)
+            .take_until(tokio::time::sleep(RESOLVE_TIMEOUT));
+        Some(stream.boxed())
+    }
+
+    fn subscribe(&self) -> Option<BoxStream<'static, DiscoveryItem>> {
+        let (tx, rx) = flume::bounded(64);
+        self.sender.try_send(Command::Subscribe { tx }).ok()?;
+        Some(rx.into_stream().boxed())
+    }
+}
+
+impl Drop for LocalSwarmDiscovery {
+    fn drop(&mut self) {
+        // this means we're dropping the last reference
+        if let Some(handle) = Arc::get_mut(&mut self.join_handle) {
+            handle.abort();
+        }
+    }
+}
+
+/// Commands from the [`LocalSwarmDiscovery`] handle to the service task.
+#[derive(Debug)]
+enum Command {
+    /// Announce new address info for this node.
+    Publish(AddrInfo),
+    /// Send announcements of a node to `tx`.
+    Resolve {
+        node_id: NodeId,
+        tx: flume::Sender<DiscoveryItem>,
+    },
+    /// Send announcements of new nodes, or of nodes with new address info, to `tx`.
+    Subscribe { tx: flume::Sender<DiscoveryItem> },
+}
+
+/// A packet sent to the multicast group.
+#[derive(Debug, Serialize, Deserialize)]
+enum Packet {
+    /// Address info of a node, signed by the node.
+    Announce {
+        /// The postcard encoded [`Announcement`].
+        announcement: Vec<u8>,
+        /// Signature of the announcement by the announced node.
+        signature: Signature,
+    },
+    /// Ask a node to announce itself.
+    Query { node_id: NodeId },
+}
+
+/// The content of an announce packet.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Announcement {
+    node_id: NodeId,
+    relay_url: Option<RelayUrl>,
+    direct_addresses: BTreeSet<SocketAddr>,
+    /// Microseconds since the unix epoch.
+    timestamp: u64,
+}
+
+impl Announcement {
+    fn new(node_id: NodeId, info: &AddrInfo) -> Self {
+        Self {
+            node_id,
+            relay_url: info.relay_url.clone(),
+            direct_addresses: info.direct_addresses.clone(),
+            timestamp: system_time_now(),
+        }
+    }
+
+    fn sign(&self, secret_key: &SecretKey) -> Result<Packet> {
+        let mut this = self.clone();
+        loop {
+            let announcement = postcard::to_stdvec(&this)?;
+            let signature = secret_key.sign(&announcement);
+            let packet = Packet::Announce {
+                announcement,
+                signature,
+            };
+            // drop direct addresses until the packet fits
+            if postcard::experimental::serialized_size(&packet)? <= MAX_PACKET_SIZE
+                || this.direct_addresses.pop_last().is_none()
+            {
+                return Ok(packet);
+            }
+        }
+    }
+
+    fn verify(announcement: &[u8], signature: &Signature) -> Result<Self> {
+        let this: Self = postcard::from_bytes(announcement)?;
+        PublicKey::verify(&this.node_id, announcement, signature)?;
+        Ok(this)
+    }
+
+    fn into_item(self) -> DiscoveryItem {
+        let addr_info = AddrInfo {
+            relay_url: self.relay_url,
+            direct_addresses: self.direct_addresses,
+        };
+        DiscoveryItem::new(self.node_id, NAME, Some(self.timestamp), addr_info)
+    }
+}
+
+/// The task that sends and receives multicast packets.
+#[derive(derive_more::Debug)]
+struct LocalSwarmService {
+    #[debug("SecretKey")]
+    secret_key: SecretKey,
+    socket: UdpSocket,
+    group: SocketAddr,
+    announce_interval: Duration,
+    receiver: mpsc::Receiver<Command>,
+    /// Our own address info, once it is known.
+    info: Option<AddrInfo>,
+    /// The latest announcement of every node we have heard from recently.
+    nodes: HashMap<NodeId, NodeState>,
+    /// Pending resolves, by node id.
+    resolvers: HashMap<NodeId, Vec<flume::Sender<DiscoveryItem>>>,
+    /// Subscribers for new nodes.
+    subscribers: Vec<flume::Sender<DiscoveryItem>>,
+}
+
+/// What we know about a node on the local network.
+#[derive(Debug)]
+struct NodeState {
+    announcement: Announcement,
+    /// When we last received an announcement of the node.
+    last_seen: Instant,
+}
+
+impl LocalSwarmService {
+    async fn run(mut self) {
+        let announce = tokio::time::sleep(Duration::MAX);
+        tokio::pin!(announce);
+        let mut expire = tokio::time::interval(self.announce_interval);
+        expire.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        let mut buf = vec![0u8; u16::MAX as usize];
+        loop {
+            tokio::select! {
+                command = self.receiver.recv() => match command {
+                    Some(Command::Publish(info)) => {
+                        debug!("Announce node info (info changed)");
+                        self.info = Some(info);
+                        self.announce().await;
+                        announce.as_mut().reset(Instant::now() + self.announce_interval);
+                    }
+                    Some(Command::Resolve { node_id, tx }) => {
+                        self.resolve(node_id, tx).await;
+                    }
+                    Some(Command::Subscribe { tx }) => {
+                        self.subscribers.push(tx);
+                    }
+                    None => break,
+                },
+                res = self.socket.recv_from(&mut buf) => match res {
+                    Ok((len, from)) => self.handle_packet(&buf[..len], from).await,
+                    Err(err) => warn!(?err, "Failed to receive multicast packet"),
+                },
+                _ = &mut announce => {
+                    debug!("Announce node info (interval elapsed)");
+                    self.announce().await;
+                    announce.as_mut().reset(Instant::now() + self.announce_interval);
+                }
+                _ = expire.tick() => self.expire(),
+            }
+        }
+    }
+
+    /// How long we remember a node after its last announcement.
+    fn expiry(&self) -> Duration {
+        self.announce_interval * EXPIRY_INTERVALS
+    }
+
+    /// Forget nodes we have not heard from in a while, and drop closed resolvers and
+    /// subscribers.
+    fn expire(&mut self) {
+        let expiry = self.expiry();
+        self.nodes
+            .retain(|_, state| state.last_seen.elapsed() <= expiry);
+        self.resolvers.retain(|_, resolvers| {
+            resolvers.retain(|tx| !tx.is_disconnected());
+            !resolvers.is_empty()
+        });
+        self.subscribers.retain(|tx| !tx.is_disconnected());
+    }
+
+    async fn announce(&self) {
+        let Some(info) = &self.info else {
+            return;
+        };
+        let announcement = Announcement::new(self.secret_key.public(), info);
+        match announcement.sign(&self.secret_key) {
+            Ok(packet) => self.send(&packet).await,
+            Err(err) => warn!(?err, "Failed to create announcement"),
+        }
+    }
+
+    async fn resolve(&mut self, node_id: NodeId, tx: flume::Sender<DiscoveryItem>) {
+        if let Some(state) = self.nodes.get(&node_id) {
+            if state.last_seen.elapsed() <= self.expiry() {
+                tx.try_send(state.announcement.clone().into_item()).ok();
+            }
+        }
+        self.resolvers.entry(node_id).or_default().push(tx);
+        self.send(&Packet::Query { node_id }).await;
+    }
+
+    async fn handle_packet(&mut self, packet: &[u8], from: SocketAddr) {
+        let packet: Packet = match postcard::from_bytes(packet) {
+            Ok(packet) => packet,
+            Err(err) => {
+                trace!(%from, ?err, "Ignoring invalid packet");
+                return;
+            }
+        };
+        match packet {
+            Packet::Query { node_id } => {
+                if node_id == self.secret_key.public() {
+                    debug!(%from, "Announce node info (query received)");
+                    self.announce().await;
+                }
+            }
+            Packet::Announce {
+                announcement,
+                signature,
+            } => {
+                let announcement = match Announcement::verify(&announcement, &signature) {
+                    Ok(announcement) => announcement,
+                    Err(err) => {
+                        debug!(%from, ?err, "Ignoring announcement with invalid signature");
+                        return;
+                    }
+                };
+                let node_id = announcement.node_id;
+                if node_id == self.secret_key.public() {
+                    return;
+                }
+                let changed = match self.nodes.get(&node_id).map(|state| &state.announcement) {
+                    Some(previous) if previous.timestamp >= announcement.timestamp => return,
+                    Some(previous) => {
+                        previous.relay_url != announcement.relay_url
+                            || previous.direct_addresses != announcement.direct_addresses
+                    }
+                    None => true,
+                };
+                trace!(node = %node_id.fmt_short(), %from, "Received announcement");
+                if changed {
+                    debug!(node = %node_id.fmt_short(), %from, "Found node");
+                    let item = announcement.clone().into_item();
+                    // drop subscribers that are gone, skip the ones that are lagging
+                    self.subscribers.retain(|tx| {
+                        !matches!(
+                            tx.try_send(item.clone()),
+                            Err(flume::TrySendError::Disconnected(_))
+                        )
+                    });
+                }
+                if let Some(resolvers) = self.resolvers.get_mut(&node_id) {
+                    let item = announcement.clone().into_item();
+                    resolvers.retain(|tx| tx.try_send(item.clone()).is_ok());
+                    if resolvers.is_empty() {
+                        self.resolvers.remove(&node_id);
+                    }
+                }
+                if !self.nodes.contains_key(&node_id) && self.nodes.len() >= MAX_NODES {
+                    self.expire();
+                    // still full, make room by dropping the node we heard from least recently
+                    if self.nodes.len() >= MAX_NODES {
+                        let oldest = self
+                            .nodes
+                            .iter()
+                            .min_by_key(|(_, state)| state.last_seen)
+                            .map(|(node_id, _)| *node_id);
+                        if let Some(oldest) = oldest {
+                            self.nodes.remove(&oldest);
+                        }
+                    }
+                }
+                let state = NodeState {
+                    announcement,
+                    last_seen: Instant::now(),
+                };
+                self.nodes.insert(node_id, state);
+            }
+        }
+    }
+
+    async fn send(&self, packet: &Packet) {
+        let packet = match postcard::to_stdvec(packet) {
+            Ok(packet) => packet,
+            Err(err) => {
+                warn!(?err, "Failed to encode packet");
+                return;
+            }
+        };
+        if let Err(err) = self.socket.send_to(&packet, self.group).await {
+            warn!(?err, group = %self.group, "Failed to send multicast packet");
+        }
+    }
+}
+
+/// Create a socket that is bound to `port` and joined to the [`MULTICAST_ADDR`] group.
+///
+/// Several sockets on the same host can be bound to the same port, so multiple nodes
+/// on one machine can discover each other.
+fn bind_multicast(port: u16) -> Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+    socket.set_reuse_port(true)?;
+    socket.set_nonblocking(true)?;
+    socket
+        .bind(&SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port).into())
+        .with_context(|| format!("failed to bind local swarm socket to port {port}"))?;
+    socket
+        .join_multicast_v4(&MULTICAST_ADDR, &Ipv4Addr::UNSPECIFIED)
+        .context("failed to join local swarm multicast group")?;
+    socket.set_multicast_loop_v4(true)?;
+    let socket = UdpSocket::from_std(socket.into())?;
+    Ok(socket)
+}
+
+fn system_time_now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .expect("time drift")
+        .as_micros() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn announcement_roundtrip() {
+        let secret_key = SecretKey::generate();
+        let info = AddrInfo {
+            relay_url: Some("https://relay.example".parse().unwrap()),
+            direct_addresses: ["192.168.1.2:1234".parse().unwrap()].into_iter().collect(),
+        };
+        let packet = Announcement::new(secret_key.public(), &info)
+            .sign(&secret_key)
+            .unwrap();
+        let Packet::Announce {
+            announcement,
+            signature,
+        } = packet
+        else {
+            panic!("not an announcement");
+        };
+        let decoded = Announcement::verify(&announcement, &signature).unwrap();
+        assert_eq!(decoded.node_id, secret_key.public());
+        assert_eq!(decoded.into_item().addr_info, info);
+        // a node can not announce addresses for another node
+        let other = SecretKey::generate();
+        let mut forged: Announcement = postcard::from_bytes(&announcement).unwrap();
+        forged.node_id = other.public();
+        let forged = postcard::to_stdvec(&forged).unwrap();
+        assert!(Announcement::verify(&forged, &signature).is_err());
+    }
+
+    #[test]
+    fn announcement_is_truncated() {
+        let secret_key = SecretKey::generate();
+        let info = AddrInfo {
+            relay_url: None,
+            direct_addresses: (0..1000u16)
+                .map(|port| SocketAddr::from(([10, 0, 0, 1], port)))
+                .collect(),
+        };
+        let packet = Announcement::new(secret_key.public(), &info)
+            .sign(&secret_key)
+            .unwrap();
+        assert!(postcard::to_stdvec(&packet).unwrap().len() <= MAX_PACKET_SIZE);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn local_swarm_bounded_state() -> Result<()> {
+        let port = rand::random::<u16>() | 0x8000;
+        let interval = Duration::from_secs(10);
+        let (_sender, receiver) = mpsc::channel(1);
+        let mut service = LocalSwarmService {
+            secret_key: SecretKey::generate(),
+            socket: bind_multicast(port)?,
+            group: SocketAddrV4::new(MULTICAST_ADDR, port).into(),
+            announce_interval: interval,
+            receiver,
+            info: None,
+            nodes: HashMap::new(),
+            resolvers: HashMap::new(),
+            subscribers: Vec::new(),
+        };
+        let info = AddrInfo {
+            relay_url: None,
+            direct_addresses: ["192.168.1.2:1234".parse().unwrap()].into_iter().collect(),
+        };
+        let from = SocketAddr::from(([192, 168, 1, 2], port));
+        let mut first = None;
+        for _ in 0..MAX_NODES + 10 {
+            let key = SecretKey::generate();
+            first.get_or_insert(key.public());
+            let packet = Announcement::new(key.public(), &info).sign(&key)?;
+            service
+                .handle_packet(&postcard::to_stdvec(&packet)?, from)
+                .await;
+            tokio::time::advance(Duration::from_millis(1)).await;
+        }
+        // the map is capped, the nodes we heard from least recently are dropped
+        assert_eq!(service.nodes.len(), MAX_NODES);
+        assert!(!service.nodes.contains_key(&first.unwrap()));
+        // resolvers that are gone are dropped
+        let (tx, rx) = flume::bounded(1);
+        service
+            .resolvers
+            .entry(first.unwrap())
+            .or_default()
+            .push(tx);
+        drop(rx);
+        service.expire();
+        assert!(service.resolvers.is_empty());
+        // nodes we have not heard from for a while are forgotten
+        tokio::time::advance(interval * EXPIRY_INTERVALS).await;
+        service.expire();
+        assert!(service.nodes.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn local_swarm_resolve() -> Result<()> {
+        let _logging_guard = iroh_test::logging::setup();
+        // use a random port, so concurrent test runs don't see each other
+        let port = rand::random::<u16>() | 0x8000;
+        let interval = Duration::from_millis(500);
+        let key_a = SecretKey::generate();
+        let key_b = SecretKey::generate();
+        let a = LocalSwarmDiscovery::with_options(key_a.clone(), port, interval)?;
+        let b = LocalSwarmDiscovery::with_options(key_b.clone(), port, interval)?;
+        let info = AddrInfo {
+            relay_url: None,
+            direct_addresses: ["192.168.1.2:1234".parse().unwrap()].into_iter().collect(),
+        };
+        a.publish(&info);
+        let ep = MagicEndpoint::builder()
+            .secret_key(key_b)
+            .relay_mode(crate::relay::RelayMode::Disabled)
+            .bind(0)
+            .await?;
+        let mut stream = b.resolve(ep, key_a.public()).unwrap();
+        let item = tokio::time::timeout(Duration::from_secs(5), stream.next())
+            .await?
+            .context("no announcement received")??;
+        assert_eq!(item.provenance, NAME);
+        assert_eq!(item.addr_info, info);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn local_swarm_subscribe() -> Result<()> {
+        let _logging_guard = iroh_test::logging::setup();
+        let port = rand::random::<u16>() | 0x8000;
+        let interval = Duration::from_millis(500);
+        let key_a = SecretKey::generate();
+        let a = LocalSwarmDiscovery::with_options(key_a.clone(), port, interval)?;
+        let b = LocalSwarmDiscovery::with_options(SecretKey::generate(), port, interval)?;
+        let mut events = b.subscribe().unwrap();
+        let info = AddrInfo {
+            relay_url: None,
+            direct_addresses: ["192.168.1.2:1234".parse().unwrap()].into_iter().collect(),
+        };
+        a.publish(&info);
+        let item = tokio::time::timeout(Duration::from_secs(5), events.next())
+            .await?
+            .context("no node found")?;
+        assert_eq!(item.node_id, key_a.public());
+        assert_eq!(item.addr_info, info);
+        // periodic announcements of unchanged info are not reported again
+        let next = tokio::time::timeout(interval * 3, events.next()).await;
+        assert!(next.is_err());
+        Ok(())
+    }
+}
@@ -4,9 +4,16 @@
 //! It encodes the node information into a DNS packet in the format resolvable by the
 //! [`super::dns::DnsDiscovery`].
 //!
+//! By default only the home relay URL is published. Direct addresses are only published if
+//! enabled with [`PkarrPublisher::with_addr_filter`].
+//!
 //! [pkarr]: https://pkarr.org
 
-use std::sync::Arc;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    net::SocketAddr,
+    sync::Arc,
+};
 
 use anyhow::Result;
 use pkarr::SignedPacket;
@@ -18,7 +25,13 @@ use tracing::{debug, error_span, info, warn, Instrument};
 use url::Url;
 use watchable::{Watchable, Watcher};
 
-use crate::{discovery::Discovery, dns::node_info::NodeInfo, key::SecretKey, AddrInfo, NodeId};
+use crate::{
+    discovery::Discovery,
+    dns::node_info::{ensure_custom_attr_key, CustomAttr, NodeInfo},
+    key::SecretKey,
+    net::ip::{is_link_local, is_private, to_canonical},
+    AddrInfo, NodeId,
+};
 
 /// The pkarr relay run by n0.
 pub const N0_DNS_PKARR_RELAY: &str = "https://dns.iroh.link/pkarr";
@@ -29,10 +42,49 @@ pub const DEFAULT_PKARR_TTL: u32 = 30;
 /// Interval in which we will republish our node info even if unchanged: 5 minutes.
 pub const DEFAULT_REPUBLISH_INTERVAL: Duration = Duration::from_secs(60 * 5);
 
+/// Filter for the direct addresses published to a pkarr relay.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AddrFilter {
+    /// Publish no direct addresses, only the home relay URL.
+    #[default]
+    None,
+    /// Publish only addresses which are globally routable.
+    Public,
+    /// Publish only private (RFC 1918 and RFC 4193) and link-local addresses.
+    Private,
+    /// Publish all direct addresses.
+    All,
+}
+
+impl AddrFilter {
+    /// Whether the filter allows to publish this address.
+    pub fn allows(&self, addr: &SocketAddr) -> bool {
+        let ip = to_canonical(addr.ip());
+        let is_private = is_private(&ip) || is_link_local(ip);
+        let is_public = !is_private && !ip.is_loopback() && !ip.is_unspecified();
+        match self {
+            AddrFilter::None => false,
+            AddrFilter::Public => is_public,
+            AddrFilter::Private => is_private,
+            AddrFilter::All => true,
+        }
+    }
+
+    fn apply<'a>(&self, addrs: impl IntoIterator<Item = &'a SocketAddr>) -> BTreeSet<SocketAddr> {
+        addrs
+            .into_iter()
+            .filter(|a| self.allows(a))
+            .copied()
+            .collect()
+    }
+}
+
 /// Publish node info to a pkarr relay.
 #[derive(derive_more::Debug, Clone)]
 pub struct PkarrPublisher {
     node_id: NodeId,
+    addr_filter: AddrFilter,
+    custom_attrs: BTreeMap<String, Vec<String>>,
     watchable: Watchable<Option<NodeInfo>>,
     join_handle: Arc<JoinHandle<()>>,
 }
@@ -77,10 +129,36 @@ impl PkarrPublisher {
         Self {
             watchable,
             node_id,
+            addr_filter: AddrFilter::default(),
+            custom_attrs: Default::default(),
             join_handle: Arc::new(join_handle),
         }
     }
 
+    /// Set which direct addresses are published.
+    ///
+    /// Defaults to [`AddrFilter::None`], which publishes only the home relay URL.
+    pub fn with_addr_filter(mut self, addr_filter: AddrFilter) -> Self {
+        self.addr_filter = addr_filter;
+        self
+    }
+
+    /// Add an application defined attribute to the published node info.
+    ///
+    /// The attribute is included from the next call to [`Self::update_addr_info`] on.
+    /// Fails if the [`CustomAttr::KEY`] is not a valid custom attribute key.
+    ///
+    /// Custom attributes are published after the relay URL and the direct addresses,
+    /// and are dropped if the packet would exceed the size limit of pkarr.
+    pub fn with_custom_attr<A: CustomAttr>(mut self, value: &A) -> Result<Self> {
+        ensure_custom_attr_key(A::KEY)?;
+        self.custom_attrs
+            .entry(A::KEY.to_string())
+            .or_default()
+            .push(value.to_string());
+        Ok(self)
+    }
+
     /// Create a config that publishes to the n0 dns server through [`N0_DNS_PKARR_RELAY`].
     pub fn n0_dns(secret_key: SecretKey) -> Self {
         let pkarr_relay: Url = N0_DNS_PKARR_RELAY.parse().expect("url is valid");
@@ -91,7 +169,9 @@ impl PkarrPublisher {
     ///
     /// This is a nonblocking function, the actual update is performed in the background.
     pub fn update_addr_info(&self, info: &AddrInfo) {
-        let info = NodeInfo::new(self.node_id, info.relay_url.clone().map(Into::into));
+        let mut info = NodeInfo::new(self.node_id, info.relay_url.clone().map(Into::into))
+            .with_direct_addresses(self.addr_filter.apply(&info.direct_addresses));
+        info.custom_attrs = self.custom_attrs.clone();
         self.watchable.update(Some(info)).ok();
     }
 }
@@ -162,6 +242,7 @@ impl PublisherService {
                 .relay_url
                 .as_ref()
                 .map(|s| s.as_str()),
+            direct_addresses = ?info.direct_addresses,
             "Publish node info to pkarr"
         );
         let signed_packet = info.to_pkarr_signed_packet(&self.secret_key, self.ttl)?;
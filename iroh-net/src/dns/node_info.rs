@@ -1,11 +1,18 @@
 //! This module contains functions and structs to lookup node information from DNS
 //! and to encode node information in Pkarr signed packets.
 
-use std::{collections::BTreeMap, fmt::Display, hash::Hash, str::FromStr};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Display,
+    hash::Hash,
+    net::SocketAddr,
+    str::FromStr,
+};
 
 use anyhow::{anyhow, ensure, Result};
 use hickory_proto::error::ProtoError;
 use hickory_resolver::{Name, TokioAsyncResolver};
+use tracing::debug;
 use url::Url;
 
 use crate::{key::SecretKey, AddrInfo, NodeAddr, NodeId};
@@ -13,6 +20,9 @@ use crate::{key::SecretKey, AddrInfo, NodeAddr, NodeId};
 /// The DNS name for the iroh TXT record
 pub const IROH_TXT_NAME: &str = "_iroh";
 
+/// Maximum size of the encoded DNS packet in a [`pkarr::SignedPacket`].
+const MAX_PKARR_PACKET_SIZE: usize = 1000;
+
 /// The attributes supported by iroh for `_iroh` DNS records
 #[derive(
     Debug, strum::Display, strum::AsRefStr, strum::EnumString, Hash, Eq, PartialEq, Ord, PartialOrd,
@@ -21,6 +31,18 @@ pub const IROH_TXT_NAME: &str = "_iroh";
 pub enum IrohAttr {
     /// `relay`: URL of home relay
     Relay,
+    /// `addr`: Direct socket address
+    Addr,
+}
+
+/// An application defined attribute for `_iroh` DNS records.
+///
+/// Custom attributes are stored in the TXT records as `{KEY}={value}`, next to the
+/// [`IrohAttr`]s. Attributes whose [`CustomAttr::KEY`] is empty, contains a `=` or
+/// collides with one of the [`IrohAttr`] keys are rejected.
+pub trait CustomAttr: FromStr + Display {
+    /// The key under which the attribute is stored.
+    const KEY: &'static str;
 }
 
 /// Lookup node info by domain name
@@ -28,7 +50,7 @@ pub enum IrohAttr {
 /// The domain name must either contain an _iroh TXT record or be a CNAME record that leads to
 /// an _iroh TXT record.
 pub async fn lookup_by_domain(resolver: &TokioAsyncResolver, domain: &str) -> Result<NodeAddr> {
-    let attrs = TxtAttrs::<IrohAttr>::lookup_by_domain(resolver, domain).await?;
+    let attrs = TxtAttrs::<String>::lookup_by_domain(resolver, domain).await?;
     let info: NodeInfo = attrs.into();
     Ok(info.into())
 }
@@ -39,7 +61,7 @@ pub async fn lookup_by_id(
     node_id: &NodeId,
     origin: &str,
 ) -> Result<NodeAddr> {
-    let attrs = TxtAttrs::<IrohAttr>::lookup_by_id(resolver, node_id, origin).await?;
+    let attrs = TxtAttrs::<String>::lookup_by_id(resolver, node_id, origin).await?;
     let info: NodeInfo = attrs.into();
     Ok(info.into())
 }
@@ -69,6 +91,10 @@ pub struct NodeInfo {
     /// Home relay server for this node
     #[debug("{:?}", self.relay_url.as_ref().map(|s| s.to_string()))]
     pub relay_url: Option<Url>,
+    /// Direct socket addresses of this node
+    pub direct_addresses: BTreeSet<SocketAddr>,
+    /// Application defined attributes, see [`CustomAttr`]
+    pub custom_attrs: BTreeMap<String, Vec<String>>,
 }
 
 impl From<TxtAttrs<IrohAttr>> for NodeInfo {
@@ -79,25 +105,52 @@ impl From<TxtAttrs<IrohAttr>> for NodeInfo {
 
 impl From<&TxtAttrs<IrohAttr>> for NodeInfo {
     fn from(attrs: &TxtAttrs<IrohAttr>) -> Self {
-        let node_id = attrs.node_id();
-        let attrs = attrs.attrs();
-        let relay_url = attrs
-            .get(&IrohAttr::Relay)
-            .into_iter()
-            .flatten()
-            .next()
-            .and_then(|s| Url::parse(s).ok());
-        Self { node_id, relay_url }
+        let mut info = Self::new(attrs.node_id(), None);
+        for (attr, values) in attrs.attrs() {
+            info.add_iroh_attr(attr, values);
+        }
+        info
+    }
+}
+
+impl From<TxtAttrs<String>> for NodeInfo {
+    fn from(attrs: TxtAttrs<String>) -> Self {
+        (&attrs).into()
+    }
+}
+
+impl From<&TxtAttrs<String>> for NodeInfo {
+    fn from(attrs: &TxtAttrs<String>) -> Self {
+        let mut info = Self::new(attrs.node_id(), None);
+        for (key, values) in attrs.attrs() {
+            match IrohAttr::from_str(key) {
+                Ok(attr) => info.add_iroh_attr(&attr, values),
+                Err(_) => {
+                    info.custom_attrs
+                        .entry(key.clone())
+                        .or_default()
+                        .extend(values.iter().cloned());
+                }
+            }
+        }
+        info
     }
 }
 
 impl From<&NodeInfo> for TxtAttrs<IrohAttr> {
     fn from(info: &NodeInfo) -> Self {
-        let mut attrs = vec![];
-        if let Some(relay_url) = &info.relay_url {
-            attrs.push((IrohAttr::Relay, relay_url.to_string()));
-        }
-        Self::from_parts(info.node_id, attrs.into_iter())
+        Self::from_parts(info.node_id, info.iroh_attrs())
+    }
+}
+
+impl From<&NodeInfo> for TxtAttrs<String> {
+    fn from(info: &NodeInfo) -> Self {
+        let iroh_attrs = info.iroh_attrs().map(|(k, v)| (k.to_string(), v));
+        let custom_attrs = info
+            .custom_attrs
+            .iter()
+            .flat_map(|(k, vs)| vs.iter().map(move |v| (k.clone(), v.clone())));
+        Self::from_parts(info.node_id, iroh_attrs.chain(custom_attrs))
     }
 }
 
@@ -114,7 +167,7 @@ impl From<NodeInfo> for AddrInfo {
     fn from(value: NodeInfo) -> Self {
         AddrInfo {
             relay_url: value.relay_url.map(|u| u.into()),
-            direct_addresses: Default::default(),
+            direct_addresses: value.direct_addresses,
         }
     }
 }
@@ -122,33 +175,112 @@ impl From<NodeInfo> for AddrInfo {
 impl NodeInfo {
     /// Create a new [`NodeInfo`] from its parts.
     pub fn new(node_id: NodeId, relay_url: Option<Url>) -> Self {
-        Self { node_id, relay_url }
+        Self {
+            node_id,
+            relay_url,
+            direct_addresses: Default::default(),
+            custom_attrs: Default::default(),
+        }
+    }
+
+    /// Set the direct socket addresses of this node.
+    pub fn with_direct_addresses(mut self, addrs: impl IntoIterator<Item = SocketAddr>) -> Self {
+        self.direct_addresses = addrs.into_iter().collect();
+        self
+    }
+
+    /// Add an application defined attribute.
+    ///
+    /// Can be called multiple times with the same attribute type to add several values.
+    /// Fails if the [`CustomAttr::KEY`] is not a valid custom attribute key.
+    pub fn with_custom_attr<A: CustomAttr>(mut self, value: &A) -> Result<Self> {
+        ensure_custom_attr_key(A::KEY)?;
+        self.custom_attrs
+            .entry(A::KEY.to_string())
+            .or_default()
+            .push(value.to_string());
+        Ok(self)
+    }
+
+    /// Get the first value of an application defined attribute which parses successfully.
+    pub fn custom_attr<A: CustomAttr>(&self) -> Option<A> {
+        self.custom_attrs_of::<A>().next()
+    }
+
+    /// Get all values of an application defined attribute which parse successfully.
+    pub fn custom_attrs_of<A: CustomAttr>(&self) -> impl Iterator<Item = A> + '_ {
+        self.custom_attrs
+            .get(A::KEY)
+            .into_iter()
+            .flatten()
+            .filter_map(|s| A::from_str(s).ok())
     }
 
-    fn to_attrs(&self) -> TxtAttrs<IrohAttr> {
+    fn add_iroh_attr(&mut self, attr: &IrohAttr, values: &[String]) {
+        match attr {
+            IrohAttr::Relay => {
+                if self.relay_url.is_none() {
+                    self.relay_url = values.iter().find_map(|s| Url::parse(s).ok());
+                }
+            }
+            IrohAttr::Addr => {
+                let addrs = values.iter().filter_map(|s| SocketAddr::from_str(s).ok());
+                self.direct_addresses.extend(addrs);
+            }
+        }
+    }
+
+    fn iroh_attrs(&self) -> impl Iterator<Item = (IrohAttr, String)> + '_ {
+        let relay = self
+            .relay_url
+            .iter()
+            .map(|url| (IrohAttr::Relay, url.to_string()));
+        let addrs = self
+            .direct_addresses
+            .iter()
+            .map(|addr| (IrohAttr::Addr, addr.to_string()));
+        relay.chain(addrs)
+    }
+
+    fn to_attrs(&self) -> TxtAttrs<String> {
         self.into()
     }
 
+    /// The TXT strings in order of importance: the relay URL, the direct addresses and
+    /// then the custom attributes.
+    fn to_txt_strings(&self) -> impl Iterator<Item = String> + '_ {
+        let iroh_attrs = self.iroh_attrs().map(|(k, v)| format!("{k}={v}"));
+        let custom_attrs = self
+            .custom_attrs
+            .iter()
+            .flat_map(|(k, vs)| vs.iter().map(move |v| format!("{k}={v}")));
+        iroh_attrs.chain(custom_attrs)
+    }
+
     /// Try to parse a [`NodeInfo`] from a set of DNS records.
     pub fn from_hickory_records(records: &[hickory_proto::rr::Record]) -> Result<Self> {
-        let attrs = TxtAttrs::from_hickory_records(records)?;
+        let attrs = TxtAttrs::<String>::from_hickory_records(records)?;
         Ok(attrs.into())
     }
 
     /// Try to parse a [`NodeInfo`] from a [`pkarr::SignedPacket`].
     pub fn from_pkarr_signed_packet(packet: &pkarr::SignedPacket) -> Result<Self> {
-        let attrs = TxtAttrs::from_pkarr_signed_packet(packet)?;
+        let attrs = TxtAttrs::<String>::from_pkarr_signed_packet(packet)?;
         Ok(attrs.into())
     }
 
     /// Create a [`pkarr::SignedPacket`] by constructing a DNS packet and
     /// signing it with a [`SecretKey`].
+    ///
+    /// The relay URL is always included. Direct addresses and then custom attributes
+    /// are added until the packet reaches the size limit of pkarr, the rest is dropped.
     pub fn to_pkarr_signed_packet(
         &self,
         secret_key: &SecretKey,
         ttl: u32,
     ) -> Result<pkarr::SignedPacket> {
-        self.to_attrs().to_pkarr_signed_packet(secret_key, ttl)
+        let packet = pkarr_dns_packet(&self.node_id, self.to_txt_strings(), ttl)?;
+        sign_pkarr_packet(secret_key, &packet)
     }
 
     /// Convert into a [`hickory_proto::rr::Record`] DNS record.
@@ -202,11 +334,12 @@ impl<T: FromStr + Display + Hash + Ord> TxtAttrs<T> {
     }
 
     /// Create from a node id and an iterator of "{key}={value}" strings.
+    ///
+    /// The string is split at the first `=`, so values may contain `=` characters.
     pub fn from_strings(node_id: NodeId, strings: impl Iterator<Item = String>) -> Result<Self> {
         let mut attrs: BTreeMap<T, Vec<String>> = BTreeMap::new();
         for s in strings {
-            let mut parts = s.split('=');
-            let (Some(key), Some(value)) = (parts.next(), parts.next()) else {
+            let Some((key, value)) = s.split_once('=') else {
                 continue;
             };
             let Ok(attr) = T::from_str(key) else {
@@ -273,21 +406,24 @@ impl<T: FromStr + Display + Hash + Ord> TxtAttrs<T> {
     /// Try to parse a from a set of DNS records.
     pub fn from_hickory_records(records: &[hickory_proto::rr::Record]) -> Result<Self> {
         use hickory_proto::rr;
-        let mut records = records.iter().filter_map(|rr| match rr.data() {
-            Some(rr::RData::TXT(txt)) => {
-                node_id_from_hickory_name(rr.name()).map(|node_id| (node_id, txt))
-            }
-            _ => None,
-        });
-        let (node_id, first) = records.next().ok_or_else(|| {
+        let records = records
+            .iter()
+            .filter_map(|rr| match rr.data() {
+                Some(rr::RData::TXT(txt)) => {
+                    node_id_from_hickory_name(rr.name()).map(|node_id| (node_id, txt))
+                }
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        let (node_id, _) = records.first().ok_or_else(|| {
             anyhow!("invalid DNS answer: no TXT record with name _iroh.z32encodedpubkey found")
         })?;
+        let node_id = *node_id;
         ensure!(
-            &records.all(|(n, _)| n == node_id),
+            records.iter().all(|(n, _)| *n == node_id),
             "invalid DNS answer: all _iroh txt records must belong to the same node domain"
         );
-        let records = records.map(|(_, txt)| txt).chain(Some(first));
-        let strings = records.map(ToString::to_string);
+        let strings = records.into_iter().map(|(_, txt)| txt.to_string());
         Self::from_strings(node_id, strings)
     }
 
@@ -316,35 +452,76 @@ impl<T: FromStr + Display + Hash + Ord> TxtAttrs<T> {
 
     /// Create a [`pkarr::SignedPacket`] by constructing a DNS packet and
     /// signing it with a [`SecretKey`].
+    ///
+    /// Attributes are added in order until the packet reaches the size limit of pkarr,
+    /// the rest is dropped.
     pub fn to_pkarr_signed_packet(
         &self,
         secret_key: &SecretKey,
         ttl: u32,
     ) -> Result<pkarr::SignedPacket> {
-        let packet = self.to_pkarr_dns_packet(ttl)?;
-        let keypair = pkarr::Keypair::from_secret_key(&secret_key.to_bytes());
-        let signed_packet = pkarr::SignedPacket::from_packet(&keypair, &packet)?;
-        Ok(signed_packet)
-    }
-
-    fn to_pkarr_dns_packet(&self, ttl: u32) -> Result<pkarr::dns::Packet<'static>> {
-        use pkarr::dns::{self, rdata};
-        let name = dns::Name::new(IROH_TXT_NAME)?.into_owned();
-
-        let mut packet = dns::Packet::new_reply(0);
-        for s in self.to_txt_strings() {
-            let mut txt = rdata::TXT::new();
-            txt.add_string(&s)?;
-            let rdata = rdata::RData::TXT(txt.into_owned());
-            packet.answers.push(dns::ResourceRecord::new(
-                name.clone(),
-                dns::CLASS::IN,
-                ttl,
-                rdata,
-            ));
+        let packet = pkarr_dns_packet(&self.node_id, self.to_txt_strings(), ttl)?;
+        sign_pkarr_packet(secret_key, &packet)
+    }
+}
+
+/// Check that `key` can be used as the key of a [`CustomAttr`].
+pub(crate) fn ensure_custom_attr_key(key: &str) -> Result<()> {
+    ensure!(
+        !key.is_empty() && !key.contains('='),
+        "invalid custom attribute key {key:?}"
+    );
+    ensure!(
+        IrohAttr::from_str(key).is_err(),
+        "custom attribute key {key:?} is reserved for iroh"
+    );
+    Ok(())
+}
+
+/// Build the DNS packet for a [`pkarr::SignedPacket`] from TXT strings.
+///
+/// Strings are added in order until the next one would push the encoded packet over
+/// [`MAX_PKARR_PACKET_SIZE`], the remaining ones are dropped.
+fn pkarr_dns_packet(
+    node_id: &NodeId,
+    strings: impl Iterator<Item = String>,
+    ttl: u32,
+) -> Result<pkarr::dns::Packet<'static>> {
+    use pkarr::dns::{self, rdata};
+    // use the name pkarr normalizes to, so the size we measure is the size it checks
+    let name = format!("{}.{}", IROH_TXT_NAME, to_z32(node_id));
+    let name = dns::Name::new(&name)?.into_owned();
+
+    let mut packet = dns::Packet::new_reply(0);
+    for s in strings {
+        let mut txt = rdata::TXT::new();
+        txt.add_string(&s)?;
+        let rdata = rdata::RData::TXT(txt.into_owned());
+        packet.answers.push(dns::ResourceRecord::new(
+            name.clone(),
+            dns::CLASS::IN,
+            ttl,
+            rdata,
+        ));
+        if packet.build_bytes_vec_compressed()?.len() > MAX_PKARR_PACKET_SIZE {
+            packet.answers.pop();
+            debug!(
+                node = %node_id.fmt_short(),
+                "node info does not fit into a pkarr packet, dropping attributes from {s:?} on"
+            );
+            break;
         }
-        Ok(packet)
     }
+    Ok(packet)
+}
+
+fn sign_pkarr_packet(
+    secret_key: &SecretKey,
+    packet: &pkarr::dns::Packet<'_>,
+) -> Result<pkarr::SignedPacket> {
+    let keypair = pkarr::Keypair::from_secret_key(&secret_key.to_bytes());
+    let signed_packet = pkarr::SignedPacket::from_packet(&keypair, packet)?;
+    Ok(signed_packet)
 }
 
 fn ensure_iroh_txt_label(name: Name) -> Result<Name, ProtoError> {
@@ -360,3 +537,116 @@ fn node_domain(node_id: &NodeId, origin: &str) -> Result<Name> {
     let domain = Name::from_str(&domain)?;
     Ok(domain)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fmt;
+
+    use super::*;
+
+    #[derive(Debug, PartialEq, Eq)]
+    struct Tag(String);
+
+    impl CustomAttr for Tag {
+        const KEY: &'static str = "tag";
+    }
+
+    impl Display for Tag {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "{}", self.0)
+        }
+    }
+
+    impl FromStr for Tag {
+        type Err = std::convert::Infallible;
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            Ok(Self(s.to_string()))
+        }
+    }
+
+    #[test]
+    fn node_info_pkarr_roundtrip() -> Result<()> {
+        let secret_key = SecretKey::generate();
+        let info = NodeInfo::new(
+            secret_key.public(),
+            Some("https://relay.example".parse().unwrap()),
+        )
+        .with_direct_addresses([
+            "192.168.1.2:1234".parse().unwrap(),
+            "[2001:db8::1]:4321".parse().unwrap(),
+        ])
+        .with_custom_attr(&Tag("a=b".to_string()))?
+        .with_custom_attr(&Tag("c".to_string()))?;
+
+        let packet = info.to_pkarr_signed_packet(&secret_key, 30)?;
+        let parsed = NodeInfo::from_pkarr_signed_packet(&packet)?;
+        assert_eq!(parsed, info);
+        assert_eq!(parsed.custom_attr::<Tag>(), Some(Tag("a=b".to_string())));
+        assert_eq!(parsed.custom_attrs_of::<Tag>().count(), 2);
+
+        let records = info
+            .to_hickory_records("example.com", 30)?
+            .collect::<Vec<_>>();
+        let parsed = NodeInfo::from_hickory_records(&records)?;
+        assert_eq!(parsed, info);
+
+        // custom attributes are ignored when parsing into iroh attributes only
+        let attrs = TxtAttrs::<IrohAttr>::from_pkarr_signed_packet(&packet)?;
+        let parsed = NodeInfo::from(attrs);
+        assert_eq!(parsed.direct_addresses, info.direct_addresses);
+        assert!(parsed.custom_attrs.is_empty());
+        Ok(())
+    }
+
+    #[derive(Debug)]
+    struct FakeRelay(String);
+
+    impl CustomAttr for FakeRelay {
+        const KEY: &'static str = "relay";
+    }
+
+    impl Display for FakeRelay {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "{}", self.0)
+        }
+    }
+
+    impl FromStr for FakeRelay {
+        type Err = std::convert::Infallible;
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            Ok(Self(s.to_string()))
+        }
+    }
+
+    #[test]
+    fn custom_attr_reserved_key() {
+        let info = NodeInfo::new(SecretKey::generate().public(), None);
+        let res = info.with_custom_attr(&FakeRelay("https://evil.example".to_string()));
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn node_info_pkarr_size_limit() -> Result<()> {
+        let secret_key = SecretKey::generate();
+        let relay_url: Url = "https://relay.example".parse().unwrap();
+        let mut info = NodeInfo::new(secret_key.public(), Some(relay_url.clone()))
+            .with_direct_addresses(
+                (0..100u16).map(|port| SocketAddr::from(([192, 168, 1, 2], 1000 + port))),
+            );
+        for i in 0..10 {
+            info = info.with_custom_attr(&Tag(format!("tag-{i}")))?;
+        }
+        let packet = info.to_pkarr_signed_packet(&secret_key, 30)?;
+        assert!(packet.encoded_packet().len() <= MAX_PKARR_PACKET_SIZE);
+        let parsed = NodeInfo::from_pkarr_signed_packet(&packet)?;
+        // the relay url comes first, then as many direct addresses as fit
+        assert_eq!(parsed.relay_url, Some(relay_url));
+        assert!(!parsed.direct_addresses.is_empty());
+        assert!(parsed.direct_addresses.len() < info.direct_addresses.len());
+        assert!(parsed.direct_addresses.is_subset(&info.direct_addresses));
+        assert!(parsed.custom_attrs.is_empty());
+        Ok(())
+    }
+}
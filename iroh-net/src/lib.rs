@@ -22,6 +22,7 @@ pub mod metrics;
 pub mod net;
 pub mod netcheck;
 pub mod ping;
+pub mod pool;
 pub mod portmapper;
 pub mod relay;
 pub mod stun;
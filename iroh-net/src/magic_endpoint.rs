@@ -1,26 +1,32 @@
 //! An endpoint that leverages a [quinn::Endpoint] backed by a [magicsock::MagicSock].
 
-use std::{net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
+use std::{collections::HashMap, net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
 
 use anyhow::{anyhow, bail, ensure, Context, Result};
 use derive_more::Debug;
-use futures::StreamExt;
+use futures::{stream::BoxStream, StreamExt};
 use quinn_proto::VarInt;
 use tokio_util::sync::{CancellationToken, WaitForCancellationFuture};
 use tracing::{debug, trace};
 
+#[cfg(any(test, feature = "test-utils"))]
+use crate::test_utils::sim::SimHost;
 use crate::{
     config,
     defaults::default_relay_map,
-    discovery::{Discovery, DiscoveryTask},
+    discovery::{Discovery, DiscoveryItem, DiscoveryTask},
     dns::{default_resolver, DnsResolver},
     key::{PublicKey, SecretKey},
     magicsock::{self, ConnectionTypeStream, MagicSock},
-    relay::{RelayMap, RelayMode, RelayUrl},
+    pool::{ConnectionPool, PoolState, DEFAULT_IDLE_TIMEOUT},
+    relay::{http::Proxy, RelayMap, RelayMode, RelayUrl},
     tls, NodeId,
 };
 
-pub use super::magicsock::{ConnectionInfo, LocalEndpointsStream};
+pub use super::magicsock::{
+    ConnectionEvent, ConnectionEventStream, ConnectionInfo, IpFamilyPreference,
+    LocalEndpointsStream, PathMode, PathPolicy,
+};
 
 pub use iroh_base::node_addr::{AddrInfo, NodeAddr};
 
@@ -41,8 +47,14 @@ pub struct MagicEndpointBuilder {
     /// Path for known peers. See [`MagicEndpointBuilder::peers_data_path`].
     peers_path: Option<PathBuf>,
     dns_resolver: Option<DnsResolver>,
+    proxy: Option<Proxy>,
+    pool_idle_timeout: Duration,
+    path_policy: PathPolicy,
+    node_path_policies: HashMap<NodeId, PathPolicy>,
     #[cfg(any(test, feature = "test-utils"))]
     insecure_skip_relay_cert_verify: bool,
+    #[cfg(any(test, feature = "test-utils"))]
+    sim_host: Option<SimHost>,
 }
 
 impl Default for MagicEndpointBuilder {
@@ -57,8 +69,14 @@ impl Default for MagicEndpointBuilder {
             discovery: Default::default(),
             peers_path: None,
             dns_resolver: None,
+            proxy: None,
+            pool_idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            path_policy: PathPolicy::default(),
+            node_path_policies: HashMap::new(),
             #[cfg(any(test, feature = "test-utils"))]
             insecure_skip_relay_cert_verify: false,
+            #[cfg(any(test, feature = "test-utils"))]
+            sim_host: None,
         }
     }
 }
@@ -97,6 +115,18 @@ impl MagicEndpointBuilder {
         self
     }
 
+    /// Binds the endpoint on a host in a simulated network instead of the host network.
+    ///
+    /// The port passed to [`Self::bind`] is bound on the simulated host.  See
+    /// [`crate::test_utils::sim`] for details.
+    ///
+    /// May only be used in tests.
+    #[cfg(any(test, feature = "test-utils"))]
+    pub fn sim_host(mut self, host: SimHost) -> Self {
+        self.sim_host = Some(host);
+        self
+    }
+
     /// Sets the relay servers to assist in establishing connectivity.
     ///
     /// relay servers are used to discover other peers by [`PublicKey`] and also help
@@ -169,6 +199,39 @@ impl MagicEndpointBuilder {
         self
     }
 
+    /// Tunnel connections to relay servers through a HTTP `CONNECT` or SOCKS5 proxy.
+    ///
+    /// If unset, the proxy is read from the `HTTPS_PROXY`, `ALL_PROXY` or `HTTP_PROXY`
+    /// environment variables, see [`Proxy::from_env`].
+    pub fn proxy(mut self, proxy: Proxy) -> Self {
+        self.proxy = Some(proxy);
+        self
+    }
+
+    /// Sets how long an unused connection stays in the [`ConnectionPool`] before it is closed.
+    ///
+    /// Defaults to [`DEFAULT_IDLE_TIMEOUT`].
+    pub fn pool_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.pool_idle_timeout = timeout;
+        self
+    }
+
+    /// Sets the [`PathPolicy`] for all nodes without a policy of their own.
+    ///
+    /// Use [`PathPolicy::relay_only`] to never expose the IP addresses of this endpoint to
+    /// other nodes, or [`PathPolicy::direct_only`] to never send traffic via a relay server.
+    /// By default relay and direct paths are both used.
+    pub fn path_policy(mut self, path_policy: PathPolicy) -> Self {
+        self.path_policy = path_policy;
+        self
+    }
+
+    /// Sets the [`PathPolicy`] for a single node, overriding [`Self::path_policy`].
+    pub fn node_path_policy(mut self, node_id: NodeId, path_policy: PathPolicy) -> Self {
+        self.node_path_policies.insert(node_id, path_policy);
+        self
+    }
+
     /// Bind the magic endpoint on the specified socket address.
     ///
     /// The *bind_port* is the port that should be bound locally.
@@ -205,10 +268,21 @@ impl MagicEndpointBuilder {
             nodes_path: self.peers_path,
             discovery: self.discovery,
             dns_resolver,
+            proxy: self.proxy.or_else(Proxy::from_env),
+            path_policy: self.path_policy,
+            node_path_policies: self.node_path_policies,
             #[cfg(any(test, feature = "test-utils"))]
             insecure_skip_relay_cert_verify: self.insecure_skip_relay_cert_verify,
+            #[cfg(any(test, feature = "test-utils"))]
+            sim_host: self.sim_host,
         };
-        MagicEndpoint::bind(Some(server_config), msock_opts, self.keylog).await
+        MagicEndpoint::bind(
+            Some(server_config),
+            msock_opts,
+            self.keylog,
+            self.pool_idle_timeout,
+        )
+        .await
     }
 }
 
@@ -234,6 +308,7 @@ pub struct MagicEndpoint {
     endpoint: quinn::Endpoint,
     keylog: bool,
     cancel_token: CancellationToken,
+    pool: Arc<PoolState>,
 }
 
 impl MagicEndpoint {
@@ -250,6 +325,7 @@ impl MagicEndpoint {
         server_config: Option<quinn::ServerConfig>,
         msock_opts: magicsock::Options,
         keylog: bool,
+        pool_idle_timeout: Duration,
     ) -> Result<Self> {
         let secret_key = msock_opts.secret_key.clone();
         let msock = magicsock::MagicSock::new(msock_opts).await?;
@@ -277,9 +353,18 @@ impl MagicEndpoint {
             endpoint,
             keylog,
             cancel_token: CancellationToken::new(),
+            pool: Arc::new(PoolState::new(pool_idle_timeout)),
         })
     }
 
+    /// Get the connection pool shared by all users of this endpoint.
+    ///
+    /// Connecting through the pool reuses live connections to a node for the same ALPN instead
+    /// of dialing a new one, see [`ConnectionPool`].
+    pub fn connection_pool(&self) -> ConnectionPool {
+        ConnectionPool::new(self.clone(), self.pool.clone())
+    }
+
     /// Accept an incoming connection on the socket.
     pub fn accept(&self) -> quinn::Accept<'_> {
         self.endpoint.accept()
@@ -300,6 +385,17 @@ impl MagicEndpoint {
         self.msock.discovery()
     }
 
+    /// Subscribe to nodes that the discovery mechanism finds on its own.
+    ///
+    /// See [`Discovery::subscribe`]. The addressing information of these nodes is also
+    /// added to the endpoint automatically, so they can be dialed by [`NodeId`] alone.
+    ///
+    /// Returns `None` if no discovery mechanism is configured, or if it does not find
+    /// nodes on its own.
+    pub fn subscribe_discovery(&self) -> Option<BoxStream<'static, DiscoveryItem>> {
+        self.discovery()?.subscribe()
+    }
+
     /// Get the local endpoint addresses on which the underlying magic socket is bound.
     ///
     /// Returns a tuple of the IPv4 and the optional IPv6 address.
@@ -412,6 +508,15 @@ impl MagicEndpoint {
         self.msock.conn_type_stream(node_id)
     }
 
+    /// Returns a stream of [`ConnectionEvent`]s for all nodes of this endpoint.
+    ///
+    /// Unlike [`Self::conn_type_stream`] this covers every node, and also reports found and
+    /// lost direct paths and changes of the relay connections. Only events happening after this
+    /// call are reported, use [`Self::connection_infos`] for the current state.
+    pub fn connection_events(&self) -> ConnectionEventStream {
+        self.msock.connection_events()
+    }
+
     /// Connect to a remote endpoint.
     ///
     /// A [`NodeAddr`] is required. It must contain the [`NodeId`] to dial and may also contain a
@@ -428,7 +533,8 @@ impl MagicEndpoint {
     /// none of the existing or provided direct addresses are reachable.
     ///
     /// If addresses or relay servers are neither provided nor can be discovered, the connection
-    /// attempt will fail with an error.
+    /// attempt will fail with an error. The same holds if the node's [`PathPolicy`] is
+    /// [`PathMode::DirectOnly`] and no direct addresses are known.
     pub async fn connect(&self, node_addr: NodeAddr, alpn: &[u8]) -> Result<quinn::Connection> {
         // Connecting to ourselves is not supported.
         if node_addr.node_id == self.node_id() {
@@ -476,6 +582,20 @@ impl MagicEndpoint {
             }
         };
 
+        // Without direct addresses a direct-only node cannot be reached, don't wait for the
+        // connection to time out.
+        if self.msock.path_policy(&node_id).mode == PathMode::DirectOnly
+            && !self.msock.has_direct_addrs(&node_id)
+        {
+            if let Some(discovery) = discovery {
+                discovery.cancel();
+            }
+            bail!(
+                "No direct addresses known for node {}, which may only be reached directly",
+                node_id.fmt_short()
+            );
+        }
+
         debug!(
             "connecting to {}: (via {} - {:?})",
             node_id, addr, info.direct_addresses
@@ -626,6 +746,21 @@ pub async fn get_alpn(connecting: &mut quinn::Connecting) -> Result<String> {
     }
 }
 
+/// QUIC transport error code of the TLS `no_application_protocol` alert (0x100 + 120).
+const NO_APPLICATION_PROTOCOL: u64 = 0x178;
+
+/// Whether connecting failed because the peer supports none of the offered ALPN protocols.
+///
+/// Works on the errors of [`MagicEndpoint::connect`] and
+/// [`ConnectionPool::connect`](crate::pool::ConnectionPool::connect).
+pub fn is_alpn_unsupported(err: &anyhow::Error) -> bool {
+    matches!(
+        err.downcast_ref::<quinn::ConnectionError>(),
+        Some(quinn::ConnectionError::ConnectionClosed(close))
+            if u64::from(close.error_code) == NO_APPLICATION_PROTOCOL
+    )
+}
+
 /// Extract the [`PublicKey`] from the peer's TLS certificate.
 pub fn get_remote_node_id(connection: &quinn::Connection) -> Result<PublicKey> {
     let data = connection.peer_identity();
@@ -658,7 +793,14 @@ mod tests {
     use rand_core::SeedableRng;
     use tracing::{error_span, info, info_span, Instrument};
 
-    use crate::{magicsock::ConnectionType, test_utils::run_relay_server};
+    use crate::{
+        magicsock::ConnectionType,
+        test_utils::{
+            run_relay_server,
+            sim::{NatType, SimNetwork},
+            CleanupDropGuard,
+        },
+    };
 
     use super::*;
 
@@ -1000,6 +1142,89 @@ mod tests {
         p2_connect.await.unwrap();
     }
 
+    #[tokio::test]
+    async fn magic_endpoint_connection_events() {
+        let _logging_guard = iroh_test::logging::setup();
+        let (relay_map, relay_url, _relay_guard) = run_relay_server().await.unwrap();
+        let ep1 = MagicEndpoint::builder()
+            .insecure_skip_relay_cert_verify(true)
+            .alpns(vec![TEST_ALPN.to_vec()])
+            .relay_mode(RelayMode::Custom(relay_map.clone()))
+            .bind(0)
+            .await
+            .unwrap();
+        let ep2 = MagicEndpoint::builder()
+            .insecure_skip_relay_cert_verify(true)
+            .alpns(vec![TEST_ALPN.to_vec()])
+            .relay_mode(RelayMode::Custom(relay_map))
+            .bind(0)
+            .await
+            .unwrap();
+        let mut events = ep2.connection_events();
+
+        let ep1_nodeid = ep1.node_id();
+        let ep1_addrs = ep1
+            .my_addr()
+            .await
+            .unwrap()
+            .direct_addresses()
+            .copied()
+            .collect();
+        let ep1_nodeaddr = NodeAddr::from_parts(ep1_nodeid, Some(relay_url), ep1_addrs);
+        let accept = tokio::spawn({
+            let ep1 = ep1.clone();
+            async move {
+                let conn = ep1.accept().await.unwrap().await.unwrap();
+                conn.closed().await;
+            }
+        });
+        let _accept_guard = CallOnDrop::new({
+            let handle = accept.abort_handle();
+            move || handle.abort()
+        });
+        let conn = ep2.connect(ep1_nodeaddr, TEST_ALPN).await.unwrap();
+
+        let mut connected = false;
+        tokio::time::timeout(Duration::from_secs(15), async {
+            while let Some(event) = events.next().await {
+                tracing::info!(?event, "connection event");
+                match event {
+                    ConnectionEvent::NodeConnected { node_id, .. } if node_id == ep1_nodeid => {
+                        connected = true;
+                    }
+                    ConnectionEvent::DirectPathFound { node_id, .. } if node_id == ep1_nodeid => {
+                        return;
+                    }
+                    _ => {}
+                }
+            }
+            panic!("event stream ended");
+        })
+        .await
+        .expect("no direct path found");
+        assert!(connected, "no NodeConnected event before the direct path");
+        drop(conn);
+    }
+
+    #[tokio::test]
+    async fn magic_endpoint_direct_only_without_direct_addrs() {
+        let _logging_guard = iroh_test::logging::setup();
+        let ep = MagicEndpoint::builder()
+            .alpns(vec![TEST_ALPN.to_vec()])
+            .relay_mode(RelayMode::Disabled)
+            .path_policy(PathPolicy::direct_only())
+            .bind(0)
+            .await
+            .unwrap();
+        let relay_url: RelayUrl = "https://relay.example.com".parse().unwrap();
+        let node_addr =
+            NodeAddr::from_parts(SecretKey::generate().public(), Some(relay_url), vec![]);
+        let res = tokio::time::timeout(Duration::from_secs(2), ep.connect(node_addr, TEST_ALPN))
+            .await
+            .expect("connect did not fail fast");
+        assert!(res.is_err());
+    }
+
     #[tokio::test]
     async fn magic_endpoint_conn_type_stream() {
         let _logging_guard = iroh_test::logging::setup();
@@ -1095,4 +1320,118 @@ mod tests {
         res_ep1.await.unwrap().unwrap();
         res_ep2.await.unwrap().unwrap();
     }
+
+    /// Two endpoints behind their own router in a simulated network, the second one
+    /// connected to the first one.
+    struct SimPair {
+        host_1: SimHost,
+        ep_1: MagicEndpoint,
+        ep_2: MagicEndpoint,
+        conn: quinn::Connection,
+        _accepted: quinn::Connection,
+        _relay_guard: CleanupDropGuard,
+    }
+
+    impl SimPair {
+        /// Connects the endpoints, with only the relay url of the first one known.
+        ///
+        /// If `link_1_up` is false, the first host's link is down while connecting.
+        async fn connect(nat_1: NatType, nat_2: NatType, link_1_up: bool) -> Result<Self> {
+            let network = SimNetwork::new();
+            let (relay_map, relay_url, relay_guard) = network.run_relay_server().await?;
+            let host_1 = network.add_router(nat_1).add_host();
+            let host_2 = network.add_router(nat_2).add_host();
+            host_1.set_link_up(link_1_up);
+
+            let bind = |host: SimHost| {
+                MagicEndpoint::builder()
+                    .alpns(vec![TEST_ALPN.to_vec()])
+                    .relay_mode(RelayMode::Custom(relay_map.clone()))
+                    .sim_host(host)
+                    .bind(0)
+            };
+            let ep_1 = bind(host_1.clone()).await?;
+            let ep_2 = bind(host_2).await?;
+
+            let accept = {
+                let ep_1 = ep_1.clone();
+                tokio::spawn(async move {
+                    let incoming = ep_1.accept().await.context("endpoint closed")?;
+                    accept_conn(incoming).await
+                })
+            };
+            let addr_1 = NodeAddr::from_parts(ep_1.node_id(), Some(relay_url), vec![]);
+            let conn = ep_2.connect(addr_1, TEST_ALPN).await?;
+            let (_, _, accepted) = accept.await??;
+
+            Ok(Self {
+                host_1,
+                ep_1,
+                ep_2,
+                conn,
+                _accepted: accepted,
+                _relay_guard: relay_guard,
+            })
+        }
+
+        fn conn_type(&self) -> Option<ConnectionType> {
+            self.ep_2
+                .connection_info(self.ep_1.node_id())
+                .map(|info| info.conn_type)
+        }
+
+        /// Waits for the second endpoint to use a direct path to the first one.
+        async fn wait_for_direct(&self, timeout: Duration) -> Result<()> {
+            tokio::time::timeout(timeout, async {
+                loop {
+                    // keep the connection busy, so the endpoints keep looking for better paths
+                    let mut stream = self.conn.open_uni().await?;
+                    stream.write_all(b"ping").await?;
+                    stream.finish().await?;
+                    if let Some(ConnectionType::Direct(addr)) = self.conn_type() {
+                        info!(%addr, "direct path established");
+                        return Ok(());
+                    }
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                }
+            })
+            .await
+            .context("no direct path established")?
+        }
+    }
+
+    #[tokio::test]
+    async fn sim_holepunch_full_cone() -> Result<()> {
+        let _guard = iroh_test::logging::setup();
+        let pair = SimPair::connect(NatType::FullCone, NatType::FullCone, true).await?;
+        pair.wait_for_direct(Duration::from_secs(15)).await
+    }
+
+    #[tokio::test]
+    async fn sim_holepunch_port_restricted() -> Result<()> {
+        let _guard = iroh_test::logging::setup();
+        let pair = SimPair::connect(NatType::PortRestricted, NatType::PortRestricted, true).await?;
+        pair.wait_for_direct(Duration::from_secs(15)).await
+    }
+
+    #[tokio::test]
+    async fn sim_symmetric_nat_stays_relayed() -> Result<()> {
+        let _guard = iroh_test::logging::setup();
+        let pair = SimPair::connect(NatType::Symmetric, NatType::Symmetric, true).await?;
+        // the connection works, but holepunching can not succeed
+        let res = pair.wait_for_direct(Duration::from_secs(5)).await;
+        assert!(res.is_err(), "unexpected direct path");
+        assert!(!matches!(pair.conn_type(), Some(ConnectionType::Direct(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sim_path_upgrade_after_link_flap() -> Result<()> {
+        let _guard = iroh_test::logging::setup();
+        let pair = SimPair::connect(NatType::PortRestricted, NatType::FullCone, false).await?;
+        assert!(!matches!(pair.conn_type(), Some(ConnectionType::Direct(_))));
+
+        pair.host_1.set_link_up(true);
+        pair.wait_for_direct(Duration::from_secs(30)).await
+    }
 }
@@ -35,13 +35,13 @@ use std::{
 
 use anyhow::{anyhow, Context as _, Result};
 use bytes::Bytes;
-use futures::{FutureExt, Stream};
+use futures::{stream::BoxStream, FutureExt, Stream, StreamExt};
 use iroh_metrics::{inc, inc_by};
 use quinn::AsyncUdpSocket;
 use rand::{seq::SliceRandom, Rng, SeedableRng};
 use smallvec::{smallvec, SmallVec};
 use tokio::{
-    sync::{self, mpsc, Mutex},
+    sync::{self, broadcast, mpsc, Mutex},
     task::JoinSet,
     time,
 };
@@ -51,6 +51,8 @@ use tracing::{
 };
 use watchable::Watchable;
 
+#[cfg(any(test, feature = "test-utils"))]
+use crate::test_utils::sim::SimHost;
 use crate::{
     config,
     disco::{self, SendAddr},
@@ -60,7 +62,7 @@ use crate::{
     magic_endpoint::NodeAddr,
     net::{interfaces, ip::LocalAddresses, netmon, IpFamily},
     netcheck, portmapper,
-    relay::{RelayMap, RelayUrl},
+    relay::{http::Proxy, RelayMap, RelayUrl},
     stun, AddrInfo,
 };
 
@@ -68,12 +70,14 @@ use self::{
     metrics::Metrics as MagicsockMetrics,
     node_map::{NodeMap, PingAction, PingRole, SendPing},
     relay_actor::{RelayActor, RelayActorMessage, RelayReadResult},
+    relay_health::RelayHealth,
     udp_conn::UdpConn,
 };
 
 mod metrics;
 mod node_map;
 mod relay_actor;
+mod relay_health;
 mod timer;
 mod udp_conn;
 
@@ -81,7 +85,8 @@ pub use crate::net::UdpSocket;
 
 pub use self::metrics::Metrics;
 pub use self::node_map::{
-    ConnectionType, ConnectionTypeStream, ControlMsg, DirectAddrInfo, NodeInfo as ConnectionInfo,
+    ConnectionType, ConnectionTypeStream, ControlMsg, DirectAddrInfo, IpFamilyPreference,
+    NodeInfo as ConnectionInfo, PathMode, PathPolicy,
 };
 pub use self::timer::Timer;
 
@@ -94,9 +99,18 @@ const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
 /// How often to save node data.
 const SAVE_NODES_INTERVAL: Duration = Duration::from_secs(30);
 
+/// Capacity of the channel on which [`ConnectionEvent`]s are broadcast.
+const CONNECTION_EVENTS_CAPACITY: usize = 256;
+
 /// Maximum duration to wait for a netcheck report.
 const NETCHECK_REPORT_TIMEOUT: Duration = Duration::from_secs(10);
 
+/// Minimum interval between adding two unknown nodes found by discovery to the node map.
+const DISCOVERED_NODE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Unknown nodes found by discovery are only added while the node map is smaller than this.
+const MAX_NODES_FOR_DISCOVERY: usize = 512;
+
 /// Contains options for `MagicSock::listen`.
 #[derive(derive_more::Debug)]
 pub struct Options {
@@ -122,11 +136,26 @@ pub struct Options {
     /// configuration.
     pub dns_resolver: DnsResolver,
 
+    /// Proxy to tunnel relay connections and netcheck HTTP requests through.
+    pub proxy: Option<Proxy>,
+
+    /// [`PathPolicy`] for nodes without an entry in `node_path_policies`.
+    pub path_policy: PathPolicy,
+
+    /// [`PathPolicy`]s for specific nodes.
+    pub node_path_policies: HashMap<PublicKey, PathPolicy>,
+
     /// Skip verification of SSL certificates from relay servers
     ///
     /// May only be used in tests.
     #[cfg(any(test, feature = "test-utils"))]
     pub insecure_skip_relay_cert_verify: bool,
+
+    /// Host in a simulated network to bind the UDP socket on, instead of the host network.
+    ///
+    /// May only be used in tests.
+    #[cfg(any(test, feature = "test-utils"))]
+    pub sim_host: Option<SimHost>,
 }
 
 impl Default for Options {
@@ -138,8 +167,13 @@ impl Default for Options {
             nodes_path: None,
             discovery: None,
             dns_resolver: crate::dns::default_resolver().clone(),
+            proxy: None,
+            path_policy: PathPolicy::default(),
+            node_path_policies: HashMap::new(),
             #[cfg(any(test, feature = "test-utils"))]
             insecure_skip_relay_cert_verify: false,
+            #[cfg(any(test, feature = "test-utils"))]
+            sim_host: None,
         }
     }
 }
@@ -181,6 +215,9 @@ struct Inner {
     /// The DNS resolver to be used in this magicsock.
     dns_resolver: DnsResolver,
 
+    /// Proxy to tunnel relay connections through.
+    proxy: Option<Proxy>,
+
     /// Key for this node.
     secret_key: SecretKey,
 
@@ -203,6 +240,8 @@ struct Inner {
     my_relay: std::sync::RwLock<Option<RelayUrl>>,
     /// Tracks the networkmap node entity for each node discovery key.
     node_map: NodeMap,
+    /// Sends [`ConnectionEvent`]s, shared with the [`NodeMap`].
+    events: EventSender,
     /// UDP IPv4 socket
     pconn4: UdpConn,
     /// UDP IPv6 socket
@@ -537,6 +576,9 @@ impl Inner {
                     let packet2 = Bytes::copy_from_slice(packet);
                     self.net_checker.receive_stun_packet(packet2, meta.addr);
                     false
+                } else if self.net_checker.receive_quic_packet(packet, meta.addr) {
+                    trace!(src = %meta.addr, len = %meta.stride, "UDP recv: QUIC address discovery packet");
+                    false
                 } else if let Some((sender, sealed_box)) = disco::source_and_box(packet) {
                     // Disco?
                     trace!(src = %meta.addr, len = %meta.stride, "UDP recv: disco packet");
@@ -730,6 +772,10 @@ impl Inner {
             PingRole::Reactivate => {
                 debug!(%src, tx = %hex::encode(dm.tx_id), "received ping: path active");
             }
+            PingRole::Disallowed => {
+                debug!(%src, tx = %hex::encode(dm.tx_id), "received ping: path not allowed by path policy, skip");
+                return;
+            }
         }
 
         // Send a pong.
@@ -1166,8 +1212,13 @@ impl MagicSock {
             discovery,
             nodes_path,
             dns_resolver,
+            proxy,
+            path_policy,
+            node_path_policies,
             #[cfg(any(test, feature = "test-utils"))]
             insecure_skip_relay_cert_verify,
+            #[cfg(any(test, feature = "test-utils"))]
+            sim_host,
         } = opts;
 
         let nodes_path = match nodes_path {
@@ -1184,11 +1235,18 @@ impl MagicSock {
 
         let (relay_recv_sender, relay_recv_receiver) = flume::bounded(128);
 
+        #[cfg(any(test, feature = "test-utils"))]
+        let (pconn4, pconn6) = match sim_host {
+            Some(host) => (UdpConn::bind_sim(&host, port)?, None),
+            None => bind(port)?,
+        };
+        #[cfg(not(any(test, feature = "test-utils")))]
         let (pconn4, pconn6) = bind(port)?;
         let port = pconn4.port();
 
         // NOTE: we can end up with a zero port if `std::net::UdpSocket::socket_addr` fails
         match port.try_into() {
+            Ok(_) if pconn4.is_simulated() => debug!("Skipping port mapping in simulated network"),
             Ok(non_zero_port) => {
                 port_mapper.update_local_port(non_zero_port);
             }
@@ -1197,7 +1255,11 @@ impl MagicSock {
         let ipv4_addr = pconn4.local_addr()?;
         let ipv6_addr = pconn6.as_ref().and_then(|c| c.local_addr().ok());
 
-        let net_checker = netcheck::Client::new(Some(port_mapper.clone()), dns_resolver.clone())?;
+        let net_checker = netcheck::Client::with_proxy(
+            Some(port_mapper.clone()),
+            dns_resolver.clone(),
+            proxy.clone(),
+        )?;
 
         let (actor_sender, actor_receiver) = mpsc::channel(256);
         let (relay_actor_sender, relay_actor_receiver) = mpsc::channel(256);
@@ -1218,7 +1280,12 @@ impl MagicSock {
             },
             _ => NodeMap::default(),
         };
+        node_map.set_path_policies(node_map::PathPolicies {
+            default: path_policy,
+            nodes: node_path_policies,
+        });
 
+        let events = node_map.events();
         let udp_state = quinn_udp::UdpState::default();
         let inner = Arc::new(Inner {
             me,
@@ -1239,6 +1306,7 @@ impl MagicSock {
             net_checker: net_checker.clone(),
             disco_secrets: DiscoSecrets::default(),
             node_map,
+            events,
             relay_actor_sender: relay_actor_sender.clone(),
             udp_state,
             send_buffer: Default::default(),
@@ -1248,6 +1316,7 @@ impl MagicSock {
             pending_call_me_maybes: Default::default(),
             endpoints_update_state: EndpointUpdateState::new(),
             dns_resolver,
+            proxy,
             #[cfg(any(test, feature = "test-utils"))]
             insecure_skip_relay_cert_verify,
         });
@@ -1272,6 +1341,35 @@ impl MagicSock {
             }
         });
 
+        // add nodes that the discovery service finds on its own to the node map. Updates
+        // for known nodes are applied right away, unknown nodes are rate limited and only
+        // added while the node map is not too large. Inactive nodes are pruned periodically.
+        if let Some(mut events) = inner.discovery.as_ref().and_then(|d| d.subscribe()) {
+            let inner2 = inner.clone();
+            actor_tasks.spawn(
+                async move {
+                    let mut throttle = time::interval(DISCOVERED_NODE_INTERVAL);
+                    throttle.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+                    while let Some(item) = events.next().await {
+                        let node = item.node_id.fmt_short();
+                        if !inner2.node_map.contains_node(&item.node_id) {
+                            if inner2.node_map.node_count() >= MAX_NODES_FOR_DISCOVERY {
+                                trace!(%node, provenance = %item.provenance, "node map is full, ignoring discovered node");
+                                continue;
+                            }
+                            throttle.tick().await;
+                        }
+                        trace!(%node, provenance = %item.provenance, "discovered node");
+                        inner2.node_map.add_node_addr(NodeAddr {
+                            node_id: item.node_id,
+                            info: item.addr_info,
+                        });
+                    }
+                }
+                .instrument(info_span!("discovery-events")),
+            );
+        }
+
         let inner2 = inner.clone();
         let network_monitor = netmon::Monitor::new().await?;
         actor_tasks.spawn(
@@ -1292,6 +1390,7 @@ impl MagicSock {
                     no_v4_send: false,
                     net_checker,
                     network_monitor,
+                    relay_health: Default::default(),
                 };
 
                 if let Err(err) = actor.run().await {
@@ -1368,6 +1467,28 @@ impl MagicSock {
         self.inner.node_map.conn_type_stream(node_id)
     }
 
+    /// Returns a stream of [`ConnectionEvent`]s for all nodes.
+    ///
+    /// Reports when a node becomes reachable, when direct paths to nodes are found or lost,
+    /// when the [`ConnectionType`] to a node changes and when the home relay or the
+    /// connections to relay servers change. Only events happening after this call are
+    /// reported, use [`MagicSock::connection_infos`] for the current state.  If the stream
+    /// is not polled quickly enough events are dropped, this is reported by a
+    /// [`ConnectionEvent::Lagged`] event.
+    pub fn connection_events(&self) -> ConnectionEventStream {
+        self.inner.events.stream()
+    }
+
+    /// Returns the [`PathPolicy`] used for a node.
+    pub fn path_policy(&self, node_id: &PublicKey) -> PathPolicy {
+        self.inner.node_map.path_policy(node_id)
+    }
+
+    /// Whether any direct address of a node is known.
+    pub(crate) fn has_direct_addrs(&self, node_id: &PublicKey) -> bool {
+        self.inner.node_map.has_direct_addrs(node_id)
+    }
+
     /// Get the cached version of the Ipv4 and Ipv6 addrs of the current connection.
     pub fn local_addr(&self) -> Result<(SocketAddr, Option<SocketAddr>)> {
         Ok(self.inner.local_addr())
@@ -1470,6 +1591,116 @@ impl MagicSock {
     }
 }
 
+/// An event about the connectivity of a [`MagicSock`], see [`MagicSock::connection_events`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectionEvent {
+    /// A first path to a node became usable.
+    NodeConnected {
+        /// The node.
+        node_id: PublicKey,
+        /// The type of connection we now have to the node.
+        conn_type: ConnectionType,
+    },
+    /// The [`ConnectionType`] to a node changed, e.g. it fell back to relay.
+    ConnectionTypeChanged {
+        /// The node.
+        node_id: PublicKey,
+        /// The new type of connection to the node.
+        conn_type: ConnectionType,
+        /// The latency of the new connection type, if known.
+        latency: Option<Duration>,
+    },
+    /// A direct path to a node was selected.
+    DirectPathFound {
+        /// The node.
+        node_id: PublicKey,
+        /// The address of the direct path.
+        addr: SocketAddr,
+        /// The latency measured on the path.
+        latency: Duration,
+    },
+    /// The direct path to a node was lost.
+    DirectPathLost {
+        /// The node.
+        node_id: PublicKey,
+        /// The address of the lost path.
+        addr: SocketAddr,
+    },
+    /// Our home relay changed.
+    HomeRelayChanged {
+        /// The new home relay.
+        relay_url: RelayUrl,
+    },
+    /// A connection to a relay server was established.
+    RelayConnected {
+        /// The relay server.
+        relay_url: RelayUrl,
+    },
+    /// The connection to a relay server broke.
+    RelayDisconnected {
+        /// The relay server.
+        relay_url: RelayUrl,
+    },
+    /// The subscriber did not keep up and missed some events.
+    ///
+    /// Any state built from previous events is stale, use [`MagicSock::connection_infos`] to
+    /// get the current state.
+    Lagged {
+        /// The number of events which were missed.
+        missed: u64,
+    },
+}
+
+/// Broadcasts [`ConnectionEvent`]s to the subscribers of a [`MagicSock`].
+#[derive(Debug, Clone)]
+struct EventSender(broadcast::Sender<ConnectionEvent>);
+
+impl Default for EventSender {
+    fn default() -> Self {
+        Self(broadcast::channel(CONNECTION_EVENTS_CAPACITY).0)
+    }
+}
+
+impl EventSender {
+    fn send(&self, event: ConnectionEvent) {
+        trace!(?event, "connection event");
+        // there may be no subscribers
+        self.0.send(event).ok();
+    }
+
+    fn stream(&self) -> ConnectionEventStream {
+        let receiver = self.0.subscribe();
+        let inner = futures::stream::unfold(receiver, |mut receiver| async move {
+            let event = match receiver.recv().await {
+                Ok(event) => event,
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    warn!("connection event stream lagged, skipped {missed} events");
+                    ConnectionEvent::Lagged { missed }
+                }
+                Err(broadcast::error::RecvError::Closed) => return None,
+            };
+            Some((event, receiver))
+        })
+        .boxed();
+        ConnectionEventStream { inner }
+    }
+}
+
+/// Stream of [`ConnectionEvent`]s, see [`MagicSock::connection_events`].
+#[derive(derive_more::Debug)]
+pub struct ConnectionEventStream {
+    #[debug(skip)]
+    inner: BoxStream<'static, ConnectionEvent>,
+}
+
+impl Stream for ConnectionEventStream {
+    type Item = ConnectionEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.inner.poll_next_unpin(cx)
+    }
+}
+
 /// Stream returning local endpoints of a [`MagicSock`] as they change.
 #[derive(Debug)]
 pub struct LocalEndpointsStream {
@@ -1631,6 +1862,11 @@ enum ActorMessage {
     EndpointPingExpired(usize, stun::TransactionId),
     NetcheckReport(Result<Option<Arc<netcheck::Report>>>, &'static str),
     NetworkChange,
+    /// A connection to a relay server was established or failed.
+    RelayHealth {
+        url: RelayUrl,
+        healthy: bool,
+    },
     #[cfg(test)]
     ForceNetworkChange(bool),
 }
@@ -1666,6 +1902,9 @@ struct Actor {
     net_checker: netcheck::Client,
 
     network_monitor: netmon::Monitor,
+
+    /// Health of the relay servers, to fail over to another home relay.
+    relay_health: RelayHealth,
 }
 
 impl Actor {
@@ -1838,6 +2077,9 @@ impl Actor {
             ActorMessage::NetworkChange => {
                 self.network_monitor.network_change().await.ok();
             }
+            ActorMessage::RelayHealth { url, healthy } => {
+                self.handle_relay_health(url, healthy);
+            }
             #[cfg(test)]
             ActorMessage::ForceNetworkChange(is_major) => {
                 self.handle_network_change(is_major).await;
@@ -1979,6 +2221,15 @@ impl Actor {
             if let Some(global_v6) = nr.global_v6 {
                 add_addr!(already, eps, global_v6.into(), config::EndpointType::Stun);
             }
+
+            // The QUIC address discovery probes are sent from our sockets as well.  They
+            // usually observe the addresses found by STUN, but also work if STUN is blocked.
+            if let Some(quic_v4) = nr.quic_v4 {
+                add_addr!(already, eps, quic_v4.into(), config::EndpointType::Quic);
+            }
+            if let Some(quic_v6) = nr.quic_v6 {
+                add_addr!(already, eps, quic_v6.into(), config::EndpointType::Quic);
+            }
         }
         let local_addr_v4 = self.pconn4.local_addr().ok();
         let local_addr_v6 = self.pconn6.as_ref().and_then(|c| c.local_addr().ok());
@@ -2220,6 +2471,19 @@ impl Actor {
                 ni.preferred_relay = self.pick_relay_fallback();
             }
 
+            // The relay with the lowest STUN latency might still not accept connections.
+            self.relay_health.update_latencies(r.relay_latency.iter());
+            let now = Instant::now();
+            if let Some(ref preferred) = ni.preferred_relay {
+                if !self.relay_health.is_healthy(preferred, now) {
+                    let next = self.relay_health.best_relay(&self.inner.relay_map, now);
+                    debug!(%preferred, ?next, "preferred relay is unhealthy, using next best");
+                    if next.is_some() {
+                        ni.preferred_relay = next;
+                    }
+                }
+            }
+
             if !self.set_nearest_relay(ni.preferred_relay.clone()) {
                 ni.preferred_relay = None;
             }
@@ -2245,6 +2509,9 @@ impl Actor {
             // start connecting to our home relay if we are not already.
             info!("home is now relay {}, was {:?}", relay_url, old_relay);
             self.inner.publish_my_addr();
+            self.inner.events.send(ConnectionEvent::HomeRelayChanged {
+                relay_url: relay_url.clone(),
+            });
 
             self.send_relay_actor(RelayActorMessage::SetHome {
                 url: relay_url.clone(),
@@ -2254,6 +2521,30 @@ impl Actor {
         true
     }
 
+    /// Tracks the health of a relay, failing over to the next best relay once our home relay
+    /// is unhealthy.
+    fn handle_relay_health(&mut self, url: RelayUrl, healthy: bool) {
+        if healthy {
+            self.relay_health.note_connected(&url);
+            return;
+        }
+        let now = Instant::now();
+        self.relay_health.note_failed(&url, now);
+        if self.inner.my_relay().as_ref() != Some(&url) || self.relay_health.is_healthy(&url, now) {
+            return;
+        }
+        match self.relay_health.best_relay(&self.inner.relay_map, now) {
+            Some(next) => {
+                warn!(failed = %url, %next, "home relay failed, failing over");
+                inc!(MagicsockMetrics, relay_home_failover);
+                self.set_nearest_relay(Some(next));
+                // Our network might have changed, check whether this is still the best choice.
+                self.inner.re_stun("relay-failover");
+            }
+            None => debug!(%url, "home relay failed, no healthy relay to fail over to"),
+        }
+    }
+
     /// Returns a deterministic relay node to connect to. This is only used if netcheck
     /// couldn't find the nearest one, for instance, if UDP is blocked and thus STUN
     /// latency checks aren't working.
@@ -2996,6 +3287,76 @@ pub(crate) mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_connection_events_lagged() {
+        let events = EventSender::default();
+        let mut stream = events.stream();
+        let relay_url: RelayUrl = "https://example.com".parse().unwrap();
+        for _ in 0..CONNECTION_EVENTS_CAPACITY + 2 {
+            events.send(ConnectionEvent::RelayConnected {
+                relay_url: relay_url.clone(),
+            });
+        }
+        assert_eq!(
+            stream.next().await,
+            Some(ConnectionEvent::Lagged { missed: 2 })
+        );
+        assert_eq!(
+            stream.next().await,
+            Some(ConnectionEvent::RelayConnected { relay_url })
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_home_relay_failover() -> Result<()> {
+        iroh_test::logging::setup_multithreaded();
+        let (map_a, url_a, guard_a) = run_relay_server().await?;
+        let (map_b, url_b, guard_b) = run_relay_server().await?;
+        let relay_map = RelayMap::from_nodes(
+            map_a
+                .nodes()
+                .chain(map_b.nodes())
+                .map(|node| node.as_ref().clone()),
+        )?;
+        let mut guards = HashMap::from([(url_a.clone(), guard_a), (url_b.clone(), guard_b)]);
+
+        let endpoint = MagicEndpoint::builder()
+            .relay_mode(RelayMode::Custom(relay_map))
+            .insecure_skip_relay_cert_verify(true)
+            .bind(0)
+            .await?;
+        let mut events = endpoint.connection_events();
+        let home = tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                let event = events.next().await.expect("events closed");
+                if let ConnectionEvent::RelayConnected { relay_url } = event {
+                    if endpoint.my_relay().as_ref() == Some(&relay_url) {
+                        break relay_url;
+                    }
+                }
+            }
+        })
+        .await
+        .context("no home relay connection")?;
+        let other = if home == url_a { url_b } else { url_a };
+
+        info!(%home, %other, "taking the home relay down");
+        drop(guards.remove(&home));
+        let new_home = tokio::time::timeout(Duration::from_secs(30), async {
+            loop {
+                let event = events.next().await.expect("events closed");
+                if let ConnectionEvent::HomeRelayChanged { relay_url } = event {
+                    break relay_url;
+                }
+            }
+        })
+        .await
+        .context("no failover")?;
+        assert_eq!(new_home, other);
+        assert_eq!(endpoint.my_relay(), Some(other));
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_two_devices_roundtrip_quinn_raw() -> Result<()> {
         let _guard = iroh_test::logging::setup();
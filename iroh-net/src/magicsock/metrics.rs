@@ -54,6 +54,8 @@ pub struct Metrics {
 
     // How many times our relay home node DI has changed from non-zero to a different non-zero.
     pub relay_home_change: Counter,
+    // How many times we switched to another home relay because ours failed.
+    pub relay_home_failover: Counter,
 
     /*
      * Connection Metrics
@@ -120,6 +122,8 @@ impl Default for Metrics {
 
             // How many times our relay home node DI has changed from non-zero to a different non-zero.
             relay_home_change: Counter::new("relay_home_change"),
+            // How many times we switched to another home relay because ours failed.
+            relay_home_failover: Counter::new("relay_home_failover"),
 
             num_direct_conns_added: Counter::new(
                 "number of direct connections to a peer we have added",
@@ -18,8 +18,10 @@ use tokio::io::AsyncWriteExt;
 use tracing::{debug, info, instrument, trace, warn};
 
 use self::node_state::{NodeState, Options, PingHandled};
+pub(crate) use self::path_policy::PathPolicies;
 use super::{
-    metrics::Metrics as MagicsockMetrics, ActorMessage, DiscoMessageSource, QuicMappedAddr,
+    metrics::Metrics as MagicsockMetrics, ActorMessage, DiscoMessageSource, EventSender,
+    QuicMappedAddr,
 };
 use crate::{
     disco::{CallMeMaybe, Pong, SendAddr},
@@ -30,9 +32,11 @@ use crate::{
 
 mod best_addr;
 mod node_state;
+mod path_policy;
 
 pub use node_state::{ConnectionType, ControlMsg, DirectAddrInfo, NodeInfo};
 pub(super) use node_state::{DiscoPingPurpose, PingAction, PingRole, SendPing};
+pub use path_policy::{IpFamilyPreference, PathMode, PathPolicy};
 
 /// Number of nodes that are inactive for which we keep info about. This limit is enforced
 /// periodically via [`NodeMap::prune_inactive`].
@@ -67,6 +71,10 @@ pub(super) struct NodeMapInner {
     by_quic_mapped_addr: HashMap<QuicMappedAddr, usize>,
     by_id: HashMap<usize, NodeState>,
     next_id: usize,
+    /// Sends [`super::ConnectionEvent`]s, handed to each [`NodeState`].
+    events: EventSender,
+    /// The [`PathPolicy`]s applied to the nodes.
+    path_policies: PathPolicies,
 }
 
 /// Identifier to look up a [`NodeState`] in the [`NodeMap`].
@@ -93,6 +101,34 @@ impl NodeMap {
         }
     }
 
+    /// Returns the sender of the [`super::ConnectionEvent`]s of the nodes in this map.
+    pub(super) fn events(&self) -> EventSender {
+        self.inner.lock().events.clone()
+    }
+
+    /// Sets the [`PathPolicy`]s of all nodes, including those already in the map.
+    pub(super) fn set_path_policies(&self, path_policies: PathPolicies) {
+        let mut inner = self.inner.lock();
+        for ns in inner.by_id.values_mut() {
+            ns.set_path_policy(path_policies.get(ns.public_key()));
+        }
+        inner.path_policies = path_policies;
+    }
+
+    /// Returns the [`PathPolicy`] of a node.
+    pub(super) fn path_policy(&self, node_id: &NodeId) -> PathPolicy {
+        self.inner.lock().path_policies.get(node_id)
+    }
+
+    /// Whether any direct address of a node is known.
+    pub(super) fn has_direct_addrs(&self, node_id: &NodeId) -> bool {
+        self.inner
+            .lock()
+            .get(NodeStateKey::NodeId(node_id))
+            .map(|ns| ns.has_direct_addrs())
+            .unwrap_or(false)
+    }
+
     /// Get the known node addresses stored in the map. Nodes with empty addressing information are
     /// filtered out.
     #[cfg(test)]
@@ -105,6 +141,14 @@ impl NodeMap {
         self.inner.lock().add_node_addr(node_addr)
     }
 
+    /// Whether the node is listed.
+    pub(super) fn contains_node(&self, node_id: &NodeId) -> bool {
+        self.inner
+            .lock()
+            .get(NodeStateKey::NodeId(node_id))
+            .is_some()
+    }
+
     /// Number of nodes currently listed.
     pub(super) fn node_count(&self) -> usize {
         self.inner.lock().node_count()
@@ -503,7 +547,8 @@ impl NodeMapInner {
         );
         let id = self.next_id;
         self.next_id = self.next_id.wrapping_add(1);
-        let node_state = NodeState::new(id, options);
+        let mut node_state = NodeState::new(id, options, self.events.clone());
+        node_state.set_path_policy(self.path_policies.get(node_state.public_key()));
 
         // update indices
         self.by_quic_mapped_addr
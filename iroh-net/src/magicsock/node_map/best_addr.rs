@@ -8,6 +8,7 @@ use std::{
 use iroh_metrics::inc;
 use tracing::{debug, info};
 
+use super::path_policy::IpFamilyPreference;
 use crate::magicsock::metrics::Metrics as MagicsockMetrics;
 
 /// How long we trust a UDP address as the exclusive path (without using relay) without having heard a Pong reply.
@@ -145,6 +146,7 @@ impl BestAddr {
         source: Source,
         confirmed_at: Instant,
         has_relay: bool,
+        ip_family: IpFamilyPreference,
     ) {
         match self.0.as_mut() {
             None => {
@@ -152,7 +154,9 @@ impl BestAddr {
             }
             Some(state) => {
                 let candidate = AddrLatency { addr, latency };
-                if !state.is_trusted(confirmed_at) || candidate.is_better_than(&state.addr) {
+                if !state.is_trusted(confirmed_at)
+                    || candidate.is_better_than(&state.addr, ip_family)
+                {
                     self.insert(addr, latency, source, confirmed_at, has_relay);
                 } else if state.addr.addr == addr {
                     state.confirmed_at = confirmed_at;
@@ -223,6 +227,10 @@ impl BestAddr {
     pub fn addr(&self) -> Option<SocketAddr> {
         self.0.as_ref().map(|a| a.addr.addr)
     }
+
+    pub fn latency(&self) -> Option<Duration> {
+        self.0.as_ref().map(|a| a.addr.latency)
+    }
 }
 
 /// A `SocketAddr` with an associated latency.
@@ -234,17 +242,28 @@ pub struct AddrLatency {
 
 impl AddrLatency {
     /// Reports whether `self` is a better addr to use than `other`.
-    fn is_better_than(&self, other: &Self) -> bool {
+    fn is_better_than(&self, other: &Self, ip_family: IpFamilyPreference) -> bool {
         if self.addr == other.addr {
             return false;
         }
+        if self.addr.is_ipv4() != other.addr.is_ipv4() {
+            // A working path of the preferred family always wins.
+            match ip_family {
+                IpFamilyPreference::Auto => {}
+                IpFamilyPreference::Ipv4 => return self.addr.is_ipv4(),
+                IpFamilyPreference::Ipv6 => return self.addr.is_ipv6(),
+            }
+        }
         if self.addr.is_ipv6() && other.addr.is_ipv4() {
             // Prefer IPv6 for being a bit more robust, as long as
             // the latencies are roughly equivalent.
             if self.latency / 10 * 9 < other.latency {
                 return true;
             }
-        } else if self.addr.is_ipv4() && other.addr.is_ipv6() && other.is_better_than(self) {
+        } else if self.addr.is_ipv4()
+            && other.addr.is_ipv6()
+            && other.is_better_than(self, ip_family)
+        {
             return false;
         }
         self.latency < other.latency
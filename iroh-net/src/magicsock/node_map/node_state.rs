@@ -24,9 +24,13 @@ use crate::{
     NodeAddr, NodeId,
 };
 
-use crate::magicsock::{metrics::Metrics as MagicsockMetrics, ActorMessage, QuicMappedAddr};
+use crate::magicsock::{
+    metrics::Metrics as MagicsockMetrics, ActorMessage, ConnectionEvent, EventSender,
+    QuicMappedAddr,
+};
 
 use super::best_addr::{self, BestAddr, ClearReason};
+use super::path_policy::PathPolicy;
 use super::IpPort;
 
 /// Number of addresses that are not active that we keep around per node.
@@ -94,6 +98,8 @@ pub enum PingRole {
     NewPath,
     LikelyHeartbeat,
     Reactivate,
+    /// The path is not allowed by the node's [`PathPolicy`].
+    Disallowed,
 }
 
 /// An iroh node, which we can have connections with.
@@ -138,6 +144,10 @@ pub(super) struct NodeState {
     last_call_me_maybe: Option<Instant>,
     /// The type of connection we have to the node, either direct, relay, mixed, or none.
     conn_type: Watchable<ConnectionType>,
+    /// Sends [`ConnectionEvent`]s about this node.
+    events: EventSender,
+    /// Restricts the paths used to reach this node.
+    path_policy: PathPolicy,
 }
 
 #[derive(Debug)]
@@ -149,7 +159,7 @@ pub(super) struct Options {
 }
 
 impl NodeState {
-    pub(super) fn new(id: usize, options: Options) -> Self {
+    pub(super) fn new(id: usize, options: Options, events: EventSender) -> Self {
         let quic_mapped_addr = QuicMappedAddr::generate();
 
         if options.relay_url.is_some() {
@@ -169,9 +179,20 @@ impl NodeState {
             last_used: options.active.then(Instant::now),
             last_call_me_maybe: None,
             conn_type: Watchable::new(ConnectionType::None),
+            events,
+            path_policy: Default::default(),
         }
     }
 
+    pub(super) fn set_path_policy(&mut self, path_policy: PathPolicy) {
+        self.path_policy = path_policy;
+    }
+
+    /// Whether any direct address of this node is known.
+    pub(super) fn has_direct_addrs(&self) -> bool {
+        !self.direct_addr_state.is_empty()
+    }
+
     pub(super) fn public_key(&self) -> &PublicKey {
         &self.node_id
     }
@@ -195,7 +216,35 @@ impl NodeState {
     /// Returns info about this endpoint
     pub(super) fn info(&self, now: Instant) -> NodeInfo {
         let conn_type = self.conn_type.get();
-        let latency = match conn_type {
+        let latency = self.latency(&conn_type);
+        let addrs = self
+            .direct_addr_state
+            .iter()
+            .map(|(addr, endpoint_state)| DirectAddrInfo {
+                addr: SocketAddr::from(*addr),
+                latency: endpoint_state.recent_pong().map(|pong| pong.latency),
+                last_control: endpoint_state.last_control_msg(now),
+                last_payload: endpoint_state
+                    .last_payload_msg
+                    .as_ref()
+                    .map(|instant| now.duration_since(*instant)),
+            })
+            .collect();
+
+        NodeInfo {
+            id: self.id,
+            node_id: self.node_id,
+            relay_url: self.relay_url(),
+            addrs,
+            conn_type,
+            latency,
+            last_used: self.last_used.map(|instant| now.duration_since(instant)),
+        }
+    }
+
+    /// Returns the latency of a connection type to this endpoint, if known.
+    fn latency(&self, conn_type: &ConnectionType) -> Option<Duration> {
+        match *conn_type {
             ConnectionType::Direct(addr) => self
                 .direct_addr_state
                 .get(&addr.into())
@@ -218,29 +267,44 @@ impl NodeState {
                 addr_latency.min(relay_latency)
             }
             ConnectionType::None => None,
+        }
+    }
+
+    /// Updates the [`ConnectionType`], reporting changes as [`ConnectionEvent`]s.
+    fn set_conn_type(&self, conn_type: ConnectionType) {
+        let Ok(prev) = self.conn_type.update(conn_type.clone()) else {
+            return;
         };
-        let addrs = self
-            .direct_addr_state
-            .iter()
-            .map(|(addr, endpoint_state)| DirectAddrInfo {
-                addr: SocketAddr::from(*addr),
-                latency: endpoint_state.recent_pong().map(|pong| pong.latency),
-                last_control: endpoint_state.last_control_msg(now),
-                last_payload: endpoint_state
-                    .last_payload_msg
-                    .as_ref()
-                    .map(|instant| now.duration_since(*instant)),
-            })
-            .collect();
+        let node_id = self.node_id;
+        if prev == ConnectionType::None {
+            self.events
+                .send(ConnectionEvent::NodeConnected { node_id, conn_type });
+        } else {
+            let latency = self.latency(&conn_type);
+            self.events.send(ConnectionEvent::ConnectionTypeChanged {
+                node_id,
+                conn_type,
+                latency,
+            });
+        }
+    }
 
-        NodeInfo {
-            id: self.id,
-            node_id: self.node_id,
-            relay_url: self.relay_url(),
-            addrs,
-            conn_type,
-            latency,
-            last_used: self.last_used.map(|instant| now.duration_since(instant)),
+    /// Reports changes of the best address since `prev` as [`ConnectionEvent`]s.
+    fn note_best_addr_change(&self, prev: Option<SocketAddr>) {
+        let node_id = self.node_id;
+        match (prev, self.best_addr.addr(), self.best_addr.latency()) {
+            (prev, Some(addr), Some(latency)) if prev != Some(addr) => {
+                self.events.send(ConnectionEvent::DirectPathFound {
+                    node_id,
+                    addr,
+                    latency,
+                });
+            }
+            (Some(addr), None, _) => {
+                self.events
+                    .send(ConnectionEvent::DirectPathLost { node_id, addr });
+            }
+            _ => {}
         }
     }
 
@@ -261,6 +325,15 @@ impl NodeState {
             debug!("in `DEV_relay_ONLY` mode, giving the relay address as the only viable address for this endpoint");
             return (None, self.relay_url());
         }
+        if !self.path_policy.allows_direct() {
+            trace!("relay-only path policy, giving the relay address only");
+            let relay_url = self.relay_url();
+            self.set_conn_type(match relay_url {
+                Some(ref url) => ConnectionType::Relay(url.clone()),
+                None => ConnectionType::None,
+            });
+            return (None, relay_url);
+        }
         // Update our best addr from candidate addresses (only if it is empty and if we have
         // recent pongs).
         self.assign_best_addr_from_candidates_if_empty();
@@ -283,35 +356,36 @@ impl NodeState {
                 // No direct connection has been used before.  If we know of any possible
                 // candidate addresses, randomly try to use one while also sending via relay
                 // at the same time.
-                let addr = self
+                let mut candidates: Vec<SocketAddr> = self
                     .direct_addr_state
                     .keys()
                     .filter(|ipp| match ipp.ip() {
                         IpAddr::V4(_) => true,
                         IpAddr::V6(_) => have_ipv6,
                     })
-                    .choose_stable(&mut rand::thread_rng())
-                    .map(|ipp| SocketAddr::from(*ipp));
+                    .map(|ipp| SocketAddr::from(*ipp))
+                    .collect();
+                let is_preferred =
+                    |addr: &SocketAddr| self.path_policy.is_preferred_family(addr) == Some(true);
+                if candidates.iter().any(is_preferred) {
+                    candidates.retain(is_preferred);
+                }
+                let addr = candidates
+                    .into_iter()
+                    .choose_stable(&mut rand::thread_rng());
                 trace!(udp_addr = ?addr, "best_addr is unset, use candidate addr and relay");
                 (addr, self.relay_url())
             }
         };
-        match (best_addr, relay_url.clone()) {
-            (Some(best_addr), Some(relay_url)) => {
-                let _ = self
-                    .conn_type
-                    .update(ConnectionType::Mixed(best_addr, relay_url));
-            }
-            (Some(best_addr), None) => {
-                let _ = self.conn_type.update(ConnectionType::Direct(best_addr));
-            }
-            (None, Some(relay_url)) => {
-                let _ = self.conn_type.update(ConnectionType::Relay(relay_url));
-            }
-            (None, None) => {
-                let _ = self.conn_type.update(ConnectionType::None);
-            }
-        }
+        // A direct-only node is never reached via the relay.
+        let relay_url = relay_url.filter(|_| self.path_policy.allows_relay());
+        let conn_type = match (best_addr, relay_url.clone()) {
+            (Some(best_addr), Some(relay_url)) => ConnectionType::Mixed(best_addr, relay_url),
+            (Some(best_addr), None) => ConnectionType::Direct(best_addr),
+            (None, Some(relay_url)) => ConnectionType::Relay(relay_url),
+            (None, None) => ConnectionType::None,
+        };
+        self.set_conn_type(conn_type);
         (best_addr, relay_url)
     }
 
@@ -328,9 +402,19 @@ impl NodeState {
         // The highest acceptable latency for an endpoint path.  If the latency is higher
         // then this the path will be ignored.
         const MAX_LATENCY: Duration = Duration::from_secs(60 * 60);
+        // Only consider the preferred address family if it has any candidates.
+        let is_preferred = |ipp: &IpPort| {
+            self.path_policy
+                .is_preferred_family(&SocketAddr::from(*ipp))
+        };
+        let only_preferred = self
+            .direct_addr_state
+            .iter()
+            .any(|(ipp, state)| state.recent_pong().is_some() && is_preferred(ipp) == Some(true));
         let best_pong = self
             .direct_addr_state
             .iter()
+            .filter(|(ipp, _state)| !only_preferred || is_preferred(ipp) == Some(true))
             .fold(None, |best_pong, (ipp, state)| {
                 let best_latency = best_pong
                     .map(|p: &PongReply| p.latency)
@@ -352,13 +436,16 @@ impl NodeState {
         if let Some(pong) = best_pong {
             if let SendAddr::Udp(addr) = pong.from {
                 warn!(%addr, "No best_addr was set, choose candidate with lowest latency");
+                let prev = self.best_addr.addr();
                 self.best_addr.insert_if_better_or_reconfirm(
                     addr,
                     pong.latency,
                     best_addr::Source::BestCandidate,
                     pong.pong_at,
                     self.relay_url.is_some(),
-                )
+                    self.path_policy.ip_family,
+                );
+                self.note_best_addr_change(prev);
             }
         }
     }
@@ -413,12 +500,14 @@ impl NodeState {
                     }
 
                     // If we fail to ping our current best addr, it is not that good anymore.
+                    let prev = self.best_addr.addr();
                     self.best_addr.clear_if_addr_older(
                         addr,
                         sp.at,
                         ClearReason::PongTimeout,
                         self.relay_url.is_some(),
                     );
+                    self.note_best_addr_change(prev);
                 }
                 SendAddr::Relay(ref url) => {
                     if let Some((home_relay, relay_state)) = self.relay_url.as_mut() {
@@ -439,6 +528,10 @@ impl NodeState {
             warn!("in `DEV_relay_ONLY` mode, ignoring request to start a hole punching attempt.");
             return None;
         }
+        if !self.path_policy.allows(&dst) {
+            trace!(%dst, "path not allowed by path policy, not pinging");
+            return None;
+        }
         let tx_id = stun::TransactionId::default();
         trace!(tx = %hex::encode(tx_id), %dst, ?purpose,
                dst = %self.node_id.fmt_short(), "start ping");
@@ -516,6 +609,10 @@ impl NodeState {
     /// The caller is responsible for sending the messages.
     #[must_use = "actions must be handled"]
     fn send_call_me_maybe(&mut self, now: Instant, always: SendCallMeMaybe) -> Vec<PingAction> {
+        if !self.path_policy.allows_direct() {
+            // a call-me-maybe would tell the node our direct addresses
+            return Vec::new();
+        }
         match always {
             SendCallMeMaybe::Always => (),
             SendCallMeMaybe::IfNoRecent => {
@@ -536,7 +633,9 @@ impl NodeState {
         // accepts the connection.
         let mut msgs = self.send_pings(now);
 
-        if let Some(url) = self.relay_url() {
+        if !self.path_policy.allows_relay() {
+            trace!("direct-only path policy, not sending call-me-maybe via relay");
+        } else if let Some(url) = self.relay_url() {
             debug!(%url, "queue call-me-maybe");
             msgs.push(PingAction::SendCallMeMaybe {
                 relay_url: url,
@@ -661,6 +760,13 @@ impl NodeState {
     ) -> PingHandled {
         let now = Instant::now();
 
+        if !self.path_policy.allows(&path) {
+            return PingHandled {
+                role: PingRole::Disallowed,
+                needs_ping_back: None,
+            };
+        }
+
         let role = match path {
             SendAddr::Udp(addr) => match self.direct_addr_state.entry(addr.into()) {
                 Entry::Occupied(mut occupied) => occupied.get_mut().handle_ping(tx_id, now),
@@ -761,11 +867,13 @@ impl NodeState {
                 None => debug!(%ip_port, last_seen=%"never", "pruning address"),
             }
 
+            let prev = self.best_addr.addr();
             self.best_addr.clear_if_equals(
                 ip_port.into(),
                 ClearReason::Inactive,
                 self.relay_url.is_some(),
             );
+            self.note_best_addr_change(prev);
         }
         debug!(
             paths = %summarize_node_paths(&self.direct_addr_state),
@@ -870,13 +978,16 @@ impl NodeState {
                 // TODO(bradfitz): decide how latency vs. preference order affects decision
                 if let SendAddr::Udp(to) = sp.to {
                     debug_assert!(!is_relay, "mismatching relay & udp");
+                    let prev = self.best_addr.addr();
                     self.best_addr.insert_if_better_or_reconfirm(
                         to,
                         latency,
                         best_addr::Source::ReceivedPong,
                         now,
                         self.relay_url.is_some(),
+                        self.path_policy.ip_family,
                     );
+                    self.note_best_addr_change(prev);
                 }
 
                 node_map_insert
@@ -895,6 +1006,10 @@ impl NodeState {
     /// least open the firewalls on our side, giving the other side another change of making
     /// it through when it pings in response.
     pub(super) fn handle_call_me_maybe(&mut self, m: disco::CallMeMaybe) -> Vec<PingAction> {
+        if !self.path_policy.allows_direct() {
+            debug!("relay-only path policy, ignoring call-me-maybe");
+            return Vec::new();
+        }
         let now = Instant::now();
         let mut call_me_maybe_ipps = BTreeSet::new();
 
@@ -1418,7 +1533,7 @@ mod tests {
     use std::net::Ipv4Addr;
 
     use super::{
-        super::{NodeMap, NodeMapInner},
+        super::{path_policy::IpFamilyPreference, NodeMap, NodeMapInner},
         *,
     };
     use crate::key::SecretKey;
@@ -1468,6 +1583,8 @@ mod tests {
                     last_used: Some(now),
                     last_call_me_maybe: None,
                     conn_type: Watchable::new(ConnectionType::Direct(ip_port.into())),
+                    events: Default::default(),
+                    path_policy: Default::default(),
                 },
                 ip_port.into(),
             )
@@ -1494,6 +1611,8 @@ mod tests {
                 last_used: Some(now),
                 last_call_me_maybe: None,
                 conn_type: Watchable::new(ConnectionType::Relay(send_addr.clone())),
+                events: Default::default(),
+                path_policy: Default::default(),
             }
         };
 
@@ -1514,6 +1633,8 @@ mod tests {
                 last_used: Some(now),
                 last_call_me_maybe: None,
                 conn_type: Watchable::new(ConnectionType::Relay(send_addr.clone())),
+                events: Default::default(),
+                path_policy: Default::default(),
             }
         };
 
@@ -1558,6 +1679,8 @@ mod tests {
                         socket_addr,
                         send_addr.clone(),
                     )),
+                    events: Default::default(),
+                    path_policy: Default::default(),
                 },
                 socket_addr,
             )
@@ -1635,6 +1758,8 @@ mod tests {
                 (d_endpoint.id, d_endpoint),
             ]),
             next_id: 5,
+            events: Default::default(),
+            path_policies: Default::default(),
         });
         let mut got = node_map.node_infos(later);
         got.sort_by_key(|p| p.id);
@@ -1652,7 +1777,7 @@ mod tests {
             relay_url: None,
             active: true,
         };
-        let mut ep = NodeState::new(0, opts);
+        let mut ep = NodeState::new(0, opts, Default::default());
 
         let my_numbers_count: u16 = (MAX_INACTIVE_DIRECT_ADDRESSES + 5).try_into().unwrap();
         let my_numbers = (0u16..my_numbers_count)
@@ -1666,4 +1791,78 @@ mod tests {
         // number of pings as direct addresses in the call-me-maybe.
         assert_eq!(ping_messages.len(), my_numbers_count as usize);
     }
+
+    /// A node with a relay URL and an IPv4 and IPv6 direct address.
+    fn node_with_paths(path_policy: PathPolicy) -> (NodeState, RelayUrl) {
+        let relay_url: RelayUrl = "https://my-relay.com".parse().unwrap();
+        let opts = Options {
+            node_id: SecretKey::generate().public(),
+            relay_url: Some(relay_url.clone()),
+            active: true,
+        };
+        let mut ep = NodeState::new(0, opts, Default::default());
+        ep.set_path_policy(path_policy);
+        ep.update_from_node_addr(&AddrInfo {
+            relay_url: Some(relay_url.clone()),
+            direct_addresses: BTreeSet::from([
+                "1.1.1.1:1".parse().unwrap(),
+                "[2001:db8::1]:1".parse().unwrap(),
+            ]),
+        });
+        (ep, relay_url)
+    }
+
+    #[test]
+    fn test_relay_only_path_policy() {
+        let (mut ep, relay_url) = node_with_paths(PathPolicy::relay_only());
+
+        let (udp_addr, relay) = ep.addr_for_send(&Instant::now(), true);
+        assert_eq!(udp_addr, None);
+        assert_eq!(relay, Some(relay_url.clone()));
+        assert_eq!(ep.conn_type.get(), ConnectionType::Relay(relay_url.clone()));
+
+        // no direct addresses are probed or exchanged
+        let call_me_maybe = disco::CallMeMaybe {
+            my_numbers: vec!["2.2.2.2:2".parse().unwrap()],
+        };
+        assert!(ep.handle_call_me_maybe(call_me_maybe).is_empty());
+        assert!(ep
+            .send_call_me_maybe(Instant::now(), SendCallMeMaybe::Always)
+            .is_empty());
+
+        let udp_ping = ep.handle_ping(
+            SendAddr::Udp("2.2.2.2:2".parse().unwrap()),
+            stun::TransactionId::default(),
+        );
+        assert!(matches!(udp_ping.role, PingRole::Disallowed));
+        let relay_ping = ep.handle_ping(SendAddr::Relay(relay_url), stun::TransactionId::default());
+        assert!(!matches!(relay_ping.role, PingRole::Disallowed));
+    }
+
+    #[test]
+    fn test_direct_only_path_policy() {
+        let (mut ep, relay_url) = node_with_paths(PathPolicy::direct_only());
+
+        let (udp_addr, relay) = ep.addr_for_send(&Instant::now(), true);
+        assert!(udp_addr.is_some());
+        assert_eq!(relay, None);
+
+        let relay_ping = ep.handle_ping(SendAddr::Relay(relay_url), stun::TransactionId::default());
+        assert!(matches!(relay_ping.role, PingRole::Disallowed));
+    }
+
+    #[test]
+    fn test_ip_family_preference() {
+        for (ip_family, is_ipv6) in [
+            (IpFamilyPreference::Ipv4, false),
+            (IpFamilyPreference::Ipv6, true),
+        ] {
+            let policy = PathPolicy::default().ip_family(ip_family);
+            let (mut ep, _relay_url) = node_with_paths(policy);
+            for _ in 0..10 {
+                let (udp_addr, _relay) = ep.addr_for_send(&Instant::now(), true);
+                assert_eq!(udp_addr.unwrap().is_ipv6(), is_ipv6);
+            }
+        }
+    }
 }
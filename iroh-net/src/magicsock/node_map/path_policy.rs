@@ -0,0 +1,116 @@
+//! Policies restricting which paths are used to reach a node.
+
+use std::{collections::HashMap, net::SocketAddr};
+
+use iroh_base::key::NodeId;
+
+use crate::disco::SendAddr;
+
+/// Which kinds of paths may be used to reach a node.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PathMode {
+    /// Start out via the relay and upgrade to a direct path once one is found.
+    #[default]
+    Any,
+    /// Only ever use the relay.
+    ///
+    /// No direct addresses are exchanged with the node and no direct paths are probed, so
+    /// the node does not learn our IP addresses from us. Note that it may still learn them
+    /// from a discovery service we publish our addresses to.
+    RelayOnly,
+    /// Only use direct paths, never the relay.
+    ///
+    /// Connecting fails right away if no direct address of the node is known.
+    DirectOnly,
+}
+
+/// Which IP address family to prefer for direct paths.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IpFamilyPreference {
+    /// Prefer IPv6 if its latency is roughly equivalent to IPv4, otherwise use the lowest
+    /// latency path.
+    #[default]
+    Auto,
+    /// Use an IPv4 path whenever one works.
+    Ipv4,
+    /// Use an IPv6 path whenever one works.
+    Ipv6,
+}
+
+/// Policy for the paths used to reach a node.
+///
+/// The default uses relay and direct paths alike, picking direct paths by latency.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PathPolicy {
+    /// Which kinds of paths may be used.
+    pub mode: PathMode,
+    /// Which IP address family to prefer for direct paths.
+    pub ip_family: IpFamilyPreference,
+}
+
+impl PathPolicy {
+    /// A policy only using the relay, see [`PathMode::RelayOnly`].
+    pub fn relay_only() -> Self {
+        Self {
+            mode: PathMode::RelayOnly,
+            ..Default::default()
+        }
+    }
+
+    /// A policy only using direct paths, see [`PathMode::DirectOnly`].
+    pub fn direct_only() -> Self {
+        Self {
+            mode: PathMode::DirectOnly,
+            ..Default::default()
+        }
+    }
+
+    /// Sets the IP address family to prefer for direct paths.
+    pub fn ip_family(mut self, ip_family: IpFamilyPreference) -> Self {
+        self.ip_family = ip_family;
+        self
+    }
+
+    /// Whether the relay may be used.
+    pub(super) fn allows_relay(&self) -> bool {
+        self.mode != PathMode::DirectOnly
+    }
+
+    /// Whether direct paths may be used.
+    pub(super) fn allows_direct(&self) -> bool {
+        self.mode != PathMode::RelayOnly
+    }
+
+    /// Whether the path may be used.
+    pub(super) fn allows(&self, path: &SendAddr) -> bool {
+        match path {
+            SendAddr::Udp(_) => self.allows_direct(),
+            SendAddr::Relay(_) => self.allows_relay(),
+        }
+    }
+
+    /// Whether `addr` is of the preferred IP address family, if there is one.
+    pub(super) fn is_preferred_family(&self, addr: &SocketAddr) -> Option<bool> {
+        match self.ip_family {
+            IpFamilyPreference::Auto => None,
+            IpFamilyPreference::Ipv4 => Some(addr.is_ipv4()),
+            IpFamilyPreference::Ipv6 => Some(addr.is_ipv6()),
+        }
+    }
+}
+
+/// The endpoint-wide [`PathPolicy`] together with the policies for specific nodes.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct PathPolicies {
+    /// Policy for nodes without a policy of their own.
+    pub(crate) default: PathPolicy,
+    /// Policies for specific nodes.
+    pub(crate) nodes: HashMap<NodeId, PathPolicy>,
+}
+
+impl PathPolicies {
+    /// The policy to use for `node_id`.
+    pub(super) fn get(&self, node_id: &NodeId) -> PathPolicy {
+        self.nodes.get(node_id).copied().unwrap_or(self.default)
+    }
+}
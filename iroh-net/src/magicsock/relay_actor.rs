@@ -1,6 +1,7 @@
 use std::{
     collections::{BTreeMap, HashSet},
     net::{IpAddr, SocketAddr},
+    pin::Pin,
     sync::{atomic::Ordering, Arc},
     time::{Duration, Instant},
 };
@@ -8,7 +9,7 @@ use std::{
 use anyhow::Context;
 use backoff::backoff::Backoff;
 use bytes::{Bytes, BytesMut};
-use futures::Future;
+use futures::{future::OptionFuture, Future};
 use iroh_metrics::{inc, inc_by};
 use tokio::{
     sync::{mpsc, oneshot},
@@ -23,7 +24,7 @@ use crate::{
     relay::{self, http::ClientError, ReceivedMessage, RelayUrl, MAX_PACKET_SIZE},
 };
 
-use super::{ActorMessage, Inner};
+use super::{ActorMessage, ConnectionEvent, EventSender, Inner};
 use super::{Metrics as MagicsockMetrics, RelayContents};
 
 /// How long a non-home relay connection needs to be idle (last written to) before we close it.
@@ -66,6 +67,13 @@ struct ActiveRelay {
     backoff: backoff::exponential::ExponentialBackoff<backoff::SystemClock>,
     last_packet_time: Option<Instant>,
     last_packet_src: Option<PublicKey>,
+    /// Whether the relay server confirmed the current connection.
+    connected: bool,
+    /// Whether this is our home relay, which is reconnected to as soon as the connection broke.
+    is_preferred: bool,
+    /// Fires when the next attempt to reconnect to our home relay is due.
+    reconnect_timer: Option<Pin<Box<time::Sleep>>>,
+    events: EventSender,
 }
 
 #[derive(Debug)]
@@ -86,6 +94,8 @@ impl ActiveRelay {
         relay_client: relay::http::Client,
         relay_client_receiver: relay::http::ClientReceiver,
         msg_sender: mpsc::Sender<ActorMessage>,
+        is_preferred: bool,
+        events: EventSender,
     ) -> Self {
         ActiveRelay {
             last_write: Instant::now(),
@@ -101,15 +111,78 @@ impl ActiveRelay {
             last_packet_src: None,
             relay_client,
             relay_client_receiver,
+            connected: false,
+            is_preferred,
+            reconnect_timer: None,
+            events,
+        }
+    }
+
+    /// Reports changes of the connection state as [`ConnectionEvent`]s.
+    fn set_connected(&mut self, connected: bool) {
+        if self.connected == connected {
+            return;
+        }
+        self.connected = connected;
+        let relay_url = self.url.clone();
+        self.events.send(match connected {
+            true => ConnectionEvent::RelayConnected { relay_url },
+            false => ConnectionEvent::RelayDisconnected { relay_url },
+        });
+    }
+
+    /// Notes that the connection to the relay server is established.
+    fn note_connected(&mut self) {
+        self.backoff.reset();
+        self.reconnect_timer = None;
+        self.set_connected(true);
+        self.report_health(true);
+    }
+
+    /// Reconnects to the relay server if it is our home relay.
+    ///
+    /// Every failed attempt is reported to the magicsock actor, and the next one is scheduled
+    /// with backoff, so that a relay which stays down is noticed and failed over from.
+    /// Connections to other relays are only re-established once they are used again.
+    async fn reconnect(&mut self) -> ReadResult {
+        self.reconnect_timer = None;
+        if !self.is_preferred || self.connected {
+            return ReadResult::Continue;
+        }
+        debug!("reconnecting to home relay");
+        match self.relay_client.connect().await {
+            Ok(_) => self.note_connected(),
+            Err(err) => {
+                warn!("reconnect failed: {:?}", err);
+                self.report_health(false);
+                match self.backoff.next_backoff() {
+                    Some(t) => self.reconnect_timer = Some(Box::pin(time::sleep(t))),
+                    None => return ReadResult::Break,
+                }
+            }
+        }
+        ReadResult::Continue
+    }
+
+    /// Reports the health of this relay to the magicsock actor, for home relay failover.
+    fn report_health(&self, healthy: bool) {
+        let msg = ActorMessage::RelayHealth {
+            url: self.url.clone(),
+            healthy,
+        };
+        if let Err(err) = self.msg_sender.try_send(msg) {
+            warn!("dropping relay health report: {:?}", err);
         }
     }
 
     async fn run(mut self, mut inbox: mpsc::Receiver<ActiveRelayMessage>) -> anyhow::Result<()> {
         debug!("initial dial {}", self.url);
-        self.relay_client
-            .connect()
-            .await
-            .context("initial connection")?;
+        let res = self.relay_client.connect().await;
+        match res {
+            Ok(_) => self.note_connected(),
+            Err(_) => self.report_health(false),
+        }
+        res.context("initial connection")?;
 
         loop {
             tokio::select! {
@@ -130,6 +203,7 @@ impl ActiveRelay {
                             r.send(self.relay_client.clone()).ok();
                         }
                         ActiveRelayMessage::NotePreferred(is_preferred) => {
+                            self.is_preferred = is_preferred;
                             self.relay_client.note_preferred(is_preferred).await;
                         }
                         ActiveRelayMessage::GetPeerRoute(peer, r) => {
@@ -141,6 +215,7 @@ impl ActiveRelay {
                             r.send(res).ok();
                         }
                         ActiveRelayMessage::Shutdown => {
+                            self.set_connected(false);
                             self.relay_client.close().await.ok();
                             break;
                         }
@@ -151,11 +226,19 @@ impl ActiveRelay {
                     if let Some(msg) = msg {
                         if self.handle_relay_msg(msg).await == ReadResult::Break {
                             // fatal error
+                            self.set_connected(false);
                             self.relay_client.close().await.ok();
                             break;
                         }
                     }
                 }
+                Some(()) = OptionFuture::from(self.reconnect_timer.as_mut()) => {
+                    trace!("tick: reconnect");
+                    if self.reconnect().await == ReadResult::Break {
+                        self.relay_client.close().await.ok();
+                        break;
+                    }
+                }
                 else => {
                     break;
                 }
@@ -172,6 +255,8 @@ impl ActiveRelay {
         match msg {
             Err(err) => {
                 warn!("recv error {:?}", err);
+                self.set_connected(false);
+                self.report_health(false);
 
                 // Forget that all these peers have routes.
                 let peers: Vec<_> = self.peer_present.drain().collect();
@@ -195,14 +280,18 @@ impl ActiveRelay {
                     Some(t) => {
                         debug!("backoff sleep: {}ms", t.as_millis());
                         time::sleep(t).await;
-                        ReadResult::Continue
+                        self.reconnect().await
                     }
                     None => ReadResult::Break,
                 }
             }
             Ok((msg, conn_gen)) => {
-                // reset
-                self.backoff.reset();
+                if self.connected {
+                    self.backoff.reset();
+                } else {
+                    // reconnected when the connection was used again
+                    self.note_connected();
+                }
                 let now = Instant::now();
                 if self
                     .last_packet_time
@@ -473,7 +562,7 @@ impl RelayActor {
         };
         info!("adding connection to relay: {url} for {why}");
 
-        let my_relay = self.conn.my_relay();
+        let is_preferred = self.conn.my_relay().as_ref() == Some(url);
         let ipv6_reported = self.conn.ipv6_reported.clone();
         let url = url.clone();
         let url1 = url.clone();
@@ -485,7 +574,17 @@ impl RelayActor {
                 Box::pin(async move { ipv6_reported.load(Ordering::Relaxed) })
             })
             .can_ack_pings(true)
-            .is_preferred(my_relay.as_ref() == Some(&url1));
+            .is_preferred(is_preferred)
+            .proxy(self.conn.proxy.clone());
+        let auth_token = self
+            .conn
+            .relay_map
+            .get_node(&url1)
+            .and_then(|node| node.auth_token.clone());
+        let builder = match auth_token {
+            Some(token) => builder.auth_token(token),
+            None => builder,
+        };
 
         #[cfg(any(test, feature = "test-utils"))]
         let builder = builder.insecure_skip_cert_verify(self.conn.insecure_skip_relay_cert_verify);
@@ -497,10 +596,11 @@ impl RelayActor {
 
         let c = dc.clone();
         let msg_sender = self.msg_sender.clone();
+        let events = self.conn.events.clone();
         let url1 = url.clone();
         let handle = tokio::task::spawn(
             async move {
-                let ad = ActiveRelay::new(url1, c, dc_receiver, msg_sender);
+                let ad = ActiveRelay::new(url1, c, dc_receiver, msg_sender, is_preferred, events);
 
                 if let Err(err) = ad.run(r).await {
                     warn!("connection error: {:?}", err);
@@ -0,0 +1,170 @@
+//! Health tracking of the relay servers, used to fail over to another home relay.
+
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use crate::relay::{RelayMap, RelayUrl};
+
+/// How long a relay is not chosen as home relay after its connection failed.
+///
+/// A successful connection to the relay ends this early.
+const RELAY_UNHEALTHY_DURATION: Duration = Duration::from_secs(60);
+
+/// The number of consecutive connection failures after which a relay is unhealthy.
+///
+/// A single failure is usually a blip which the reconnect of the relay client recovers from,
+/// failing over on it would needlessly move all nodes talking to us to another relay.
+const RELAY_FAILURES_UNHEALTHY: u32 = 3;
+
+/// Tracks which relays of the [`RelayMap`] are usable as home relay.
+///
+/// A relay is healthy unless connecting to it failed [`RELAY_FAILURES_UNHEALTHY`] times in a
+/// row, with the last failure within [`RELAY_UNHEALTHY_DURATION`]. Healthy relays are ranked
+/// by the latencies of the last netcheck report, which actively probes all relays.
+#[derive(Debug, Default)]
+pub(super) struct RelayHealth {
+    /// Relay latencies from the last netcheck report, lowest first.
+    latencies: Vec<(RelayUrl, Duration)>,
+    /// Relays whose connection failed since it last succeeded.
+    failed: HashMap<RelayUrl, Failures>,
+}
+
+/// The connection failures of a relay since its last successful connection.
+#[derive(Debug)]
+struct Failures {
+    /// The number of consecutive failures.
+    count: u32,
+    /// The time of the last failure.
+    last: Instant,
+}
+
+impl RelayHealth {
+    /// Updates the ranking of the relays with the latencies of a netcheck report.
+    pub(super) fn update_latencies<'a>(
+        &mut self,
+        latencies: impl IntoIterator<Item = (&'a RelayUrl, Duration)>,
+    ) {
+        self.latencies = latencies
+            .into_iter()
+            .map(|(url, latency)| (url.clone(), latency))
+            .collect();
+        self.latencies.sort_by_key(|(_url, latency)| *latency);
+    }
+
+    /// Notes that a connection to the relay succeeded.
+    pub(super) fn note_connected(&mut self, url: &RelayUrl) {
+        self.failed.remove(url);
+    }
+
+    /// Notes that a connection to the relay failed.
+    pub(super) fn note_failed(&mut self, url: &RelayUrl, now: Instant) {
+        let failures = self.failed.entry(url.clone()).or_insert(Failures {
+            count: 0,
+            last: now,
+        });
+        // failures spread out further than the unhealthy duration do not add up
+        if now.duration_since(failures.last) >= RELAY_UNHEALTHY_DURATION {
+            failures.count = 0;
+        }
+        failures.count += 1;
+        failures.last = now;
+    }
+
+    /// Whether the relay may be chosen as home relay.
+    pub(super) fn is_healthy(&self, url: &RelayUrl, now: Instant) -> bool {
+        match self.failed.get(url) {
+            Some(failures) => {
+                failures.count < RELAY_FAILURES_UNHEALTHY
+                    || now.duration_since(failures.last) >= RELAY_UNHEALTHY_DURATION
+            }
+            None => true,
+        }
+    }
+
+    /// Returns the healthy relay with the lowest latency.
+    ///
+    /// If no healthy relay has a known latency, the first healthy relay of the map is returned.
+    /// STUN-only relays are never returned.
+    pub(super) fn best_relay(&self, relay_map: &RelayMap, now: Instant) -> Option<RelayUrl> {
+        let ranked = self.latencies.iter().map(|(url, _latency)| url);
+        ranked
+            .chain(relay_map.urls())
+            .filter(|url| relay_map.get_node(url).is_some_and(|node| !node.stun_only))
+            .find(|url| self.is_healthy(url, now))
+            .cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn relay_map(urls: &[&RelayUrl]) -> RelayMap {
+        RelayMap::from_nodes(urls.iter().map(|url| crate::relay::RelayNode {
+            url: (*url).clone(),
+            stun_only: false,
+            stun_port: 0,
+            auth_token: None,
+            quic_port: None,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_best_relay() {
+        let a: RelayUrl = "https://a.example.com".parse().unwrap();
+        let b: RelayUrl = "https://b.example.com".parse().unwrap();
+        let c: RelayUrl = "https://c.example.com".parse().unwrap();
+        let map = relay_map(&[&a, &b, &c]);
+        let now = Instant::now();
+
+        let fail = |health: &mut RelayHealth, url: &RelayUrl| {
+            for _ in 0..RELAY_FAILURES_UNHEALTHY {
+                health.note_failed(url, now);
+            }
+        };
+
+        let mut health = RelayHealth::default();
+        // without latencies the map order is used
+        assert_eq!(health.best_relay(&map, now), Some(a.clone()));
+
+        health.update_latencies([
+            (&c, Duration::from_millis(20)),
+            (&b, Duration::from_millis(10)),
+        ]);
+        assert_eq!(health.best_relay(&map, now), Some(b.clone()));
+
+        // fail over to the next best relay, after repeated failures only
+        health.note_failed(&b, now);
+        assert!(health.is_healthy(&b, now));
+        fail(&mut health, &b);
+        assert!(!health.is_healthy(&b, now));
+        assert_eq!(health.best_relay(&map, now), Some(c.clone()));
+
+        // relays without latency are the last resort
+        fail(&mut health, &c);
+        assert_eq!(health.best_relay(&map, now), Some(a.clone()));
+        fail(&mut health, &a);
+        assert_eq!(health.best_relay(&map, now), None);
+
+        // failures expire, or end once a connection succeeds
+        let later = now + RELAY_UNHEALTHY_DURATION;
+        assert_eq!(health.best_relay(&map, later), Some(b.clone()));
+        health.note_connected(&c);
+        assert_eq!(health.best_relay(&map, now), Some(c));
+    }
+
+    #[test]
+    fn test_failures_do_not_add_up_over_time() {
+        let a: RelayUrl = "https://a.example.com".parse().unwrap();
+        let mut now = Instant::now();
+        let mut health = RelayHealth::default();
+        for _ in 0..RELAY_FAILURES_UNHEALTHY {
+            health.note_failed(&a, now);
+            assert!(health.is_healthy(&a, now));
+            now += RELAY_UNHEALTHY_DURATION;
+        }
+    }
+}
@@ -14,12 +14,17 @@ use tracing::{debug, trace, warn};
 
 use crate::net::IpFamily;
 use crate::net::UdpSocket;
+#[cfg(any(test, feature = "test-utils"))]
+use crate::test_utils::sim::{SimHost, SimSocket};
 
 /// A UDP socket implementing Quinn's [`AsyncUdpSocket`].
 #[derive(Clone, Debug)]
 pub struct UdpConn {
     io: Arc<UdpSocket>,
     state: Arc<quinn_udp::UdpSocketState>,
+    /// Socket in a simulated network carrying all traffic, `io` then only sends STUN probes.
+    #[cfg(any(test, feature = "test-utils"))]
+    sim: Option<SimSocket>,
 }
 
 impl UdpConn {
@@ -32,9 +37,33 @@ impl UdpConn {
         Ok(Self {
             io: Arc::new(sock),
             state: Default::default(),
+            #[cfg(any(test, feature = "test-utils"))]
+            sim: None,
         })
     }
 
+    /// Binds a socket on a host in a simulated network.
+    #[cfg(any(test, feature = "test-utils"))]
+    pub(super) fn bind_sim(host: &SimHost, port: u16) -> anyhow::Result<Self> {
+        let sim = host.bind(port)?;
+        // netcheck sends STUN probes on `io`, the simulated STUN servers route them through
+        // the simulated network as if they were sent by `sim`.
+        let sock = sim.bridge_socket()?;
+        Ok(Self {
+            io: Arc::new(sock),
+            state: Default::default(),
+            sim: Some(sim),
+        })
+    }
+
+    /// Whether this socket is bound in a simulated network.
+    pub(super) fn is_simulated(&self) -> bool {
+        #[cfg(any(test, feature = "test-utils"))]
+        return self.sim.is_some();
+        #[cfg(not(any(test, feature = "test-utils")))]
+        false
+    }
+
     pub fn port(&self) -> u16 {
         self.local_addr().map(|p| p.port()).unwrap_or_default()
     }
@@ -53,6 +82,10 @@ impl AsyncUdpSocket for UdpConn {
         cx: &mut Context,
         transmits: &[quinn_udp::Transmit],
     ) -> Poll<io::Result<usize>> {
+        #[cfg(any(test, feature = "test-utils"))]
+        if let Some(ref sim) = self.sim {
+            return sim.poll_send(state, cx, transmits);
+        }
         let inner = &self.state;
         let io = &self.io;
         loop {
@@ -81,6 +114,10 @@ impl AsyncUdpSocket for UdpConn {
         bufs: &mut [io::IoSliceMut<'_>],
         meta: &mut [quinn_udp::RecvMeta],
     ) -> Poll<io::Result<usize>> {
+        #[cfg(any(test, feature = "test-utils"))]
+        if let Some(ref sim) = self.sim {
+            return sim.poll_recv(cx, bufs, meta);
+        }
         loop {
             ready!(self.io.poll_recv_ready(cx))?;
             if let Ok(res) = self.io.try_io(Interest::READABLE, || {
@@ -102,6 +139,10 @@ impl AsyncUdpSocket for UdpConn {
     }
 
     fn local_addr(&self) -> io::Result<SocketAddr> {
+        #[cfg(any(test, feature = "test-utils"))]
+        if let Some(ref sim) = self.sim {
+            return Ok(sim.local_addr());
+        }
         self.io.local_addr()
     }
 }
@@ -22,6 +22,8 @@ use tracing::{debug, error, info_span, trace, warn, Instrument};
 use crate::dns::DnsResolver;
 use crate::net::ip::to_canonical;
 use crate::net::{IpFamily, UdpSocket};
+use crate::relay::http::Proxy;
+use crate::relay::quic::QuicClient;
 use crate::relay::RelayUrl;
 use crate::util::CancelOnDrop;
 
@@ -91,6 +93,13 @@ pub struct Report {
     pub global_v4: Option<SocketAddrV4>,
     /// `[ip]:port` of global IPv6
     pub global_v6: Option<SocketAddrV6>,
+    /// ip:port of global IPv4 observed by QUIC address discovery.
+    ///
+    /// The QUIC probes are sent from the same socket as the STUN probes, so this usually
+    /// equals `global_v4`, but is also known when STUN is blocked.
+    pub quic_v4: Option<SocketAddrV4>,
+    /// `[ip]:port` of global IPv6 observed by QUIC address discovery.
+    pub quic_v6: Option<SocketAddrV6>,
     /// CaptivePortal is set when we think there's a captive portal that is
     /// intercepting HTTP traffic.
     pub captive_portal: Option<bool>,
@@ -175,6 +184,8 @@ pub struct Client {
     /// If all senders are dropped, in other words all clones of this struct are dropped,
     /// the actor will terminate.
     addr: Addr,
+    /// The client of the QUIC address discovery probes, shared with the [`Actor`].
+    quic_client: QuicClient,
     /// Ensures the actor is terminated when the client is dropped.
     _drop_guard: Arc<CancelOnDrop>,
 }
@@ -208,13 +219,26 @@ impl Client {
     /// This starts a connected actor in the background.  Once the client is dropped it will
     /// stop running.
     pub fn new(port_mapper: Option<portmapper::Client>, dns_resolver: DnsResolver) -> Result<Self> {
-        let mut actor = Actor::new(port_mapper, dns_resolver)?;
+        Self::with_proxy(port_mapper, dns_resolver, None)
+    }
+
+    /// Creates a new netcheck client which tunnels its HTTP requests through a proxy.
+    ///
+    /// See [`Client::new`].
+    pub fn with_proxy(
+        port_mapper: Option<portmapper::Client>,
+        dns_resolver: DnsResolver,
+        proxy: Option<Proxy>,
+    ) -> Result<Self> {
+        let quic_client = QuicClient::new();
+        let mut actor = Actor::new(port_mapper, dns_resolver, proxy, quic_client.clone())?;
         let addr = actor.addr();
         let task =
             tokio::spawn(async move { actor.run().await }.instrument(info_span!("netcheck.actor")));
         let drop_guard = CancelOnDrop::new("netcheck actor", task.abort_handle());
         Ok(Client {
             addr,
+            quic_client,
             _drop_guard: Arc::new(drop_guard),
         })
     }
@@ -242,6 +266,18 @@ impl Client {
         }
     }
 
+    /// Pass a received QUIC packet to the netchecker.
+    ///
+    /// The QUIC address discovery probes are sent from the same sockets as the STUN probes.
+    /// Like STUN packets, the packets received from the QUIC address discovery servers on
+    /// those sockets have to be passed to the netchecker.
+    ///
+    /// Returns `false` if the packet is not from a QUIC address discovery server, the
+    /// packet is then not consumed.
+    pub(crate) fn receive_quic_packet(&self, payload: &[u8], src: SocketAddr) -> bool {
+        self.quic_client.receive(payload, src)
+    }
+
     /// Runs a netcheck, returning the report.
     ///
     /// It may not be called concurrently with itself, `&mut self` takes care of that.
@@ -411,6 +447,12 @@ struct Actor {
 
     /// The DNS resolver to use for probes that need to perform DNS lookups
     dns_resolver: DnsResolver,
+    /// The proxy to tunnel HTTP requests through
+    proxy: Option<Proxy>,
+    /// The client of the QUIC address discovery probes.
+    ///
+    /// Kept across reports, so that its endpoints are reused.
+    quic_client: QuicClient,
 }
 
 impl Actor {
@@ -418,7 +460,12 @@ impl Actor {
     ///
     /// This does not start the actor, see [`Actor::run`] for this.  You should not
     /// normally create this directly but rather create a [`Client`].
-    fn new(port_mapper: Option<portmapper::Client>, dns_resolver: DnsResolver) -> Result<Self> {
+    fn new(
+        port_mapper: Option<portmapper::Client>,
+        dns_resolver: DnsResolver,
+        proxy: Option<Proxy>,
+        quic_client: QuicClient,
+    ) -> Result<Self> {
         // TODO: consider an instrumented flume channel so we have metrics.
         let (sender, receiver) = mpsc::channel(32);
         Ok(Self {
@@ -429,6 +476,8 @@ impl Actor {
             in_flight_stun_requests: Default::default(),
             current_report_run: None,
             dns_resolver,
+            proxy,
+            quic_client,
         })
     }
 
@@ -498,11 +547,21 @@ impl Actor {
         let cancel_token = CancellationToken::new();
         let stun_sock_v4 = match stun_sock_v4 {
             Some(sock) => Some(sock),
-            None => bind_local_stun_socket(IpFamily::V4, self.addr(), cancel_token.clone()),
+            None => bind_local_stun_socket(
+                IpFamily::V4,
+                self.addr(),
+                self.quic_client.clone(),
+                cancel_token.clone(),
+            ),
         };
         let stun_sock_v6 = match stun_sock_v6 {
             Some(sock) => Some(sock),
-            None => bind_local_stun_socket(IpFamily::V6, self.addr(), cancel_token.clone()),
+            None => bind_local_stun_socket(
+                IpFamily::V6,
+                self.addr(),
+                self.quic_client.clone(),
+                cancel_token.clone(),
+            ),
         };
         let mut do_full = self.reports.next_full
             || now.duration_since(self.reports.last_full) > FULL_REPORT_INTERVAL;
@@ -530,7 +589,9 @@ impl Actor {
             relay_map,
             stun_sock_v4,
             stun_sock_v6,
+            self.quic_client.clone(),
             self.dns_resolver.clone(),
+            self.proxy.clone(),
         );
 
         self.current_report_run = Some(ReportRun {
@@ -715,6 +776,7 @@ struct ReportRun {
 fn bind_local_stun_socket(
     network: IpFamily,
     actor_addr: Addr,
+    quic_client: QuicClient,
     cancel_token: CancellationToken,
 ) -> Option<Arc<UdpSocket>> {
     let sock = match UdpSocket::bind(network, 0) {
@@ -742,7 +804,7 @@ fn bind_local_stun_socket(
                     tokio::select! {
                         biased;
                         _ = cancel_token.cancelled() => break,
-                        res = recv_stun_once(&sock, &mut buf, &actor_addr) => {
+                        res = recv_stun_once(&sock, &mut buf, &actor_addr, &quic_client) => {
                             if let Err(err) = res {
                                 warn!(%err, "stun recv failed");
                                 break;
@@ -759,13 +821,23 @@ fn bind_local_stun_socket(
 }
 
 /// Receive STUN response from a UDP socket, pass it to the actor.
-async fn recv_stun_once(sock: &UdpSocket, buf: &mut [u8], actor_addr: &Addr) -> Result<()> {
+///
+/// Packets of the QUIC address discovery probes are passed to the QUIC client instead.
+async fn recv_stun_once(
+    sock: &UdpSocket,
+    buf: &mut [u8],
+    actor_addr: &Addr,
+    quic_client: &QuicClient,
+) -> Result<()> {
     let (count, mut from_addr) = sock
         .recv_from(buf)
         .await
         .context("Error reading from stun socket")?;
     let payload = &buf[..count];
     from_addr.set_ip(to_canonical(from_addr.ip()));
+    if quic_client.receive(payload, from_addr) {
+        return Ok(());
+    }
     let msg = Message::StunPacket {
         payload: Bytes::from(payload.to_vec()),
         from_addr,
@@ -844,6 +916,8 @@ mod tests {
             url: url.clone(),
             stun_only: true,
             stun_port: DEFAULT_RELAY_STUN_PORT,
+            auth_token: None,
+            quic_port: None,
         }])
         .expect("hardcoded");
 
@@ -883,6 +957,55 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_quic_addr_disc_without_stun() -> Result<()> {
+        let _guard = iroh_test::logging::setup();
+
+        // STUN is blocked, but the relay serves QUIC address discovery.
+        let blackhole = tokio::net::UdpSocket::bind("127.0.0.1:0").await?;
+        let stun_addr = blackhole.local_addr()?;
+        let quic_server = crate::relay::QuicServer::spawn("127.0.0.1:0".parse()?)?;
+        let dm = RelayMap::from_nodes([RelayNode {
+            url: format!("http://{stun_addr}").parse()?,
+            stun_only: true,
+            stun_port: stun_addr.port(),
+            auth_token: None,
+            quic_port: Some(quic_server.local_addr()?.port()),
+        }])?;
+
+        let resolver = crate::dns::default_resolver().clone();
+        let mut client = Client::new(None, resolver)?;
+
+        // Probe from a socket which carries other traffic as well, like magicsock does.
+        let sock = Arc::new(UdpSocket::bind_local(IpFamily::V4, 0)?);
+        let reader = tokio::spawn({
+            let sock = sock.clone();
+            let client = client.clone();
+            async move {
+                let mut buf = vec![0u8; 64 << 10];
+                loop {
+                    let (len, src) = sock.recv_from(&mut buf).await?;
+                    if !client.receive_quic_packet(&buf[..len], src) {
+                        client.receive_stun_packet(Bytes::copy_from_slice(&buf[..len]), src);
+                    }
+                }
+                #[allow(unreachable_code)]
+                anyhow::Ok(())
+            }
+        });
+        let r = client.get_report(dm, Some(sock.clone()), None).await?;
+
+        assert!(r.udp, "want UDP");
+        assert!(r.global_v4.is_none(), "STUN is blocked");
+        let quic_v4 = r.quic_v4.context("expected QUIC address")?;
+        assert_eq!(SocketAddr::V4(quic_v4), sock.local_addr()?);
+        assert_eq!(r.relay_v4_latency.len(), 1);
+
+        reader.abort();
+        quic_server.shutdown().await;
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_udp_blocked() -> Result<()> {
         let _guard = iroh_test::logging::setup();
@@ -1098,7 +1221,7 @@ mod tests {
         for mut tt in tests {
             println!("test: {}", tt.name);
             let resolver = crate::dns::default_resolver().clone();
-            let mut actor = Actor::new(None, resolver).unwrap();
+            let mut actor = Actor::new(None, resolver, None, QuicClient::new()).unwrap();
             for s in &mut tt.steps {
                 // trigger the timer
                 time::advance(Duration::from_secs(s.after)).await;
@@ -12,6 +12,7 @@ pub struct Metrics {
     pub stun_packets_sent_ipv6: Counter,
     pub stun_packets_recv_ipv4: Counter,
     pub stun_packets_recv_ipv6: Counter,
+    pub quic_probes_succeeded: Counter,
     pub reports: Counter,
     pub reports_full: Counter,
     pub reports_error: Counter,
@@ -27,6 +28,9 @@ impl Default for Metrics {
             stun_packets_sent_ipv6: Counter::new("Number of IPv6 STUN packets sent"),
             stun_packets_recv_ipv4: Counter::new("Number of IPv4 STUN packets received"),
             stun_packets_recv_ipv6: Counter::new("Number of IPv6 STUN packets received"),
+            quic_probes_succeeded: Counter::new(
+                "Number of QUIC address discovery probes which reported an address",
+            ),
             reports: Counter::new("Number of reports executed by netcheck, including full reports"),
             reports_full: Counter::new("Number of full reports executed by netcheck"),
             reports_error: Counter::new("Number of executed reports resulting in an error"),
@@ -22,7 +22,7 @@ use std::pin::Pin;
 use std::sync::Arc;
 use std::time::Duration;
 
-use anyhow::{anyhow, bail, Context, Result};
+use anyhow::{anyhow, bail, ensure, Context, Result};
 use iroh_metrics::inc;
 use rand::seq::IteratorRandom;
 use tokio::sync::{mpsc, oneshot};
@@ -38,6 +38,8 @@ use crate::net::ip;
 use crate::net::UdpSocket;
 use crate::netcheck::{self, Report};
 use crate::ping::{PingError, Pinger};
+use crate::relay::http::Proxy;
+use crate::relay::quic::QuicClient;
 use crate::relay::{RelayMap, RelayNode, RelayUrl};
 use crate::util::{CancelOnDrop, MaybeFuture};
 use crate::{portmapper, stun};
@@ -63,6 +65,9 @@ const PROBES_TIMEOUT: Duration = Duration::from_secs(3);
 /// STUN is blocked.
 const CAPTIVE_PORTAL_DELAY: Duration = Duration::from_millis(200);
 
+/// The path on relay servers which answers HTTPS latency probes.
+const RELAY_PROBE_PATH: &str = "/derp/probe";
+
 /// Timeout for captive portal checks
 ///
 /// Must be lower than [`OVERALL_REPORT_TIMEOUT`] minus
@@ -87,6 +92,7 @@ impl Client {
     ///
     /// The actor starts running immediately and only generates a single report, after which
     /// it shuts down.  Dropping this handle will abort the actor.
+    #[allow(clippy::too_many_arguments)]
     pub(super) fn new(
         netcheck: netcheck::Addr,
         last_report: Option<Arc<Report>>,
@@ -94,7 +100,9 @@ impl Client {
         relay_map: RelayMap,
         stun_sock4: Option<Arc<UdpSocket>>,
         stun_sock6: Option<Arc<UdpSocket>>,
+        quic_client: QuicClient,
         dns_resolver: DnsResolver,
+        proxy: Option<Proxy>,
     ) -> Self {
         let (msg_tx, msg_rx) = mpsc::channel(32);
         let addr = Addr {
@@ -109,10 +117,12 @@ impl Client {
             relay_map,
             stun_sock4,
             stun_sock6,
+            quic_client,
             report: Report::default(),
             hairpin_actor: hairpin::Client::new(netcheck, addr),
             outstanding_tasks: OutstandingTasks::default(),
             dns_resolver,
+            proxy,
         };
         let task = tokio::spawn(
             async move { actor.run().await }.instrument(info_span!("reportgen.actor")),
@@ -182,6 +192,8 @@ struct Actor {
     stun_sock4: Option<Arc<UdpSocket>>,
     /// Socket so send IPv6 STUN requests from.
     stun_sock6: Option<Arc<UdpSocket>>,
+    /// Client of the QUIC address discovery probes, sent from the STUN sockets.
+    quic_client: QuicClient,
 
     // Internal state.
     /// The report being built.
@@ -194,6 +206,8 @@ struct Actor {
     outstanding_tasks: OutstandingTasks,
     /// The DNS resolver to use for probes that need to resolve DNS records
     dns_resolver: DnsResolver,
+    /// The proxy to tunnel HTTP requests through
+    proxy: Option<Proxy>,
 }
 
 impl Actor {
@@ -400,7 +414,9 @@ impl Actor {
         }
 
         // If the probe is for IPv6 and we don't yet have an IPv6 report, that would help.
-        if probe.proto() == ProbeProto::StunIpv6 && self.report.relay_v6_latency.is_empty() {
+        if matches!(probe.proto(), ProbeProto::StunIpv6 | ProbeProto::QuicIpv6)
+            && self.report.relay_v6_latency.is_empty()
+        {
             return true;
         }
 
@@ -475,6 +491,7 @@ impl Actor {
                 .and_then(|l| l.preferred_relay.clone());
 
             let dm = self.relay_map.clone();
+            let proxy = self.proxy.clone();
             self.outstanding_tasks.captive_task = true;
             MaybeFuture {
                 inner: Some(Box::pin(async move {
@@ -482,7 +499,7 @@ impl Actor {
                     debug!("Captive portal check started after {CAPTIVE_PORTAL_DELAY:?}");
                     let captive_portal_check = tokio::time::timeout(
                         CAPTIVE_PORTAL_TIMEOUT,
-                        check_captive_portal(&dm, preferred_relay)
+                        check_captive_portal(&dm, preferred_relay, proxy.as_ref())
                             .instrument(debug_span!("captive-portal")),
                     );
                     match captive_portal_check.await {
@@ -542,7 +559,6 @@ impl Actor {
         // shared between the probes that use it.  It binds sockets lazily, so we can always
         // create it.
         let pinger = Pinger::new();
-
         // A collection of futures running probe sets.
         let mut probes = JoinSet::default();
         for probe_set in plan.iter() {
@@ -555,7 +571,9 @@ impl Actor {
                 let probe = probe.clone();
                 let netcheck = self.netcheck.clone();
                 let pinger = pinger.clone();
+                let quic_client = self.quic_client.clone();
                 let dns_resolver = self.dns_resolver.clone();
+                let proxy = self.proxy.clone();
 
                 set.spawn(
                     run_probe(
@@ -566,7 +584,9 @@ impl Actor {
                         probe.clone(),
                         netcheck,
                         pinger,
+                        quic_client,
                         dns_resolver,
+                        proxy,
                     )
                     .instrument(debug_span!("run_probe", %probe)),
                 );
@@ -674,7 +694,7 @@ enum ProbeError {
 
 /// Executes a particular [`Probe`], including using a delayed start if needed.
 ///
-/// If *stun_sock4* and *stun_sock6* are `None` the STUN probes are disabled.
+/// If *stun_sock4* and *stun_sock6* are `None` the STUN and QUIC probes are disabled.
 #[allow(clippy::too_many_arguments)]
 async fn run_probe(
     reportstate: Addr,
@@ -684,7 +704,9 @@ async fn run_probe(
     probe: Probe,
     netcheck: netcheck::Addr,
     pinger: Pinger,
+    quic_client: QuicClient,
     dns_resolver: DnsResolver,
+    proxy: Option<Proxy>,
 ) -> Result<ProbeReport, ProbeError> {
     if !probe.delay().is_zero() {
         trace!("delaying probe");
@@ -718,12 +740,37 @@ async fn run_probe(
         ));
     }
 
+    let mut result = ProbeReport::new(probe.clone());
+    if let Probe::Https { ref node, .. } = probe {
+        // connects by host name, possibly through the proxy, so no relay addr is needed
+        debug!("sending probe HTTPS");
+        match measure_https_latency(node, proxy.as_ref()).await {
+            Ok((latency, ip)) => {
+                result.latency = Some(latency);
+                // We set these IPv4 and IPv6 but they're not really used
+                // and we don't necessarily set them both. If UDP is blocked
+                // and both IPv4 and IPv6 are available over TCP, it's basically
+                // random which fields end up getting set here.
+                // Since they're not needed, that's fine for now.
+                match ip {
+                    Some(IpAddr::V4(_)) => result.ipv4_can_send = true,
+                    Some(IpAddr::V6(_)) => result.ipv6_can_send = true,
+                    None => {}
+                }
+            }
+            Err(err) => {
+                warn!("https latency measurement failed: {:?}", err);
+            }
+        }
+        trace!("probe successful");
+        return Ok(result);
+    }
+
     let relay_addr = get_relay_addr(&dns_resolver, &relay_node, probe.proto())
         .await
         .context("no relay node addr")
         .map_err(|e| ProbeError::AbortSet(e, probe.clone()))?;
 
-    let mut result = ProbeReport::new(probe.clone());
     match probe {
         Probe::StunIpv4 { .. } | Probe::StunIpv6 { .. } => {
             let maybe_sock = if matches!(probe, Probe::StunIpv4 { .. }) {
@@ -746,26 +793,25 @@ async fn run_probe(
         Probe::IcmpV4 { .. } | Probe::IcmpV6 { .. } => {
             result = run_icmp_probe(probe, relay_addr, pinger).await?
         }
-        Probe::Https { ref node, .. } => {
-            debug!("sending probe HTTPS");
-            match measure_https_latency(node).await {
-                Ok((latency, ip)) => {
-                    result.latency = Some(latency);
-                    // We set these IPv4 and IPv6 but they're not really used
-                    // and we don't necessarily set them both. If UDP is blocked
-                    // and both IPv4 and IPv6 are available over TCP, it's basically
-                    // random which fields end up getting set here.
-                    // Since they're not needed, that's fine for now.
-                    match ip {
-                        IpAddr::V4(_) => result.ipv4_can_send = true,
-                        IpAddr::V6(_) => result.ipv6_can_send = true,
-                    }
+        Probe::QuicIpv4 { .. } | Probe::QuicIpv6 { .. } => {
+            let maybe_sock = if matches!(probe, Probe::QuicIpv4 { .. }) {
+                stun_sock4.as_ref()
+            } else {
+                stun_sock6.as_ref()
+            };
+            match maybe_sock {
+                Some(sock) => {
+                    result = run_quic_probe(&quic_client, sock, relay_addr, probe).await?;
                 }
-                Err(err) => {
-                    warn!("https latency measurement failed: {:?}", err);
+                None => {
+                    return Err(ProbeError::AbortSet(
+                        anyhow!("No socket for {}, aborting probeset", probe.proto()),
+                        probe.clone(),
+                    ));
                 }
             }
         }
+        Probe::Https { .. } => unreachable!("handled above"),
     }
 
     trace!("probe successful");
@@ -853,12 +899,45 @@ async fn run_stun_probe(
     }
 }
 
+/// Runs a QUIC address discovery IPv4 or IPv6 probe.
+async fn run_quic_probe(
+    quic_client: &QuicClient,
+    sock: &Arc<UdpSocket>,
+    relay_addr: SocketAddr,
+    probe: Probe,
+) -> Result<ProbeReport, ProbeError> {
+    match probe.proto() {
+        ProbeProto::QuicIpv4 => debug_assert!(relay_addr.is_ipv4()),
+        ProbeProto::QuicIpv6 => debug_assert!(relay_addr.is_ipv6()),
+        _ => debug_assert!(false, "wrong probe"),
+    }
+    debug!(%relay_addr, "sending {} probe", probe.proto());
+    let (addr, latency) = quic_client
+        .get_addr(sock.clone(), relay_addr)
+        .await
+        .map_err(|err| ProbeError::Error(err, probe.clone()))?;
+    inc!(NetcheckMetrics, quic_probes_succeeded);
+    let mut result = ProbeReport::new(probe.clone());
+    if matches!(probe, Probe::QuicIpv4 { .. }) {
+        result.ipv4_can_send = true;
+    } else {
+        result.ipv6_can_send = true;
+    }
+    result.latency = Some(latency);
+    result.addr = Some(addr);
+    Ok(result)
+}
+
 /// Reports whether or not we think the system is behind a
 /// captive portal, detected by making a request to a URL that we know should
 /// return a "204 No Content" response and checking if that's what we get.
 ///
 /// The boolean return is whether we think we have a captive portal.
-async fn check_captive_portal(dm: &RelayMap, preferred_relay: Option<RelayUrl>) -> Result<bool> {
+async fn check_captive_portal(
+    dm: &RelayMap,
+    preferred_relay: Option<RelayUrl>,
+    proxy: Option<&Proxy>,
+) -> Result<bool> {
     // If we have a preferred relay node and we can use it for non-STUN requests, try that;
     // otherwise, pick a random one suitable for non-STUN requests.
     let preferred_relay = preferred_relay.and_then(|url| match dm.get_node(&url) {
@@ -886,9 +965,11 @@ async fn check_captive_portal(dm: &RelayMap, preferred_relay: Option<RelayUrl>)
         }
     };
 
-    let client = reqwest::ClientBuilder::new()
-        .redirect(reqwest::redirect::Policy::none())
-        .build()?;
+    let mut builder = reqwest::ClientBuilder::new().redirect(reqwest::redirect::Policy::none());
+    if let Some(proxy) = proxy {
+        builder = builder.proxy(proxy.to_reqwest()?);
+    }
+    let client = builder.build()?;
 
     // Note: the set of valid characters in a challenge and the total
     // length is limited; see is_challenge_char in bin/iroh-relay for more
@@ -931,52 +1012,67 @@ async fn get_relay_addr(
     relay_node: &RelayNode,
     proto: ProbeProto,
 ) -> Result<SocketAddr> {
-    let port = if relay_node.stun_port == 0 {
-        DEFAULT_RELAY_STUN_PORT
-    } else {
-        relay_node.stun_port
+    let port = match proto {
+        ProbeProto::QuicIpv4 | ProbeProto::QuicIpv6 => relay_node
+            .quic_port
+            .context("Relay node does not serve QUIC address discovery")?,
+        _ if relay_node.stun_port == 0 => DEFAULT_RELAY_STUN_PORT,
+        _ => relay_node.stun_port,
     };
 
-    if relay_node.stun_only && !matches!(proto, ProbeProto::StunIpv4 | ProbeProto::StunIpv6) {
+    // QUIC address discovery is an alternative to STUN, so STUN-only nodes may serve it.
+    if relay_node.stun_only
+        && !matches!(
+            proto,
+            ProbeProto::StunIpv4
+                | ProbeProto::StunIpv6
+                | ProbeProto::QuicIpv4
+                | ProbeProto::QuicIpv6
+        )
+    {
         bail!("Relay node not suitable for non-STUN probes");
     }
 
     match proto {
-        ProbeProto::StunIpv4 | ProbeProto::IcmpV4 => match relay_node.url.host() {
-            Some(url::Host::Domain(hostname)) => {
-                debug!(?proto, %hostname, "Performing DNS A lookup for relay addr");
-                match lookup_ipv4(dns_resolver, hostname, DNS_TIMEOUT).await {
-                    Ok(addrs) => addrs
-                        .first()
-                        .map(|addr| ip::to_canonical(*addr))
-                        .map(|addr| SocketAddr::new(addr, port))
-                        .ok_or(anyhow!("No suitable relay addr found")),
-                    Err(err) => Err(err.context("No suitable relay addr found")),
+        ProbeProto::StunIpv4 | ProbeProto::IcmpV4 | ProbeProto::QuicIpv4 => {
+            match relay_node.url.host() {
+                Some(url::Host::Domain(hostname)) => {
+                    debug!(?proto, %hostname, "Performing DNS A lookup for relay addr");
+                    match lookup_ipv4(dns_resolver, hostname, DNS_TIMEOUT).await {
+                        Ok(addrs) => addrs
+                            .first()
+                            .map(|addr| ip::to_canonical(*addr))
+                            .map(|addr| SocketAddr::new(addr, port))
+                            .ok_or(anyhow!("No suitable relay addr found")),
+                        Err(err) => Err(err.context("No suitable relay addr found")),
+                    }
                 }
+                Some(url::Host::Ipv4(addr)) => Ok(SocketAddr::new(addr.into(), port)),
+                Some(url::Host::Ipv6(_addr)) => Err(anyhow!("No suitable relay addr found")),
+                None => Err(anyhow!("No valid hostname in RelayUrl")),
             }
-            Some(url::Host::Ipv4(addr)) => Ok(SocketAddr::new(addr.into(), port)),
-            Some(url::Host::Ipv6(_addr)) => Err(anyhow!("No suitable relay addr found")),
-            None => Err(anyhow!("No valid hostname in RelayUrl")),
-        },
-
-        ProbeProto::StunIpv6 | ProbeProto::IcmpV6 => match relay_node.url.host() {
-            Some(url::Host::Domain(hostname)) => {
-                debug!(?proto, %hostname, "Performing DNS AAAA lookup for relay addr");
-                match lookup_ipv6(dns_resolver, hostname, DNS_TIMEOUT).await {
-                    Ok(addrs) => addrs
-                        .first()
-                        .map(|addr| ip::to_canonical(*addr))
-                        .map(|addr| SocketAddr::new(addr, port))
-                        .ok_or(anyhow!("No suitable relay addr found")),
-                    Err(err) => Err(err.context("No suitable relay addr found")),
+        }
+
+        ProbeProto::StunIpv6 | ProbeProto::IcmpV6 | ProbeProto::QuicIpv6 => {
+            match relay_node.url.host() {
+                Some(url::Host::Domain(hostname)) => {
+                    debug!(?proto, %hostname, "Performing DNS AAAA lookup for relay addr");
+                    match lookup_ipv6(dns_resolver, hostname, DNS_TIMEOUT).await {
+                        Ok(addrs) => addrs
+                            .first()
+                            .map(|addr| ip::to_canonical(*addr))
+                            .map(|addr| SocketAddr::new(addr, port))
+                            .ok_or(anyhow!("No suitable relay addr found")),
+                        Err(err) => Err(err.context("No suitable relay addr found")),
+                    }
                 }
+                Some(url::Host::Ipv4(_addr)) => Err(anyhow!("No suitable relay addr found")),
+                Some(url::Host::Ipv6(addr)) => Ok(SocketAddr::new(addr.into(), port)),
+                None => Err(anyhow!("No valid hostname in RelayUrl")),
             }
-            Some(url::Host::Ipv4(_addr)) => Err(anyhow!("No suitable relay addr found")),
-            Some(url::Host::Ipv6(addr)) => Ok(SocketAddr::new(addr.into(), port)),
-            None => Err(anyhow!("No valid hostname in RelayUrl")),
-        },
+        }
 
-        ProbeProto::Https => Err(anyhow!("Not implemented")),
+        ProbeProto::Https => Err(anyhow!("HTTPS probes connect by host name")),
     }
 }
 
@@ -1022,33 +1118,71 @@ async fn run_icmp_probe(
     Ok(report)
 }
 
-#[allow(clippy::unused_async)]
-async fn measure_https_latency(_node: &RelayNode) -> Result<(Duration, IpAddr)> {
-    bail!("not implemented");
-    // TODO:
-    // - needs relayhttp::Client
-    // - measurement hooks to measure server processing time
-
-    // metricHTTPSend.Add(1)
-    // let ctx, cancel := context.WithTimeout(httpstat.WithHTTPStat(ctx, &result), overallProbeTimeout);
-    // let dc := relayhttp.NewNetcheckClient(c.logf);
-    // let tlsConn, tcpConn, node := dc.DialRegionTLS(ctx, reg)?;
-    // if ta, ok := tlsConn.RemoteAddr().(*net.TCPAddr);
-    // req, err := http.NewRequestWithContext(ctx, "GET", "https://"+node.HostName+"/relay/latency-check", nil);
-    // resp, err := hc.Do(req);
-
-    // // relays should give us a nominal status code, so anything else is probably
-    // // an access denied by a MITM proxy (or at the very least a signal not to
-    // // trust this latency check).
-    // if resp.StatusCode > 299 {
-    //     return 0, ip, fmt.Errorf("unexpected status code: %d (%s)", resp.StatusCode, resp.Status)
-    // }
-    // _, err = io.Copy(io.Discard, io.LimitReader(resp.Body, 8<<10));
-    // result.End(c.timeNow())
-
-    // // TODO: decide best timing heuristic here.
-    // // Maybe the server should return the tcpinfo_rtt?
-    // return result.ServerProcessing, ip, nil
+/// Measures the latency of HTTPS requests to a relay node.
+///
+/// The requests are tunneled through `proxy` if one is configured. The first request sets
+/// up the connection and the second one is timed, so the handshakes are not included in
+/// the latency. Returns the IP address of the relay node if it was connected to directly.
+async fn measure_https_latency(
+    node: &RelayNode,
+    proxy: Option<&Proxy>,
+) -> Result<(Duration, Option<IpAddr>)> {
+    let mut builder = reqwest::ClientBuilder::new().redirect(reqwest::redirect::Policy::none());
+    if let Some(proxy) = proxy {
+        builder = builder.proxy(proxy.to_reqwest()?);
+    }
+    let client = builder.build()?;
+    let url = node.url.join(RELAY_PROBE_PATH)?;
+
+    let mut latency = Duration::ZERO;
+    let mut remote_addr = None;
+    for _ in 0..2 {
+        let start = Instant::now();
+        let res = client.get(url.clone()).send().await?;
+        // relays should give us a nominal status code, so anything else is probably
+        // an access denied by a MITM proxy (or at the very least a signal not to
+        // trust this latency check).
+        ensure!(
+            res.status().is_success(),
+            "unexpected status code: {}",
+            res.status()
+        );
+        remote_addr = res.remote_addr();
+        res.bytes().await?;
+        latency = start.elapsed();
+    }
+    // through a proxy, the remote address is the one of the proxy
+    let ip = match proxy {
+        Some(proxy) if !proxy.bypasses(&url) => None,
+        _ => remote_addr.map(|addr| ip::to_canonical(addr.ip())),
+    };
+    Ok((latency, ip))
+}
+
+/// Records an address observed by a STUN or QUIC probe.
+///
+/// The address is stored in `observed` if it is the first one, and compared with the first
+/// address observed by either kind of probe to update `mapping_varies`.  Returns whether
+/// the address differs from that first one.
+fn update_observed_addr<A: Copy + PartialEq>(
+    addr: A,
+    observed: &mut Option<A>,
+    observed_other: Option<A>,
+    mapping_varies: &mut Option<bool>,
+) -> bool {
+    let varies = match observed.or(observed_other) {
+        None => false,
+        Some(first) if first != addr => {
+            *mapping_varies = Some(true);
+            true
+        }
+        Some(_) => {
+            mapping_varies.get_or_insert(false);
+            false
+        }
+    };
+    observed.get_or_insert(addr);
+    varies
 }
 
 /// Updates a netcheck [`Report`] with a new [`ProbeReport`].
@@ -1059,43 +1193,58 @@ fn update_report(report: &mut Report, probe_report: ProbeReport) {
             .relay_latency
             .update_relay(relay_node.url.clone(), latency);
 
-        if matches!(
+        let is_stun = matches!(
             probe_report.probe.proto(),
             ProbeProto::StunIpv4 | ProbeProto::StunIpv6
-        ) {
+        );
+        if is_stun
+            || matches!(
+                probe_report.probe.proto(),
+                ProbeProto::QuicIpv4 | ProbeProto::QuicIpv6
+            )
+        {
             report.udp = true;
 
+            // The STUN and QUIC probes are sent from the same sockets, so the addresses
+            // observed by both are compared to detect whether the mapping varies.
             match probe_report.addr {
                 Some(SocketAddr::V4(ipp)) => {
                     report.ipv4 = true;
                     report
                         .relay_v4_latency
                         .update_relay(relay_node.url.clone(), latency);
-                    if report.global_v4.is_none() {
-                        report.global_v4 = Some(ipp);
-                    } else if report.global_v4 != Some(ipp) {
-                        report.mapping_varies_by_dest_ip = Some(true);
-                    } else if report.mapping_varies_by_dest_ip.is_none() {
-                        report.mapping_varies_by_dest_ip = Some(false);
-                    }
+                    let (observed, observed_other) = match is_stun {
+                        true => (&mut report.global_v4, report.quic_v4),
+                        false => (&mut report.quic_v4, report.global_v4),
+                    };
+                    update_observed_addr(
+                        ipp,
+                        observed,
+                        observed_other,
+                        &mut report.mapping_varies_by_dest_ip,
+                    );
                 }
                 Some(SocketAddr::V6(ipp)) => {
                     report.ipv6 = true;
                     report
                         .relay_v6_latency
                         .update_relay(relay_node.url.clone(), latency);
-                    if report.global_v6.is_none() {
-                        report.global_v6 = Some(ipp);
-                    } else if report.global_v6 != Some(ipp) {
-                        report.mapping_varies_by_dest_ipv6 = Some(true);
-                        warn!("IPv6 Address detected by STUN varies by destination");
-                    } else if report.mapping_varies_by_dest_ipv6.is_none() {
-                        report.mapping_varies_by_dest_ipv6 = Some(false);
+                    let (observed, observed_other) = match is_stun {
+                        true => (&mut report.global_v6, report.quic_v6),
+                        false => (&mut report.quic_v6, report.global_v6),
+                    };
+                    if update_observed_addr(
+                        ipp,
+                        observed,
+                        observed_other,
+                        &mut report.mapping_varies_by_dest_ipv6,
+                    ) {
+                        warn!("IPv6 Address varies by destination");
                     }
                 }
                 None => {
-                    // If we are here we had a relay server latency reported from a STUN probe.
-                    // Thus we must have a reported address.
+                    // If we are here we had a relay server latency reported from a STUN or
+                    // QUIC probe.  Thus we must have a reported address.
                     debug_assert!(probe_report.addr.is_some());
                 }
             }
@@ -1115,7 +1264,7 @@ fn update_report(report: &mut Report, probe_report: ProbeReport) {
 
 #[cfg(test)]
 mod tests {
-    use std::net::{Ipv4Addr, Ipv6Addr};
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV4};
 
     use super::*;
 
@@ -1206,6 +1355,59 @@ mod tests {
         assert!(report.ipv6_can_send);
     }
 
+    #[test]
+    fn test_update_report_quic_mapping() {
+        let eu_relayer = Arc::new(default_eu_relay_node());
+        let na_relayer = Arc::new(default_na_relay_node());
+        let addr: SocketAddrV4 = "203.0.113.1:1234".parse().unwrap();
+
+        let mut report = Report::default();
+
+        // A QUIC probe, while STUN is blocked.
+        let probe_report_quic = ProbeReport {
+            ipv4_can_send: true,
+            ipv6_can_send: false,
+            icmpv4: None,
+            icmpv6: None,
+            latency: Some(Duration::from_millis(5)),
+            probe: Probe::QuicIpv4 {
+                delay: Duration::ZERO,
+                node: eu_relayer.clone(),
+            },
+            addr: Some(addr.into()),
+        };
+        update_report(&mut report, probe_report_quic.clone());
+        assert!(report.udp);
+        assert_eq!(report.quic_v4, Some(addr));
+        assert_eq!(report.global_v4, None);
+        assert_eq!(report.mapping_varies_by_dest_ip, None);
+
+        // A STUN probe observing the same address from the same socket.
+        let probe_report_stun = ProbeReport {
+            probe: Probe::StunIpv4 {
+                delay: Duration::ZERO,
+                node: na_relayer.clone(),
+            },
+            ..probe_report_quic.clone()
+        };
+        update_report(&mut report, probe_report_stun);
+        assert_eq!(report.global_v4, Some(addr));
+        assert_eq!(report.mapping_varies_by_dest_ip, Some(false));
+
+        // A QUIC probe to another relay observing a different port.
+        let probe_report_quic_na = ProbeReport {
+            probe: Probe::QuicIpv4 {
+                delay: Duration::ZERO,
+                node: na_relayer,
+            },
+            addr: Some((*addr.ip(), 4321).into()),
+            ..probe_report_quic
+        };
+        update_report(&mut report, probe_report_quic_na);
+        assert_eq!(report.quic_v4, Some(addr));
+        assert_eq!(report.mapping_varies_by_dest_ip, Some(true));
+    }
+
     #[test]
     fn test_update_report_icmp() {
         let eu_relayer = Arc::new(default_eu_relay_node());
@@ -1,7 +1,9 @@
 //! The relay probes.
 //!
 //! All the probes try and establish the latency to the relay servers.  Preferably the STUN
-//! probes work and we also learn about our public IP addresses and ports.  But fallback
+//! probes work and we also learn about our public IP addresses and ports.  Relay servers
+//! serving QUIC address discovery are also probed over QUIC from the same sockets, which
+//! tells us our public IP addresses and ports in networks blocking STUN.  But fallback
 //! probes for HTTPS and ICMP exist as well.
 
 use std::collections::BTreeSet;
@@ -53,6 +55,10 @@ pub(super) enum ProbeProto {
     StunIpv6,
     /// HTTPS
     Https,
+    /// QUIC address discovery IPv4
+    QuicIpv4,
+    /// QUIC address discovery IPv6
+    QuicIpv6,
     /// ICMP IPv4
     IcmpV4,
     /// ICMP IPv6
@@ -91,6 +97,16 @@ pub(super) enum Probe {
         delay: Duration,
         node: Arc<RelayNode>,
     },
+    #[display("QUIC Ipv4 after {delay:?} to {node}")]
+    QuicIpv4 {
+        delay: Duration,
+        node: Arc<RelayNode>,
+    },
+    #[display("QUIC Ipv6 after {delay:?} to {node}")]
+    QuicIpv6 {
+        delay: Duration,
+        node: Arc<RelayNode>,
+    },
 }
 
 impl Probe {
@@ -100,7 +116,9 @@ impl Probe {
             | Probe::StunIpv6 { delay, .. }
             | Probe::Https { delay, .. }
             | Probe::IcmpV4 { delay, .. }
-            | Probe::IcmpV6 { delay, .. } => *delay,
+            | Probe::IcmpV6 { delay, .. }
+            | Probe::QuicIpv4 { delay, .. }
+            | Probe::QuicIpv6 { delay, .. } => *delay,
         }
     }
 
@@ -111,6 +129,8 @@ impl Probe {
             Probe::Https { .. } => ProbeProto::Https,
             Probe::IcmpV4 { .. } => ProbeProto::IcmpV4,
             Probe::IcmpV6 { .. } => ProbeProto::IcmpV6,
+            Probe::QuicIpv4 { .. } => ProbeProto::QuicIpv4,
+            Probe::QuicIpv6 { .. } => ProbeProto::QuicIpv6,
         }
     }
 
@@ -120,7 +140,9 @@ impl Probe {
             | Probe::StunIpv6 { node, .. }
             | Probe::Https { node, .. }
             | Probe::IcmpV4 { node, .. }
-            | Probe::IcmpV6 { node, .. } => node,
+            | Probe::IcmpV6 { node, .. }
+            | Probe::QuicIpv4 { node, .. }
+            | Probe::QuicIpv6 { node, .. } => node,
         }
     }
 }
@@ -229,7 +251,10 @@ impl ProbePlan {
             plan.add(stun_ipv4_probes);
             plan.add(stun_ipv6_probes);
 
-            // The HTTP and ICMP probes only start after the STUN probes have had a chance.
+            // The QUIC, HTTP and ICMP probes only start after the STUN probes have had a
+            // chance.
+            let mut quic_ipv4_probes = ProbeSet::new(ProbeProto::QuicIpv4);
+            let mut quic_ipv6_probes = ProbeSet::new(ProbeProto::QuicIpv6);
             let mut https_probes = ProbeSet::new(ProbeProto::Https);
             let mut icmp_probes_ipv4 = ProbeSet::new(ProbeProto::IcmpV4);
             let mut icmp_probes_ipv6 = ProbeSet::new(ProbeProto::IcmpV6);
@@ -237,6 +262,25 @@ impl ProbePlan {
                 let start = plan.max_delay() + DEFAULT_INITIAL_RETRANSMIT;
                 let delay = start + DEFAULT_INITIAL_RETRANSMIT * attempt as u32;
 
+                if relay_node.quic_port.is_some() {
+                    if if_state.have_v4 {
+                        quic_ipv4_probes
+                            .push(Probe::QuicIpv4 {
+                                delay,
+                                node: relay_node.clone(),
+                            })
+                            .expect("adding QuicIpv4 probe to a QuicIpv4 probe set");
+                    }
+                    if if_state.have_v6 {
+                        quic_ipv6_probes
+                            .push(Probe::QuicIpv6 {
+                                delay,
+                                node: relay_node.clone(),
+                            })
+                            .expect("adding QuicIpv6 probe to a QuicIpv6 probe set");
+                    }
+                }
+
                 https_probes
                     .push(Probe::Https {
                         delay,
@@ -260,6 +304,8 @@ impl ProbePlan {
                         .expect("adding IcmpIpv6 probe to and IcmpIpv6 probe set");
                 }
             }
+            plan.add(quic_ipv4_probes);
+            plan.add(quic_ipv6_probes);
             plan.add(https_probes);
             plan.add(icmp_probes_ipv4);
             plan.add(icmp_probes_ipv6);
@@ -345,7 +391,10 @@ impl ProbePlan {
             plan.add(stun_ipv4_probes);
             plan.add(stun_ipv6_probes);
 
-            // The HTTP and ICMP probes only start after the STUN probes have had a chance.
+            // The QUIC, HTTP and ICMP probes only start after the STUN probes have had a
+            // chance.
+            let mut quic_ipv4_probes = ProbeSet::new(ProbeProto::QuicIpv4);
+            let mut quic_ipv6_probes = ProbeSet::new(ProbeProto::QuicIpv6);
             let mut https_probes = ProbeSet::new(ProbeProto::Https);
             let mut icmp_v4_probes = ProbeSet::new(ProbeProto::IcmpV4);
             let mut icmp_v6_probes = ProbeSet::new(ProbeProto::IcmpV6);
@@ -354,6 +403,24 @@ impl ProbePlan {
                 let delay = start
                     + (retransmit_delay * attempt as u32)
                     + (ACTIVE_RETRANSMIT_EXTRA_DELAY * (attempt as u32 + 1));
+                if relay_node.quic_port.is_some() {
+                    if do4 {
+                        quic_ipv4_probes
+                            .push(Probe::QuicIpv4 {
+                                delay,
+                                node: relay_node.clone(),
+                            })
+                            .expect("Pushing QuicIpv4 Probe to a QuicIpv4 ProbeSet");
+                    }
+                    if do6 {
+                        quic_ipv6_probes
+                            .push(Probe::QuicIpv6 {
+                                delay,
+                                node: relay_node.clone(),
+                            })
+                            .expect("Pushing QuicIpv6 Probe to a QuicIpv6 ProbeSet");
+                    }
+                }
                 https_probes
                     .push(Probe::Https {
                         delay,
@@ -377,6 +444,8 @@ impl ProbePlan {
                         .expect("Pusying IcmpV6 Probe to an IcmpV6 ProbeSet");
                 }
             }
+            plan.add(quic_ipv4_probes);
+            plan.add(quic_ipv6_probes);
             plan.add(https_probes);
             plan.add(icmp_v4_probes);
             plan.add(icmp_v6_probes);
@@ -681,6 +750,38 @@ mod tests {
         assert_eq!(plan, expected_plan);
     }
 
+    #[tokio::test]
+    async fn test_initial_probeplan_quic() {
+        let mut relay_node = crate::defaults::default_na_relay_node();
+        relay_node.quic_port = Some(7842);
+        let relay_map = RelayMap::from_nodes([relay_node]).unwrap();
+        let relay_node = relay_map.nodes().next().unwrap();
+        let if_state = interfaces::State::fake();
+        let plan = ProbePlan::initial(&relay_map, &if_state);
+
+        // The QUIC probes run alongside the HTTPS probes, after the STUN probes.
+        let https_delays: Vec<_> = plan
+            .iter()
+            .find(|set| set.proto == ProbeProto::Https)
+            .unwrap()
+            .into_iter()
+            .map(|probe| probe.delay())
+            .collect();
+        let quic_probes = plan
+            .iter()
+            .find(|set| set.proto == ProbeProto::QuicIpv4)
+            .unwrap();
+        let expected: Vec<_> = https_delays
+            .into_iter()
+            .map(|delay| Probe::QuicIpv4 {
+                delay,
+                node: relay_node.clone(),
+            })
+            .collect();
+        assert_eq!(quic_probes.probes, expected);
+        assert!(plan.iter().any(|set| set.proto == ProbeProto::QuicIpv6));
+    }
+
     #[tokio::test]
     async fn test_plan_with_report() {
         for i in 0..10 {
@@ -711,6 +812,8 @@ mod tests {
                 relay_v6_latency: latencies.clone(),
                 global_v4: None,
                 global_v6: None,
+                quic_v4: None,
+                quic_v6: None,
                 captive_portal: None,
             };
             let plan = ProbePlan::with_last_report(&relay_map, &if_state, &last_report);
@@ -933,6 +1036,8 @@ mod tests {
             relay_v6_latency: latencies.clone(),
             global_v4: None,
             global_v6: None,
+            quic_v4: None,
+            quic_v6: None,
             captive_portal: None,
         }
     }
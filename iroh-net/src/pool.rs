@@ -0,0 +1,494 @@
+//! A pool of connections shared between the protocols running on a [`MagicEndpoint`].
+//!
+//! Connections are keyed by the [`NodeId`] of the remote node and the ALPN they were negotiated
+//! with. Concurrent dials for the same key are deduplicated, live connections are reused, and a
+//! connection is closed once it has not been used for the pool's idle timeout.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Weak},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use futures::{
+    future::{BoxFuture, Shared},
+    FutureExt,
+};
+use parking_lot::Mutex;
+use tokio::sync::broadcast;
+use tracing::{debug, trace};
+
+use crate::{MagicEndpoint, NodeAddr, NodeId};
+
+/// The default time after which an unused pooled connection is closed.
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Capacity of the channel on which [`PoolEvent`]s are broadcast.
+const EVENTS_CAPACITY: usize = 256;
+
+/// Error code with which the pool closes idle connections.
+const IDLE_CLOSE_CODE: u32 = 0;
+
+/// Reason with which the pool closes idle connections.
+const IDLE_CLOSE_REASON: &[u8] = b"idle";
+
+type PoolKey = (NodeId, Vec<u8>);
+
+type DialFuture = Shared<BoxFuture<'static, Result<quinn::Connection, Arc<anyhow::Error>>>>;
+
+/// An event emitted by a [`ConnectionPool`].
+#[derive(Debug, Clone)]
+pub enum PoolEvent {
+    /// A new connection was established and added to the pool.
+    Connected {
+        /// The node the connection is to.
+        node_id: NodeId,
+        /// The ALPN of the connection.
+        alpn: Vec<u8>,
+    },
+    /// A pooled connection was closed and removed from the pool.
+    Closed {
+        /// The node the connection was to.
+        node_id: NodeId,
+        /// The ALPN of the connection.
+        alpn: Vec<u8>,
+        /// Why the connection was closed.
+        reason: quinn::ConnectionError,
+    },
+}
+
+/// A pool of connections, shared by all users of a [`MagicEndpoint`].
+///
+/// Obtained from [`MagicEndpoint::connection_pool`]. Cloning the pool is cheap, all clones
+/// share the same connections.
+#[derive(Debug, Clone)]
+pub struct ConnectionPool {
+    endpoint: MagicEndpoint,
+    state: Arc<PoolState>,
+}
+
+impl ConnectionPool {
+    pub(crate) fn new(endpoint: MagicEndpoint, state: Arc<PoolState>) -> Self {
+        Self { endpoint, state }
+    }
+
+    /// Connect to a node, reusing a pooled connection if one exists.
+    ///
+    /// If a connection for this node and ALPN is already being dialed, this waits for that dial
+    /// instead of starting another one. The connection stays in the pool while any
+    /// [`PooledConnection`] for it is alive, and is closed after it was unused for the idle
+    /// timeout.
+    pub async fn connect(&self, node_addr: NodeAddr, alpn: &[u8]) -> Result<PooledConnection> {
+        let key = (node_addr.node_id, alpn.to_vec());
+        let dial = {
+            let mut entries = self.state.entries.lock();
+            if let Some(pooled) = self.state.lease(&mut entries, &key) {
+                trace!(node = %key.0.fmt_short(), "reusing pooled connection");
+                return Ok(pooled);
+            }
+            match entries.get(&key) {
+                Some(Entry::Dialing(dial)) => dial.clone(),
+                _ => {
+                    let dial = self.spawn_dial(node_addr, key.clone());
+                    entries.insert(key.clone(), Entry::Dialing(dial.clone()));
+                    dial
+                }
+            }
+        };
+        let conn = dial.await.map_err(|err| {
+            // Keep connection errors inspectable for callers, e.g. to detect an unsupported ALPN.
+            match err.downcast_ref::<quinn::ConnectionError>() {
+                Some(err) => anyhow::Error::new(err.clone()),
+                None => anyhow!("{err:#}"),
+            }
+        })?;
+        let mut entries = self.state.entries.lock();
+        match self.state.lease(&mut entries, &key) {
+            Some(pooled) if pooled.stable_id() == conn.stable_id() => Ok(pooled),
+            // The connection was closed and evicted before we got to use it.
+            _ => Err(anyhow!("connection closed")),
+        }
+    }
+
+    /// Get the pooled connection to a node, without dialing.
+    pub fn get(&self, node_id: &NodeId, alpn: &[u8]) -> Option<PooledConnection> {
+        let key = (*node_id, alpn.to_vec());
+        let mut entries = self.state.entries.lock();
+        self.state.lease(&mut entries, &key)
+    }
+
+    /// Subscribe to the [`PoolEvent`]s of this pool.
+    pub fn subscribe(&self) -> broadcast::Receiver<PoolEvent> {
+        self.state.events.subscribe()
+    }
+
+    /// The number of live connections in the pool.
+    pub fn len(&self) -> usize {
+        self.state
+            .entries
+            .lock()
+            .values()
+            .filter(|entry| matches!(entry, Entry::Connected { .. }))
+            .count()
+    }
+
+    /// Whether the pool holds no live connections.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Spawns a task dialing the node, which moves the connection into the pool once established.
+    fn spawn_dial(&self, node_addr: NodeAddr, key: PoolKey) -> DialFuture {
+        let endpoint = self.endpoint.clone();
+        let state = self.state.clone();
+        let task = tokio::spawn(async move {
+            let res = endpoint.connect(node_addr, &key.1).await;
+            let mut entries = state.entries.lock();
+            match res {
+                Ok(conn) => {
+                    debug!(node = %key.0.fmt_short(), "pooled new connection");
+                    entries.insert(
+                        key.clone(),
+                        Entry::Connected {
+                            conn: conn.clone(),
+                            lease: Weak::new(),
+                            epoch: 0,
+                        },
+                    );
+                    drop(entries);
+                    // Nobody might lease the connection, in which case it is idle from the start.
+                    state.spawn_idle_timer(key.clone(), 0);
+                    tokio::spawn(watch_closed(
+                        Arc::downgrade(&state),
+                        key.clone(),
+                        conn.clone(),
+                    ));
+                    state
+                        .events
+                        .send(PoolEvent::Connected {
+                            node_id: key.0,
+                            alpn: key.1,
+                        })
+                        .ok();
+                    Ok(conn)
+                }
+                Err(err) => {
+                    entries.remove(&key);
+                    Err(Arc::new(err))
+                }
+            }
+        });
+        async move {
+            match task.await {
+                Ok(res) => res,
+                Err(err) => Err(Arc::new(anyhow!("dial task failed: {err}"))),
+            }
+        }
+        .boxed()
+        .shared()
+    }
+}
+
+/// The state of a [`ConnectionPool`], owned by the [`MagicEndpoint`].
+#[derive(Debug)]
+pub(crate) struct PoolState {
+    idle_timeout: Duration,
+    entries: Mutex<HashMap<PoolKey, Entry>>,
+    events: broadcast::Sender<PoolEvent>,
+}
+
+#[derive(derive_more::Debug)]
+enum Entry {
+    Dialing(#[debug(skip)] DialFuture),
+    Connected {
+        conn: quinn::Connection,
+        /// The lease shared by all [`PooledConnection`]s of this connection, if any are alive.
+        lease: Weak<Lease>,
+        /// Incremented whenever a new lease is created, to invalidate pending idle timers.
+        epoch: u64,
+    },
+}
+
+impl PoolState {
+    pub(crate) fn new(idle_timeout: Duration) -> Self {
+        Self {
+            idle_timeout,
+            entries: Default::default(),
+            events: broadcast::channel(EVENTS_CAPACITY).0,
+        }
+    }
+
+    /// Hands out a [`PooledConnection`] for a live pooled connection.
+    fn lease(
+        self: &Arc<Self>,
+        entries: &mut HashMap<PoolKey, Entry>,
+        key: &PoolKey,
+    ) -> Option<PooledConnection> {
+        let Some(Entry::Connected { conn, lease, epoch }) = entries.get_mut(key) else {
+            return None;
+        };
+        if conn.close_reason().is_some() {
+            return None;
+        }
+        let current = match lease.upgrade() {
+            Some(current) => current,
+            None => {
+                *epoch += 1;
+                let current = Arc::new(Lease {
+                    state: self.clone(),
+                    key: key.clone(),
+                    epoch: *epoch,
+                });
+                *lease = Arc::downgrade(&current);
+                current
+            }
+        };
+        Some(PooledConnection {
+            conn: conn.clone(),
+            lease: Some(current),
+        })
+    }
+
+    /// Closes the connection for `key` after the idle timeout, unless it was leased in between.
+    fn spawn_idle_timer(self: &Arc<Self>, key: PoolKey, epoch: u64) {
+        let Ok(rt) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        let state = Arc::downgrade(self);
+        let timeout = self.idle_timeout;
+        rt.spawn(async move {
+            tokio::time::sleep(timeout).await;
+            let Some(state) = state.upgrade() else {
+                return;
+            };
+            let entries = state.entries.lock();
+            if let Some(Entry::Connected {
+                conn,
+                lease,
+                epoch: current,
+            }) = entries.get(&key)
+            {
+                if *current == epoch && lease.strong_count() == 0 {
+                    debug!(node = %key.0.fmt_short(), "closing idle connection");
+                    // Eviction and the closed event are handled by `watch_closed`.
+                    conn.close(IDLE_CLOSE_CODE.into(), IDLE_CLOSE_REASON);
+                }
+            }
+        });
+    }
+}
+
+/// Waits for a pooled connection to close, then evicts it and reports the closure.
+async fn watch_closed(state: Weak<PoolState>, key: PoolKey, conn: quinn::Connection) {
+    let reason = conn.closed().await;
+    let Some(state) = state.upgrade() else {
+        return;
+    };
+    let mut entries = state.entries.lock();
+    if let Some(Entry::Connected { conn: current, .. }) = entries.get(&key) {
+        if current.stable_id() == conn.stable_id() {
+            entries.remove(&key);
+        }
+    }
+    drop(entries);
+    debug!(node = %key.0.fmt_short(), %reason, "pooled connection closed");
+    state
+        .events
+        .send(PoolEvent::Closed {
+            node_id: key.0,
+            alpn: key.1,
+            reason,
+        })
+        .ok();
+}
+
+/// Keeps a pooled connection in use, starts the idle timer once dropped.
+#[derive(Debug)]
+struct Lease {
+    state: Arc<PoolState>,
+    key: PoolKey,
+    epoch: u64,
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        self.state.spawn_idle_timer(self.key.clone(), self.epoch);
+    }
+}
+
+/// A connection obtained from a [`ConnectionPool`].
+///
+/// Dereferences to the [`quinn::Connection`]. While any clone of it is alive the connection is
+/// considered in use and is not closed by the pool. A connection not managed by a pool can be
+/// wrapped with [`From<quinn::Connection>`].
+#[derive(Debug, Clone, derive_more::Deref)]
+pub struct PooledConnection {
+    #[deref]
+    conn: quinn::Connection,
+    lease: Option<Arc<Lease>>,
+}
+
+impl PooledConnection {
+    /// The underlying connection.
+    ///
+    /// Note that the pool may close the connection once all [`PooledConnection`]s for it are
+    /// dropped, even if clones of the returned connection are still alive.
+    pub fn connection(&self) -> &quinn::Connection {
+        &self.conn
+    }
+
+    /// Whether this connection is managed by a [`ConnectionPool`].
+    pub fn is_pooled(&self) -> bool {
+        self.lease.is_some()
+    }
+}
+
+impl From<quinn::Connection> for PooledConnection {
+    fn from(conn: quinn::Connection) -> Self {
+        Self { conn, lease: None }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{key::SecretKey, relay::RelayMode};
+
+    const TEST_ALPN: &[u8] = b"n0/iroh/test/pool";
+
+    async fn endpoint(idle_timeout: Duration) -> MagicEndpoint {
+        MagicEndpoint::builder()
+            .secret_key(SecretKey::generate())
+            .alpns(vec![TEST_ALPN.to_vec()])
+            .relay_mode(RelayMode::Disabled)
+            .pool_idle_timeout(idle_timeout)
+            .bind(0)
+            .await
+            .unwrap()
+    }
+
+    /// Accepts connections on the endpoint and keeps them open until the peer closes them.
+    fn spawn_accept(ep: MagicEndpoint) -> tokio::task::JoinHandle<usize> {
+        tokio::spawn(async move {
+            let mut accepted = 0;
+            while let Some(connecting) = ep.accept().await {
+                let Ok(conn) = connecting.await else {
+                    continue;
+                };
+                accepted += 1;
+                tokio::spawn(async move { conn.closed().await });
+            }
+            accepted
+        })
+    }
+
+    async fn addr(ep: &MagicEndpoint) -> NodeAddr {
+        ep.my_addr().await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_pool_dedups_dials() {
+        let _guard = iroh_test::logging::setup();
+        let a = endpoint(DEFAULT_IDLE_TIMEOUT).await;
+        let b = endpoint(DEFAULT_IDLE_TIMEOUT).await;
+        let accept = spawn_accept(b.clone());
+        let b_addr = addr(&b).await;
+
+        let pool = a.connection_pool();
+        let (c1, c2) = tokio::join!(
+            pool.connect(b_addr.clone(), TEST_ALPN),
+            pool.connect(b_addr.clone(), TEST_ALPN)
+        );
+        let (c1, c2) = (c1.unwrap(), c2.unwrap());
+        assert_eq!(c1.stable_id(), c2.stable_id());
+        assert!(c1.is_pooled());
+        assert_eq!(pool.len(), 1);
+
+        // a later connect reuses the live connection
+        let c3 = a
+            .connection_pool()
+            .connect(b_addr.clone(), TEST_ALPN)
+            .await
+            .unwrap();
+        assert_eq!(c1.stable_id(), c3.stable_id());
+
+        a.close(0u32.into(), b"done").await.unwrap();
+        b.close(0u32.into(), b"done").await.unwrap();
+        assert_eq!(accept.await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_pool_closes_idle_connections() {
+        let _guard = iroh_test::logging::setup();
+        let a = endpoint(Duration::from_millis(200)).await;
+        let b = endpoint(DEFAULT_IDLE_TIMEOUT).await;
+        let _accept = spawn_accept(b.clone());
+        let b_addr = addr(&b).await;
+
+        let pool = a.connection_pool();
+        let mut events = pool.subscribe();
+        let conn = pool.connect(b_addr.clone(), TEST_ALPN).await.unwrap();
+        let clone = conn.clone();
+        drop(conn);
+
+        // still leased by the clone
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        assert!(clone.close_reason().is_none());
+        assert_eq!(pool.len(), 1);
+        let id = clone.stable_id();
+        drop(clone);
+
+        let closed = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let PoolEvent::Closed { node_id, alpn, .. } = events.recv().await.unwrap() {
+                    break (node_id, alpn);
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(closed, (b.node_id(), TEST_ALPN.to_vec()));
+        assert!(pool.is_empty());
+
+        // a new connection is dialed after the idle one was closed
+        let conn = pool.connect(b_addr, TEST_ALPN).await.unwrap();
+        assert_ne!(conn.stable_id(), id);
+    }
+
+    #[tokio::test]
+    async fn test_pool_reports_remote_close() {
+        let _guard = iroh_test::logging::setup();
+        let a = endpoint(DEFAULT_IDLE_TIMEOUT).await;
+        let b = endpoint(DEFAULT_IDLE_TIMEOUT).await;
+        let b_addr = addr(&b).await;
+        let accept = tokio::spawn({
+            let b = b.clone();
+            async move {
+                let conn = b.accept().await.unwrap().await.unwrap();
+                conn.close(7u32.into(), b"bye");
+            }
+        });
+
+        let pool = a.connection_pool();
+        let mut events = pool.subscribe();
+        let conn = pool.connect(b_addr, TEST_ALPN).await.unwrap();
+        accept.await.unwrap();
+
+        let reason = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let PoolEvent::Closed { reason, .. } = events.recv().await.unwrap() {
+                    break reason;
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert!(matches!(
+            reason,
+            quinn::ConnectionError::ApplicationClosed(ref close) if close.error_code == 7u32.into()
+        ));
+        assert!(conn.close_reason().is_some());
+        assert!(pool.get(&b.node_id(), TEST_ALPN).is_none());
+    }
+}
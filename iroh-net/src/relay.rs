@@ -10,20 +10,29 @@
 
 #![deny(missing_docs, rustdoc::broken_intra_doc_links)]
 
+mod access;
 pub(crate) mod client;
 pub(crate) mod client_conn;
 pub(crate) mod clients;
 mod codec;
 pub mod http;
 mod map;
+mod mesh;
 mod metrics;
+pub(crate) mod quic;
 pub(crate) mod server;
 pub(crate) mod types;
+pub(crate) mod websocket;
 
+pub use self::access::{AccessControl, AccessDenied};
 pub use self::client::{Client as RelayClient, ReceivedMessage};
 pub use self::codec::MAX_PACKET_SIZE;
 pub use self::http::Client as HttpClient;
 pub use self::map::{RelayMap, RelayMode, RelayNode};
 pub use self::metrics::Metrics;
-pub use self::server::{ClientConnHandler, MaybeTlsStream as MaybeTlsStreamServer, Server};
+pub use self::quic::{QuicServer, ALPN_QUIC_ADDR_DISC};
+pub use self::server::{
+    AdminHandle, ClientConnHandler, MaybeTlsStream as MaybeTlsStreamServer, Server,
+};
+pub use self::types::{ClientRateLimits, ClientStats, FlowStats};
 pub use iroh_base::node_addr::RelayUrl;
@@ -0,0 +1,142 @@
+//! Access control for clients connecting to a relay [`super::Server`].
+
+use std::collections::HashSet;
+
+use crate::key::PublicKey;
+
+/// Decides which clients may connect to a relay [`super::Server`].
+///
+/// By default all clients are admitted. A client is checked after it completed the
+/// `ClientInfo` handshake, before it is registered with the server:
+///
+/// * Clients on the deny list are always rejected.
+/// * If neither an allow list nor any tokens are configured, all other clients are admitted.
+/// * Otherwise a client is admitted if it is on the allow list, or if it presented one of
+///   the configured tokens in its handshake.
+#[derive(Debug, Clone, Default)]
+pub struct AccessControl {
+    allow: Option<HashSet<PublicKey>>,
+    deny: HashSet<PublicKey>,
+    tokens: HashSet<String>,
+}
+
+/// Reason a client was rejected by [`AccessControl::check`].
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum AccessDenied {
+    /// The client is on the deny list.
+    #[error("node is denied access to this relay")]
+    Denied,
+    /// The client is not on the allow list and did not present a token.
+    #[error("node is not allowed on this relay")]
+    NotAllowed,
+    /// The client presented a token which is not accepted by this relay.
+    #[error("invalid auth token")]
+    InvalidToken,
+    /// The client presented a mesh key which does not match the relay's mesh key.
+    #[error("invalid mesh key")]
+    InvalidMeshKey,
+}
+
+impl AccessControl {
+    /// Creates an [`AccessControl`] which admits all clients.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Admits the given node, restricting the relay to allowed nodes and token holders.
+    pub fn allow(mut self, node: PublicKey) -> Self {
+        self.allow.get_or_insert_with(Default::default).insert(node);
+        self
+    }
+
+    /// Rejects the given node, regardless of the allow list and tokens.
+    pub fn deny(mut self, node: PublicKey) -> Self {
+        self.deny.insert(node);
+        self
+    }
+
+    /// Admits clients presenting this bearer token in their handshake.
+    pub fn token(mut self, token: impl Into<String>) -> Self {
+        self.tokens.insert(token.into());
+        self
+    }
+
+    /// Whether all clients are admitted.
+    pub fn is_open(&self) -> bool {
+        self.allow.is_none() && self.deny.is_empty() && self.tokens.is_empty()
+    }
+
+    /// Checks whether a client with this key and token may connect.
+    pub fn check(&self, node: &PublicKey, token: Option<&str>) -> Result<(), AccessDenied> {
+        if self.deny.contains(node) {
+            return Err(AccessDenied::Denied);
+        }
+        if self.allow.is_none() && self.tokens.is_empty() {
+            return Ok(());
+        }
+        if self
+            .allow
+            .as_ref()
+            .is_some_and(|allow| allow.contains(node))
+        {
+            return Ok(());
+        }
+        match token {
+            Some(token) if self.is_valid_token(token) => Ok(()),
+            Some(_) => Err(AccessDenied::InvalidToken),
+            None => Err(AccessDenied::NotAllowed),
+        }
+    }
+
+    /// Whether the token is one of the configured tokens.
+    ///
+    /// Every configured token is compared in constant time, without stopping at a match, so
+    /// the time taken does not reveal how much of a token was guessed correctly.
+    fn is_valid_token(&self, token: &str) -> bool {
+        self.tokens.iter().fold(false, |valid, expected| {
+            let eq =
+                ring::constant_time::verify_slices_are_equal(expected.as_bytes(), token.as_bytes())
+                    .is_ok();
+            valid | eq
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key::SecretKey;
+
+    #[test]
+    fn test_access_control() {
+        let a = SecretKey::generate().public();
+        let b = SecretKey::generate().public();
+        let c = SecretKey::generate().public();
+
+        let open = AccessControl::new();
+        assert!(open.is_open());
+        assert_eq!(open.check(&a, None), Ok(()));
+
+        let access = AccessControl::new().allow(a).deny(b).token("secret");
+        assert_eq!(access.check(&a, None), Ok(()));
+        assert_eq!(access.check(&b, Some("secret")), Err(AccessDenied::Denied));
+        assert_eq!(access.check(&c, None), Err(AccessDenied::NotAllowed));
+        assert_eq!(
+            access.check(&c, Some("wrong")),
+            Err(AccessDenied::InvalidToken)
+        );
+        assert_eq!(access.check(&c, Some("secret")), Ok(()));
+
+        let tokens = AccessControl::new().token("one").token("two");
+        assert_eq!(tokens.check(&c, Some("two")), Ok(()));
+        assert_eq!(
+            tokens.check(&c, Some("tw")),
+            Err(AccessDenied::InvalidToken)
+        );
+        assert_eq!(tokens.check(&c, Some("")), Err(AccessDenied::InvalidToken));
+
+        let deny_only = AccessControl::new().deny(b);
+        assert_eq!(deny_only.check(&a, None), Ok(()));
+        assert_eq!(deny_only.check(&b, None), Err(AccessDenied::Denied));
+    }
+}
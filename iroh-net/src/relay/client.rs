@@ -5,18 +5,13 @@ use std::time::Duration;
 
 use anyhow::{anyhow, bail, ensure, Result};
 use bytes::Bytes;
-use futures::{Sink, SinkExt, StreamExt};
-use tokio::io::{AsyncRead, AsyncWrite};
+use futures::{Sink, SinkExt, Stream, StreamExt};
 use tokio::sync::mpsc;
-use tokio_util::codec::{FramedRead, FramedWrite};
 use tracing::{debug, info_span, trace, Instrument};
 
 use super::codec::PER_CLIENT_READ_QUEUE_DEPTH;
 use super::{
-    codec::{
-        write_frame, DerpCodec, Frame, MAX_PACKET_SIZE, PER_CLIENT_SEND_QUEUE_DEPTH,
-        PROTOCOL_VERSION,
-    },
+    codec::{write_frame, Frame, MAX_PACKET_SIZE, PER_CLIENT_SEND_QUEUE_DEPTH, PROTOCOL_VERSION},
     types::{ClientInfo, RateLimiter},
 };
 
@@ -61,7 +56,13 @@ impl ClientReceiver {
     }
 }
 
-type RelayReader = FramedRead<Box<dyn AsyncRead + Unpin + Send + Sync + 'static>, DerpCodec>;
+/// Receives the frames sent by the server.
+pub(crate) type RelayReader =
+    std::pin::Pin<Box<dyn Stream<Item = Result<Frame>> + Send + Sync + 'static>>;
+
+/// Sends frames to the server.
+pub(crate) type RelayWriter =
+    std::pin::Pin<Box<dyn Sink<Frame, Error = std::io::Error> + Send + Sync + 'static>>;
 
 #[derive(derive_more::Debug)]
 pub struct InnerClient {
@@ -119,6 +120,39 @@ impl Client {
         Ok(())
     }
 
+    /// Subscribes to clients connecting to and disconnecting from the server.
+    ///
+    /// The server answers with a [`ReceivedMessage::PeerPresent`] for each connected client,
+    /// followed by [`ReceivedMessage::PeerPresent`] and [`ReceivedMessage::PeerGone`] messages
+    /// as clients come and go. Only allowed for mesh peers, see [`ClientBuilder::mesh_key`].
+    pub async fn watch_connection_changes(&self) -> Result<()> {
+        self.inner
+            .writer_channel
+            .send(ClientWriterMessage::WatchConns)
+            .await?;
+        Ok(())
+    }
+
+    /// Forwards a packet from `srckey` to the node identified by `dstkey`, which is connected to
+    /// the server.
+    ///
+    /// Only allowed for mesh peers, see [`ClientBuilder::mesh_key`]. Errors if the packet is
+    /// larger than [`super::MAX_PACKET_SIZE`].
+    pub async fn forward_packet(
+        &self,
+        srckey: PublicKey,
+        dstkey: PublicKey,
+        packet: Bytes,
+    ) -> Result<()> {
+        trace!(%srckey, %dstkey, len = packet.len(), "[RELAY] forward");
+
+        self.inner
+            .writer_channel
+            .send(ClientWriterMessage::FwdPacket((srckey, dstkey, packet)))
+            .await?;
+        Ok(())
+    }
+
     /// The local address that the [`Client`] is listening on.
     pub fn local_addr(&self) -> Result<SocketAddr> {
         Ok(self.inner.local_addr)
@@ -157,6 +191,7 @@ fn process_incoming_frame(frame: Frame) -> Result<ReceivedMessage> {
             Ok(ReceivedMessage::KeepAlive)
         }
         Frame::PeerGone { peer } => Ok(ReceivedMessage::PeerGone(peer)),
+        Frame::PeerPresent { peer } => Ok(ReceivedMessage::PeerPresent(peer)),
         Frame::RecvPacket { src_key, content } => {
             let packet = ReceivedMessage::ReceivedPacket {
                 source: src_key,
@@ -191,6 +226,10 @@ fn process_incoming_frame(frame: Frame) -> Result<ReceivedMessage> {
 enum ClientWriterMessage {
     /// Send a packet (addressed to the [`PublicKey`]) to the server
     Packet((PublicKey, Bytes)),
+    /// Forward a packet from the first [`PublicKey`] to the second one
+    FwdPacket((PublicKey, PublicKey, Bytes)),
+    /// Subscribe to clients connecting and disconnecting
+    WatchConns,
     /// Send a pong to the server
     Pong([u8; 8]),
     /// Send a ping to the server
@@ -206,19 +245,37 @@ enum ClientWriterMessage {
 ///
 /// Shutsdown when you send a [`ClientWriterMessage::Shutdown`], or if there is an error writing to
 /// the server.
-struct ClientWriter<W: AsyncWrite + Unpin + Send + 'static> {
+struct ClientWriter {
     recv_msgs: mpsc::Receiver<ClientWriterMessage>,
-    writer: FramedWrite<W, DerpCodec>,
+    writer: RelayWriter,
     rate_limiter: Option<RateLimiter>,
 }
 
-impl<W: AsyncWrite + Unpin + Send + 'static> ClientWriter<W> {
+impl ClientWriter {
     async fn run(mut self) -> Result<()> {
         while let Some(msg) = self.recv_msgs.recv().await {
             match msg {
                 ClientWriterMessage::Packet((key, bytes)) => {
                     send_packet(&mut self.writer, &self.rate_limiter, key, bytes).await?;
                 }
+                ClientWriterMessage::FwdPacket((src_key, dst_key, packet)) => {
+                    ensure!(
+                        packet.len() <= MAX_PACKET_SIZE,
+                        "packet too big: {}",
+                        packet.len()
+                    );
+                    let frame = Frame::ForwardPacket {
+                        src_key,
+                        dst_key,
+                        packet,
+                    };
+                    write_frame(&mut self.writer, frame, None).await?;
+                    self.writer.flush().await?;
+                }
+                ClientWriterMessage::WatchConns => {
+                    write_frame(&mut self.writer, Frame::WatchConns, None).await?;
+                    self.writer.flush().await?;
+                }
                 ClientWriterMessage::Pong(data) => {
                     write_frame(&mut self.writer, Frame::Pong { data }, None).await?;
                     self.writer.flush().await?;
@@ -245,29 +302,50 @@ impl<W: AsyncWrite + Unpin + Send + 'static> ClientWriter<W> {
 pub struct ClientBuilder {
     secret_key: SecretKey,
     reader: RelayReader,
-    writer: FramedWrite<Box<dyn AsyncWrite + Unpin + Send + Sync + 'static>, DerpCodec>,
+    writer: RelayWriter,
     local_addr: SocketAddr,
+    auth_token: Option<String>,
+    mesh_key: Option<String>,
 }
 
 impl ClientBuilder {
     pub fn new(
         secret_key: SecretKey,
         local_addr: SocketAddr,
-        reader: Box<dyn AsyncRead + Unpin + Send + Sync + 'static>,
-        writer: Box<dyn AsyncWrite + Unpin + Send + Sync + 'static>,
+        reader: RelayReader,
+        writer: RelayWriter,
     ) -> Self {
         Self {
             secret_key,
-            reader: FramedRead::new(reader, DerpCodec),
-            writer: FramedWrite::new(writer, DerpCodec),
+            reader,
+            writer,
             local_addr,
+            auth_token: None,
+            mesh_key: None,
         }
     }
 
+    /// Sets the bearer token sent to the server during the handshake.
+    pub fn auth_token(mut self, token: Option<String>) -> Self {
+        self.auth_token = token;
+        self
+    }
+
+    /// Sets the mesh key sent to the server during the handshake.
+    ///
+    /// Relay servers sharing a mesh key admit each other as mesh peers, which may watch the
+    /// server's connections and forward packets to its clients.
+    pub fn mesh_key(mut self, mesh_key: Option<String>) -> Self {
+        self.mesh_key = mesh_key;
+        self
+    }
+
     async fn server_handshake(&mut self) -> Result<Option<RateLimiter>> {
         debug!("server_handshake: started");
         let client_info = ClientInfo {
             version: PROTOCOL_VERSION,
+            auth_token: self.auth_token.clone(),
+            mesh_key: self.mesh_key.clone(),
         };
         debug!("server_handshake: sending client_key: {:?}", &client_info);
         crate::relay::codec::send_client_key(&mut self.writer, &self.secret_key, &client_info)
@@ -368,7 +446,13 @@ pub enum ReceivedMessage {
     },
     /// Indicates that the client identified by the underlying public key had previously sent you a
     /// packet but has now disconnected from the server.
+    ///
+    /// Mesh peers watching the server's connections receive this for every client which
+    /// disconnects.
     PeerGone(PublicKey),
+    /// Sent to mesh peers watching the server's connections, indicating that the client
+    /// identified by the underlying public key is connected to the server.
+    PeerPresent(PublicKey),
     /// Sent by the server upon first connect.
     ServerInfo {
         /// How many bytes per second the server says it will accept, including all framing bytes.
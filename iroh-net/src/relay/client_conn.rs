@@ -1,12 +1,11 @@
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 
-use anyhow::{Context, Result};
+use anyhow::{ensure, Context, Result};
 use bytes::Bytes;
 use futures::{SinkExt, StreamExt};
 use tokio::sync::mpsc;
-use tokio_util::codec::Framed;
 use tokio_util::sync::CancellationToken;
 use tracing::{trace, Instrument};
 
@@ -15,12 +14,12 @@ use crate::{disco::looks_like_disco_wrapper, key::PublicKey};
 
 use iroh_metrics::{inc, inc_by};
 
-use super::codec::{DerpCodec, Frame};
-use super::server::MaybeTlsStream;
+use super::codec::Frame;
+use super::server::RelayIo;
 use super::{
     codec::{write_frame, KEEP_ALIVE},
     metrics::Metrics,
-    types::{Packet, ServerMessage},
+    types::{ClientRateLimiter, ClientRateLimits, Packet, PeerConnState, ServerMessage},
 };
 
 /// The [`super::server::Server`] side representation of a [`super::client::Client`]'s connection
@@ -29,12 +28,13 @@ pub(crate) struct ClientConnManager {
     /// Static after construction, process-wide unique counter, incremented each time we accept  
     pub(crate) conn_num: usize,
 
-    // TODO: in the go impl, we have a ptr to the server & use that ptr to update stats
-    // in rust, we should probably have a stats struct separate from the server that we
-    // can update in different threads, this may become a series of channels on which to
-    // send updates
-    // stats: Stats,
     pub(crate) key: PublicKey,
+    /// When the connection was accepted
+    pub(crate) connected_at: SystemTime,
+    /// Traffic counters, updated by the [`ClientConnIo`]
+    pub(crate) stats: Arc<ConnStats>,
+    /// Whether the client considers this its preferred connection
+    pub(crate) preferred: Arc<AtomicBool>,
     /// Sent when connection closes
     // TODO: maybe should be a receiver
     done: CancellationToken,
@@ -52,6 +52,20 @@ pub(crate) struct ClientConnManager {
     pub(crate) client_channels: ClientChannels,
 }
 
+/// Traffic counters of a single client connection, shared between the [`ClientConnManager`]
+/// and its [`ClientConnIo`].
+#[derive(Debug, Default)]
+pub(crate) struct ConnStats {
+    /// Bytes of packets written to the client
+    pub(crate) bytes_sent: AtomicU64,
+    /// Number of packets written to the client
+    pub(crate) packets_sent: AtomicU64,
+    /// Bytes of packets read from the client
+    pub(crate) bytes_recv: AtomicU64,
+    /// Number of packets read from the client
+    pub(crate) packets_recv: AtomicU64,
+}
+
 /// Channels that the [`ClientConnManager`] uses to communicate with the
 /// [`ClientConnIo`] to forward the client:
 ///  - information about a peer leaving the network (This should only happen for peers that this
@@ -65,6 +79,10 @@ pub(crate) struct ClientChannels {
     pub(crate) disco_send_queue: mpsc::Sender<Packet>,
     /// Notify the client that a previous sender has disconnected
     pub(crate) peer_gone: mpsc::Sender<PublicKey>,
+    /// Notify a watching mesh peer that a client connected or disconnected
+    ///
+    /// Unbounded, as a mesh peer missing a notification would keep stale routes.
+    pub(crate) peer_conn_state: mpsc::UnboundedSender<PeerConnState>,
 }
 
 /// A builds a [`ClientConnManager`] from a [`PublicKey`] and an io connection.
@@ -72,10 +90,13 @@ pub(crate) struct ClientChannels {
 pub struct ClientConnBuilder {
     pub(crate) key: PublicKey,
     pub(crate) conn_num: usize,
-    pub(crate) io: Framed<MaybeTlsStream, DerpCodec>,
+    pub(crate) io: RelayIo,
     pub(crate) write_timeout: Option<Duration>,
     pub(crate) channel_capacity: usize,
+    pub(crate) rate_limits: ClientRateLimits,
     pub(crate) server_channel: mpsc::Sender<ServerMessage>,
+    /// Whether the client is a mesh peer, allowed to watch connections and forward packets
+    pub(crate) can_mesh: bool,
 }
 
 impl ClientConnBuilder {
@@ -88,7 +109,9 @@ impl ClientConnBuilder {
             self.io,
             self.write_timeout,
             self.channel_capacity,
+            self.rate_limits,
             self.server_channel,
+            self.can_mesh,
         )
     }
 }
@@ -101,10 +124,12 @@ impl ClientConnManager {
     pub fn new(
         key: PublicKey,
         conn_num: usize,
-        io: Framed<MaybeTlsStream, DerpCodec>,
+        io: RelayIo,
         write_timeout: Option<Duration>,
         channel_capacity: usize,
+        rate_limits: ClientRateLimits,
         server_channel: mpsc::Sender<ServerMessage>,
+        can_mesh: bool,
     ) -> ClientConnManager {
         let done = CancellationToken::new();
         let client_id = (key, conn_num);
@@ -112,16 +137,27 @@ impl ClientConnManager {
 
         let (disco_send_queue_s, disco_send_queue_r) = mpsc::channel(channel_capacity);
         let (peer_gone_s, peer_gone_r) = mpsc::channel(channel_capacity);
+        let (peer_conn_state_s, peer_conn_state_r) = mpsc::unbounded_channel();
 
         let preferred = Arc::from(AtomicBool::from(false));
+        let stats = Arc::new(ConnStats::default());
+
+        let rate_limiter = ClientRateLimiter::new(&rate_limits).unwrap_or_else(|err| {
+            tracing::warn!("invalid client rate limits, not enforcing them: {err:?}");
+            None
+        });
 
         let conn_io = ClientConnIo {
             io,
             timeout: write_timeout,
+            rate_limiter,
             send_queue: send_queue_r,
             disco_send_queue: disco_send_queue_r,
             peer_gone: peer_gone_r,
+            peer_conn_state: peer_conn_state_r,
             key,
+            can_mesh,
+            stats: Arc::clone(&stats),
             preferred: Arc::clone(&preferred),
             server_channel: server_channel.clone(),
         };
@@ -156,12 +192,16 @@ impl ClientConnManager {
         ClientConnManager {
             conn_num,
             key,
+            connected_at: SystemTime::now(),
+            stats,
+            preferred,
             io_handle: io_handle.into(),
             done,
             client_channels: ClientChannels {
                 send_queue: send_queue_s,
                 disco_send_queue: disco_send_queue_s,
                 peer_gone: peer_gone_s,
+                peer_conn_state: peer_conn_state_s,
             },
         }
     }
@@ -194,26 +234,36 @@ impl ClientConnManager {
 ///  - a PEER_GONE frame to inform the client that a peer they have previously sent messages to
 ///  is gone from the network
 ///  - packets from other peers
+///  - PEER_PRESENT and PEER_GONE frames to a mesh peer watching the server's connections
 ///
 /// On the "read" side, it can:
 ///     - receive a ping and write a pong back
 ///     - note whether the client is `preferred`, aka this client is the preferred way
 ///     to speak to the node ID associated with that client.
+///     - for mesh peers, subscribe to connection changes and receive forwarded packets
 #[derive(Debug)]
 pub(crate) struct ClientConnIo {
     /// Io to talk to the client
-    io: Framed<MaybeTlsStream, DerpCodec>,
+    io: RelayIo,
     /// Max time we wait to complete a write to the client
     timeout: Option<Duration>,
+    /// Limits the packets this client may send, packets over the limit are dropped
+    rate_limiter: Option<ClientRateLimiter>,
     /// Packets queued to send to the client
     send_queue: mpsc::Receiver<Packet>,
     /// Important packets queued to send to the client
     disco_send_queue: mpsc::Receiver<Packet>,
     /// Notify the client that a previous sender has disconnected
     peer_gone: mpsc::Receiver<PublicKey>,
+    /// Notify a watching mesh peer that a client connected or disconnected
+    peer_conn_state: mpsc::UnboundedReceiver<PeerConnState>,
 
     /// [`PublicKey`] of this client
     key: PublicKey,
+    /// Whether this client is a mesh peer
+    can_mesh: bool,
+    /// Traffic counters of this connection
+    stats: Arc<ConnStats>,
 
     /// Channels used to communicate with the server about actions
     /// it needs to take on behalf of the client
@@ -266,6 +316,11 @@ impl ClientConnIo {
                     trace!("peer gone: {:?}", peer);
                     self.send_peer_gone(peer).await?;
                 }
+                state = self.peer_conn_state.recv() => {
+                    let state = state.context("Server.peer_conn_state dropped")?;
+                    trace!("peer conn state: {:?}", state);
+                    self.send_peer_conn_state(state).await?;
+                }
                 packet = self.send_queue.recv() => {
                     let packet = packet.context("Server.send_queue dropped")?;
                     trace!("send packet");
@@ -317,6 +372,17 @@ impl ClientConnIo {
         write_frame(&mut self.io, Frame::PeerGone { peer }, self.timeout).await
     }
 
+    /// Sends a peer present or peer gone frame to a watching mesh peer, does not flush
+    ///
+    /// Errors if the send does not happen within the `timeout` duration
+    async fn send_peer_conn_state(&mut self, state: PeerConnState) -> Result<()> {
+        let frame = match state {
+            PeerConnState::Present(peer) => Frame::PeerPresent { peer },
+            PeerConnState::Gone(peer) => Frame::PeerGone { peer },
+        };
+        write_frame(&mut self.io, frame, self.timeout).await
+    }
+
     /// Writes contents to the client in a `RECV_PACKET` frame. If `srcKey.is_zero`, it uses the
     /// old DERPv1 framing format, otherwise uses the DERPv2 framing format. The bytes of contents
     /// are only valid until this function returns, do not retain the slices.
@@ -327,7 +393,9 @@ impl ClientConnIo {
 
         if let Ok(len) = content.len().try_into() {
             inc_by!(Metrics, bytes_sent, len);
+            self.stats.bytes_sent.fetch_add(len, Ordering::Relaxed);
         }
+        self.stats.packets_sent.fetch_add(1, Ordering::Relaxed);
         write_frame(
             &mut self.io,
             Frame::RecvPacket { src_key, content },
@@ -349,8 +417,19 @@ impl ClientConnIo {
             }
             Frame::SendPacket { dst_key, packet } => {
                 let packet_len = packet.len();
-                self.handle_frame_send_packet(dst_key, packet).await?;
                 inc_by!(Metrics, bytes_recv, packet_len as u64);
+                self.record_recv(packet_len);
+                if self
+                    .rate_limiter
+                    .as_mut()
+                    .is_some_and(|limiter| !limiter.check(packet_len))
+                {
+                    trace!("rate limit exceeded, dropping packet to {dst_key:?}");
+                    inc!(Metrics, packets_rate_limited);
+                    inc_by!(Metrics, bytes_rate_limited, packet_len as u64);
+                    return Ok(());
+                }
+                self.handle_frame_send_packet(dst_key, packet).await?;
             }
             Frame::Ping { data } => {
                 self.handle_frame_ping(data).await?;
@@ -359,6 +438,31 @@ impl ClientConnIo {
             Frame::Health { .. } => {
                 inc!(Metrics, other_packets_recv);
             }
+            Frame::WatchConns => {
+                ensure!(
+                    self.can_mesh,
+                    "insufficient permissions to watch connections"
+                );
+                inc!(Metrics, other_packets_recv);
+                self.send_server(ServerMessage::AddWatcher(self.key))
+                    .await?;
+            }
+            Frame::ForwardPacket {
+                src_key,
+                dst_key,
+                packet,
+            } => {
+                ensure!(self.can_mesh, "insufficient permissions to forward packets");
+                inc!(Metrics, packets_forwarded_in);
+                inc_by!(Metrics, bytes_recv, packet.len() as u64);
+                self.record_recv(packet.len());
+                let packet = Packet {
+                    src: src_key,
+                    bytes: packet,
+                };
+                self.send_server(ServerMessage::ForwardedPacket((dst_key, packet)))
+                    .await?;
+            }
             _ => {
                 inc!(Metrics, unknown_frames);
             }
@@ -366,6 +470,14 @@ impl ClientConnIo {
         Ok(())
     }
 
+    /// Counts a packet read from the client in the connection's stats.
+    fn record_recv(&self, len: usize) {
+        self.stats
+            .bytes_recv
+            .fetch_add(len as u64, Ordering::Relaxed);
+        self.stats.packets_recv.fetch_add(1, Ordering::Relaxed);
+    }
+
     /// Preferred indicates if this is the preferred connection to the client with
     /// this public key.
     fn set_preferred(&mut self, v: bool) -> Result<()> {
@@ -451,8 +563,11 @@ impl ClientConnIo {
 
 #[cfg(test)]
 mod tests {
+    use tokio_util::codec::Framed;
+
     use crate::key::SecretKey;
-    use crate::relay::codec::{recv_frame, FrameType};
+    use crate::relay::codec::{recv_frame, DerpCodec, FrameType};
+    use crate::relay::server::MaybeTlsStream;
 
     use super::*;
 
@@ -463,6 +578,7 @@ mod tests {
         let (send_queue_s, send_queue_r) = mpsc::channel(10);
         let (disco_send_queue_s, disco_send_queue_r) = mpsc::channel(10);
         let (peer_gone_s, peer_gone_r) = mpsc::channel(10);
+        let (peer_conn_state_s, peer_conn_state_r) = mpsc::unbounded_channel();
 
         let preferred = Arc::from(AtomicBool::from(true));
         let key = SecretKey::generate().public();
@@ -471,13 +587,17 @@ mod tests {
         let (server_channel_s, mut server_channel_r) = mpsc::channel(10);
 
         let conn_io = ClientConnIo {
-            io: Framed::new(MaybeTlsStream::Test(io), DerpCodec),
+            io: RelayIo::Derp(Framed::new(MaybeTlsStream::Test(io), DerpCodec)),
             timeout: None,
+            rate_limiter: None,
             send_queue: send_queue_r,
             disco_send_queue: disco_send_queue_r,
             peer_gone: peer_gone_r,
+            peer_conn_state: peer_conn_state_r,
 
             key,
+            can_mesh: false,
+            stats: Default::default(),
             server_channel: server_channel_s,
             preferred: Arc::clone(&preferred),
         };
@@ -524,6 +644,12 @@ mod tests {
         let frame = recv_frame(FrameType::PeerGone, &mut io_rw).await?;
         assert_eq!(frame, Frame::PeerGone { peer: key });
 
+        // send peer conn state
+        println!("send peer present");
+        peer_conn_state_s.send(PeerConnState::Present(key))?;
+        let frame = recv_frame(FrameType::PeerPresent, &mut io_rw).await?;
+        assert_eq!(frame, Frame::PeerPresent { peer: key });
+
         // Read tests
         println!("--read");
 
@@ -597,6 +723,7 @@ mod tests {
         let (_send_queue_s, send_queue_r) = mpsc::channel(10);
         let (_disco_send_queue_s, disco_send_queue_r) = mpsc::channel(10);
         let (_peer_gone_s, peer_gone_r) = mpsc::channel(10);
+        let (_peer_conn_state_s, peer_conn_state_r) = mpsc::unbounded_channel();
 
         let preferred = Arc::from(AtomicBool::from(true));
         let key = SecretKey::generate().public();
@@ -606,13 +733,17 @@ mod tests {
 
         println!("-- create client conn");
         let conn_io = ClientConnIo {
-            io: Framed::new(MaybeTlsStream::Test(io), DerpCodec),
+            io: RelayIo::Derp(Framed::new(MaybeTlsStream::Test(io), DerpCodec)),
             timeout: None,
+            rate_limiter: None,
             send_queue: send_queue_r,
             disco_send_queue: disco_send_queue_r,
             peer_gone: peer_gone_r,
+            peer_conn_state: peer_conn_state_r,
 
             key,
+            can_mesh: false,
+            stats: Default::default(),
             server_channel: server_channel_s,
             preferred: Arc::clone(&preferred),
         };
@@ -663,4 +794,62 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_client_conn_rate_limit() -> Result<()> {
+        let (_send_queue_s, send_queue_r) = mpsc::channel(10);
+        let (_disco_send_queue_s, disco_send_queue_r) = mpsc::channel(10);
+        let (_peer_gone_s, peer_gone_r) = mpsc::channel(10);
+        let (_peer_conn_state_s, peer_conn_state_r) = mpsc::unbounded_channel();
+
+        let key = SecretKey::generate().public();
+        let (io, io_rw) = tokio::io::duplex(1024);
+        let mut io_rw = Framed::new(io_rw, DerpCodec);
+        let (server_channel_s, mut server_channel_r) = mpsc::channel(10);
+
+        // allow a single packet per second, without burst
+        let limits = ClientRateLimits {
+            packets_per_second: Some(1),
+            packets_burst: Some(1),
+            ..Default::default()
+        };
+        let conn_io = ClientConnIo {
+            io: RelayIo::Derp(Framed::new(MaybeTlsStream::Test(io), DerpCodec)),
+            timeout: None,
+            rate_limiter: ClientRateLimiter::new(&limits)?,
+            send_queue: send_queue_r,
+            disco_send_queue: disco_send_queue_r,
+            peer_gone: peer_gone_r,
+            peer_conn_state: peer_conn_state_r,
+
+            key,
+            can_mesh: false,
+            stats: Default::default(),
+            server_channel: server_channel_s,
+            preferred: Arc::new(AtomicBool::new(true)),
+        };
+
+        let done = CancellationToken::new();
+        let io_done = done.clone();
+        let io_handle = tokio::task::spawn(async move { conn_io.run(io_done).await });
+
+        let target = SecretKey::generate().public();
+        for data in [&b"first"[..], b"second", b"third"] {
+            crate::relay::client::send_packet(&mut io_rw, &None, target, data.into()).await?;
+        }
+        // the ping is answered only after all packets before it were handled
+        let data = b"pingpong";
+        write_frame(&mut io_rw, Frame::Ping { data: *data }, None).await?;
+        recv_frame(FrameType::Pong, &mut io_rw).await?;
+
+        match server_channel_r.try_recv()? {
+            ServerMessage::SendPacket((_, packet)) => assert_eq!(&packet.bytes[..], b"first"),
+            m => bail!("expected ServerMessage::SendPacket, got {m:?}"),
+        }
+        assert!(server_channel_r.try_recv().is_err());
+
+        done.cancel();
+        io_handle.await??;
+        Ok(())
+    }
 }
@@ -3,6 +3,7 @@
 //! The "Server" side of the client. Uses the `ClientConnManager`.
 use crate::key::PublicKey;
 use std::collections::{HashMap, HashSet};
+use std::sync::atomic::Ordering;
 
 use futures::future::join_all;
 use tokio::sync::mpsc;
@@ -13,7 +14,7 @@ use tracing::{Instrument, Span};
 use super::{
     client_conn::{ClientConnBuilder, ClientConnManager},
     metrics::Metrics,
-    types::Packet,
+    types::{ClientStats, FlowStats, Packet, PeerConnState},
 };
 
 /// Number of times we try to send to a client connection before dropping the data;
@@ -38,21 +39,40 @@ const RETRIES: usize = 3;
 struct Client {
     /// The client connection associated with the [`PublicKey`]
     conn: ClientConnManager,
-    /// list of peers we have sent messages to
-    sent_to: HashSet<PublicKey>,
+    /// peers we have sent messages to, with the packets and bytes sent to each
+    sent_to: HashMap<PublicKey, (u64, u64)>,
+    /// Whether this client is a mesh peer rather than a node
+    can_mesh: bool,
 }
 
 impl Client {
-    pub fn new(conn: ClientConnManager) -> Self {
+    pub fn new(conn: ClientConnManager, can_mesh: bool) -> Self {
         Self {
             conn,
-            sent_to: HashSet::default(),
+            sent_to: HashMap::default(),
+            can_mesh,
         }
     }
 
-    /// Record that this client sent a packet to the `dst` client
-    pub fn record_send(&mut self, dst: PublicKey) {
-        self.sent_to.insert(dst);
+    /// Record that this client sent a packet of `len` bytes to the `dst` client
+    pub fn record_send(&mut self, dst: PublicKey, len: usize) {
+        let (packets, bytes) = self.sent_to.entry(dst).or_default();
+        *packets += 1;
+        *bytes += len as u64;
+    }
+
+    pub fn stats(&self) -> ClientStats {
+        let stats = &self.conn.stats;
+        ClientStats {
+            key: self.conn.key,
+            connected_at: self.conn.connected_at,
+            bytes_sent: stats.bytes_sent.load(Ordering::Relaxed),
+            packets_sent: stats.packets_sent.load(Ordering::Relaxed),
+            bytes_recv: stats.bytes_recv.load(Ordering::Relaxed),
+            packets_recv: stats.packets_recv.load(Ordering::Relaxed),
+            preferred: self.conn.preferred.load(Ordering::Relaxed),
+            mesh_peer: self.can_mesh,
+        }
     }
 
     pub fn shutdown(self) {
@@ -103,6 +123,19 @@ impl Client {
         }
         res
     }
+
+    pub fn send_peer_conn_state(&self, state: PeerConnState) -> Result<(), SendError> {
+        match self.conn.client_channels.peer_conn_state.send(state) {
+            Ok(_) => {
+                inc!(Metrics, other_packets_sent);
+                Ok(())
+            }
+            Err(_) => {
+                inc!(Metrics, other_packets_dropped);
+                Err(SendError::SenderClosed)
+            }
+        }
+    }
 }
 
 // TODO: in the goimpl, it also tries 3 times to send a packet. But, in go we can clone receiver
@@ -134,6 +167,8 @@ enum SendError {
 #[derive(Debug)]
 pub(crate) struct Clients {
     inner: HashMap<PublicKey, Client>,
+    /// Mesh peers notified about clients connecting and disconnecting
+    watchers: HashSet<PublicKey>,
 }
 
 impl Drop for Clients {
@@ -144,6 +179,7 @@ impl Clients {
     pub fn new() -> Self {
         Self {
             inner: HashMap::default(),
+            watchers: HashSet::default(),
         }
     }
 
@@ -158,13 +194,36 @@ impl Clients {
         join_all(handles).await;
     }
 
-    /// Record that `src` sent or forwarded a packet to `dst`
-    pub fn record_send(&mut self, src: &PublicKey, dst: PublicKey) {
+    /// Record that `src` sent or forwarded a packet of `len` bytes to `dst`
+    pub fn record_send(&mut self, src: &PublicKey, dst: PublicKey, len: usize) {
         if let Some(client) = self.inner.get_mut(src) {
-            client.record_send(dst);
+            client.record_send(dst, len);
         }
     }
 
+    /// Returns the stats of all connected clients.
+    pub fn stats(&self) -> Vec<ClientStats> {
+        self.inner.values().map(Client::stats).collect()
+    }
+
+    /// Returns the flows from all connected clients.
+    pub fn flows(&self) -> Vec<FlowStats> {
+        self.inner
+            .iter()
+            .flat_map(|(src, client)| {
+                client
+                    .sent_to
+                    .iter()
+                    .map(|(dst, (packets, bytes))| FlowStats {
+                        src: *src,
+                        dst: *dst,
+                        packets: *packets,
+                        bytes: *bytes,
+                    })
+            })
+            .collect()
+    }
+
     pub fn contains_key(&self, key: &PublicKey) -> bool {
         self.inner.contains_key(key)
     }
@@ -179,16 +238,60 @@ impl Clients {
     pub fn register(&mut self, client_builder: ClientConnBuilder) {
         // this builds the client handler & starts the read & write loops to that client connection
         let key = client_builder.key;
+        let can_mesh = client_builder.can_mesh;
         tracing::trace!("registering client: {:?}", key);
         let client = client_builder.build();
         // TODO: in future, do not remove clients that share a publicKey, instead,
         // expand the `Client` struct to handle multiple connections & a policy for
         // how to handle who we write to when multiple connections exist.
-        let client = Client::new(client);
+        let client = Client::new(client, can_mesh);
         if let Some(old_client) = self.inner.insert(key, client) {
             tracing::warn!("multiple connections found for {key:?}, pruning old connection",);
+            if old_client.can_mesh {
+                self.watchers.remove(&key);
+            }
             old_client.shutdown();
         }
+        if !can_mesh {
+            self.broadcast_peer_conn_state(PeerConnState::Present(key));
+        }
+    }
+
+    /// Subscribes the mesh peer `watcher` to clients connecting and disconnecting.
+    ///
+    /// The watcher is immediately sent the currently connected clients.
+    pub fn add_watcher(&mut self, watcher: PublicKey) {
+        let Some(client) = self.inner.get(&watcher) else {
+            return;
+        };
+        tracing::trace!("adding watcher: {:?}", watcher);
+        for (key, _) in self.inner.iter().filter(|(_, c)| !c.can_mesh) {
+            if client
+                .send_peer_conn_state(PeerConnState::Present(*key))
+                .is_err()
+            {
+                break;
+            }
+        }
+        self.watchers.insert(watcher);
+    }
+
+    /// Notifies all watching mesh peers about a client connecting or disconnecting.
+    fn broadcast_peer_conn_state(&mut self, state: PeerConnState) {
+        let mut closed = Vec::new();
+        for watcher in self.watchers.iter() {
+            let res = match self.inner.get(watcher) {
+                Some(client) => client.send_peer_conn_state(state),
+                None => Err(SendError::SenderClosed),
+            };
+            if res.is_err() {
+                closed.push(*watcher);
+            }
+        }
+        for watcher in closed {
+            tracing::warn!("Can no longer write to watcher {watcher:?}, removing it");
+            self.watchers.remove(&watcher);
+        }
     }
 
     /// Removes the client from the map of clients, & sends a notification
@@ -197,9 +300,14 @@ impl Clients {
     pub fn unregister(&mut self, peer: &PublicKey) {
         tracing::trace!("unregistering client: {:?}", peer);
         if let Some(client) = self.inner.remove(peer) {
-            for key in client.sent_to.iter() {
+            for key in client.sent_to.keys() {
                 self.send_peer_gone(key, *peer);
             }
+            if client.can_mesh {
+                self.watchers.remove(peer);
+            } else {
+                self.broadcast_peer_conn_state(PeerConnState::Gone(*peer));
+            }
             tracing::warn!("pruning connection {peer:?}");
             client.shutdown();
         }
@@ -259,6 +367,7 @@ mod tests {
     use crate::{
         key::SecretKey,
         relay::codec::{recv_frame, DerpCodec, Frame, FrameType},
+        relay::server::{MaybeTlsStream, RelayIo},
     };
 
     use anyhow::Result;
@@ -276,10 +385,12 @@ mod tests {
             ClientConnBuilder {
                 key,
                 conn_num,
-                io: Framed::new(crate::relay::server::MaybeTlsStream::Test(io), DerpCodec),
+                io: RelayIo::Derp(Framed::new(MaybeTlsStream::Test(io), DerpCodec)),
                 write_timeout: None,
                 channel_capacity: 10,
+                rate_limits: Default::default(),
                 server_channel,
+                can_mesh: false,
             },
             FramedRead::new(test_io, DerpCodec),
         )
@@ -334,4 +445,40 @@ mod tests {
         clients.shutdown().await;
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_clients_watchers() -> Result<()> {
+        let a_key = SecretKey::generate().public();
+        let b_key = SecretKey::generate().public();
+        let mesh_key = SecretKey::generate().public();
+
+        let (builder_a, _a_rw) = test_client_builder(a_key, 0);
+        let (mut builder_mesh, mut mesh_rw) = test_client_builder(mesh_key, 1);
+        builder_mesh.can_mesh = true;
+
+        let mut clients = Clients::new();
+        clients.register(builder_a);
+        clients.register(builder_mesh);
+
+        // the watcher learns about connected clients, but not about mesh peers
+        clients.add_watcher(mesh_key);
+        let frame = recv_frame(FrameType::PeerPresent, &mut mesh_rw).await?;
+        assert_eq!(frame, Frame::PeerPresent { peer: a_key });
+
+        // and about clients connecting and disconnecting
+        let (builder_b, _b_rw) = test_client_builder(b_key, 2);
+        clients.register(builder_b);
+        let frame = recv_frame(FrameType::PeerPresent, &mut mesh_rw).await?;
+        assert_eq!(frame, Frame::PeerPresent { peer: b_key });
+
+        clients.unregister(&a_key);
+        let frame = recv_frame(FrameType::PeerGone, &mut mesh_rw).await?;
+        assert_eq!(frame, Frame::PeerGone { peer: a_key });
+
+        clients.unregister(&mesh_key);
+        assert!(clients.watchers.is_empty());
+
+        clients.shutdown().await;
+        Ok(())
+    }
 }
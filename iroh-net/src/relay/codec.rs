@@ -14,7 +14,7 @@ use crate::key::{PublicKey, SecretKey};
 /// including its on-wire framing overhead)
 pub const MAX_PACKET_SIZE: usize = 64 * 1024;
 
-const MAX_FRAME_SIZE: usize = 1024 * 1024;
+pub(super) const MAX_FRAME_SIZE: usize = 1024 * 1024;
 
 /// The Relay magic number, sent in the FrameType::ClientInfo frame upon initial connection.
 const MAGIC: &str = "RELAY🔑";
@@ -29,12 +29,15 @@ pub(super) const PER_CLIENT_READ_QUEUE_DEPTH: usize = 512;
 /// ProtocolVersion is bumped whenever there's a wire-incompatible change.
 ///  - version 1 (zero on wire): consistent box headers, in use by employee dev nodes a bit
 ///  - version 2: received packets have src addrs in FrameType::RecvPacket at beginning
+///
 /// NOTE: we are techincally running a modified version of the protocol.
-/// `FrameType::PeerPresent`, `FrameType::WatchConn`, `FrameType::ClosePeer`, have been removed.
-/// The server will error on that connection if a client sends one of these frames.
+/// `FrameType::ClosePeer` has been removed.
+/// The server will error on that connection if a client sends this frame.
 /// We have split with the DERP protocol significantly starting with our relay protocol 3
-/// `FrameType::PeerPresent`, `FrameType::WatchConn`, `FrameType::ClosePeer`, `FrameType::ServerKey`, and `FrameType::ServerInfo` have been removed.
+/// `FrameType::ClosePeer`, `FrameType::ServerKey`, and `FrameType::ServerInfo` have been removed.
 /// The server will error on that connection if a client sends one of these frames.
+/// `FrameType::WatchConns` and `FrameType::ForwardPacket` are only accepted from mesh peers,
+/// and only mesh peers are sent `FrameType::PeerPresent`, see "Meshing" below.
 /// This materially affects the handshake protocol, and so relay nodes on version 3 will be unable to communicate
 /// with nodes running earlier protocol versions.
 pub(super) const PROTOCOL_VERSION: usize = 3;
@@ -52,6 +55,13 @@ pub(super) const PROTOCOL_VERSION: usize = 3;
 ///  * clients sends FrameType::SendPacket
 ///  * server then sends FrameType::RecvPacket to recipient
 ///
+/// Meshing:
+///  * a peer relay connects as a client, presenting the shared mesh key in its ClientInfo
+///  * -> peer relay sends FrameType::WatchConns
+///  * server sends FrameType::PeerPresent for each connected client, and whenever a client
+///    connects, and FrameType::PeerGone whenever a client disconnects
+///  * peer relay sends FrameType::ForwardPacket for recipients connected to this server
+///
 
 const PREFERRED: u8 = 1u8;
 /// indicates this is NOT the client's home node
@@ -80,8 +90,16 @@ pub(crate) enum FrameType {
     ///
     /// 32B pub key of peer that's gone
     PeerGone = 8,
-    /// Frames 9-11 concern meshing, which we have eliminated from our version of the protocol.
-    /// Messages with these frames will be ignored.
+    /// Sent from a mesh peer to the server to subscribe to `FrameType::PeerPresent` and
+    /// `FrameType::PeerGone` frames for all clients of the server.
+    ///
+    /// no payload
+    WatchConns = 9,
+    /// Sent from server to a watching mesh peer to signal that a client connected.
+    ///
+    /// 32B pub key of peer that's present
+    PeerPresent = 10,
+    // Frame 11 (`ClosePeer`) has been eliminated from our version of the protocol.
     /// 8 byte ping payload, to be echoed back in FrameType::Pong
     Ping = 12,
     /// 8 byte payload, the contents of ping being replied to
@@ -99,6 +117,8 @@ pub(crate) enum FrameType {
     ///
     /// Handled on the `[relay::Client]`, but currently never sent on the `[relay::Server]`
     Restarting = 15,
+    /// Sent from a mesh peer to the server, to deliver a packet to a client of the server.
+    ///
     /// 32B src pub key + 32B dst pub key + packet bytes
     ForwardPacket = 16,
     #[num_enum(default)]
@@ -178,7 +198,7 @@ pub(super) async fn recv_client_key<S: Stream<Item = anyhow::Result<Frame>> + Un
         client_public_key
             .verify(&message, &signature)
             .context("invalid signature")?;
-        let info: ClientInfo = postcard::from_bytes(&message).context("deserialization")?;
+        let info = ClientInfo::from_bytes(&message).context("deserialization")?;
         Ok((client_public_key, info))
     } else {
         anyhow::bail!("expected FrameType::ClientInfo");
@@ -223,6 +243,15 @@ pub(crate) enum Frame {
         reconnect_in: u32,
         try_for: u32,
     },
+    WatchConns,
+    PeerPresent {
+        peer: PublicKey,
+    },
+    ForwardPacket {
+        src_key: PublicKey,
+        dst_key: PublicKey,
+        packet: Bytes,
+    },
 }
 
 impl Frame {
@@ -238,6 +267,9 @@ impl Frame {
             Frame::Pong { .. } => FrameType::Pong,
             Frame::Health { .. } => FrameType::Health,
             Frame::Restarting { .. } => FrameType::Restarting,
+            Frame::WatchConns => FrameType::WatchConns,
+            Frame::PeerPresent { .. } => FrameType::PeerPresent,
+            Frame::ForwardPacket { .. } => FrameType::ForwardPacket,
         }
     }
 
@@ -261,6 +293,9 @@ impl Frame {
             Frame::Pong { .. } => 8,
             Frame::Health { problem } => problem.len(),
             Frame::Restarting { .. } => 4 + 4,
+            Frame::WatchConns => 0,
+            Frame::PeerPresent { .. } => PUBLIC_KEY_LENGTH,
+            Frame::ForwardPacket { packet, .. } => PUBLIC_KEY_LENGTH * 2 + packet.len(),
         }
     }
 
@@ -312,6 +347,19 @@ impl Frame {
                 dst.put_u32(*reconnect_in);
                 dst.put_u32(*try_for);
             }
+            Frame::WatchConns => {}
+            Frame::PeerPresent { peer } => {
+                dst.put(peer.as_ref());
+            }
+            Frame::ForwardPacket {
+                src_key,
+                dst_key,
+                packet,
+            } => {
+                dst.put(src_key.as_ref());
+                dst.put(dst_key.as_ref());
+                dst.put(packet.as_ref());
+            }
         }
     }
 
@@ -420,6 +468,39 @@ impl Frame {
                     try_for,
                 }
             }
+            FrameType::WatchConns => {
+                ensure!(content.is_empty(), "invalid watch conns frame length");
+                Self::WatchConns
+            }
+            FrameType::PeerPresent => {
+                ensure!(
+                    content.len() == PUBLIC_KEY_LENGTH,
+                    "invalid peer present frame length"
+                );
+                let peer = PublicKey::try_from(&content[..32])?;
+                Self::PeerPresent { peer }
+            }
+            FrameType::ForwardPacket => {
+                ensure!(
+                    content.len() >= PUBLIC_KEY_LENGTH * 2,
+                    "invalid forward packet frame length: {}",
+                    content.len()
+                );
+                let packet_len = content.len() - PUBLIC_KEY_LENGTH * 2;
+                ensure!(
+                    packet_len <= MAX_PACKET_SIZE,
+                    "data packet longer ({packet_len}) than max of {MAX_PACKET_SIZE}"
+                );
+                let src_key = PublicKey::try_from(&content[..PUBLIC_KEY_LENGTH])?;
+                let dst_key =
+                    PublicKey::try_from(&content[PUBLIC_KEY_LENGTH..PUBLIC_KEY_LENGTH * 2])?;
+                let packet = content.slice(PUBLIC_KEY_LENGTH * 2..);
+                Self::ForwardPacket {
+                    src_key,
+                    dst_key,
+                    packet,
+                }
+            }
             _ => {
                 anyhow::bail!("invalid frame type: {:?}", frame_type);
             }
@@ -428,7 +509,7 @@ impl Frame {
     }
 }
 
-const HEADER_LEN: usize = 5;
+pub(super) const HEADER_LEN: usize = 5;
 
 impl Decoder for DerpCodec {
     type Item = Frame;
@@ -544,6 +625,32 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_mesh_frames() -> anyhow::Result<()> {
+        let (reader, writer) = tokio::io::duplex(1024);
+        let mut reader = FramedRead::new(reader, DerpCodec);
+        let mut writer = FramedWrite::new(writer, DerpCodec);
+
+        let src_key = SecretKey::generate().public();
+        let dst_key = SecretKey::generate().public();
+        let frames = [
+            Frame::WatchConns,
+            Frame::PeerPresent { peer: src_key },
+            Frame::ForwardPacket {
+                src_key,
+                dst_key,
+                packet: Bytes::from_static(b"hello world!"),
+            },
+        ];
+        for frame in frames {
+            write_frame(&mut writer, frame.clone(), None).await?;
+            writer.flush().await?;
+            let got = recv_frame(frame.typ(), &mut reader).await?;
+            assert_eq!(frame, got);
+        }
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_send_recv_client_key() -> anyhow::Result<()> {
         let (reader, writer) = tokio::io::duplex(1024);
@@ -553,6 +660,8 @@ mod tests {
         let client_key = SecretKey::generate();
         let client_info = ClientInfo {
             version: PROTOCOL_VERSION,
+            auth_token: Some("secret".to_string()),
+            mesh_key: Some("mesh".to_string()),
         };
         println!("client_key pub {:?}", client_key.public());
         send_client_key(&mut writer, &client_key, &client_info).await?;
@@ -561,6 +670,29 @@ mod tests {
         assert_eq!(client_info, got_client_info);
         Ok(())
     }
+
+    #[test]
+    fn test_client_info_without_auth_token() -> anyhow::Result<()> {
+        // clients without auth support only serialize the version
+        let legacy = postcard::to_stdvec(&PROTOCOL_VERSION)?;
+        let info = ClientInfo::from_bytes(&legacy)?;
+        assert_eq!(
+            info,
+            ClientInfo {
+                version: PROTOCOL_VERSION,
+                auth_token: None,
+                mesh_key: None,
+            }
+        );
+
+        // clients without mesh support only serialize the version and auth token
+        let mut legacy = postcard::to_stdvec(&PROTOCOL_VERSION)?;
+        legacy.extend(postcard::to_stdvec(&Some("secret"))?);
+        let info = ClientInfo::from_bytes(&legacy)?;
+        assert_eq!(info.auth_token.as_deref(), Some("secret"));
+        assert_eq!(info.mesh_key, None);
+        Ok(())
+    }
 }
 
 /// these test are slow in debug mode, so only run them in release mode
@@ -633,11 +765,14 @@ mod proptests {
                 | FrameType::Ping
                 | FrameType::Pong
                 | FrameType::Restarting
+                | FrameType::WatchConns
+                | FrameType::PeerPresent
                 | FrameType::PeerGone => true,
                 FrameType::ClientInfo
                 | FrameType::Health
                 | FrameType::SendPacket
                 | FrameType::RecvPacket
+                | FrameType::ForwardPacket
                 | FrameType::Unknown => false,
             }
         }
@@ -2,9 +2,11 @@
 //! upgrades.
 //!
 mod client;
+mod proxy;
 mod server;
 
 pub use self::client::{Client, ClientBuilder, ClientError, ClientReceiver};
+pub use self::proxy::{Proxy, ProxyKind};
 pub use self::server::{Server, ServerBuilder, TlsAcceptor, TlsConfig};
 
 pub(crate) const HTTP_UPGRADE_PROTOCOL: &str = "iroh derp http";
@@ -35,6 +37,8 @@ pub(crate) fn make_tls_config() -> TlsConfig {
 mod tests {
     use super::*;
 
+    use std::time::Duration;
+
     use anyhow::Result;
     use bytes::Bytes;
     use reqwest::Url;
@@ -119,6 +123,223 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_websocket_clients_and_server() -> Result<()> {
+        let _guard = iroh_test::logging::setup();
+
+        let server = ServerBuilder::new("127.0.0.1:0".parse().unwrap())
+            .secret_key(Some(SecretKey::generate()))
+            .spawn()
+            .await?;
+        let port = server.addr().port();
+
+        // client a uses a websocket, client b the plain http upgrade
+        let ws_url: Url = format!("ws://127.0.0.1:{port}").parse().unwrap();
+        let http_url: Url = format!("http://127.0.0.1:{port}").parse().unwrap();
+        let (a_key, mut a_recv, client_a_task, client_a) =
+            create_test_client(SecretKey::generate(), ws_url);
+        let (b_key, mut b_recv, client_b_task, client_b) =
+            create_test_client(SecretKey::generate(), http_url);
+
+        client_a.ping().await?;
+        client_b.ping().await?;
+
+        let msg = Bytes::from_static(b"hi there, client b!");
+        client_a.send(b_key, msg.clone()).await?;
+        let (got_key, got_msg) = b_recv.recv().await.expect("expected message from client_a");
+        assert_eq!(a_key, got_key);
+        assert_eq!(msg, got_msg);
+
+        let msg = Bytes::from(vec![42u8; 32 * 1024]);
+        client_b.send(a_key, msg.clone()).await?;
+        let (got_key, got_msg) = a_recv.recv().await.expect("expected message from client_b");
+        assert_eq!(b_key, got_key);
+        assert_eq!(msg, got_msg);
+
+        client_a.close().await?;
+        client_a_task.abort();
+        client_b.close().await?;
+        client_b_task.abort();
+        server.shutdown().await;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_http_proxy_clients_and_server() -> Result<()> {
+        let _guard = iroh_test::logging::setup();
+
+        let server = ServerBuilder::new("127.0.0.1:0".parse().unwrap())
+            .secret_key(Some(SecretKey::generate()))
+            .spawn()
+            .await?;
+        let relay_url: Url = format!("http://127.0.0.1:{}", server.addr().port())
+            .parse()
+            .unwrap();
+
+        let (proxy_addr, proxy_task) = spawn_connect_proxy("Basic dXNlcjpwYXNz").await?;
+        let proxy: Proxy = format!("http://user:pass@{proxy_addr}").parse()?;
+
+        let (a_key, _a_recv, client_a_task, client_a) = create_test_client_with(
+            SecretKey::generate(),
+            ClientBuilder::new(relay_url.clone()).proxy(Some(proxy)),
+        );
+        let (b_key, mut b_recv, client_b_task, client_b) =
+            create_test_client(SecretKey::generate(), relay_url.clone());
+
+        client_a.ping().await?;
+        client_b.ping().await?;
+
+        let msg = Bytes::from_static(b"hi there, client b!");
+        client_a.send(b_key, msg.clone()).await?;
+        let (got_key, got_msg) = b_recv.recv().await.expect("expected message from client_a");
+        assert_eq!(a_key, got_key);
+        assert_eq!(msg, got_msg);
+
+        // wrong credentials are rejected by the proxy
+        let proxy: Proxy = format!("http://user:wrong@{proxy_addr}").parse()?;
+        let (_, _, client_c_task, client_c) = create_test_client_with(
+            SecretKey::generate(),
+            ClientBuilder::new(relay_url).proxy(Some(proxy)),
+        );
+        assert!(matches!(client_c.ping().await, Err(ClientError::Proxy(_))));
+
+        client_a.close().await?;
+        client_a_task.abort();
+        client_b.close().await?;
+        client_b_task.abort();
+        client_c_task.abort();
+        proxy_task.abort();
+        server.shutdown().await;
+
+        Ok(())
+    }
+
+    /// Spawns a minimal HTTP `CONNECT` proxy which requires the given authorization.
+    async fn spawn_connect_proxy(
+        authorization: &'static str,
+    ) -> Result<(std::net::SocketAddr, JoinHandle<()>)> {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let task = tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut stream = BufReader::new(stream);
+                    let mut target = None;
+                    let mut authorized = false;
+                    let mut line = String::new();
+                    while stream.read_line(&mut line).await? > 2 {
+                        if let Some(rest) = line.strip_prefix("CONNECT ") {
+                            target = rest.split(' ').next().map(ToString::to_string);
+                        }
+                        if let Some((name, value)) = line.split_once(':') {
+                            authorized |= name.eq_ignore_ascii_case("proxy-authorization")
+                                && value.trim() == authorization;
+                        }
+                        line.clear();
+                    }
+                    let (Some(target), true) = (target, authorized) else {
+                        stream
+                            .write_all(b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n")
+                            .await?;
+                        return anyhow::Ok(());
+                    };
+                    let mut upstream = tokio::net::TcpStream::connect(target).await?;
+                    stream
+                        .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+                        .await?;
+                    tokio::io::copy_bidirectional(&mut stream, &mut upstream).await?;
+                    anyhow::Ok(())
+                });
+            }
+        });
+        Ok((addr, task))
+    }
+
+    #[tokio::test]
+    async fn test_mesh_clients_and_servers() -> Result<()> {
+        let _guard = iroh_test::logging::setup();
+
+        const MESH_KEY: &str = "mesh secret";
+        let mut server1 = ServerBuilder::new("127.0.0.1:0".parse().unwrap())
+            .secret_key(Some(SecretKey::generate()))
+            .mesh(MESH_KEY, Vec::new())
+            .spawn()
+            .await?;
+        let mut server2 = ServerBuilder::new("127.0.0.1:0".parse().unwrap())
+            .secret_key(Some(SecretKey::generate()))
+            .mesh(MESH_KEY, Vec::new())
+            .spawn()
+            .await?;
+        let url1: Url = format!("http://127.0.0.1:{}", server1.addr().port()).parse()?;
+        let url2: Url = format!("http://127.0.0.1:{}", server2.addr().port()).parse()?;
+        server1.add_mesh_peer(url2.clone().into())?;
+        server2.add_mesh_peer(url1.clone().into())?;
+
+        // client a connects to server 1, client b to server 2
+        let (a_key, mut a_recv, client_a_task, client_a) =
+            create_test_client(SecretKey::generate(), url1);
+        let b_secret_key = SecretKey::generate();
+        let b_key = b_secret_key.public();
+        let (client_b, mut b_recv) =
+            ClientBuilder::new(url2).build(b_secret_key, crate::dns::default_resolver().clone());
+        client_a.ping().await?;
+        client_b.ping().await?;
+
+        // the servers learn about each other's clients asynchronously, so retry until the
+        // routes are in place
+        info!("sending message from a to b");
+        let msg = Bytes::from_static(b"hi there, client b!");
+        let got_key = tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                client_a.send(b_key, msg.clone()).await?;
+                let recv = tokio::time::timeout(Duration::from_millis(100), b_recv.recv()).await;
+                if let Ok(Some(Ok((ReceivedMessage::ReceivedPacket { source, data }, _)))) = recv {
+                    assert_eq!(msg, data);
+                    return anyhow::Ok(source);
+                }
+            }
+        })
+        .await??;
+        assert_eq!(a_key, got_key);
+
+        info!("sending message from b to a");
+        let msg = Bytes::from_static(b"right back at ya, client b!");
+        let (got_key, got_msg) = tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                client_b.send(a_key, msg.clone()).await?;
+                let recv = tokio::time::timeout(Duration::from_millis(100), a_recv.recv()).await;
+                if let Ok(Some(received)) = recv {
+                    return anyhow::Ok(received);
+                }
+            }
+        })
+        .await??;
+        assert_eq!(b_key, got_key);
+        assert_eq!(msg, got_msg);
+
+        info!("disconnecting a, b should learn that a is gone");
+        client_a.close().await?;
+        client_a_task.abort();
+        tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                match b_recv.recv().await {
+                    Some(Ok((ReceivedMessage::PeerGone(key), _))) if key == a_key => return,
+                    Some(Ok(_)) => continue,
+                    other => panic!("unexpected message on b: {other:?}"),
+                }
+            }
+        })
+        .await?;
+
+        client_b.close().await?;
+        server1.shutdown().await;
+        server2.shutdown().await;
+        Ok(())
+    }
+
     fn create_test_client(
         key: SecretKey,
         server_url: Url,
@@ -128,7 +349,19 @@ mod tests {
         JoinHandle<()>,
         Client,
     ) {
-        let client = ClientBuilder::new(server_url).insecure_skip_cert_verify(true);
+        create_test_client_with(key, ClientBuilder::new(server_url))
+    }
+
+    fn create_test_client_with(
+        key: SecretKey,
+        builder: ClientBuilder,
+    ) -> (
+        PublicKey,
+        mpsc::Receiver<(PublicKey, Bytes)>,
+        JoinHandle<()>,
+        Client,
+    ) {
+        let client = builder.insecure_skip_cert_verify(true);
         let dns_resolver = crate::dns::default_resolver();
         let (client, mut client_reader) = client.build(key.clone(), dns_resolver.clone());
         let public_key = key.public();
@@ -8,8 +8,11 @@ use std::time::Duration;
 use anyhow::bail;
 use bytes::Bytes;
 use futures::future::BoxFuture;
+use futures::StreamExt;
 use hyper::body::Incoming;
-use hyper::header::UPGRADE;
+use hyper::header::{
+    CONNECTION, HOST, SEC_WEBSOCKET_ACCEPT, SEC_WEBSOCKET_KEY, SEC_WEBSOCKET_VERSION, UPGRADE,
+};
 use hyper::upgrade::{Parts, Upgraded};
 use hyper::Request;
 use rand::Rng;
@@ -19,15 +22,24 @@ use tokio::net::TcpStream;
 use tokio::sync::{mpsc, oneshot};
 use tokio::task::JoinSet;
 use tokio::time::Instant;
+use tokio_util::codec::{FramedRead, FramedWrite};
 use tracing::{debug, error, info_span, trace, warn, Instrument};
 use url::Url;
 
 use crate::dns::{lookup_ipv4_ipv6, DnsResolver};
 use crate::key::{PublicKey, SecretKey};
+use crate::relay::http::{Proxy, ProxyKind};
+use crate::relay::websocket::{
+    self, Role, WebSocketStream, WEBSOCKET_UPGRADE_PROTOCOL, WEBSOCKET_VERSION,
+};
 use crate::relay::RelayUrl;
 use crate::relay::{
-    client::Client as RelayClient, client::ClientBuilder as RelayClientBuilder,
-    client::ClientReceiver as RelayClientReceiver, ReceivedMessage,
+    client::Client as RelayClient,
+    client::ClientBuilder as RelayClientBuilder,
+    client::ClientReceiver as RelayClientReceiver,
+    client::{RelayReader, RelayWriter},
+    codec::DerpCodec,
+    ReceivedMessage,
 };
 use crate::util::AbortingJoinHandle;
 
@@ -90,6 +102,9 @@ pub enum ClientError {
     /// The relay [`super::client::Client`] failed to build
     #[error("failed to build relay client: {0}")]
     Build(String),
+    /// Could not tunnel through the configured proxy
+    #[error("proxy error: {0}")]
+    Proxy(String),
     /// The ping request timed out
     #[error("ping timeout")]
     PingTimeout,
@@ -155,6 +170,12 @@ struct Actor {
         Option<Box<dyn Fn() -> BoxFuture<'static, bool> + Send + Sync + 'static>>,
     conn_gen: usize,
     url: RelayUrl,
+    #[debug(skip)]
+    auth_token: Option<String>,
+    #[debug(skip)]
+    mesh_key: Option<String>,
+    websocket: bool,
+    proxy: Option<Proxy>,
     #[debug("TlsConnector")]
     tls_connector: tokio_rustls::TlsConnector,
     pings: PingTracker,
@@ -199,6 +220,14 @@ pub struct ClientBuilder {
     server_public_key: Option<PublicKey>,
     /// Server url.
     url: RelayUrl,
+    /// Bearer token for relays which restrict access
+    auth_token: Option<String>,
+    /// Shared secret of meshed relay servers, default is None
+    mesh_key: Option<String>,
+    /// Default is false
+    websocket: bool,
+    /// Proxy to tunnel connections through, default is None
+    proxy: Option<Proxy>,
     /// Allow self-signed certificates from relay servers
     #[cfg(any(test, feature = "test-utils"))]
     insecure_skip_cert_verify: bool,
@@ -224,6 +253,10 @@ impl ClientBuilder {
             is_prober: false,
             server_public_key: None,
             url: url.into(),
+            auth_token: None,
+            mesh_key: None,
+            websocket: false,
+            proxy: None,
             #[cfg(any(test, feature = "test-utils"))]
             insecure_skip_cert_verify: false,
         }
@@ -249,6 +282,36 @@ impl ClientBuilder {
         self
     }
 
+    /// Sets the bearer token presented to relay servers which restrict access.
+    pub fn auth_token(mut self, token: impl Into<String>) -> Self {
+        self.auth_token = Some(token.into());
+        self
+    }
+
+    /// Sets the mesh key presented to relay servers, connecting as a mesh peer.
+    ///
+    /// Used by relay servers meshing with each other, see
+    /// [`crate::relay::server::Server::add_mesh_peer`].
+    pub fn mesh_key(mut self, mesh_key: Option<String>) -> Self {
+        self.mesh_key = mesh_key;
+        self
+    }
+
+    /// Carry the relay protocol over a WebSocket instead of a plain HTTP upgrade.
+    ///
+    /// Useful behind proxies which only permit WebSocket upgrades. Relay urls with a `ws` or
+    /// `wss` scheme always use WebSockets.
+    pub fn websocket(mut self, websocket: bool) -> Self {
+        self.websocket = websocket;
+        self
+    }
+
+    /// Tunnels connections to the relay server through this proxy.
+    pub fn proxy(mut self, proxy: Option<Proxy>) -> Self {
+        self.proxy = proxy;
+        self
+    }
+
     /// Enable this [`Client`] to acknowledge pings.
     pub fn can_ack_pings(mut self, can: bool) -> Self {
         self.can_ack_pings = can;
@@ -305,6 +368,7 @@ impl ClientBuilder {
         let tls_connector: tokio_rustls::TlsConnector = Arc::new(config).into();
         let public_key = key.public();
 
+        let websocket = self.websocket || matches!(self.url.scheme(), "ws" | "wss");
         let inner = Actor {
             secret_key: key,
             can_ack_pings: self.can_ack_pings,
@@ -316,6 +380,10 @@ impl ClientBuilder {
             pings: PingTracker::default(),
             ping_tasks: Default::default(),
             url: self.url,
+            auth_token: self.auth_token,
+            mesh_key: self.mesh_key,
+            websocket,
+            proxy: self.proxy,
             tls_connector,
             dns_resolver,
         };
@@ -570,6 +638,7 @@ impl Actor {
 
         debug!(server_addr = ?tcp_stream.peer_addr(), %local_addr, "TCP stream connected");
 
+        let websocket_key = self.websocket.then(websocket::generate_key);
         let response = if self.use_https() {
             debug!("Starting TLS handshake");
             let hostname = self
@@ -577,10 +646,12 @@ impl Actor {
                 .ok_or_else(|| ClientError::InvalidUrl("No tls servername".into()))?;
             let tls_stream = self.tls_connector.connect(hostname, tcp_stream).await?;
             debug!("tls_connector connect success");
-            Self::start_upgrade(tls_stream).await?
+            self.start_upgrade(tls_stream, websocket_key.as_deref())
+                .await?
         } else {
             debug!("Starting handshake");
-            Self::start_upgrade(tcp_stream).await?
+            self.start_upgrade(tcp_stream, websocket_key.as_deref())
+                .await?
         };
 
         if response.status() != hyper::StatusCode::SWITCHING_PROTOCOLS {
@@ -594,6 +665,16 @@ impl Actor {
             ));
         }
 
+        if let Some(key) = websocket_key {
+            let expected = websocket::accept_key(key.as_bytes());
+            let accept = response.headers().get(SEC_WEBSOCKET_ACCEPT);
+            if accept.map(|v| v.as_bytes()) != Some(expected.as_bytes()) {
+                return Err(ClientError::Upgrade(format!(
+                    "invalid websocket accept header: {accept:?}"
+                )));
+            }
+        }
+
         debug!("starting upgrade");
         let upgraded = match hyper::upgrade::on(response).await {
             Ok(upgraded) => upgraded,
@@ -604,11 +685,13 @@ impl Actor {
         };
 
         debug!("connection upgraded");
-        let (reader, writer) =
-            downcast_upgrade(upgraded).map_err(|e| ClientError::Upgrade(e.to_string()))?;
+        let (reader, writer) = downcast_upgrade(upgraded, self.websocket)
+            .map_err(|e| ClientError::Upgrade(e.to_string()))?;
 
         let (relay_client, receiver) =
             RelayClientBuilder::new(self.secret_key.clone(), local_addr, reader, writer)
+                .auth_token(self.auth_token.clone())
+                .mesh_key(self.mesh_key.clone())
                 .build()
                 .await
                 .map_err(|e| ClientError::Build(e.to_string()))?;
@@ -623,7 +706,13 @@ impl Actor {
     }
 
     /// Sends the HTTP upgrade request to the relay server.
-    async fn start_upgrade<T>(io: T) -> Result<hyper::Response<Incoming>, ClientError>
+    ///
+    /// If a `websocket_key` is given, asks for a WebSocket upgrade instead.
+    async fn start_upgrade<T>(
+        &self,
+        io: T,
+        websocket_key: Option<&str>,
+    ) -> Result<hyper::Response<Incoming>, ClientError>
     where
         T: AsyncRead + AsyncWrite + Send + Unpin + 'static,
     {
@@ -643,10 +732,23 @@ impl Actor {
             .instrument(info_span!("http-driver")),
         );
         debug!("Sending upgrade request");
-        let req = Request::builder()
-            .uri("/derp")
-            .header(UPGRADE, super::HTTP_UPGRADE_PROTOCOL)
-            .body(http_body_util::Empty::<hyper::body::Bytes>::new())?;
+        let mut req = Request::builder().uri("/derp");
+        req = match websocket_key {
+            Some(key) => {
+                let host = match (self.url.host_str(), self.url.port()) {
+                    (Some(host), Some(port)) => format!("{host}:{port}"),
+                    (Some(host), None) => host.to_string(),
+                    (None, _) => return Err(ClientError::InvalidUrl("missing host".into())),
+                };
+                req.header(HOST, host)
+                    .header(UPGRADE, WEBSOCKET_UPGRADE_PROTOCOL)
+                    .header(CONNECTION, "Upgrade")
+                    .header(SEC_WEBSOCKET_VERSION, WEBSOCKET_VERSION)
+                    .header(SEC_WEBSOCKET_KEY, key)
+            }
+            None => req.header(UPGRADE, super::HTTP_UPGRADE_PROTOCOL),
+        };
+        let req = req.body(http_body_util::Empty::<hyper::body::Bytes>::new())?;
         request_sender.send_request(req).await.map_err(From::from)
     }
 
@@ -770,21 +872,24 @@ impl Actor {
         }
 
         match self.url.scheme() {
-            "http" => Some(80),
-            "https" => Some(443),
+            "http" | "ws" => Some(80),
+            "https" | "wss" => Some(443),
             _ => None,
         }
     }
 
     fn use_https(&self) -> bool {
-        // only disable https if we are explicitly dialing a http url
-        if self.url.scheme() == "http" {
+        // only disable https if we are explicitly dialing a http or ws url
+        if matches!(self.url.scheme(), "http" | "ws") {
             return false;
         }
         true
     }
 
     async fn dial_url(&self) -> Result<TcpStream, ClientError> {
+        if let Some(proxy) = self.proxy.as_ref().filter(|p| !p.bypasses(&self.url)) {
+            return self.dial_url_proxy(proxy).await;
+        }
         debug!(%self.url, "dial url");
 
         let prefer_ipv6 = self.prefer_ipv6().await;
@@ -810,6 +915,48 @@ impl Actor {
         Ok(tcp_stream)
     }
 
+    /// Connects to the relay server through a tunnel opened by the proxy.
+    async fn dial_url_proxy(&self, proxy: &Proxy) -> Result<TcpStream, ClientError> {
+        debug!(%self.url, proxy = %proxy.url(), "dial url via proxy");
+
+        let prefer_ipv6 = self.prefer_ipv6().await;
+        let proxy_ip = resolve_host(&self.dns_resolver, proxy.url(), prefer_ipv6).await?;
+        let proxy_port = proxy
+            .url()
+            .port_or_known_default()
+            .ok_or_else(|| ClientError::InvalidUrl("missing proxy port".into()))?;
+        let proxy_addr = SocketAddr::new(proxy_ip, proxy_port);
+
+        let host = match self.url.host() {
+            Some(url::Host::Ipv6(ip)) => ip.to_string(),
+            Some(host) => host.to_string(),
+            None => return Err(ClientError::InvalidUrl("missing host".into())),
+        };
+        let port = self
+            .url_port()
+            .ok_or_else(|| ClientError::InvalidUrl("missing url port".into()))?;
+        // Only SOCKS5 proxies without remote DNS need the relay address resolved locally
+        let target_addr = match proxy.kind() {
+            ProxyKind::Socks5 => {
+                let ip = resolve_host(&self.dns_resolver, &self.url, prefer_ipv6).await?;
+                Some(SocketAddr::new(ip, port))
+            }
+            ProxyKind::Http | ProxyKind::Socks5h => None,
+        };
+
+        debug!("connecting to proxy {}", proxy_addr);
+        let tcp_stream = tokio::time::timeout(DIAL_NODE_TIMEOUT, async move {
+            let proxy_stream = TcpStream::connect(proxy_addr).await?;
+            proxy_stream.set_nodelay(true)?;
+            proxy.tunnel(proxy_stream, &host, port, target_addr).await
+        })
+        .await
+        .map_err(|_| ClientError::ConnectTimeout)?
+        .map_err(|e| ClientError::Proxy(format!("{e:#}")))?;
+
+        Ok(tcp_stream)
+    }
+
     /// Reports whether IPv4 dials should be slightly
     /// delayed to give IPv6 a better chance of winning dial races.
     /// Implementations should only return true if IPv6 is expected
@@ -885,27 +1032,15 @@ async fn resolve_host(
 
 fn downcast_upgrade(
     upgraded: Upgraded,
-) -> anyhow::Result<(
-    Box<dyn AsyncRead + Unpin + Send + Sync + 'static>,
-    Box<dyn AsyncWrite + Unpin + Send + Sync + 'static>,
-)> {
+    websocket: bool,
+) -> anyhow::Result<(RelayReader, RelayWriter)> {
     match upgraded.downcast::<hyper_util::rt::TokioIo<tokio::net::TcpStream>>() {
-        Ok(Parts { read_buf, io, .. }) => {
-            let (reader, writer) = tokio::io::split(io.into_inner());
-            // Prepend data to the reader to avoid data loss
-            let reader = std::io::Cursor::new(read_buf).chain(reader);
-
-            Ok((Box::new(reader), Box::new(writer)))
-        }
+        Ok(Parts { read_buf, io, .. }) => Ok(split_upgraded(io.into_inner(), read_buf, websocket)),
         Err(upgraded) => {
             if let Ok(Parts { read_buf, io, .. }) =
                 upgraded.downcast::<hyper_util::rt::TokioIo<tokio_rustls::client::TlsStream<tokio::net::TcpStream>>>()
             {
-                let (reader, writer) = tokio::io::split(io.into_inner());
-                // Prepend data to the reader to avoid data loss
-                let reader = std::io::Cursor::new(read_buf).chain(reader);
-
-                return Ok((Box::new(reader), Box::new(writer)));
+                return Ok(split_upgraded(io.into_inner(), read_buf, websocket));
             }
 
             bail!(
@@ -915,6 +1050,26 @@ fn downcast_upgrade(
     }
 }
 
+/// Splits an upgraded connection into a reader and a writer of relay frames.
+fn split_upgraded<T>(io: T, read_buf: Bytes, websocket: bool) -> (RelayReader, RelayWriter)
+where
+    T: AsyncRead + AsyncWrite + Unpin + Send + Sync + 'static,
+{
+    if websocket {
+        // The websocket stream parses the buffered data itself
+        let io = WebSocketStream::new(io, Role::Client, read_buf);
+        let (writer, reader) = io.split();
+        return (Box::pin(reader), Box::pin(writer));
+    }
+    let (reader, writer) = tokio::io::split(io);
+    // Prepend data to the reader to avoid data loss
+    let reader = std::io::Cursor::new(read_buf).chain(reader);
+    (
+        Box::pin(FramedRead::new(reader, DerpCodec)),
+        Box::pin(FramedWrite::new(writer, DerpCodec)),
+    )
+}
+
 /// Used to allow self signed certificates in tests
 #[cfg(any(test, feature = "test-utils"))]
 struct NoCertVerifier;
@@ -9,7 +9,7 @@ use derive_more::Debug;
 use futures::future::{Future, FutureExt};
 use http::response::Builder as ResponseBuilder;
 use hyper::body::Incoming;
-use hyper::header::{HeaderValue, UPGRADE};
+use hyper::header::{HeaderValue, CONNECTION, SEC_WEBSOCKET_ACCEPT, SEC_WEBSOCKET_KEY, UPGRADE};
 use hyper::service::Service;
 use hyper::upgrade::Upgraded;
 use hyper::{HeaderMap, Method, Request, Response, StatusCode};
@@ -20,9 +20,12 @@ use tokio_util::sync::CancellationToken;
 use tracing::{debug, error, info, info_span, warn, Instrument};
 
 use crate::key::SecretKey;
+use crate::relay::access::AccessControl;
 use crate::relay::http::HTTP_UPGRADE_PROTOCOL;
-use crate::relay::server::{ClientConnHandler, MaybeTlsStream};
-use crate::relay::MaybeTlsStreamServer;
+use crate::relay::server::{AdminHandle, ClientConnHandler, MaybeTlsStream};
+use crate::relay::types::ClientRateLimits;
+use crate::relay::websocket::{self, Role, WebSocketStream, WEBSOCKET_UPGRADE_PROTOCOL};
+use crate::relay::{MaybeTlsStreamServer, RelayUrl};
 
 type BytesBody = http_body_util::Full<hyper::body::Bytes>;
 type HyperError = Box<dyn std::error::Error + Send + Sync>;
@@ -54,12 +57,20 @@ fn downcast_upgrade(upgraded: Upgraded) -> Result<(MaybeTlsStream, Bytes)> {
 }
 
 /// The server HTTP handler to do HTTP upgrades
+///
+/// If `websocket` is true, the connection was upgraded to a WebSocket and the relay frames
+/// are carried in WebSocket messages.
 async fn relay_connection_handler(
     conn_handler: &ClientConnHandler,
     upgraded: Upgraded,
+    websocket: bool,
 ) -> Result<()> {
-    debug!("relay_connection upgraded");
+    debug!(websocket, "relay_connection upgraded");
     let (io, read_buf) = downcast_upgrade(upgraded)?;
+    if websocket {
+        let io = WebSocketStream::new(io, Role::Server, read_buf);
+        return conn_handler.accept_websocket(io).await;
+    }
     ensure!(
         read_buf.is_empty(),
         "can not deal with buffered data yet: {:?}",
@@ -96,6 +107,24 @@ impl Server {
     pub fn addr(&self) -> SocketAddr {
         self.addr
     }
+
+    /// Create an [`AdminHandle`] to inspect and disconnect the clients of the relay server.
+    ///
+    /// Returns `None` if this server does not run a relay service.
+    pub fn admin_handle(&self) -> Option<AdminHandle> {
+        self.server.as_ref().map(|server| server.admin_handle())
+    }
+
+    /// Mesh with the relay server at `url`.
+    ///
+    /// Errors if this server does not run a relay service, or was built without a mesh key,
+    /// see [`ServerBuilder::mesh`].
+    pub fn add_mesh_peer(&mut self, url: RelayUrl) -> Result<()> {
+        self.server
+            .as_mut()
+            .context("no relay server running")?
+            .add_mesh_peer(url)
+    }
 }
 
 /// Configuration to use for the TLS connection
@@ -143,6 +172,21 @@ pub struct ServerBuilder {
     /// When `None`, a default is provided.
     #[debug("{}", not_found_fn.as_ref().map_or("None", |_| "Some(Box<Fn(ResponseBuilder) -> Result<Response<Body>> + Send + Sync + 'static>)"))]
     not_found_fn: Option<HyperHandler>,
+    /// Decides which clients may connect to the relay.
+    ///
+    /// Defaults to admitting all clients.
+    access_control: AccessControl,
+    /// Limits on the packets each client may send.
+    ///
+    /// Defaults to no limits.
+    client_rate_limits: ClientRateLimits,
+    /// Shared secret of meshed relay servers.
+    ///
+    /// Defaults to `None`, not meshing.
+    #[debug(skip)]
+    mesh_key: Option<String>,
+    /// Relay servers to mesh with.
+    mesh_peers: Vec<RelayUrl>,
 }
 
 impl ServerBuilder {
@@ -157,6 +201,10 @@ impl ServerBuilder {
             relay_override: None,
             headers: HeaderMap::new(),
             not_found_fn: None,
+            access_control: AccessControl::default(),
+            client_rate_limits: ClientRateLimits::default(),
+            mesh_key: None,
+            mesh_peers: Vec::new(),
         }
     }
 
@@ -203,6 +251,31 @@ impl ServerBuilder {
         self
     }
 
+    /// Restrict which clients may connect to the relay.
+    pub fn access_control(mut self, access_control: AccessControl) -> Self {
+        self.access_control = access_control;
+        self
+    }
+
+    /// Limit the bandwidth and packet rate of each client.
+    ///
+    /// [`ServerBuilder::spawn`] fails if a limit is set to zero.
+    pub fn client_rate_limits(mut self, limits: ClientRateLimits) -> Self {
+        self.client_rate_limits = limits;
+        self
+    }
+
+    /// Mesh with other relay servers sharing the `mesh_key`.
+    ///
+    /// Packets for clients connected to the `peers` are forwarded to them, and the peers may
+    /// forward packets to clients of this server. More peers can be added to a running server
+    /// with [`Server::add_mesh_peer`].
+    pub fn mesh(mut self, mesh_key: impl Into<String>, peers: Vec<RelayUrl>) -> Self {
+        self.mesh_key = Some(mesh_key.into());
+        self.mesh_peers = peers;
+        self
+    }
+
     /// Add http headers.
     pub fn headers(mut self, headers: HeaderMap) -> Self {
         for (k, v) in headers.iter() {
@@ -215,7 +288,15 @@ impl ServerBuilder {
     pub async fn spawn(self) -> Result<Server> {
         ensure!(self.secret_key.is_some() || self.relay_override.is_some(), "Must provide a `SecretKey` for the relay server OR pass in an override function for the 'relay' endpoint");
         let (relay_handler, relay_server) = if let Some(secret_key) = self.secret_key {
-            let server = crate::relay::server::Server::new(secret_key.clone());
+            let mut server = crate::relay::server::Server::new(secret_key.clone());
+            server.set_access_control(self.access_control);
+            server.set_client_rate_limits(self.client_rate_limits)?;
+            if let Some(mesh_key) = self.mesh_key {
+                server.set_mesh_key(mesh_key);
+                for peer in self.mesh_peers {
+                    server.add_mesh_peer(peer)?;
+                }
+            }
             (
                 RelayHandler::ConnHandler(server.client_conn_handler(self.headers.clone())),
                 Some(server),
@@ -343,10 +424,33 @@ impl Service<Request<Incoming>> for ClientConnHandler {
                 let mut res = builder.body(body_empty()).expect("valid body");
 
                 // Send a 400 to any request that doesn't have an `Upgrade` header.
-                if !req.headers().contains_key(UPGRADE) {
+                let Some(upgrade) = req.headers().get(UPGRADE) else {
                     *res.status_mut() = StatusCode::BAD_REQUEST;
                     return Ok(res);
-                }
+                };
+
+                // Clients which can only do WebSocket upgrades ask for "websocket" instead
+                // of the HTTP_UPGRADE_PROTOCOL.
+                let websocket_accept = if upgrade
+                    .as_bytes()
+                    .eq_ignore_ascii_case(WEBSOCKET_UPGRADE_PROTOCOL.as_bytes())
+                {
+                    match req.headers().get(SEC_WEBSOCKET_KEY) {
+                        Some(key) => Some(websocket::accept_key(key.as_bytes())),
+                        None => {
+                            *res.status_mut() = StatusCode::BAD_REQUEST;
+                            return Ok(res);
+                        }
+                    }
+                } else {
+                    None
+                };
+                let websocket = websocket_accept.is_some();
+                let protocol = if websocket {
+                    WEBSOCKET_UPGRADE_PROTOCOL
+                } else {
+                    HTTP_UPGRADE_PROTOCOL
+                };
 
                 // Setup a future that will eventually receive the upgraded
                 // connection and talk a new protocol, and spawn the future
@@ -359,17 +463,16 @@ impl Service<Request<Incoming>> for ClientConnHandler {
                     async move {
                         match hyper::upgrade::on(&mut req).await {
                             Ok(upgraded) => {
-                                if let Err(e) =
-                                    relay_connection_handler(&closure_conn_handler, upgraded).await
+                                if let Err(e) = relay_connection_handler(
+                                    &closure_conn_handler,
+                                    upgraded,
+                                    websocket,
+                                )
+                                .await
                                 {
-                                    tracing::warn!(
-                                        "upgrade to \"{HTTP_UPGRADE_PROTOCOL}\": io error: {:?}",
-                                        e
-                                    );
+                                    tracing::warn!("upgrade to \"{protocol}\": io error: {:?}", e);
                                 } else {
-                                    tracing::debug!(
-                                        "upgrade to \"{HTTP_UPGRADE_PROTOCOL}\" success"
-                                    );
+                                    tracing::debug!("upgrade to \"{protocol}\" success");
                                 };
                             }
                             Err(e) => tracing::warn!("upgrade error: {:?}", e),
@@ -379,10 +482,18 @@ impl Service<Request<Incoming>> for ClientConnHandler {
                 );
 
                 // Now return a 101 Response saying we agree to the upgrade to the
-                // HTTP_UPGRADE_PROTOCOL
+                // HTTP_UPGRADE_PROTOCOL or WebSocket
                 *res.status_mut() = StatusCode::SWITCHING_PROTOCOLS;
                 res.headers_mut()
-                    .insert(UPGRADE, HeaderValue::from_static(HTTP_UPGRADE_PROTOCOL));
+                    .insert(UPGRADE, HeaderValue::from_static(protocol));
+                if let Some(accept) = websocket_accept {
+                    res.headers_mut()
+                        .insert(CONNECTION, HeaderValue::from_static("Upgrade"));
+                    res.headers_mut().insert(
+                        SEC_WEBSOCKET_ACCEPT,
+                        HeaderValue::from_str(&accept).expect("base64 is a valid header value"),
+                    );
+                }
                 Ok(res)
             }
         }
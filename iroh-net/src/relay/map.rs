@@ -77,6 +77,8 @@ impl RelayMap {
                 url,
                 stun_only: false,
                 stun_port,
+                auth_token: None,
+                quic_port: None,
             }
             .into(),
         );
@@ -114,7 +116,7 @@ impl fmt::Display for RelayMap {
 /// Information on a specific relay server.
 ///
 /// Includes the Url where it can be dialed.
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, PartialOrd, Ord)]
+#[derive(derive_more::Debug, Clone, PartialEq, Eq, Serialize, Deserialize, PartialOrd, Ord)]
 pub struct RelayNode {
     /// The [`RelayUrl`] where this relay server can be dialed.
     pub url: RelayUrl,
@@ -127,6 +129,16 @@ pub struct RelayNode {
     ///
     /// Setting this to `0` means the default STUN port is used.
     pub stun_port: u16,
+    /// Bearer token presented to this relay server, if it restricts access.
+    #[debug("{}", auth_token.as_ref().map_or("None", |_| "Some(..)"))]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub auth_token: Option<String>,
+    /// The UDP port of the relay server's QUIC address discovery endpoint.
+    ///
+    /// `None` if the relay server does not serve QUIC address discovery, see
+    /// [`QuicServer`](super::QuicServer).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quic_port: Option<u16>,
 }
 
 impl fmt::Display for RelayNode {
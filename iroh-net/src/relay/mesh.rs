@@ -0,0 +1,159 @@
+//! Meshing of relay servers.
+//!
+//! Relay servers sharing a mesh key connect to each other as clients, presenting the mesh key
+//! in their handshake. Over this connection a server watches which clients are connected to
+//! its peer, and forwards packets for those clients to the peer. This lets nodes connected to
+//! different relay servers of one deployment reach each other.
+
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use iroh_metrics::inc;
+use tokio::sync::mpsc;
+use tracing::{debug, info_span, warn, Instrument};
+
+use super::{
+    codec::PER_CLIENT_SEND_QUEUE_DEPTH,
+    http::{Client as HttpClient, ClientBuilder, ClientReceiver},
+    metrics::Metrics,
+    types::{Packet, PacketForwarder, ServerMessage},
+    ReceivedMessage, RelayUrl,
+};
+use crate::dns::default_resolver;
+use crate::key::{PublicKey, SecretKey};
+use crate::util::AbortingJoinHandle;
+
+/// How long to wait before reconnecting to a mesh peer after the connection failed.
+const MESH_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Connection from a relay server to one of its mesh peers.
+#[derive(derive_more::Debug)]
+pub(crate) struct MeshClient {
+    /// Identifies the [`PacketForwarder`] of this connection
+    id: usize,
+    url: RelayUrl,
+    /// The secret key of the relay server, used as the client key
+    secret_key: SecretKey,
+    #[debug(skip)]
+    mesh_key: String,
+    /// Channel on which to communicate to the [`super::server::ServerActor`]
+    server_channel: mpsc::Sender<ServerMessage>,
+}
+
+impl MeshClient {
+    pub(crate) fn new(
+        id: usize,
+        url: RelayUrl,
+        secret_key: SecretKey,
+        mesh_key: String,
+        server_channel: mpsc::Sender<ServerMessage>,
+    ) -> Self {
+        Self {
+            id,
+            url,
+            secret_key,
+            mesh_key,
+            server_channel,
+        }
+    }
+
+    /// Spawns the task maintaining the connection to the mesh peer.
+    ///
+    /// The connection is re-established whenever it fails, until the returned handle is dropped
+    /// or the server shuts down.
+    pub(crate) fn spawn(self) -> AbortingJoinHandle<()> {
+        let span = info_span!("relay.mesh", peer = %self.url);
+        tokio::spawn(self.run().instrument(span)).into()
+    }
+
+    async fn run(self) {
+        let (client, mut receiver) = ClientBuilder::new(self.url.clone())
+            .mesh_key(Some(self.mesh_key.clone()))
+            .build(self.secret_key.clone(), default_resolver().clone());
+        let (queue_s, mut queue_r) = mpsc::channel(PER_CLIENT_SEND_QUEUE_DEPTH);
+        let forwarder = PacketForwarder {
+            id: self.id,
+            queue: queue_s,
+        };
+        loop {
+            if let Err(err) = self
+                .run_connection(&client, &mut receiver, &forwarder, &mut queue_r)
+                .await
+            {
+                warn!("mesh connection failed: {err:#}");
+            }
+            // the clients of the peer are no longer reachable through this connection
+            if self
+                .server_channel
+                .send(ServerMessage::ClearPacketForwarder(self.id))
+                .await
+                .is_err()
+            {
+                debug!("server gone, stopping mesh client");
+                return;
+            }
+            client.close_for_reconnect().await.ok();
+            tokio::time::sleep(MESH_RETRY_DELAY).await;
+        }
+    }
+
+    /// Connects to the mesh peer, and learns routes and forwards packets until the connection
+    /// fails.
+    async fn run_connection(
+        &self,
+        client: &HttpClient,
+        receiver: &mut ClientReceiver,
+        forwarder: &PacketForwarder,
+        queue: &mut mpsc::Receiver<(PublicKey, Packet)>,
+    ) -> Result<()> {
+        let (relay_client, conn_gen) = client.connect().await?;
+        relay_client.watch_connection_changes().await?;
+        debug!(conn_gen, "connected to mesh peer");
+        loop {
+            tokio::select! {
+                msg = receiver.recv() => {
+                    let (msg, _conn_gen) = msg.context("mesh client closed")??;
+                    match msg {
+                        ReceivedMessage::PeerPresent(key) => {
+                            self.send_server(ServerMessage::AddPacketForwarder {
+                                key,
+                                forwarder: forwarder.clone(),
+                            })
+                            .await?;
+                        }
+                        ReceivedMessage::PeerGone(key) => {
+                            self.send_server(ServerMessage::RemovePacketForwarder {
+                                key,
+                                forwarder_id: self.id,
+                            })
+                            .await?;
+                        }
+                        ReceivedMessage::Ping(data) => {
+                            relay_client.send_pong(data).await?;
+                        }
+                        ReceivedMessage::Health {
+                            problem: Some(problem),
+                        } => {
+                            warn!("mesh peer reports a problem: {problem}");
+                        }
+                        _ => {}
+                    }
+                }
+                Some((dst_key, packet)) = queue.recv() => {
+                    relay_client
+                        .forward_packet(packet.src, dst_key, packet.bytes)
+                        .await?;
+                    inc!(Metrics, packets_forwarded_out);
+                }
+            }
+        }
+    }
+
+    async fn send_server(&self, msg: ServerMessage) -> Result<()> {
+        self.server_channel
+            .send(msg)
+            .await
+            .map_err(|_| anyhow::anyhow!("server gone"))?;
+        Ok(())
+    }
+}
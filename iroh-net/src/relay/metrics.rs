@@ -60,6 +60,15 @@ pub struct Metrics {
     pub accepts: Counter,
     /// Number of connections we have removed because of an error
     pub disconnects: Counter,
+    /// Number of connections rejected by access control
+    pub unauthorized: Counter,
+    /// Number of packets dropped because the sending client exceeded its rate limit
+    pub packets_rate_limited: Counter,
+    /// Number of bytes dropped because the sending client exceeded its rate limit
+    pub bytes_rate_limited: Counter,
+
+    /// Number of QUIC address discovery requests answered
+    pub quic_addr_disc_requests: Counter,
     // TODO: enable when we can have multiple connections for one node id
     // pub duplicate_client_keys: Counter,
     // pub duplicate_client_conns: Counter,
@@ -115,6 +124,19 @@ impl Default for Metrics {
 
             accepts: Counter::new("Number of times this server has accepted a connection."),
             disconnects: Counter::new("Number of clients that have then disconnected."),
+            unauthorized: Counter::new(
+                "Number of connections rejected because the client was not authorized.",
+            ),
+            packets_rate_limited: Counter::new(
+                "Number of packets dropped because the sending client exceeded its rate limit.",
+            ),
+            bytes_rate_limited: Counter::new(
+                "Number of bytes dropped because the sending client exceeded its rate limit.",
+            ),
+
+            quic_addr_disc_requests: Counter::new(
+                "Number of QUIC address discovery requests answered.",
+            ),
             // TODO: enable when we can have multiple connections for one node id
             // pub duplicate_client_keys: Counter::new("Number of duplicate client keys."),
             // pub duplicate_client_conns: Counter::new("Number of duplicate client connections."),
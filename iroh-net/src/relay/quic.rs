@@ -0,0 +1,394 @@
+//! QUIC address discovery, an alternative to STUN.
+//!
+//! A relay server may run a QUIC endpoint which tells every client the address it observes
+//! the client connecting from. Unlike STUN this works in networks which block or rate-limit
+//! STUN but let QUIC through.
+//!
+//! The protocol is minimal: after the handshake the server opens a unidirectional stream,
+//! writes the observed socket address as a string and finishes the stream. The client
+//! closes the connection once it read the address.
+//!
+//! Just like STUN responses the reported address is not authenticated, so the server uses a
+//! self-signed certificate which the client does not verify.
+//!
+//! The netcheck client probes from the same sockets as its STUN probes, normally the
+//! magicsock sockets, so the observed address is the public address of those sockets.
+
+use std::{
+    collections::HashSet,
+    io,
+    net::SocketAddr,
+    sync::{Arc, Mutex, RwLock},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use anyhow::{Context as _, Result};
+use bytes::Bytes;
+use iroh_metrics::inc;
+use quinn::AsyncUdpSocket;
+use tokio::{sync::mpsc, task::JoinSet};
+use tracing::{debug, info_span, trace, warn, Instrument};
+
+use super::metrics::Metrics;
+use crate::net::{ip::to_canonical, UdpSocket};
+use crate::util::AbortingJoinHandle;
+
+/// ALPN of the QUIC address discovery protocol.
+pub const ALPN_QUIC_ADDR_DISC: &[u8] = b"/iroh-qad/0";
+
+/// Server name used when connecting, the server's certificate is not verified.
+const SERVER_NAME: &str = "localhost";
+
+/// Maximum length of the observed address sent by the server.
+const MAX_ADDR_LEN: usize = 64;
+
+/// Capacity of the queue of received packets of a client endpoint.
+const PACKETS_CAP: usize = 64;
+
+/// How long the server waits for the client to close the connection.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A QUIC endpoint reporting the observed address to every client connecting to it.
+#[derive(Debug)]
+pub struct QuicServer {
+    endpoint: quinn::Endpoint,
+    _task: AbortingJoinHandle<()>,
+}
+
+impl QuicServer {
+    /// Binds the server on `addr` and starts serving clients.
+    pub fn spawn(addr: SocketAddr) -> Result<Self> {
+        let cert = rcgen::generate_simple_self_signed(vec![SERVER_NAME.to_string()])?;
+        let key = rustls::PrivateKey(cert.serialize_private_key_der());
+        let cert = rustls::Certificate(cert.serialize_der()?);
+        let mut crypto = rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(vec![cert], key)?;
+        crypto.alpn_protocols = vec![ALPN_QUIC_ADDR_DISC.to_vec()];
+        let server_config = quinn::ServerConfig::with_crypto(Arc::new(crypto));
+        let endpoint = quinn::Endpoint::server(server_config, addr)
+            .context("failed to bind QUIC address discovery endpoint")?;
+        let local_addr = endpoint.local_addr()?;
+        let task = tokio::spawn(
+            run_server(endpoint.clone()).instrument(info_span!("quic_addr_disc", %local_addr)),
+        );
+        Ok(Self {
+            endpoint,
+            _task: task.into(),
+        })
+    }
+
+    /// The local address the server is bound to.
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.endpoint.local_addr()?)
+    }
+
+    /// Closes all connections and stops the server.
+    pub async fn shutdown(self) {
+        self.endpoint.close(0u32.into(), b"shutdown");
+        self.endpoint.wait_idle().await;
+    }
+}
+
+async fn run_server(endpoint: quinn::Endpoint) {
+    let mut tasks = JoinSet::new();
+    loop {
+        tokio::select! {
+            connecting = endpoint.accept() => {
+                let Some(connecting) = connecting else {
+                    debug!("endpoint closed");
+                    break;
+                };
+                let remote_addr = connecting.remote_address();
+                tasks.spawn(
+                    async move {
+                        if let Err(err) = handle_connection(connecting).await {
+                            debug!("failed: {err:#}");
+                        }
+                    }
+                    .instrument(info_span!("conn", %remote_addr)),
+                );
+            }
+            Some(res) = tasks.join_next(), if !tasks.is_empty() => {
+                if let Err(err) = res {
+                    warn!("connection task failed: {err:?}");
+                }
+            }
+        }
+    }
+}
+
+async fn handle_connection(connecting: quinn::Connecting) -> Result<()> {
+    let conn = connecting.await?;
+    let observed = conn.remote_address();
+    let observed = SocketAddr::new(to_canonical(observed.ip()), observed.port());
+    trace!(%observed, "reporting observed address");
+    inc!(Metrics, quic_addr_disc_requests);
+    let mut send = conn.open_uni().await?;
+    send.write_all(observed.to_string().as_bytes()).await?;
+    send.finish().await?;
+    // The client closes the connection once it has the address.
+    tokio::time::timeout(CLOSE_TIMEOUT, conn.closed())
+        .await
+        .ok();
+    Ok(())
+}
+
+/// Client of the QUIC address discovery protocol.
+///
+/// The probes are sent from the sockets netcheck sends its STUN probes from, normally the
+/// magicsock sockets, so that the observed address is the public address of the socket
+/// carrying the real traffic.  Netcheck does not read from those sockets, the packets
+/// received on them have to be passed to [`QuicClient::receive`] instead.
+///
+/// One QUIC endpoint is created per address family, lazily on first use.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct QuicClient(Arc<QuicClientInner>);
+
+#[derive(Debug, Default)]
+struct QuicClientInner {
+    /// The servers probed so far, all packets from these belong to the QUIC client.
+    servers: RwLock<HashSet<SocketAddr>>,
+    endpoint_v4: Mutex<Option<ClientEndpoint>>,
+    endpoint_v6: Mutex<Option<ClientEndpoint>>,
+}
+
+/// A QUIC endpoint sending from a socket it does not read from itself.
+#[derive(Debug)]
+struct ClientEndpoint {
+    endpoint: quinn::Endpoint,
+    /// The socket the endpoint sends from.
+    sock: Arc<UdpSocket>,
+    /// Passes the packets received on the socket to the endpoint.
+    packets_tx: mpsc::Sender<(Bytes, SocketAddr)>,
+}
+
+impl QuicClient {
+    pub(crate) fn new() -> Self {
+        Default::default()
+    }
+
+    /// Returns the address the server at `server_addr` observed us connecting from, together
+    /// with the round trip time to the server.
+    ///
+    /// The connection is made from `sock`, which must be of the same address family as
+    /// `server_addr`.
+    pub(crate) async fn get_addr(
+        &self,
+        sock: Arc<UdpSocket>,
+        server_addr: SocketAddr,
+    ) -> Result<(SocketAddr, Duration)> {
+        self.0
+            .servers
+            .write()
+            .expect("poisoned")
+            .insert(server_addr);
+        let endpoint = self.get_endpoint(sock, server_addr)?;
+        let conn = endpoint.connect(server_addr, SERVER_NAME)?.await?;
+        let mut recv = conn.accept_uni().await?;
+        let buf = recv.read_to_end(MAX_ADDR_LEN).await?;
+        let addr = std::str::from_utf8(&buf)?
+            .parse()
+            .context("invalid observed address")?;
+        let rtt = conn.rtt();
+        conn.close(0u32.into(), b"done");
+        Ok((addr, rtt))
+    }
+
+    /// Passes a packet received on a socket probes are sent from to the client.
+    ///
+    /// Returns `false` if the packet is not from a QUIC address discovery server, and thus
+    /// not for the client.
+    pub(crate) fn receive(&self, payload: &[u8], src: SocketAddr) -> bool {
+        if !self.0.servers.read().expect("poisoned").contains(&src) {
+            return false;
+        }
+        let endpoint = match src {
+            SocketAddr::V4(_) => &self.0.endpoint_v4,
+            SocketAddr::V6(_) => &self.0.endpoint_v6,
+        };
+        if let Some(ref endpoint) = *endpoint.lock().expect("poisoned") {
+            let packet = (Bytes::copy_from_slice(payload), src);
+            if endpoint.packets_tx.try_send(packet).is_err() {
+                warn!(%src, "dropping QUIC address discovery packet");
+            }
+        }
+        true
+    }
+
+    fn get_endpoint(
+        &self,
+        sock: Arc<UdpSocket>,
+        server_addr: SocketAddr,
+    ) -> Result<quinn::Endpoint> {
+        let endpoint = match server_addr {
+            SocketAddr::V4(_) => &self.0.endpoint_v4,
+            SocketAddr::V6(_) => &self.0.endpoint_v6,
+        };
+        let mut endpoint = endpoint.lock().expect("poisoned");
+        match *endpoint {
+            Some(ref endpoint) if Arc::ptr_eq(&endpoint.sock, &sock) => {
+                return Ok(endpoint.endpoint.clone())
+            }
+            _ => {}
+        }
+        let mut crypto = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_custom_certificate_verifier(Arc::new(NoCertVerifier))
+            .with_no_client_auth();
+        crypto.alpn_protocols = vec![ALPN_QUIC_ADDR_DISC.to_vec()];
+        let (packets_tx, packets_rx) = mpsc::channel(PACKETS_CAP);
+        let socket = ClientSocket {
+            sock: sock.clone(),
+            packets_rx: Mutex::new(packets_rx),
+        };
+        let mut new_endpoint = quinn::Endpoint::new_with_abstract_socket(
+            Default::default(),
+            None,
+            socket,
+            Arc::new(quinn::TokioRuntime),
+        )?;
+        new_endpoint.set_default_client_config(quinn::ClientConfig::new(Arc::new(crypto)));
+        *endpoint = Some(ClientEndpoint {
+            endpoint: new_endpoint.clone(),
+            sock,
+            packets_tx,
+        });
+        Ok(new_endpoint)
+    }
+}
+
+/// The [`AsyncUdpSocket`] of a [`ClientEndpoint`].
+///
+/// Sends directly on the socket, but receives the packets passed to [`QuicClient::receive`].
+#[derive(Debug)]
+struct ClientSocket {
+    sock: Arc<UdpSocket>,
+    packets_rx: Mutex<mpsc::Receiver<(Bytes, SocketAddr)>>,
+}
+
+impl AsyncUdpSocket for ClientSocket {
+    fn poll_send(
+        &self,
+        _state: &quinn_udp::UdpState,
+        cx: &mut Context,
+        transmits: &[quinn_udp::Transmit],
+    ) -> Poll<io::Result<usize>> {
+        let mut sent = 0;
+        for transmit in transmits {
+            // Segmentation offload is not used on this path, send each datagram on its own.
+            let segment_size = transmit.segment_size.unwrap_or(transmit.contents.len());
+            for segment in transmit.contents.chunks(segment_size.max(1)) {
+                match self.sock.poll_send_to(cx, segment, transmit.destination) {
+                    Poll::Ready(Ok(_)) => {}
+                    Poll::Ready(Err(err)) if sent == 0 => return Poll::Ready(Err(err)),
+                    Poll::Pending if sent == 0 => return Poll::Pending,
+                    Poll::Ready(Err(_)) | Poll::Pending => return Poll::Ready(Ok(sent)),
+                }
+            }
+            sent += 1;
+        }
+        Poll::Ready(Ok(sent))
+    }
+
+    fn poll_recv(
+        &self,
+        cx: &mut Context,
+        bufs: &mut [io::IoSliceMut<'_>],
+        meta: &mut [quinn_udp::RecvMeta],
+    ) -> Poll<io::Result<usize>> {
+        let mut packets_rx = self.packets_rx.lock().expect("poisoned");
+        let mut count = 0;
+        for (buf, meta) in bufs.iter_mut().zip(meta.iter_mut()) {
+            let packet = match packets_rx.poll_recv(cx) {
+                Poll::Ready(Some(packet)) => packet,
+                // The sender is only dropped together with the endpoint.
+                Poll::Ready(None) | Poll::Pending => break,
+            };
+            let (payload, addr) = packet;
+            let len = payload.len().min(buf.len());
+            buf[..len].copy_from_slice(&payload[..len]);
+            *meta = quinn_udp::RecvMeta {
+                addr,
+                len,
+                stride: len,
+                ecn: None,
+                dst_ip: None,
+            };
+            count += 1;
+        }
+        match count {
+            0 => Poll::Pending,
+            count => Poll::Ready(Ok(count)),
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.sock.local_addr()
+    }
+}
+
+/// Accepts any server certificate, the observed address is not authenticated anyway.
+struct NoCertVerifier;
+
+impl rustls::client::ServerCertVerifier for NoCertVerifier {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &rustls::Certificate,
+        _intermediates: &[rustls::Certificate],
+        _server_name: &rustls::ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: std::time::SystemTime,
+    ) -> Result<rustls::client::ServerCertVerified, rustls::Error> {
+        Ok(rustls::client::ServerCertVerified::assertion())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::IpFamily;
+
+    #[tokio::test]
+    async fn test_quic_addr_disc() -> Result<()> {
+        let _guard = iroh_test::logging::setup();
+        let server = QuicServer::spawn("127.0.0.1:0".parse()?)?;
+        let server_addr = server.local_addr()?;
+
+        let sock = Arc::new(UdpSocket::bind_local(IpFamily::V4, 0)?);
+        let local_addr = sock.local_addr()?;
+        let client = QuicClient::new();
+        // Like magicsock, read from the socket and pass the packets to the client.
+        let reader = tokio::spawn({
+            let sock = sock.clone();
+            let client = client.clone();
+            async move {
+                let mut buf = vec![0u8; 64 << 10];
+                loop {
+                    let (len, src) = sock.recv_from(&mut buf).await?;
+                    if !client.receive(&buf[..len], src) {
+                        break anyhow::Ok(src);
+                    }
+                }
+            }
+        });
+
+        let (addr, _rtt) = client.get_addr(sock.clone(), server_addr).await?;
+        assert_eq!(addr, local_addr);
+
+        // the endpoint is reused for further requests
+        let (addr_again, _rtt) = client.get_addr(sock.clone(), server_addr).await?;
+        assert_eq!(addr, addr_again);
+
+        // packets from anyone else are not for the client
+        let other = UdpSocket::bind_local(IpFamily::V4, 0)?;
+        other.send_to(b"hello", local_addr).await?;
+        assert_eq!(reader.await??, other.local_addr()?);
+
+        server.shutdown().await;
+        Ok(())
+    }
+}
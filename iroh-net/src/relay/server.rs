@@ -1,4 +1,5 @@
 //! based on tailscale/derp/derp_server.go
+use std::collections::{HashMap, HashSet};
 use std::pin::Pin;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
@@ -6,27 +7,35 @@ use std::task::{Context, Poll};
 use std::time::Duration;
 
 use anyhow::{bail, Context as _, Result};
+use bytes::Bytes;
+use futures::{Sink, SinkExt, Stream};
 use hyper::HeaderMap;
 use iroh_metrics::core::UsageStatsReport;
 use iroh_metrics::{inc, report_usage_stats};
 use tokio::io::{AsyncRead, AsyncWrite};
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, oneshot};
 use tokio::task::JoinHandle;
 use tokio_util::codec::Framed;
 use tokio_util::sync::CancellationToken;
-use tracing::{info_span, trace, Instrument};
+use tracing::{debug, info_span, trace, Instrument};
 
+use crate::disco::looks_like_disco_wrapper;
 use crate::key::{PublicKey, SecretKey};
+use crate::relay::websocket::WebSocketStream;
+use crate::util::AbortingJoinHandle;
 
 use super::{
+    access::{AccessControl, AccessDenied},
     client_conn::ClientConnBuilder,
     clients::Clients,
     codec::{
-        recv_client_key, DerpCodec, PER_CLIENT_SEND_QUEUE_DEPTH, PROTOCOL_VERSION,
-        SERVER_CHANNEL_SIZE,
+        recv_client_key, write_frame, DerpCodec, Frame, PER_CLIENT_SEND_QUEUE_DEPTH,
+        PROTOCOL_VERSION, SERVER_CHANNEL_SIZE,
     },
+    mesh::MeshClient,
     metrics::Metrics,
-    types::ServerMessage,
+    types::{ClientRateLimits, ClientStats, FlowStats, Packet, PacketForwarder, ServerMessage},
+    RelayUrl,
 };
 
 // TODO: skipping `verboseDropKeys` for now
@@ -41,7 +50,7 @@ pub(crate) const WRITE_TIMEOUT: Duration = Duration::from_secs(2);
 /// A relay server.
 ///
 /// Responsible for managing connections to relay [`super::client::Client`]s, sending packets from one client to another.
-#[derive(Debug)]
+#[derive(derive_more::Debug)]
 pub struct Server {
     /// Optionally specifies how long to wait before failing when writing
     /// to a client
@@ -50,6 +59,15 @@ pub struct Server {
     secret_key: SecretKey,
     /// The DER encoded x509 cert to send after `LetsEncrypt` cert+intermediate.
     meta_cert: Vec<u8>,
+    /// Decides which clients are admitted
+    access_control: Arc<AccessControl>,
+    /// Limits on the packets each client may send
+    client_rate_limits: ClientRateLimits,
+    /// Shared secret of the relay servers this server meshes with
+    #[debug(skip)]
+    mesh_key: Option<String>,
+    /// Connections to the relay servers this server meshes with
+    mesh_clients: Vec<AbortingJoinHandle<()>>,
     /// Channel on which to communicate to the [`ServerActor`]
     server_channel: mpsc::Sender<ServerMessage>,
     /// When true, the server has been shutdown.
@@ -77,6 +95,10 @@ impl Server {
             write_timeout: Some(WRITE_TIMEOUT),
             secret_key: key,
             meta_cert,
+            access_control: Default::default(),
+            client_rate_limits: Default::default(),
+            mesh_key: None,
+            mesh_clients: Vec::new(),
             server_channel: server_channel_s,
             closed: false,
             loop_handler: server_task,
@@ -84,6 +106,50 @@ impl Server {
         }
     }
 
+    /// Restricts which clients may connect to this server.
+    ///
+    /// Only affects [`ClientConnHandler`]s created after this call.
+    pub fn set_access_control(&mut self, access_control: AccessControl) {
+        self.access_control = Arc::new(access_control);
+    }
+
+    /// Limits the bandwidth and packet rate of each client.
+    ///
+    /// Only affects [`ClientConnHandler`]s created after this call. Fails if a limit is
+    /// set to zero.
+    pub fn set_client_rate_limits(&mut self, limits: ClientRateLimits) -> Result<()> {
+        limits.validate()?;
+        self.client_rate_limits = limits;
+        Ok(())
+    }
+
+    /// Sets the shared secret of the relay servers this server meshes with.
+    ///
+    /// Clients presenting this key in their handshake are admitted as mesh peers: they may
+    /// watch which clients are connected to this server and forward packets to them. Only
+    /// affects [`ClientConnHandler`]s created after this call.
+    pub fn set_mesh_key(&mut self, mesh_key: impl Into<String>) {
+        self.mesh_key = Some(mesh_key.into());
+    }
+
+    /// Meshes with the relay server at `url`.
+    ///
+    /// Connects to the peer using the mesh key, and forwards packets for the peer's clients
+    /// to it. For packets to flow both ways, the peer must also mesh with this server. Errors
+    /// if no mesh key is set, see [`Server::set_mesh_key`].
+    pub fn add_mesh_peer(&mut self, url: RelayUrl) -> Result<()> {
+        let mesh_key = self.mesh_key.clone().context("no mesh key set")?;
+        let mesh_client = MeshClient::new(
+            self.mesh_clients.len(),
+            url,
+            self.secret_key.clone(),
+            mesh_key,
+            self.server_channel.clone(),
+        );
+        self.mesh_clients.push(mesh_client.spawn());
+        Ok(())
+    }
+
     /// Returns the server's secret key.
     pub fn secret_key(&self) -> &SecretKey {
         &self.secret_key
@@ -97,6 +163,7 @@ impl Server {
     /// Closes the server and waits for the connections to disconnect.
     pub async fn close(mut self) {
         if !self.closed {
+            self.mesh_clients.clear();
             if let Err(err) = self.server_channel.send(ServerMessage::Shutdown).await {
                 tracing::warn!(
                     "could not shutdown the server gracefully, doing a forced shutdown: {:?}",
@@ -125,10 +192,20 @@ impl Server {
             server_channel: self.server_channel.clone(),
             secret_key: self.secret_key.clone(),
             write_timeout: self.write_timeout,
+            access_control: Arc::clone(&self.access_control),
+            client_rate_limits: self.client_rate_limits,
+            mesh_key: self.mesh_key.clone().map(Arc::new),
             default_headers: Arc::new(default_headers),
         }
     }
 
+    /// Create an [`AdminHandle`], to inspect and disconnect the clients of the [`Server`].
+    pub fn admin_handle(&self) -> AdminHandle {
+        AdminHandle {
+            server_channel: self.server_channel.clone(),
+        }
+    }
+
     /// Returns the server metadata cert that can be sent by the TLS server to
     /// let the client skip a round trip during start-up.
     pub fn meta_cert(&self) -> &[u8] {
@@ -141,11 +218,15 @@ impl Server {
 /// Created by the [`Server`] by calling [`Server::client_conn_handler`].
 ///
 /// Can be cheaply cloned.
-#[derive(Debug)]
+#[derive(derive_more::Debug)]
 pub struct ClientConnHandler {
     server_channel: mpsc::Sender<ServerMessage>,
     secret_key: SecretKey,
     write_timeout: Option<Duration>,
+    access_control: Arc<AccessControl>,
+    client_rate_limits: ClientRateLimits,
+    #[debug(skip)]
+    mesh_key: Option<Arc<String>>,
     pub(super) default_headers: Arc<HeaderMap>,
 }
 
@@ -155,6 +236,9 @@ impl Clone for ClientConnHandler {
             server_channel: self.server_channel.clone(),
             secret_key: self.secret_key.clone(),
             write_timeout: self.write_timeout,
+            access_control: Arc::clone(&self.access_control),
+            client_rate_limits: self.client_rate_limits,
+            mesh_key: self.mesh_key.clone(),
             default_headers: Arc::clone(&self.default_headers),
         }
     }
@@ -167,9 +251,24 @@ impl ClientConnHandler {
     /// some read or write error to the connection,  if the server is meant to verify clients,
     /// and is unable to verify this one, or if there is some issue communicating with the server.
     ///
+    /// Clients rejected by the server's [`AccessControl`] are sent a `Health` frame with the
+    /// reason before the connection is closed. Mesh peers presenting the server's mesh key
+    /// bypass access control and rate limits.
+    ///
     /// The provided [`AsyncRead`] and [`AsyncWrite`] must be already connected to the connection.
     pub async fn accept(&self, io: MaybeTlsStream) -> Result<()> {
-        let mut io = Framed::new(io, DerpCodec);
+        self.accept_io(RelayIo::Derp(Framed::new(io, DerpCodec)))
+            .await
+    }
+
+    /// Adds a new connection which was upgraded to a WebSocket and serves it.
+    ///
+    /// See [`ClientConnHandler::accept`].
+    pub(crate) async fn accept_websocket(&self, io: WebSocketStream<MaybeTlsStream>) -> Result<()> {
+        self.accept_io(RelayIo::WebSocket(io)).await
+    }
+
+    async fn accept_io(&self, mut io: RelayIo) -> Result<()> {
         trace!("accept: start");
         trace!("accept: recv client key");
         let (client_key, info) = recv_client_key(&mut io)
@@ -184,6 +283,32 @@ impl ClientConnHandler {
             );
         }
 
+        trace!("accept: check access");
+        let can_mesh = info.mesh_key.is_some();
+        let access = match (info.mesh_key.as_deref(), self.mesh_key.as_deref()) {
+            (Some(key), Some(mesh_key))
+                if ring::constant_time::verify_slices_are_equal(
+                    key.as_bytes(),
+                    mesh_key.as_bytes(),
+                )
+                .is_ok() =>
+            {
+                Ok(())
+            }
+            (Some(_), _) => Err(AccessDenied::InvalidMeshKey),
+            (None, _) => self
+                .access_control
+                .check(&client_key, info.auth_token.as_deref()),
+        };
+        if let Err(err) = access {
+            inc!(Metrics, unauthorized);
+            debug!(client = %client_key.fmt_short(), "rejecting client: {err}");
+            let problem = Bytes::from(format!("unauthorized: {err}"));
+            write_frame(&mut io, Frame::Health { problem }, self.write_timeout).await?;
+            io.flush().await?;
+            return Err(err).context("client rejected");
+        }
+
         trace!("accept: build client conn");
         let client_conn_builder = ClientConnBuilder {
             key: client_key,
@@ -191,7 +316,13 @@ impl ClientConnHandler {
             io,
             write_timeout: self.write_timeout,
             channel_capacity: PER_CLIENT_SEND_QUEUE_DEPTH,
+            rate_limits: if can_mesh {
+                ClientRateLimits::default()
+            } else {
+                self.client_rate_limits
+            },
             server_channel: self.server_channel.clone(),
+            can_mesh,
         };
         trace!("accept: create client");
         self.server_channel
@@ -204,11 +335,64 @@ impl ClientConnHandler {
     }
 }
 
+/// Inspects and disconnects the clients of a [`Server`].
+///
+/// Created by the [`Server`] by calling [`Server::admin_handle`].
+///
+/// Can be cheaply cloned.
+#[derive(Debug, Clone)]
+pub struct AdminHandle {
+    server_channel: mpsc::Sender<ServerMessage>,
+}
+
+impl AdminHandle {
+    /// Lists the connected clients.
+    pub async fn clients(&self) -> Result<Vec<ClientStats>> {
+        self.request(ServerMessage::ListClients).await
+    }
+
+    /// Returns the connected client with this key, if any.
+    pub async fn client(&self, key: PublicKey) -> Result<Option<ClientStats>> {
+        let clients = self.clients().await?;
+        Ok(clients.into_iter().find(|client| client.key == key))
+    }
+
+    /// Lists the packets and bytes each connected client sent to each other client.
+    pub async fn flows(&self) -> Result<Vec<FlowStats>> {
+        self.request(ServerMessage::ListFlows).await
+    }
+
+    /// Disconnects the client with this key.
+    ///
+    /// Returns whether the client was connected. The client may reconnect, use the server's
+    /// [`AccessControl`] to keep it out.
+    pub async fn disconnect(&self, key: PublicKey) -> Result<bool> {
+        self.request(|s| ServerMessage::DisconnectClient(key, s))
+            .await
+    }
+
+    async fn request<T>(&self, msg: impl FnOnce(oneshot::Sender<T>) -> ServerMessage) -> Result<T> {
+        let (s, r) = oneshot::channel();
+        self.server_channel
+            .send(msg(s))
+            .await
+            .map_err(|_| anyhow::anyhow!("server gone"))?;
+        r.await.context("server gone")
+    }
+}
+
 pub(crate) struct ServerActor {
     key: PublicKey,
     receiver: mpsc::Receiver<ServerMessage>,
     /// All clients connected to this server
     clients: Clients,
+    /// Mesh peers through which clients connected to other relay servers are reachable
+    mesh_routes: HashMap<PublicKey, PacketForwarder>,
+    /// For clients of mesh peers, the local clients they forwarded packets to
+    ///
+    /// Only tracked for clients with a route in `mesh_routes`, entries are removed with the
+    /// route and local clients are removed when they disconnect.
+    remote_sent_to: HashMap<PublicKey, HashSet<PublicKey>>,
 }
 
 impl ServerActor {
@@ -217,6 +401,80 @@ impl ServerActor {
             key,
             receiver,
             clients: Clients::new(),
+            mesh_routes: HashMap::new(),
+            remote_sent_to: HashMap::new(),
+        }
+    }
+
+    /// Forwards a packet for a client connected to a mesh peer.
+    ///
+    /// Returns false if there is no route to the client, or the route is congested.
+    fn forward_packet(&mut self, key: PublicKey, packet: Packet) -> bool {
+        let Some(forwarder) = self.mesh_routes.get(&key) else {
+            return false;
+        };
+        match forwarder.queue.try_send((key, packet)) {
+            Ok(()) => true,
+            Err(err) => {
+                tracing::warn!("forward packet: mesh peer unavailable for {key:?}: {err}");
+                false
+            }
+        }
+    }
+
+    /// Delivers a packet forwarded by a mesh peer to a local client.
+    fn handle_forwarded_packet(&mut self, key: PublicKey, packet: Packet) {
+        let src = packet.src;
+        let is_disco = looks_like_disco_wrapper(&packet.bytes);
+        if !self.clients.contains_key(&key) {
+            tracing::warn!("forwarded packet: no client {key:?}, dropped packet");
+            if is_disco {
+                inc!(Metrics, disco_packets_dropped);
+            } else {
+                inc!(Metrics, send_packets_dropped);
+            }
+            return;
+        }
+        let res = if is_disco {
+            self.clients.send_disco_packet(&key, packet)
+        } else {
+            self.clients.send_packet(&key, packet)
+        };
+        // without a route, the remote client can never be reported gone
+        if res.is_ok() && self.mesh_routes.contains_key(&src) {
+            self.remote_sent_to.entry(src).or_default().insert(key);
+        }
+    }
+
+    /// Unregisters a local client and stops tracking it as a recipient of remote clients.
+    fn unregister_client(&mut self, key: &PublicKey) {
+        self.clients.unregister(key);
+        self.remote_sent_to.retain(|_, dsts| {
+            dsts.remove(key);
+            !dsts.is_empty()
+        });
+    }
+
+    /// Removes the route to `key`, if it goes through the forwarder with `forwarder_id`.
+    fn remove_packet_forwarder(&mut self, key: PublicKey, forwarder_id: usize) {
+        if self
+            .mesh_routes
+            .get(&key)
+            .is_some_and(|forwarder| forwarder.id == forwarder_id)
+        {
+            self.mesh_routes.remove(&key);
+            inc!(Metrics, removed_pkt_fwder);
+            // notify local clients which received packets from the remote client that it is
+            // gone, unless it moved to this server
+            if let Some(dsts) = self.remote_sent_to.remove(&key) {
+                if !self.clients.contains_key(&key) {
+                    for dst in dsts {
+                        if self.clients.contains_key(&dst) {
+                            self.clients.send_peer_gone(&dst, key);
+                        }
+                    }
+                }
+            }
         }
     }
 
@@ -244,13 +502,14 @@ impl ServerActor {
                         ServerMessage::SendPacket((key, packet)) => {
                            tracing::trace!("send packet from: {:?} to: {:?} ({}b)", packet.src, key, packet.bytes.len());
                             let src = packet.src;
+                            let len = packet.bytes.len();
                             if self.clients.contains_key(&key) {
                                 // if this client is in our local network, just try to send the
                                 // packet
                                 if self.clients.send_packet(&key, packet).is_ok() {
-                                    self.clients.record_send(&src, key);
+                                    self.clients.record_send(&src, key, len);
                                 }
-                            } else {
+                            } else if !self.forward_packet(key, packet) {
                                 tracing::warn!("send packet: no way to reach client {key:?}, dropped packet");
                                 inc!(Metrics, send_packets_dropped);
                             }
@@ -258,14 +517,15 @@ impl ServerActor {
                        ServerMessage::SendDiscoPacket((key, packet)) => {
                            tracing::trace!("send disco packet from: {:?} to: {:?} ({}b)", packet.src, key, packet.bytes.len());
                             let src = packet.src;
+                            let len = packet.bytes.len();
                             if self.clients.contains_key(&key) {
                                 // if this client is in our local network, just try to send the
                                 // packet
                                 if self.clients.send_disco_packet(&key, packet).is_ok() {
 
-                                    self.clients.record_send(&src, key);
+                                    self.clients.record_send(&src, key, len);
                                 }
-                            } else {
+                            } else if !self.forward_packet(key, packet) {
                                 tracing::warn!("send disco packet: no way to reach client {key:?}, dropped packet");
                                 inc!(Metrics, disco_packets_dropped);
                             }
@@ -296,9 +556,52 @@ impl ServerActor {
                            if self.clients.has_client(&key, conn_num) {
                                // remove the client from the map of clients, & notify any peers that it
                                // has sent messages that it has left the network
-                               self.clients.unregister(&key);
+                               self.unregister_client(&key);
                             }
                        }
+                       ServerMessage::ForwardedPacket((key, packet)) => {
+                           tracing::trace!("forwarded packet from: {:?} to: {:?} ({}b)", packet.src, key, packet.bytes.len());
+                           self.handle_forwarded_packet(key, packet);
+                       }
+                       ServerMessage::AddWatcher(key) => {
+                           tracing::trace!("add watcher: {:?}", key);
+                           self.clients.add_watcher(key);
+                       }
+                       ServerMessage::AddPacketForwarder { key, forwarder } => {
+                           tracing::trace!("add packet forwarder {} for: {:?}", forwarder.id, key);
+                           inc!(Metrics, added_pkt_fwder);
+                           self.mesh_routes.insert(key, forwarder);
+                       }
+                       ServerMessage::RemovePacketForwarder { key, forwarder_id } => {
+                           tracing::trace!("remove packet forwarder {} for: {:?}", forwarder_id, key);
+                           self.remove_packet_forwarder(key, forwarder_id);
+                       }
+                       ServerMessage::ClearPacketForwarder(forwarder_id) => {
+                           tracing::trace!("clear packet forwarder {}", forwarder_id);
+                           let keys: Vec<_> = self
+                               .mesh_routes
+                               .iter()
+                               .filter(|(_, forwarder)| forwarder.id == forwarder_id)
+                               .map(|(key, _)| *key)
+                               .collect();
+                           for key in keys {
+                               self.remove_packet_forwarder(key, forwarder_id);
+                           }
+                       }
+                       ServerMessage::ListClients(s) => {
+                           s.send(self.clients.stats()).ok();
+                       }
+                       ServerMessage::ListFlows(s) => {
+                           s.send(self.clients.flows()).ok();
+                       }
+                       ServerMessage::DisconnectClient(key, s) => {
+                           tracing::debug!("disconnect client: {:?}", key);
+                           let connected = self.clients.contains_key(&key);
+                           if connected {
+                               self.unregister_client(&key);
+                           }
+                           s.send(connected).ok();
+                       }
                        ServerMessage::Shutdown => {
                         tracing::info!("server gracefully shutting down...");
                         // close all client connections and client read/write loops
@@ -420,6 +723,58 @@ impl AsyncWrite for MaybeTlsStream {
     }
 }
 
+/// The connection of a client, carrying relay frames.
+#[derive(Debug)]
+pub(crate) enum RelayIo {
+    /// Frames written directly to the upgraded connection.
+    Derp(Framed<MaybeTlsStream, DerpCodec>),
+    /// Frames carried in WebSocket messages, one frame per message.
+    WebSocket(WebSocketStream<MaybeTlsStream>),
+}
+
+impl Stream for RelayIo {
+    type Item = anyhow::Result<Frame>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match &mut *self {
+            RelayIo::Derp(ref mut io) => Pin::new(io).poll_next(cx),
+            RelayIo::WebSocket(ref mut io) => Pin::new(io).poll_next(cx),
+        }
+    }
+}
+
+impl Sink<Frame> for RelayIo {
+    type Error = std::io::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match &mut *self {
+            RelayIo::Derp(ref mut io) => Pin::new(io).poll_ready(cx),
+            RelayIo::WebSocket(ref mut io) => Pin::new(io).poll_ready(cx),
+        }
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, frame: Frame) -> std::io::Result<()> {
+        match &mut *self {
+            RelayIo::Derp(ref mut io) => Pin::new(io).start_send(frame),
+            RelayIo::WebSocket(ref mut io) => Pin::new(io).start_send(frame),
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match &mut *self {
+            RelayIo::Derp(ref mut io) => Pin::new(io).poll_flush(cx),
+            RelayIo::WebSocket(ref mut io) => Pin::new(io).poll_flush(cx),
+        }
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match &mut *self {
+            RelayIo::Derp(ref mut io) => Pin::new(io).poll_close(cx),
+            RelayIo::WebSocket(ref mut io) => Pin::new(io).poll_close(cx),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -446,10 +801,12 @@ mod tests {
             ClientConnBuilder {
                 key,
                 conn_num,
-                io: Framed::new(MaybeTlsStream::Test(io), DerpCodec),
+                io: RelayIo::Derp(Framed::new(MaybeTlsStream::Test(io), DerpCodec)),
                 write_timeout: None,
                 channel_capacity: 10,
+                rate_limits: Default::default(),
                 server_channel,
+                can_mesh: false,
             },
             Framed::new(test_io, DerpCodec),
         )
@@ -522,6 +879,38 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_server_actor_remote_sent_to_pruned() -> Result<()> {
+        let (server_channel, server_channel_r) = mpsc::channel(20);
+        let mut actor = ServerActor::new(SecretKey::generate().public(), server_channel_r);
+
+        let key_a = SecretKey::generate().public();
+        let (client_a, _a_io) = test_client_builder(key_a, 1, server_channel.clone());
+        actor.clients.register(client_a);
+
+        let routed = SecretKey::generate().public();
+        let unrouted = SecretKey::generate().public();
+        let (queue, _queue_r) = mpsc::channel(1);
+        actor
+            .mesh_routes
+            .insert(routed, PacketForwarder { id: 1, queue });
+
+        for src in [routed, unrouted] {
+            let packet = Packet {
+                src,
+                bytes: Bytes::from_static(b"hello"),
+            };
+            actor.handle_forwarded_packet(key_a, packet);
+        }
+        // only clients which can be reported gone are tracked
+        assert_eq!(actor.remote_sent_to.len(), 1);
+        assert!(actor.remote_sent_to[&routed].contains(&key_a));
+
+        actor.unregister_client(&key_a);
+        assert!(actor.remote_sent_to.is_empty());
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_client_conn_handler() -> Result<()> {
         // create client connection handler
@@ -531,6 +920,9 @@ mod tests {
             secret_key: client_key.clone(),
             write_timeout: None,
             server_channel: server_channel_s,
+            access_control: Default::default(),
+            client_rate_limits: Default::default(),
+            mesh_key: None,
             default_headers: Default::default(),
         };
 
@@ -546,6 +938,8 @@ mod tests {
             // send the client info
             let client_info = ClientInfo {
                 version: PROTOCOL_VERSION,
+                auth_token: None,
+                mesh_key: None,
             };
             crate::relay::codec::send_client_key(&mut client_writer, &client_key, &client_info)
                 .await?;
@@ -575,8 +969,8 @@ mod tests {
             ClientBuilder::new(
                 secret_key,
                 "127.0.0.1:0".parse().unwrap(),
-                Box::new(client_reader),
-                Box::new(client_writer),
+                Box::pin(FramedRead::new(client_reader, DerpCodec)),
+                Box::pin(FramedWrite::new(client_writer, DerpCodec)),
             ),
         )
     }
@@ -647,6 +1041,187 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_server_access_control() -> Result<()> {
+        let _guard = iroh_test::logging::setup();
+
+        let key_a = SecretKey::generate();
+        let key_b = SecretKey::generate();
+        let key_c = SecretKey::generate();
+
+        let mut server = Server::new(SecretKey::generate());
+        server.set_access_control(AccessControl::new().allow(key_a.public()).token("secret"));
+
+        // allow listed client is admitted
+        let (rw_a, client_a_builder) = make_test_client(key_a);
+        let handler = server.client_conn_handler(Default::default());
+        let handler_task =
+            tokio::spawn(async move { handler.accept(MaybeTlsStream::Test(rw_a)).await });
+        let (_client_a, _client_receiver_a) = client_a_builder.build().await?;
+        handler_task.await??;
+
+        // client with a valid token is admitted
+        let (rw_c, client_c_builder) = make_test_client(key_c);
+        let handler = server.client_conn_handler(Default::default());
+        let handler_task =
+            tokio::spawn(async move { handler.accept(MaybeTlsStream::Test(rw_c)).await });
+        let (_client_c, _client_receiver_c) = client_c_builder
+            .auth_token(Some("secret".to_string()))
+            .build()
+            .await?;
+        handler_task.await??;
+
+        // unknown client is rejected with a health frame
+        let (rw_b, client_b_builder) = make_test_client(key_b);
+        let handler = server.client_conn_handler(Default::default());
+        let handler_task =
+            tokio::spawn(async move { handler.accept(MaybeTlsStream::Test(rw_b)).await });
+        let (_client_b, mut client_receiver_b) = client_b_builder.build().await?;
+        let err = handler_task.await?.unwrap_err();
+        assert!(err.root_cause().to_string().contains("not allowed"));
+        match client_receiver_b.recv().await? {
+            ReceivedMessage::Health { problem } => {
+                assert!(problem.unwrap().starts_with("unauthorized"));
+            }
+            msg => {
+                anyhow::bail!("expected Health msg, got {msg:?}");
+            }
+        }
+
+        server.close().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_server_mesh_key() -> Result<()> {
+        let _guard = iroh_test::logging::setup();
+
+        let key_a = SecretKey::generate();
+        let key_mesh = SecretKey::generate();
+        let key_b = SecretKey::generate();
+
+        let mut server = Server::new(SecretKey::generate());
+        server.set_access_control(AccessControl::new().allow(key_a.public()));
+        server.set_mesh_key("mesh");
+
+        // mesh peer with the mesh key bypasses access control, and may watch connections
+        let (rw_mesh, client_mesh_builder) = make_test_client(key_mesh);
+        let handler = server.client_conn_handler(Default::default());
+        let handler_task =
+            tokio::spawn(async move { handler.accept(MaybeTlsStream::Test(rw_mesh)).await });
+        let (client_mesh, mut client_receiver_mesh) = client_mesh_builder
+            .mesh_key(Some("mesh".to_string()))
+            .build()
+            .await?;
+        handler_task.await??;
+        client_mesh.watch_connection_changes().await?;
+
+        // the mesh peer learns about connecting clients
+        let (rw_a, client_a_builder) = make_test_client(key_a.clone());
+        let handler = server.client_conn_handler(Default::default());
+        let handler_task =
+            tokio::spawn(async move { handler.accept(MaybeTlsStream::Test(rw_a)).await });
+        let (_client_a, _client_receiver_a) = client_a_builder.build().await?;
+        handler_task.await??;
+        match client_receiver_mesh.recv().await? {
+            ReceivedMessage::PeerPresent(key) => assert_eq!(key, key_a.public()),
+            msg => anyhow::bail!("expected PeerPresent msg, got {msg:?}"),
+        }
+
+        // client with a wrong mesh key is rejected with a health frame
+        let (rw_b, client_b_builder) = make_test_client(key_b);
+        let handler = server.client_conn_handler(Default::default());
+        let handler_task =
+            tokio::spawn(async move { handler.accept(MaybeTlsStream::Test(rw_b)).await });
+        let (_client_b, mut client_receiver_b) = client_b_builder
+            .mesh_key(Some("wrong".to_string()))
+            .build()
+            .await?;
+        let err = handler_task.await?.unwrap_err();
+        assert!(err.root_cause().to_string().contains("invalid mesh key"));
+        match client_receiver_b.recv().await? {
+            ReceivedMessage::Health { problem } => {
+                assert!(problem.unwrap().starts_with("unauthorized"));
+            }
+            msg => {
+                anyhow::bail!("expected Health msg, got {msg:?}");
+            }
+        }
+
+        server.close().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_server_admin() -> Result<()> {
+        let _guard = iroh_test::logging::setup();
+
+        let server = Server::new(SecretKey::generate());
+        let admin = server.admin_handle();
+
+        let key_a = SecretKey::generate();
+        let public_key_a = key_a.public();
+        let (rw_a, client_a_builder) = make_test_client(key_a);
+        let handler = server.client_conn_handler(Default::default());
+        let handler_task =
+            tokio::spawn(async move { handler.accept(MaybeTlsStream::Test(rw_a)).await });
+        let (client_a, _client_receiver_a) = client_a_builder.build().await?;
+        handler_task.await??;
+
+        let key_b = SecretKey::generate();
+        let public_key_b = key_b.public();
+        let (rw_b, client_b_builder) = make_test_client(key_b);
+        let handler = server.client_conn_handler(Default::default());
+        let handler_task =
+            tokio::spawn(async move { handler.accept(MaybeTlsStream::Test(rw_b)).await });
+        let (_client_b, mut client_receiver_b) = client_b_builder.build().await?;
+        handler_task.await??;
+
+        // send message from a to b
+        let msg = Bytes::from_static(b"hello client b!!");
+        client_a.send(public_key_b, msg.clone()).await?;
+        match client_receiver_b.recv().await? {
+            ReceivedMessage::ReceivedPacket { source, .. } => assert_eq!(public_key_a, source),
+            msg => anyhow::bail!("expected ReceivedPacket msg, got {msg:?}"),
+        }
+
+        // both clients are listed with their traffic
+        let mut clients = admin.clients().await?;
+        clients.sort_by_key(|client| client.key != public_key_a);
+        assert_eq!(clients.len(), 2);
+        assert_eq!(clients[0].key, public_key_a);
+        assert_eq!(clients[0].packets_recv, 1);
+        assert_eq!(clients[0].bytes_recv, msg.len() as u64);
+        assert_eq!(clients[1].key, public_key_b);
+        assert_eq!(clients[1].packets_sent, 1);
+        assert_eq!(clients[1].bytes_sent, msg.len() as u64);
+        assert!(!clients[1].mesh_peer);
+
+        let flows = admin.flows().await?;
+        assert_eq!(
+            flows,
+            vec![FlowStats {
+                src: public_key_a,
+                dst: public_key_b,
+                packets: 1,
+                bytes: msg.len() as u64,
+            }]
+        );
+
+        // disconnecting a notifies b
+        assert!(admin.disconnect(public_key_a).await?);
+        match client_receiver_b.recv().await? {
+            ReceivedMessage::PeerGone(key) => assert_eq!(public_key_a, key),
+            msg => anyhow::bail!("expected PeerGone msg, got {msg:?}"),
+        }
+        assert!(admin.client(public_key_a).await?.is_none());
+        assert!(admin.client(public_key_b).await?.is_some());
+        assert!(!admin.disconnect(public_key_a).await?);
+
+        server.close().await;
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_server_replace_client() -> Result<()> {
         tracing_subscriber::registry()
@@ -1,11 +1,16 @@
 use std::num::NonZeroU32;
+use std::time::SystemTime;
 
-use anyhow::{bail, Context, Result};
+use anyhow::{bail, ensure, Context, Result};
 use bytes::Bytes;
-use postcard::experimental::max_size::MaxSize;
 use serde::{Deserialize, Serialize};
+use tokio::{
+    sync::{mpsc, oneshot},
+    time::Instant,
+};
 
 use super::client_conn::ClientConnBuilder;
+use super::codec::MAX_PACKET_SIZE;
 use crate::key::PublicKey;
 
 pub(crate) struct RateLimiter {
@@ -36,12 +41,169 @@ impl RateLimiter {
     pub(crate) fn check_n(&self, n: usize) -> Result<()> {
         let n = NonZeroU32::new(u32::try_from(n)?).context("n not non-zero")?;
         match self.inner.check_n(n) {
-            Ok(_) => Ok(()),
-            Err(_) => bail!("batch cannot go through"),
+            Ok(Ok(_)) => Ok(()),
+            Ok(Err(_)) => bail!("batch cannot go through"),
+            Err(_) => bail!("batch exceeds the burst capacity"),
         }
     }
 }
 
+/// Per client limits on the packets a client may send through the relay server.
+///
+/// Packets exceeding the limits are dropped. Unset limits are not enforced, limits set
+/// to zero are invalid.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ClientRateLimits {
+    /// Sustained number of bytes per second a client may send.
+    pub bytes_per_second: Option<u32>,
+    /// Number of bytes a client may send in a burst.
+    ///
+    /// Defaults to `bytes_per_second`, and is never less than [`super::MAX_PACKET_SIZE`].
+    pub bytes_burst: Option<u32>,
+    /// Sustained number of packets per second a client may send.
+    pub packets_per_second: Option<u32>,
+    /// Number of packets a client may send in a burst.
+    ///
+    /// Defaults to `packets_per_second`.
+    pub packets_burst: Option<u32>,
+}
+
+impl ClientRateLimits {
+    /// Checks that no limit is set to zero.
+    pub fn validate(&self) -> Result<()> {
+        let limits = [
+            ("bytes_per_second", self.bytes_per_second),
+            ("bytes_burst", self.bytes_burst),
+            ("packets_per_second", self.packets_per_second),
+            ("packets_burst", self.packets_burst),
+        ];
+        for (name, limit) in limits {
+            ensure!(
+                limit != Some(0),
+                "client rate limit {name} must not be zero"
+            );
+        }
+        Ok(())
+    }
+}
+
+/// A token bucket that is refilled at a constant rate, up to its burst size.
+#[derive(Debug)]
+struct TokenBucket {
+    /// Tokens added per second.
+    rate: f64,
+    /// Maximum number of tokens.
+    burst: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u32, burst: u32) -> Self {
+        Self {
+            rate: rate as f64,
+            burst: burst as f64,
+            tokens: burst as f64,
+            last_refill: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * self.rate).min(self.burst);
+        self.last_refill = now;
+    }
+}
+
+/// Enforces [`ClientRateLimits`] for a single client connection.
+#[derive(Debug)]
+pub(crate) struct ClientRateLimiter {
+    bytes: Option<TokenBucket>,
+    packets: Option<TokenBucket>,
+}
+
+impl ClientRateLimiter {
+    /// Creates a limiter, or `None` if no limits are configured.
+    pub(crate) fn new(limits: &ClientRateLimits) -> Result<Option<Self>> {
+        limits.validate()?;
+        let bytes = limits.bytes_per_second.map(|rate| {
+            let burst = limits
+                .bytes_burst
+                .unwrap_or(rate)
+                .max(MAX_PACKET_SIZE as u32);
+            TokenBucket::new(rate, burst)
+        });
+        let packets = limits.packets_per_second.map(|rate| {
+            let burst = limits.packets_burst.unwrap_or(rate);
+            TokenBucket::new(rate, burst)
+        });
+        if bytes.is_none() && packets.is_none() {
+            return Ok(None);
+        }
+        Ok(Some(Self { bytes, packets }))
+    }
+
+    /// Returns whether a packet of `len` bytes may be sent now.
+    ///
+    /// Tokens are only taken if both limits allow the packet, so a dropped packet does
+    /// not count against either limit.
+    pub(crate) fn check(&mut self, len: usize) -> bool {
+        let now = Instant::now();
+        let len = len.max(1) as f64;
+        let buckets = [self.packets.as_mut(), self.bytes.as_mut()];
+        for bucket in buckets.into_iter().flatten() {
+            bucket.refill(now);
+        }
+        let has_packet = self.packets.as_ref().map_or(true, |b| b.tokens >= 1.0);
+        let has_bytes = self.bytes.as_ref().map_or(true, |b| b.tokens >= len);
+        if !(has_packet && has_bytes) {
+            return false;
+        }
+        if let Some(packets) = &mut self.packets {
+            packets.tokens -= 1.0;
+        }
+        if let Some(bytes) = &mut self.bytes {
+            bytes.tokens -= len;
+        }
+        true
+    }
+}
+
+/// Information about a client connected to a relay [`super::Server`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientStats {
+    /// The client's node id.
+    pub key: PublicKey,
+    /// When the client connected.
+    pub connected_at: SystemTime,
+    /// Bytes of packets the server sent to the client.
+    pub bytes_sent: u64,
+    /// Number of packets the server sent to the client.
+    pub packets_sent: u64,
+    /// Bytes of packets the server received from the client.
+    pub bytes_recv: u64,
+    /// Number of packets the server received from the client.
+    pub packets_recv: u64,
+    /// Whether the client uses the server as its home relay.
+    pub preferred: bool,
+    /// Whether the client is a relay server meshing with this server.
+    pub mesh_peer: bool,
+}
+
+/// Packets sent from one client of a relay [`super::Server`] to another, since the sender
+/// connected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FlowStats {
+    /// The sending client.
+    pub src: PublicKey,
+    /// The receiving client.
+    pub dst: PublicKey,
+    /// Number of packets delivered.
+    pub packets: u64,
+    /// Bytes of packets delivered.
+    pub bytes: u64,
+}
+
 /// A request to write a dataframe to a Client
 #[derive(Debug, Clone)]
 pub(crate) struct Packet {
@@ -51,10 +213,39 @@ pub(crate) struct Packet {
     pub(crate) bytes: Bytes,
 }
 
-#[derive(Debug, Serialize, Deserialize, MaxSize, PartialEq, Eq)]
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
 pub(crate) struct ClientInfo {
     /// The relay protocol version that the client was built with.
     pub(crate) version: usize,
+    /// Bearer token to authenticate with relays that restrict access.
+    pub(crate) auth_token: Option<String>,
+    /// Shared secret of relay servers meshing with each other.
+    pub(crate) mesh_key: Option<String>,
+}
+
+impl ClientInfo {
+    /// Deserializes a [`ClientInfo`].
+    ///
+    /// Older clients only send the `version`, or the `version` and `auth_token`, so missing
+    /// trailing fields are treated as `None`.
+    pub(crate) fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let (version, rest) = postcard::take_from_bytes(bytes)?;
+        let (auth_token, rest) = if rest.is_empty() {
+            (None, rest)
+        } else {
+            postcard::take_from_bytes(rest)?
+        };
+        let mesh_key = if rest.is_empty() {
+            None
+        } else {
+            postcard::from_bytes(rest)?
+        };
+        Ok(Self {
+            version,
+            auth_token,
+            mesh_key,
+        })
+    }
 }
 
 #[derive(derive_more::Debug)]
@@ -64,5 +255,91 @@ pub(crate) enum ServerMessage {
     #[debug("CreateClient")]
     CreateClient(ClientConnBuilder),
     RemoveClient((PublicKey, usize)),
+    /// Deliver a packet forwarded by a mesh peer to a local client.
+    ForwardedPacket((PublicKey, Packet)),
+    /// Notify the mesh client with this key about clients connecting and disconnecting.
+    AddWatcher(PublicKey),
+    /// Route packets for `key` to the peer relay behind `forwarder`.
+    AddPacketForwarder {
+        key: PublicKey,
+        forwarder: PacketForwarder,
+    },
+    /// Stop routing packets for `key` through the forwarder with `forwarder_id`.
+    RemovePacketForwarder {
+        key: PublicKey,
+        forwarder_id: usize,
+    },
+    /// Remove all routes through the forwarder with this id.
+    ClearPacketForwarder(usize),
+    /// List the connected clients.
+    #[debug("ListClients")]
+    ListClients(oneshot::Sender<Vec<ClientStats>>),
+    /// List the flows between connected clients.
+    #[debug("ListFlows")]
+    ListFlows(oneshot::Sender<Vec<FlowStats>>),
+    /// Disconnect a client, reporting whether it was connected.
+    #[debug("DisconnectClient({_0:?})")]
+    DisconnectClient(PublicKey, oneshot::Sender<bool>),
     Shutdown,
 }
+
+/// A connection to a peer relay server, which packets for its clients are forwarded over.
+#[derive(Debug, Clone)]
+pub(crate) struct PacketForwarder {
+    /// Identifies the mesh connection this forwarder belongs to.
+    pub(crate) id: usize,
+    /// Queue of `(destination, packet)` pairs to forward to the peer relay.
+    pub(crate) queue: mpsc::Sender<(PublicKey, Packet)>,
+}
+
+/// A change in the set of clients connected to a relay server, sent to watching mesh peers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum PeerConnState {
+    /// The client connected.
+    Present(PublicKey),
+    /// The client disconnected.
+    Gone(PublicKey),
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn client_rate_limits_reject_zero() {
+        let limits = ClientRateLimits {
+            packets_per_second: Some(0),
+            ..Default::default()
+        };
+        assert!(ClientRateLimiter::new(&limits).is_err());
+        let limits = ClientRateLimits {
+            bytes_per_second: Some(1024),
+            bytes_burst: Some(0),
+            ..Default::default()
+        };
+        assert!(ClientRateLimiter::new(&limits).is_err());
+        assert!(ClientRateLimiter::new(&ClientRateLimits::default())
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn client_rate_limiter_checks_both_limits() {
+        let limits = ClientRateLimits {
+            bytes_per_second: Some(MAX_PACKET_SIZE as u32),
+            packets_per_second: Some(1),
+            packets_burst: Some(2),
+            ..Default::default()
+        };
+        let mut limiter = ClientRateLimiter::new(&limits).unwrap().unwrap();
+        assert!(limiter.check(MAX_PACKET_SIZE));
+        // out of bytes, so the packet is dropped without using a packet token
+        assert!(!limiter.check(MAX_PACKET_SIZE));
+        assert_eq!(limiter.packets.as_ref().unwrap().tokens, 1.0);
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert!(limiter.check(MAX_PACKET_SIZE));
+        assert_eq!(limiter.packets.as_ref().unwrap().tokens, 1.0);
+    }
+}
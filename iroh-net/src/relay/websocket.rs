@@ -0,0 +1,612 @@
+//! A minimal WebSocket ([RFC 6455]) transport for the relay protocol.
+//!
+//! Instead of switching the upgraded HTTP connection to the relay protocol directly, the
+//! connection can be upgraded to a WebSocket. Every relay [`Frame`] is then carried as exactly
+//! one binary WebSocket message, encoded just like on a plain connection: the frame type, the
+//! length and the content. A peer, e.g. a browser, thus never needs to reassemble frames
+//! from message boundaries. [`WebSocketStream`] wraps the upgraded connection as a [`Stream`]
+//! and [`Sink`] of frames.
+//!
+//! The WebSocket handshake is done by hyper as part of the HTTP upgrade, only the framing of
+//! binary messages, pings and closing are needed after that. This subset of the RFC is
+//! implemented here rather than with a WebSocket crate, which would bring its own HTTP
+//! handshake and HTTP types, and more dependencies for every user of this crate.
+//!
+//! [RFC 6455]: https://datatracker.ietf.org/doc/html/rfc6455
+
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use anyhow::{ensure, Context as _};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use futures::{Sink, Stream};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::codec::{Decoder, Encoder};
+
+use super::codec::{DerpCodec, Frame, HEADER_LEN, MAX_FRAME_SIZE};
+
+/// The value of the `Upgrade` header for WebSocket upgrades.
+pub(crate) const WEBSOCKET_UPGRADE_PROTOCOL: &str = "websocket";
+
+/// The only WebSocket version defined by RFC 6455.
+pub(crate) const WEBSOCKET_VERSION: &str = "13";
+
+/// GUID appended to the `Sec-WebSocket-Key` to compute the `Sec-WebSocket-Accept` header.
+const ACCEPT_GUID: &[u8] = b"258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Maximum payload of a single WebSocket message we accept, the largest relay frame.
+const MAX_MESSAGE_SIZE: usize = HEADER_LEN + MAX_FRAME_SIZE;
+
+/// Maximum payload of a control frame, see RFC 6455, section 5.5.
+const MAX_CONTROL_PAYLOAD: usize = 125;
+
+/// Encoded frames buffered before [`Sink::poll_ready`] waits for them to be written.
+const BACKPRESSURE_BOUNDARY: usize = 64 * 1024;
+
+/// Status code of a normal closure, see RFC 6455, section 7.4.1.
+const CLOSE_NORMAL: u16 = 1000;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+const FIN: u8 = 0x80;
+const MASK: u8 = 0x80;
+
+/// Generates a random `Sec-WebSocket-Key` for a client handshake.
+pub(crate) fn generate_key() -> String {
+    data_encoding::BASE64.encode(&rand::random::<[u8; 16]>())
+}
+
+/// Computes the `Sec-WebSocket-Accept` value for a `Sec-WebSocket-Key`.
+pub(crate) fn accept_key(key: &[u8]) -> String {
+    let mut ctx = ring::digest::Context::new(&ring::digest::SHA1_FOR_LEGACY_USE_ONLY);
+    ctx.update(key);
+    ctx.update(ACCEPT_GUID);
+    data_encoding::BASE64.encode(ctx.finish().as_ref())
+}
+
+/// Which side of the WebSocket connection we are.
+///
+/// Clients must mask the frames they send, servers must not.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Role {
+    Client,
+    Server,
+}
+
+/// Carries relay frames in binary WebSocket messages over an upgraded connection.
+///
+/// Every frame sent is one binary message, and every message received must contain exactly
+/// one frame. Messages may be fragmented by the peer. Pings are answered, and a close frame
+/// ends the stream. Only the pong for the latest ping is kept while earlier frames are still
+/// being written, so a peer which pings but does not read can not make us buffer without
+/// bound.
+#[derive(Debug)]
+pub(crate) struct WebSocketStream<S> {
+    io: S,
+    role: Role,
+    /// Raw bytes read from `io` which do not form a complete frame yet
+    read_buf: BytesMut,
+    /// Payload of a fragmented binary message, if its final fragment was not received yet
+    message: Option<BytesMut>,
+    /// Encoded frames which have not been written to `io` yet
+    write_buf: BytesMut,
+    /// Payload of the pong to send once `write_buf` is empty
+    pending_pong: Option<BytesMut>,
+    /// Whether the peer sent a close frame
+    closed: bool,
+    /// Whether we sent a close frame
+    close_sent: bool,
+}
+
+/// Outcome of [`WebSocketStream::process_frame`].
+enum Processed {
+    /// More data needs to be read.
+    Incomplete,
+    /// A control frame or a fragment of a message was handled.
+    Handled,
+    /// The payload of a complete binary message.
+    Message(BytesMut),
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> WebSocketStream<S> {
+    /// Wraps an upgraded connection.
+    ///
+    /// `read_buf` holds bytes which were already read from `io` during the HTTP upgrade.
+    pub(crate) fn new(io: S, role: Role, read_buf: Bytes) -> Self {
+        Self {
+            io,
+            role,
+            read_buf: BytesMut::from(&read_buf[..]),
+            message: None,
+            write_buf: BytesMut::new(),
+            pending_pong: None,
+            closed: false,
+            close_sent: false,
+        }
+    }
+
+    fn encode_frame(&mut self, opcode: u8, payload: &[u8]) {
+        let mask_bit = match self.role {
+            Role::Client => MASK,
+            Role::Server => 0,
+        };
+        self.write_buf.put_u8(FIN | opcode);
+        match payload.len() {
+            len @ 0..=125 => self.write_buf.put_u8(mask_bit | len as u8),
+            len @ 126..=0xffff => {
+                self.write_buf.put_u8(mask_bit | 126);
+                self.write_buf.put_u16(len as u16);
+            }
+            len => {
+                self.write_buf.put_u8(mask_bit | 127);
+                self.write_buf.put_u64(len as u64);
+            }
+        }
+        match self.role {
+            Role::Client => {
+                let mask: [u8; 4] = rand::random();
+                self.write_buf.put_slice(&mask);
+                let start = self.write_buf.len();
+                self.write_buf.put_slice(payload);
+                apply_mask(&mut self.write_buf[start..], mask);
+            }
+            Role::Server => self.write_buf.put_slice(payload),
+        }
+    }
+
+    /// Queues a close frame with the given payload, unless one was sent already.
+    fn encode_close(&mut self, payload: &[u8]) {
+        if !self.close_sent {
+            self.encode_frame(OPCODE_CLOSE, payload);
+            self.close_sent = true;
+        }
+    }
+
+    /// Parses the next complete frame from `read_buf` and handles it.
+    fn process_frame(&mut self) -> io::Result<Processed> {
+        let Some(FrameHeader {
+            fin,
+            opcode,
+            header_len,
+            payload_len,
+            mask,
+        }) = parse_header(&self.read_buf)?
+        else {
+            return Ok(Processed::Incomplete);
+        };
+        if self.read_buf.len() < header_len + payload_len {
+            self.read_buf
+                .reserve(header_len + payload_len - self.read_buf.len());
+            return Ok(Processed::Incomplete);
+        }
+        // clients must mask their frames, servers must not
+        match (self.role, mask) {
+            (Role::Server, None) => return Err(invalid_data("unmasked client frame")),
+            (Role::Client, Some(_)) => return Err(invalid_data("masked server frame")),
+            _ => {}
+        }
+        self.read_buf.advance(header_len);
+        let mut payload = self.read_buf.split_to(payload_len);
+        if let Some(mask) = mask {
+            apply_mask(&mut payload, mask);
+        }
+        match opcode {
+            OPCODE_BINARY if self.message.is_some() => {
+                return Err(invalid_data("new message before the previous one finished"))
+            }
+            OPCODE_BINARY if fin => return Ok(Processed::Message(payload)),
+            OPCODE_BINARY => self.message = Some(payload),
+            OPCODE_CONTINUATION => {
+                let Some(mut message) = self.message.take() else {
+                    return Err(invalid_data("continuation frame without a message"));
+                };
+                if message.len() + payload.len() > MAX_MESSAGE_SIZE {
+                    return Err(invalid_data("message too large"));
+                }
+                message.unsplit(payload);
+                if fin {
+                    return Ok(Processed::Message(message));
+                }
+                self.message = Some(message);
+            }
+            // a pong for the latest ping is enough, see RFC 6455, section 5.5.3
+            OPCODE_PING => self.pending_pong = Some(payload),
+            OPCODE_PONG => {}
+            OPCODE_CLOSE => {
+                // nothing may be sent after the close frame
+                self.pending_pong = None;
+                // echo the status code, as required by the RFC
+                let code = payload.get(..2).unwrap_or_default().to_vec();
+                self.encode_close(&code);
+                self.closed = true;
+            }
+            OPCODE_TEXT => return Err(invalid_data("unexpected text message")),
+            _ => return Err(invalid_data("unknown opcode")),
+        }
+        Ok(Processed::Handled)
+    }
+
+    /// Writes all pending frames, and then the pending pong, to `io`.
+    fn poll_write_buf(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        loop {
+            if self.write_buf.is_empty() {
+                match self.pending_pong.take() {
+                    Some(pong) => self.encode_frame(OPCODE_PONG, &pong),
+                    None => return Poll::Ready(Ok(())),
+                }
+            }
+            let n = ready!(Pin::new(&mut self.io).poll_write(cx, &self.write_buf))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.write_buf.advance(n);
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Stream for WebSocketStream<S> {
+    type Item = anyhow::Result<Frame>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            if this.closed {
+                // best effort to send our close reply, the connection ends anyways
+                let _ = this.poll_write_buf(cx);
+                return Poll::Ready(None);
+            }
+            match this.process_frame()? {
+                Processed::Message(message) => return Poll::Ready(Some(decode_message(message))),
+                Processed::Handled => {
+                    if !this.write_buf.is_empty() || this.pending_pong.is_some() {
+                        // answer pings without waiting for the next write
+                        if let Poll::Ready(Err(err)) = this.poll_write_buf(cx) {
+                            return Poll::Ready(Some(Err(err.into())));
+                        }
+                    }
+                    continue;
+                }
+                Processed::Incomplete => {}
+            }
+            let n = ready!(tokio_util::io::poll_read_buf(
+                Pin::new(&mut this.io),
+                cx,
+                &mut this.read_buf
+            ))?;
+            if n == 0 {
+                if this.read_buf.is_empty() && this.message.is_none() {
+                    return Poll::Ready(None);
+                }
+                return Poll::Ready(Some(Err(
+                    io::Error::from(io::ErrorKind::UnexpectedEof).into()
+                )));
+            }
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Sink<Frame> for WebSocketStream<S> {
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.write_buf.len() >= BACKPRESSURE_BOUNDARY {
+            ready!(this.poll_write_buf(cx))?;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(self: Pin<&mut Self>, frame: Frame) -> io::Result<()> {
+        let this = self.get_mut();
+        if this.close_sent {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        let mut message = BytesMut::new();
+        DerpCodec.encode(frame, &mut message)?;
+        this.encode_frame(OPCODE_BINARY, &message);
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_buf(cx))?;
+        Pin::new(&mut this.io).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        this.encode_close(&CLOSE_NORMAL.to_be_bytes());
+        ready!(this.poll_write_buf(cx))?;
+        Pin::new(&mut this.io).poll_shutdown(cx)
+    }
+}
+
+/// Decodes the relay frame carried in a binary message.
+fn decode_message(mut message: BytesMut) -> anyhow::Result<Frame> {
+    let frame = DerpCodec
+        .decode(&mut message)?
+        .context("message contains an incomplete frame")?;
+    ensure!(message.is_empty(), "message contains more than one frame");
+    Ok(frame)
+}
+
+/// The parsed header of a WebSocket frame.
+#[derive(Debug)]
+struct FrameHeader {
+    fin: bool,
+    opcode: u8,
+    header_len: usize,
+    payload_len: usize,
+    mask: Option<[u8; 4]>,
+}
+
+/// Parses a frame header.
+///
+/// Returns `None` if the header is not complete yet.
+fn parse_header(buf: &[u8]) -> io::Result<Option<FrameHeader>> {
+    let (Some(b0), Some(b1)) = (buf.first(), buf.get(1)) else {
+        return Ok(None);
+    };
+    if b0 & 0x70 != 0 {
+        return Err(invalid_data("reserved bits set"));
+    }
+    let fin = b0 & FIN != 0;
+    let opcode = b0 & 0x0f;
+    let is_control = opcode & 0x08 != 0;
+    if is_control && (!fin || (b1 & 0x7f) as usize > MAX_CONTROL_PAYLOAD) {
+        return Err(invalid_data("fragmented or oversized control frame"));
+    }
+    let masked = b1 & MASK != 0;
+    let (payload_len, mut header_len) = match b1 & 0x7f {
+        126 => match buf.get(2..4) {
+            Some(len) => (u16::from_be_bytes([len[0], len[1]]) as u64, 4),
+            None => return Ok(None),
+        },
+        127 => match buf.get(2..10) {
+            Some(len) => (u64::from_be_bytes(len.try_into().expect("8 bytes")), 10),
+            None => return Ok(None),
+        },
+        len => (len as u64, 2),
+    };
+    if payload_len > MAX_MESSAGE_SIZE as u64 {
+        return Err(invalid_data("message too large"));
+    }
+    let mask = if masked {
+        let Some(mask) = buf.get(header_len..header_len + 4) else {
+            return Ok(None);
+        };
+        header_len += 4;
+        Some(mask.try_into().expect("4 bytes"))
+    } else {
+        None
+    };
+    Ok(Some(FrameHeader {
+        fin,
+        opcode,
+        header_len,
+        payload_len: payload_len as usize,
+        mask,
+    }))
+}
+
+fn apply_mask(buf: &mut [u8], mask: [u8; 4]) {
+    for (i, b) in buf.iter_mut().enumerate() {
+        *b ^= mask[i % 4];
+    }
+}
+
+fn invalid_data(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::{SinkExt, StreamExt};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use crate::key::SecretKey;
+
+    use super::*;
+
+    #[test]
+    fn test_accept_key() {
+        // example from RFC 6455, section 1.3
+        assert_eq!(
+            accept_key(b"dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    /// Encodes a relay frame like it is carried in a message.
+    fn encode(frame: Frame) -> Vec<u8> {
+        let mut buf = BytesMut::new();
+        DerpCodec.encode(frame, &mut buf).unwrap();
+        buf.to_vec()
+    }
+
+    #[tokio::test]
+    async fn test_websocket_stream() -> anyhow::Result<()> {
+        let (client, server) = tokio::io::duplex(1024);
+        let mut client = WebSocketStream::new(client, Role::Client, Bytes::new());
+        let mut server = WebSocketStream::new(server, Role::Server, Bytes::new());
+
+        let packet = Frame::SendPacket {
+            dst_key: SecretKey::generate().public(),
+            packet: vec![7u8; 60_000].into(),
+        };
+        let packet2 = packet.clone();
+        let client_task = tokio::spawn(async move {
+            client.send(Frame::KeepAlive).await?;
+            client.send(packet2).await?;
+            let frame = client.next().await.context("stream ended")??;
+            anyhow::Ok(frame)
+        });
+
+        assert_eq!(
+            server.next().await.context("stream ended")??,
+            Frame::KeepAlive
+        );
+        assert_eq!(server.next().await.context("stream ended")??, packet);
+
+        let pong = Frame::Pong { data: [1u8; 8] };
+        server.send(pong.clone()).await?;
+        assert_eq!(client_task.await??, pong);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_websocket_one_frame_per_message() -> anyhow::Result<()> {
+        let (server, mut raw) = tokio::io::duplex(1024);
+        let mut server = WebSocketStream::new(server, Role::Server, Bytes::new());
+
+        let ping = Frame::Ping { data: [2u8; 8] };
+        let health = Frame::Health {
+            problem: Bytes::from_static(b"problem"),
+        };
+        server.feed(ping.clone()).await?;
+        server.feed(health.clone()).await?;
+        server.flush().await?;
+
+        // every relay frame is a single, unfragmented binary message
+        for frame in [ping, health] {
+            let expected = encode(frame);
+            let mut header = [0u8; 2];
+            raw.read_exact(&mut header).await?;
+            assert_eq!(header, [FIN | OPCODE_BINARY, expected.len() as u8]);
+            let mut payload = vec![0u8; expected.len()];
+            raw.read_exact(&mut payload).await?;
+            assert_eq!(payload, expected);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_websocket_fragmented_message() -> anyhow::Result<()> {
+        let (server, mut raw) = tokio::io::duplex(1024);
+        let mut server = WebSocketStream::new(server, Role::Server, Bytes::new());
+
+        let frame = Frame::Health {
+            problem: Bytes::from_static(b"fragmented"),
+        };
+        let encoded = encode(frame.clone());
+        let (first, second) = encoded.split_at(3);
+        let mut start = client_frame(OPCODE_BINARY, first);
+        start[0] &= !FIN;
+        raw.write_all(&start).await?;
+        // a ping may be interleaved with the fragments
+        raw.write_all(&client_frame(OPCODE_PING, b"hi")).await?;
+        raw.write_all(&client_frame(OPCODE_CONTINUATION, second))
+            .await?;
+
+        assert_eq!(server.next().await.context("stream ended")??, frame);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_websocket_ping_close() -> anyhow::Result<()> {
+        let (client, mut raw) = tokio::io::duplex(1024);
+        let mut client = WebSocketStream::new(client, Role::Client, Bytes::new());
+
+        // unmasked ping with payload "hi", followed by a close frame
+        raw.write_all(&[FIN | OPCODE_PING, 2, b'h', b'i']).await?;
+        raw.write_all(&[FIN | OPCODE_CLOSE, 2, 0x03, 0xe8]).await?;
+
+        assert!(client.next().await.is_none());
+
+        // the client answered with a masked pong and a masked close
+        let mut reply = [0u8; 14];
+        raw.read_exact(&mut reply).await?;
+        assert_eq!(reply[0], FIN | OPCODE_PONG);
+        assert_eq!(reply[1], MASK | 2);
+        let mut pong = [reply[6], reply[7]];
+        apply_mask(&mut pong, reply[2..6].try_into()?);
+        assert_eq!(&pong, b"hi");
+        assert_eq!(reply[8], FIN | OPCODE_CLOSE);
+
+        // no frames may be sent after the close frame
+        assert!(client.send(Frame::KeepAlive).await.is_err());
+        Ok(())
+    }
+
+    /// Encodes a masked client frame.
+    fn client_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let (client, _server) = tokio::io::duplex(1);
+        let mut stream = WebSocketStream::new(client, Role::Client, Bytes::new());
+        stream.encode_frame(opcode, payload);
+        stream.write_buf.to_vec()
+    }
+
+    #[tokio::test]
+    async fn test_websocket_pings_are_bounded() -> anyhow::Result<()> {
+        let (server, mut raw) = tokio::io::duplex(256);
+        let mut server = WebSocketStream::new(server, Role::Server, Bytes::new());
+
+        // the peer sends lots of pings, but never reads the pongs
+        let ping = client_frame(OPCODE_PING, &[1u8; MAX_CONTROL_PAYLOAD]);
+        let writer = tokio::spawn(async move {
+            for _ in 0..100 {
+                raw.write_all(&ping).await?;
+            }
+            anyhow::Ok(raw)
+        });
+        let res = tokio::time::timeout(Duration::from_millis(200), server.next()).await;
+        assert!(res.is_err());
+        let _raw = writer.await??;
+        assert!(server.write_buf.len() <= 2 + MAX_CONTROL_PAYLOAD);
+        assert!(server.pending_pong.is_some());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_websocket_invalid_frames() -> anyhow::Result<()> {
+        let mut unfinished = client_frame(OPCODE_BINARY, &encode(Frame::KeepAlive));
+        unfinished[0] &= !FIN;
+        let invalid = vec![
+            // unmasked frame from a client
+            vec![FIN | OPCODE_BINARY, 2, b'h', b'i'],
+            // fragmented ping
+            {
+                let mut ping = client_frame(OPCODE_PING, b"hi");
+                ping[0] &= !FIN;
+                ping
+            },
+            // oversized ping
+            client_frame(OPCODE_PING, &[0u8; MAX_CONTROL_PAYLOAD + 1]),
+            // continuation without a message
+            client_frame(OPCODE_CONTINUATION, b"hi"),
+            // new message while the previous one is unfinished
+            [unfinished.clone(), unfinished].concat(),
+        ];
+        for frame in invalid {
+            let (server, mut raw) = tokio::io::duplex(1024);
+            let mut server = WebSocketStream::new(server, Role::Server, Bytes::new());
+            raw.write_all(&frame).await?;
+            let err = server.next().await.context("stream ended")?.unwrap_err();
+            let err = err.downcast::<io::Error>()?;
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_websocket_message_must_hold_one_frame() -> anyhow::Result<()> {
+        let two_frames = [encode(Frame::KeepAlive), encode(Frame::KeepAlive)].concat();
+        let partial_frame = &encode(Frame::Ping { data: [0u8; 8] })[..6];
+        for message in [&two_frames[..], partial_frame] {
+            let (server, mut raw) = tokio::io::duplex(1024);
+            let mut server = WebSocketStream::new(server, Role::Server, Bytes::new());
+            raw.write_all(&client_frame(OPCODE_BINARY, message)).await?;
+            let res = server.next().await.context("stream ended")?;
+            assert!(res.is_err());
+        }
+        Ok(())
+    }
+}
@@ -193,6 +193,8 @@ pub(crate) mod test {
             RelayNode {
                 url,
                 stun_port: port,
+                auth_token: None,
+                quic_port: None,
                 stun_only,
             }
         });
@@ -7,6 +7,8 @@ use tracing::{error_span, info_span, Instrument};
 use crate::key::SecretKey;
 use crate::relay::{RelayMap, RelayNode, RelayUrl};
 
+pub mod sim;
+
 /// A drop guard to clean up test infrastructure.
 ///
 /// After dropping the test infrastructure will asynchronously shutdown and release its
@@ -45,6 +47,8 @@ pub async fn run_relay_server() -> Result<(RelayMap, RelayUrl, CleanupDropGuard)
         url: url.clone(),
         stun_only: false,
         stun_port: stun_addr.port(),
+        auth_token: None,
+        quic_port: None,
     }])
     .expect("hardcoded");
 
@@ -0,0 +1,859 @@
+//! An in-process simulated network for connectivity tests.
+//!
+//! A [`SimNetwork`] is an emulated IPv4 internet over which [`SimSocket`]s exchange UDP
+//! datagrams without touching the host network.  Hosts are either attached directly to the
+//! internet, with a public address, or sit behind a [`SimRouter`] performing NAT for a
+//! private LAN.  Every host is connected through a link with configurable latency and loss,
+//! which can also be taken down to emulate network flaps.
+//!
+//! A [`MagicEndpoint`] is bound into the network with
+//! [`MagicEndpointBuilder::sim_host`].  [`SimNetwork::run_relay_server`] starts an
+//! in-process relay server with a STUN server attached to the simulated network, so the
+//! endpoints can discover their NAT mappings and holepunch.  The relay connections
+//! themselves run over loopback TCP and are not affected by the simulated links.
+//!
+//! Only the decision which datagrams are lost is seeded, see [`SimNetwork::with_seed`].
+//! Latency and timeouts use the tokio clock in real time, so tests built on the network are
+//! subject to scheduling like any other networked test.
+//!
+//! Addresses are assigned from the documentation ranges: public hosts get an address from
+//! `192.0.2.0/24`, routers from `203.0.113.0/24` and hosts behind the n-th router from
+//! `10.n.0.0/24`, which limits each of them to [`MAX_ADDRS`] entries.  Routers do not
+//! support hairpinning and NAT mappings never expire.
+//!
+//! [`MagicEndpoint`]: crate::MagicEndpoint
+//! [`MagicEndpointBuilder::sim_host`]: crate::magic_endpoint::MagicEndpointBuilder::sim_host
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::future::poll_fn;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Weak};
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+
+use anyhow::{Context as _, Result};
+use bytes::Bytes;
+use parking_lot::Mutex;
+use quinn::AsyncUdpSocket;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use tokio::sync::oneshot;
+use tracing::{debug, info_span, trace, Instrument};
+
+use super::CleanupDropGuard;
+use crate::key::SecretKey;
+use crate::net::{IpFamily, UdpSocket};
+use crate::relay::{RelayMap, RelayNode, RelayUrl};
+use crate::stun;
+
+/// The maximum number of public hosts, routers, and hosts behind each router.
+pub const MAX_ADDRS: usize = 254;
+
+/// Maximum number of datagrams queued on a [`SimSocket`] before further ones are dropped.
+const RECV_QUEUE_DEPTH: usize = 1024;
+
+/// First port handed out for sockets bound to port zero.
+const FIRST_EPHEMERAL_PORT: u16 = 50000;
+
+/// First port handed out for NAT mappings.
+const FIRST_MAPPED_PORT: u16 = 30000;
+
+/// The NAT behaviour of a [`SimRouter`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NatType {
+    /// Endpoint-independent mapping and filtering.
+    ///
+    /// Once a host sent a datagram, anyone can reach it on its mapped address.
+    FullCone,
+    /// Endpoint-independent mapping, address and port dependent filtering.
+    ///
+    /// A host keeps its mapped address for all destinations, but only receives datagrams
+    /// from addresses it sent to before.
+    PortRestricted,
+    /// Endpoint dependent mapping, address and port dependent filtering.
+    ///
+    /// A host gets a new mapped address for every destination, and only receives datagrams
+    /// from that destination on it.
+    Symmetric,
+}
+
+/// Properties of the link connecting a host to the network.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct LinkConfig {
+    /// Delay added to every datagram crossing the link.
+    pub latency: Duration,
+    /// Probability, between `0.0` and `1.0`, of a datagram crossing the link being dropped.
+    pub loss: f64,
+}
+
+/// An emulated network of hosts exchanging UDP datagrams.
+///
+/// Cloning returns a handle to the same network.
+#[derive(Debug, Clone)]
+pub struct SimNetwork {
+    state: Arc<Mutex<NetState>>,
+}
+
+impl Default for SimNetwork {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SimNetwork {
+    /// Creates an empty network.
+    pub fn new() -> Self {
+        Self::with_seed(0)
+    }
+
+    /// Creates an empty network, seeding the generator deciding which datagrams are lost.
+    pub fn with_seed(seed: u64) -> Self {
+        let state = NetState {
+            rng: StdRng::seed_from_u64(seed),
+            hosts: Default::default(),
+            routers: Default::default(),
+            sockets: Default::default(),
+            bridges: Default::default(),
+            next_public_host: 1,
+        };
+        Self {
+            state: Arc::new(Mutex::new(state)),
+        }
+    }
+
+    /// Adds a host attached directly to the internet, with a public address.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the network already has [`MAX_ADDRS`] public hosts.
+    pub fn add_public_host(&self) -> SimHost {
+        let mut state = self.state.lock();
+        assert!(
+            (state.next_public_host as usize) <= MAX_ADDRS,
+            "too many public hosts"
+        );
+        let ip = Ipv4Addr::new(192, 0, 2, state.next_public_host).into();
+        state.next_public_host += 1;
+        state.hosts.insert(ip, HostState::new(None));
+        SimHost {
+            network: self.clone(),
+            ip,
+        }
+    }
+
+    /// Adds a router with the given NAT behaviour, to which hosts on a private LAN can be
+    /// added.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the network already has [`MAX_ADDRS`] routers.
+    pub fn add_router(&self, nat: NatType) -> SimRouter {
+        let mut state = self.state.lock();
+        let id = state.routers.len();
+        assert!(id < MAX_ADDRS, "too many routers");
+        let public_ip = Ipv4Addr::new(203, 0, 113, id as u8 + 1).into();
+        state.routers.push(RouterState {
+            public_ip,
+            nat,
+            next_host: 1,
+            next_port: FIRST_MAPPED_PORT,
+            mappings: Default::default(),
+            ports: Default::default(),
+        });
+        SimRouter {
+            network: self.clone(),
+            id,
+            public_ip,
+        }
+    }
+
+    /// Runs a relay server for endpoints in this network.
+    ///
+    /// The relay server listens on loopback, its STUN server is attached to the network at
+    /// the same address.  Like [`super::run_relay_server`], the returned `Url` is the url of
+    /// the relay server in the returned [`RelayMap`].
+    pub async fn run_relay_server(&self) -> Result<(RelayMap, RelayUrl, CleanupDropGuard)> {
+        let server = crate::relay::http::ServerBuilder::new((Ipv4Addr::LOCALHOST, 0).into())
+            .secret_key(Some(SecretKey::generate()))
+            .spawn()
+            .await?;
+        let relay_addr = server.addr();
+
+        // STUN requests reach the gateway over loopback and are routed through the network
+        // to the STUN server, as if they were sent by the simulated socket they bridge.
+        let gateway = tokio::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let stun_port = gateway.local_addr()?.port();
+        let stun_host = self.host_with_ip(Ipv4Addr::LOCALHOST.into());
+        let stun_socket = stun_host.bind(stun_port)?;
+        let stun_addr = stun_socket.local_addr();
+
+        let url: RelayUrl = format!("http://{relay_addr}").parse()?;
+        let relay_map = RelayMap::from_nodes([RelayNode {
+            url: url.clone(),
+            stun_only: false,
+            stun_port,
+            auth_token: None,
+            quic_port: None,
+        }])?;
+
+        let (tx, rx) = oneshot::channel();
+        let network = self.clone();
+        tokio::spawn(
+            async move {
+                tokio::select! {
+                    _ = rx => {}
+                    _ = run_stun_gateway(network, gateway, stun_addr) => {}
+                    _ = run_stun_server(stun_socket) => {}
+                }
+                server.shutdown().await;
+            }
+            .instrument(info_span!("sim-relay", %stun_addr)),
+        );
+
+        Ok((relay_map, url, CleanupDropGuard(tx)))
+    }
+
+    /// Returns the public host with the given address, adding it if necessary.
+    fn host_with_ip(&self, ip: IpAddr) -> SimHost {
+        self.state
+            .lock()
+            .hosts
+            .entry(ip)
+            .or_insert_with(|| HostState::new(None));
+        SimHost {
+            network: self.clone(),
+            ip,
+        }
+    }
+
+    /// Sends a datagram from `src` to `dst`, delivering it after the links' latency.
+    fn send(&self, src: SocketAddr, dst: SocketAddr, data: Bytes) {
+        let mut state = self.state.lock();
+        let Some(route) = state.route(src, dst) else {
+            trace!(%src, %dst, "datagram dropped");
+            return;
+        };
+        let Some(socket) = state.sockets.get(&route.to).and_then(Weak::upgrade) else {
+            trace!(%src, dst = %route.to, "no socket bound, datagram dropped");
+            return;
+        };
+        drop(state);
+
+        trace!(from = %route.from, to = %route.to, len = data.len(), "datagram routed");
+        if route.latency.is_zero() {
+            socket.deliver(route.from, data);
+        } else {
+            tokio::spawn(async move {
+                tokio::time::sleep(route.latency).await;
+                socket.deliver(route.from, data);
+            });
+        }
+    }
+}
+
+/// A router performing NAT for the hosts on its LAN.
+#[derive(Debug, Clone)]
+pub struct SimRouter {
+    network: SimNetwork,
+    id: usize,
+    public_ip: IpAddr,
+}
+
+impl SimRouter {
+    /// Returns the public address of the router.
+    pub fn public_ip(&self) -> IpAddr {
+        self.public_ip
+    }
+
+    /// Adds a host to the LAN of this router.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the router already has [`MAX_ADDRS`] hosts.
+    pub fn add_host(&self) -> SimHost {
+        let mut state = self.network.state.lock();
+        let router = &mut state.routers[self.id];
+        assert!((router.next_host as usize) <= MAX_ADDRS, "too many hosts");
+        let n = router.next_host;
+        router.next_host += 1;
+        let ip = Ipv4Addr::new(10, self.id as u8 + 1, 0, n).into();
+        state.hosts.insert(ip, HostState::new(Some(self.id)));
+        SimHost {
+            network: self.network.clone(),
+            ip,
+        }
+    }
+}
+
+/// A host in a [`SimNetwork`].
+#[derive(Debug, Clone)]
+pub struct SimHost {
+    network: SimNetwork,
+    ip: IpAddr,
+}
+
+impl SimHost {
+    /// Returns the address of the host.
+    ///
+    /// For hosts behind a [`SimRouter`] this is the private address on its LAN.
+    pub fn ip(&self) -> IpAddr {
+        self.ip
+    }
+
+    /// Binds a socket on this host.
+    ///
+    /// Port zero picks a free port.
+    pub fn bind(&self, port: u16) -> io::Result<SimSocket> {
+        let mut state = self.network.state.lock();
+        let host = state
+            .hosts
+            .get_mut(&self.ip)
+            .expect("hosts are never removed");
+        let port = match port {
+            0 => {
+                let port = host.next_port;
+                host.next_port += 1;
+                port
+            }
+            port => port,
+        };
+        let addr = SocketAddr::new(self.ip, port);
+        if state
+            .sockets
+            .get(&addr)
+            .is_some_and(|socket| socket.strong_count() > 0)
+        {
+            return Err(io::ErrorKind::AddrInUse.into());
+        }
+        let inner = Arc::new(SocketInner {
+            addr,
+            network: self.network.clone(),
+            recv: Default::default(),
+        });
+        state.sockets.insert(addr, Arc::downgrade(&inner));
+        debug!(%addr, "bound simulated socket");
+        Ok(SimSocket { inner })
+    }
+
+    /// Sets the latency and loss of the link connecting this host.
+    pub fn set_link(&self, link: LinkConfig) {
+        self.with_state(|host| host.link = link);
+    }
+
+    /// Takes the link connecting this host up or down.
+    ///
+    /// All datagrams sent from or to the host are dropped while its link is down.
+    pub fn set_link_up(&self, up: bool) {
+        self.with_state(|host| host.up = up);
+    }
+
+    fn with_state(&self, f: impl FnOnce(&mut HostState)) {
+        let mut state = self.network.state.lock();
+        f(state
+            .hosts
+            .get_mut(&self.ip)
+            .expect("hosts are never removed"));
+    }
+}
+
+/// A UDP socket bound in a [`SimNetwork`].
+///
+/// Sending never blocks, datagrams are dropped if they can not be delivered.
+#[derive(Debug, Clone)]
+pub struct SimSocket {
+    inner: Arc<SocketInner>,
+}
+
+impl SimSocket {
+    /// Returns the address this socket is bound to.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.inner.addr
+    }
+
+    /// Sends a datagram to the given address.
+    pub fn send_to(&self, buf: &[u8], dst: SocketAddr) -> usize {
+        self.inner
+            .network
+            .send(self.inner.addr, dst, Bytes::copy_from_slice(buf));
+        buf.len()
+    }
+
+    /// Receives a datagram, returning its contents and the address it was sent from.
+    pub async fn recv_from(&self) -> (Bytes, SocketAddr) {
+        poll_fn(|cx| self.poll_recv_from(cx)).await
+    }
+
+    /// Polls to receive a datagram.
+    pub fn poll_recv_from(&self, cx: &mut Context<'_>) -> Poll<(Bytes, SocketAddr)> {
+        let mut recv = self.inner.recv.lock();
+        match recv.packets.pop_front() {
+            Some((src, data)) => Poll::Ready((data, src)),
+            None => {
+                recv.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+
+    /// Binds a loopback socket whose STUN requests are routed through the network as if they
+    /// were sent by this socket.
+    ///
+    /// Netcheck sends its STUN probes on a real socket, which is bridged this way.  Only the
+    /// STUN servers started by [`SimNetwork::run_relay_server`] accept bridged datagrams.
+    pub(crate) fn bridge_socket(&self) -> Result<UdpSocket> {
+        let socket = UdpSocket::bind_local(IpFamily::V4, 0)?;
+        let addr = socket.local_addr().context("bridge socket not bound")?;
+        self.inner
+            .network
+            .state
+            .lock()
+            .bridges
+            .insert(addr, Arc::downgrade(&self.inner));
+        Ok(socket)
+    }
+}
+
+impl AsyncUdpSocket for SimSocket {
+    fn poll_send(
+        &self,
+        _state: &quinn_udp::UdpState,
+        _cx: &mut Context,
+        transmits: &[quinn_udp::Transmit],
+    ) -> Poll<io::Result<usize>> {
+        for transmit in transmits {
+            let segment_size = transmit.segment_size.unwrap_or(transmit.contents.len());
+            for segment in transmit.contents.chunks(segment_size.max(1)) {
+                self.send_to(segment, transmit.destination);
+            }
+        }
+        Poll::Ready(Ok(transmits.len()))
+    }
+
+    fn poll_recv(
+        &self,
+        cx: &mut Context,
+        bufs: &mut [io::IoSliceMut<'_>],
+        meta: &mut [quinn_udp::RecvMeta],
+    ) -> Poll<io::Result<usize>> {
+        let mut recv = self.inner.recv.lock();
+        let mut n = 0;
+        for (buf, meta) in bufs.iter_mut().zip(meta.iter_mut()) {
+            let Some((src, data)) = recv.packets.pop_front() else {
+                break;
+            };
+            let len = data.len().min(buf.len());
+            buf[..len].copy_from_slice(&data[..len]);
+            *meta = quinn_udp::RecvMeta {
+                addr: src,
+                len,
+                stride: len,
+                ecn: None,
+                dst_ip: Some(self.inner.addr.ip()),
+            };
+            n += 1;
+        }
+        if n == 0 {
+            recv.waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.inner.addr)
+    }
+
+    fn may_fragment(&self) -> bool {
+        false
+    }
+}
+
+#[derive(Debug)]
+struct SocketInner {
+    addr: SocketAddr,
+    network: SimNetwork,
+    recv: Mutex<RecvQueue>,
+}
+
+impl SocketInner {
+    fn deliver(&self, src: SocketAddr, data: Bytes) {
+        let mut recv = self.recv.lock();
+        if recv.packets.len() >= RECV_QUEUE_DEPTH {
+            trace!(addr = %self.addr, "receive queue full, datagram dropped");
+            return;
+        }
+        recv.packets.push_back((src, data));
+        if let Some(waker) = recv.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+impl Drop for SocketInner {
+    fn drop(&mut self) {
+        let mut state = self.network.state.lock();
+        state.sockets.remove(&self.addr);
+        state.bridges.retain(|_, socket| socket.strong_count() > 0);
+    }
+}
+
+#[derive(Debug, Default)]
+struct RecvQueue {
+    packets: VecDeque<(SocketAddr, Bytes)>,
+    waker: Option<Waker>,
+}
+
+#[derive(Debug)]
+struct NetState {
+    rng: StdRng,
+    hosts: HashMap<IpAddr, HostState>,
+    routers: Vec<RouterState>,
+    sockets: HashMap<SocketAddr, Weak<SocketInner>>,
+    /// Simulated sockets by the address of their bridge socket.
+    bridges: HashMap<SocketAddr, Weak<SocketInner>>,
+    next_public_host: u8,
+}
+
+/// The path of a datagram through the network.
+#[derive(Debug)]
+struct Route {
+    /// The source address as seen by the receiver.
+    from: SocketAddr,
+    /// The address of the receiving socket.
+    to: SocketAddr,
+    latency: Duration,
+}
+
+impl NetState {
+    fn route(&mut self, src: SocketAddr, dst: SocketAddr) -> Option<Route> {
+        let src_host = self.hosts.get(&src.ip())?;
+        let (src_router, src_link, src_up) = (src_host.router, src_host.link, src_host.up);
+        if !src_up || self.is_lost(src_link) {
+            return None;
+        }
+
+        let dst_host = self.hosts.get(&dst.ip());
+        let same_lan = src_router.is_some() && dst_host.is_some_and(|h| h.router == src_router);
+        let from = match src_router {
+            Some(router) if !same_lan => self.routers[router].map_outbound(src, dst),
+            _ => src,
+        };
+        let to = match dst_host {
+            Some(host) if host.router.is_none() || same_lan => dst,
+            // private address on another LAN
+            Some(_) => return None,
+            None => {
+                let router = self.routers.iter().position(|r| r.public_ip == dst.ip())?;
+                if src_router == Some(router) {
+                    // no hairpinning
+                    return None;
+                }
+                self.routers[router].map_inbound(from, dst.port())?
+            }
+        };
+
+        let dst_host = self.hosts.get(&to.ip())?;
+        let (dst_link, dst_up) = (dst_host.link, dst_host.up);
+        if !dst_up || self.is_lost(dst_link) {
+            return None;
+        }
+        Some(Route {
+            from,
+            to,
+            latency: src_link.latency + dst_link.latency,
+        })
+    }
+
+    fn is_lost(&mut self, link: LinkConfig) -> bool {
+        link.loss > 0.0 && self.rng.gen_bool(link.loss.min(1.0))
+    }
+}
+
+#[derive(Debug)]
+struct HostState {
+    router: Option<usize>,
+    link: LinkConfig,
+    up: bool,
+    next_port: u16,
+}
+
+impl HostState {
+    fn new(router: Option<usize>) -> Self {
+        Self {
+            router,
+            link: LinkConfig::default(),
+            up: true,
+            next_port: FIRST_EPHEMERAL_PORT,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct RouterState {
+    public_ip: IpAddr,
+    nat: NatType,
+    next_host: u8,
+    next_port: u16,
+    /// Mapped ports by private address and, for symmetric NATs, destination.
+    mappings: HashMap<(SocketAddr, Option<SocketAddr>), u16>,
+    ports: HashMap<u16, PortMapping>,
+}
+
+#[derive(Debug)]
+struct PortMapping {
+    private: SocketAddr,
+    /// Addresses the private address sent to through this mapping.
+    contacted: HashSet<SocketAddr>,
+}
+
+impl RouterState {
+    /// Translates the source address of an outgoing datagram.
+    fn map_outbound(&mut self, src: SocketAddr, dst: SocketAddr) -> SocketAddr {
+        let key = match self.nat {
+            NatType::Symmetric => (src, Some(dst)),
+            NatType::FullCone | NatType::PortRestricted => (src, None),
+        };
+        let Self {
+            mappings,
+            ports,
+            next_port,
+            ..
+        } = self;
+        let port = *mappings.entry(key).or_insert_with(|| {
+            let port = *next_port;
+            *next_port += 1;
+            ports.insert(
+                port,
+                PortMapping {
+                    private: src,
+                    contacted: Default::default(),
+                },
+            );
+            port
+        });
+        ports.get_mut(&port).expect("mapped").contacted.insert(dst);
+        SocketAddr::new(self.public_ip, port)
+    }
+
+    /// Translates the destination address of an incoming datagram, if it passes filtering.
+    fn map_inbound(&self, from: SocketAddr, port: u16) -> Option<SocketAddr> {
+        let mapping = self.ports.get(&port)?;
+        match self.nat {
+            NatType::FullCone => Some(mapping.private),
+            NatType::PortRestricted | NatType::Symmetric => {
+                mapping.contacted.contains(&from).then_some(mapping.private)
+            }
+        }
+    }
+}
+
+/// Forwards STUN requests from bridge sockets into the network.
+async fn run_stun_gateway(
+    network: SimNetwork,
+    gateway: tokio::net::UdpSocket,
+    stun_addr: SocketAddr,
+) {
+    let mut buf = vec![0u8; 64 << 10];
+    loop {
+        let (n, addr) = match gateway.recv_from(&mut buf).await {
+            Ok(res) => res,
+            Err(err) => {
+                debug!("gateway failed to read: {err:?}");
+                continue;
+            }
+        };
+        let socket = network
+            .state
+            .lock()
+            .bridges
+            .get(&addr)
+            .and_then(Weak::upgrade);
+        match socket {
+            Some(socket) => {
+                network.send(socket.addr, stun_addr, Bytes::copy_from_slice(&buf[..n]));
+            }
+            None => trace!(%addr, "datagram from unknown bridge socket dropped"),
+        }
+    }
+}
+
+/// Answers STUN binding requests received on `socket`.
+async fn run_stun_server(socket: SimSocket) {
+    loop {
+        let (data, src) = socket.recv_from().await;
+        match stun::parse_binding_request(&data) {
+            Ok(txid) => {
+                trace!(%src, "received binding request");
+                socket.send_to(&stun::response(txid, src), src);
+            }
+            Err(_) => debug!(%src, "received non STUN datagram"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn recv(socket: &SimSocket) -> Option<(Bytes, SocketAddr)> {
+        tokio::time::timeout(Duration::from_millis(100), socket.recv_from())
+            .await
+            .ok()
+    }
+
+    #[tokio::test]
+    async fn test_public_hosts() -> Result<()> {
+        let network = SimNetwork::new();
+        let a = network.add_public_host().bind(0)?;
+        let b_host = network.add_public_host();
+        let b = b_host.bind(1234)?;
+
+        a.send_to(b"hello", b.local_addr());
+        let (data, src) = recv(&b).await.context("not delivered")?;
+        assert_eq!(&data[..], b"hello");
+        assert_eq!(src, a.local_addr());
+
+        let err = b_host.bind(1234).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+        drop(b);
+        assert!(b_host.bind(1234).is_ok());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_nat_mapping() -> Result<()> {
+        let network = SimNetwork::new();
+        let server_1 = network.add_public_host().bind(0)?;
+        let server_2 = network.add_public_host().bind(0)?;
+
+        for nat in [
+            NatType::FullCone,
+            NatType::PortRestricted,
+            NatType::Symmetric,
+        ] {
+            let router = network.add_router(nat);
+            let client = router.add_host().bind(0)?;
+
+            client.send_to(b"1", server_1.local_addr());
+            let (_, mapped_1) = recv(&server_1).await.context("not delivered")?;
+            assert_eq!(mapped_1.ip(), router.public_ip());
+            client.send_to(b"2", server_2.local_addr());
+            let (_, mapped_2) = recv(&server_2).await.context("not delivered")?;
+            assert_eq!(mapped_2.ip(), router.public_ip());
+            assert_eq!(mapped_1 == mapped_2, nat != NatType::Symmetric, "{nat:?}");
+
+            // replies to the mapped address pass
+            server_1.send_to(b"reply", mapped_1);
+            assert!(recv(&client).await.is_some(), "{nat:?}");
+
+            // datagrams from a host never contacted only pass full cone NATs
+            let stranger = network.add_public_host().bind(0)?;
+            stranger.send_to(b"hi", mapped_1);
+            assert_eq!(
+                recv(&client).await.is_some(),
+                nat == NatType::FullCone,
+                "{nat:?}"
+            );
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_private_addresses() -> Result<()> {
+        let network = SimNetwork::new();
+        let router_1 = network.add_router(NatType::FullCone);
+        let router_2 = network.add_router(NatType::FullCone);
+        let a = router_1.add_host().bind(0)?;
+        let b = router_1.add_host().bind(0)?;
+        let c = router_2.add_host().bind(0)?;
+
+        // hosts on one LAN reach each other directly
+        a.send_to(b"hello", b.local_addr());
+        let (_, src) = recv(&b).await.context("not delivered")?;
+        assert_eq!(src, a.local_addr());
+
+        // private addresses of another LAN are not routable
+        c.send_to(b"hello", a.local_addr());
+        assert!(recv(&a).await.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_address_limits() {
+        let network = SimNetwork::new();
+        let routers: Vec<_> = (0..MAX_ADDRS)
+            .map(|_| network.add_router(NatType::FullCone))
+            .collect();
+        let ips: HashSet<_> = routers.iter().map(|router| router.public_ip()).collect();
+        assert_eq!(ips.len(), MAX_ADDRS);
+        let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            network.add_router(NatType::FullCone)
+        }));
+        assert!(res.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_link_latency_loss_and_flaps() -> Result<()> {
+        let network = SimNetwork::new();
+        let a_host = network.add_public_host();
+        let a = a_host.bind(0)?;
+        let b = network.add_public_host().bind(0)?;
+
+        a_host.set_link(LinkConfig {
+            latency: Duration::from_millis(50),
+            loss: 0.0,
+        });
+        let start = tokio::time::Instant::now();
+        a.send_to(b"hello", b.local_addr());
+        recv(&b).await.context("not delivered")?;
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        a_host.set_link_up(false);
+        a.send_to(b"hello", b.local_addr());
+        assert!(recv(&b).await.is_none());
+        a_host.set_link_up(true);
+
+        a_host.set_link(LinkConfig {
+            latency: Duration::ZERO,
+            loss: 1.0,
+        });
+        a.send_to(b"hello", b.local_addr());
+        assert!(recv(&b).await.is_none());
+
+        a_host.set_link(LinkConfig {
+            latency: Duration::ZERO,
+            loss: 0.5,
+        });
+        for _ in 0..100 {
+            a.send_to(b"hello", b.local_addr());
+        }
+        let mut received = 0;
+        while recv(&b).await.is_some() {
+            received += 1;
+        }
+        assert!((20..80).contains(&received), "received {received}");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_stun() -> Result<()> {
+        let network = SimNetwork::new();
+        let (relay_map, _url, _guard) = network.run_relay_server().await?;
+        let node = relay_map.nodes().next().context("no relay node")?.clone();
+        let stun_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), node.stun_port);
+
+        let router = network.add_router(NatType::PortRestricted);
+        let socket = router.add_host().bind(0)?;
+        let bridge = socket.bridge_socket()?;
+
+        let txid = stun::TransactionId::default();
+        bridge.send_to(&stun::request(txid), stun_addr).await?;
+        let (data, src) = recv(&socket).await.context("no STUN response")?;
+        assert_eq!(src, stun_addr);
+        let (txid_back, addr) = stun::parse_response(&data)?;
+        assert_eq!(txid, txid_back);
+        assert_eq!(addr.ip(), router.public_ip());
+        Ok(())
+    }
+}
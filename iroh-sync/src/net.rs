@@ -5,7 +5,11 @@ use std::{
     time::{Duration, Instant},
 };
 
-use iroh_net::{key::PublicKey, magic_endpoint::get_remote_node_id, MagicEndpoint, NodeAddr};
+use iroh_net::{
+    key::PublicKey,
+    magic_endpoint::{get_remote_node_id, is_alpn_unsupported},
+    MagicEndpoint, NodeAddr,
+};
 use serde::{Deserialize, Serialize};
 use tracing::{debug, error_span, trace, Instrument};
 
@@ -21,11 +25,24 @@ use crate::metrics::Metrics;
 use iroh_metrics::inc;
 
 /// The ALPN identifier for the iroh-sync protocol
-pub const SYNC_ALPN: &[u8] = b"/iroh-sync/1";
+///
+/// Connections with this ALPN carry any number of sync requests, each on its own
+/// bi-directional stream, see [`handle_stream`].
+pub const SYNC_ALPN: &[u8] = b"/iroh-sync/2";
+
+/// The ALPN identifier for version 1 of the iroh-sync protocol
+///
+/// Connections with this ALPN carry a single sync request on their first stream, see
+/// [`handle_connection`]. Only accepted to serve peers which do not support [`SYNC_ALPN`] yet.
+pub const SYNC_ALPN_V1: &[u8] = b"/iroh-sync/1";
 
 mod codec;
 
 /// Connect to a peer and sync a replica
+///
+/// The connection is taken from the endpoint's [`iroh_net::pool::ConnectionPool`], so syncs
+/// with the same peer reuse a single connection, each running on its own stream. Peers which
+/// only support [`SYNC_ALPN_V1`] are synced over a new connection each time.
 pub async fn connect_and_sync(
     endpoint: &MagicEndpoint,
     sync: &SyncHandle,
@@ -35,10 +52,24 @@ pub async fn connect_and_sync(
     let t_start = Instant::now();
     let peer_id = peer.node_id;
     trace!("connect");
-    let connection = endpoint
-        .connect(peer, SYNC_ALPN)
+    let connection = match endpoint
+        .connection_pool()
+        .connect(peer.clone(), SYNC_ALPN)
         .await
-        .map_err(ConnectError::connect)?;
+    {
+        Ok(connection) => connection,
+        Err(err) if is_alpn_unsupported(&err) => {
+            // The peer only speaks version 1, which carries a single request per connection,
+            // so this connection is not pooled and is closed once dropped.
+            debug!("peer does not support {SYNC_ALPN:?}, falling back to version 1");
+            endpoint
+                .connect(peer, SYNC_ALPN_V1)
+                .await
+                .map_err(ConnectError::connect)?
+                .into()
+        }
+        Err(err) => return Err(ConnectError::connect(err)),
+    };
 
     let (mut send_stream, mut recv_stream) =
         connection.open_bi().await.map_err(ConnectError::connect)?;
@@ -104,6 +135,9 @@ pub enum AcceptOutcome {
 }
 
 /// Handle an iroh-sync connection and sync all shared documents in the replica store.
+///
+/// This handles the first sync request on the connection only, as sent by peers connecting with
+/// [`SYNC_ALPN_V1`]. Use [`handle_stream`] for the requests of [`SYNC_ALPN`] connections.
 pub async fn handle_connection<F, Fut>(
     sync: SyncHandle,
     connecting: quinn::Connecting,
@@ -116,11 +150,51 @@ where
     let t_start = Instant::now();
     let connection = connecting.await.map_err(AcceptError::connect)?;
     let peer = get_remote_node_id(&connection).map_err(AcceptError::connect)?;
-    let (mut send_stream, mut recv_stream) = connection
+    let (send_stream, recv_stream) = connection
         .accept_bi()
         .await
         .map_err(|e| AcceptError::open(peer, e))?;
+    run_bob(sync, peer, send_stream, recv_stream, accept_cb, t_start).await
+}
+
+/// Handle a single sync request, received on a stream of an iroh-sync connection.
+///
+/// Peers connecting with [`SYNC_ALPN`] through a [`iroh_net::pool::ConnectionPool`] send each
+/// sync request on a new bi-directional stream of the same connection.
+pub async fn handle_stream<F, Fut>(
+    sync: SyncHandle,
+    peer: PublicKey,
+    send_stream: quinn::SendStream,
+    recv_stream: quinn::RecvStream,
+    accept_cb: F,
+) -> Result<SyncFinished, AcceptError>
+where
+    F: Fn(NamespaceId, PublicKey) -> Fut,
+    Fut: Future<Output = AcceptOutcome>,
+{
+    run_bob(
+        sync,
+        peer,
+        send_stream,
+        recv_stream,
+        accept_cb,
+        Instant::now(),
+    )
+    .await
+}
 
+async fn run_bob<F, Fut>(
+    sync: SyncHandle,
+    peer: PublicKey,
+    mut send_stream: quinn::SendStream,
+    mut recv_stream: quinn::RecvStream,
+    accept_cb: F,
+    t_start: Instant,
+) -> Result<SyncFinished, AcceptError>
+where
+    F: Fn(NamespaceId, PublicKey) -> Fut,
+    Fut: Future<Output = AcceptOutcome>,
+{
     let t_connect = t_start.elapsed();
     let span = error_span!("accept", peer = %peer.fmt_short(), namespace = tracing::field::Empty);
     span.in_scope(|| {
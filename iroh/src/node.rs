@@ -17,6 +17,7 @@ use anyhow::{anyhow, Result};
 use futures::future::{BoxFuture, Shared};
 use futures::{FutureExt, StreamExt};
 use iroh_bytes::downloader::Downloader;
+use iroh_bytes::provider::ProviderOptions;
 use iroh_bytes::store::Store as BaoStore;
 use iroh_bytes::BlobFormat;
 use iroh_bytes::Hash;
@@ -110,6 +111,7 @@ struct NodeInner<D> {
     rt: LocalPoolHandle,
     pub(crate) sync: SyncEngine,
     downloader: Downloader,
+    provider_options: ProviderOptions,
 }
 
 /// Events emitted by the [`Node`] informing about the current status.
@@ -12,17 +12,24 @@ use iroh_base::key::SecretKey;
 use iroh_bytes::{
     downloader::Downloader,
     protocol::Closed,
+    provider::{
+        CustomGetHandler, ProviderOptions, PushHandler, RequestAuthorizationHandler, UploadLimiter,
+        UploadLimits,
+    },
     store::{GcMarkEvent, GcSweepEvent, Map, Store as BaoStore},
 };
-use iroh_gossip::net::{Gossip, GOSSIP_ALPN};
+use iroh_gossip::net::{Gossip, GOSSIP_ALPN, GOSSIP_ALPN_V0};
 use iroh_net::{
-    discovery::{dns::DnsDiscovery, pkarr_publish::PkarrPublisher, ConcurrentDiscovery, Discovery},
+    discovery::{
+        dns::DnsDiscovery, local_swarm::LocalSwarmDiscovery, pkarr_publish::PkarrPublisher,
+        ConcurrentDiscovery, Discovery,
+    },
     magic_endpoint::get_alpn,
     relay::RelayMode,
     util::AbortingJoinHandle,
     MagicEndpoint,
 };
-use iroh_sync::net::SYNC_ALPN;
+use iroh_sync::net::{SYNC_ALPN, SYNC_ALPN_V1};
 use quic_rpc::{
     transport::{misc::DummyServerEndpoint, quinn::QuinnServerEndpoint},
     RpcServer, ServiceEndpoint,
@@ -42,7 +49,13 @@ use crate::{
 
 use super::{rpc, Callbacks, EventCallback, Node, RpcStatus};
 
-pub const PROTOCOLS: [&[u8]; 3] = [iroh_bytes::protocol::ALPN, GOSSIP_ALPN, SYNC_ALPN];
+pub const PROTOCOLS: [&[u8]; 5] = [
+    iroh_bytes::protocol::ALPN,
+    GOSSIP_ALPN,
+    GOSSIP_ALPN_V0,
+    SYNC_ALPN,
+    SYNC_ALPN_V1,
+];
 
 /// Default bind address for the node.
 /// 11204 is "iroh" in leetspeak <https://simple.wikipedia.org/wiki/Leet>
@@ -86,6 +99,7 @@ where
     gc_policy: GcPolicy,
     node_discovery: NodeDiscoveryConfig,
     docs_store: iroh_sync::store::fs::Store,
+    provider_options: ProviderOptions,
     #[cfg(any(test, feature = "test-utils"))]
     insecure_skip_relay_cert_verify: bool,
 }
@@ -109,6 +123,11 @@ pub enum NodeDiscoveryConfig {
     /// This enables the [`DnsDiscovery`] service.
     #[default]
     Default,
+    /// Use the default discovery mechanism, and in addition find nodes on the
+    /// local network.
+    ///
+    /// This enables the [`DnsDiscovery`] and [`LocalSwarmDiscovery`] services.
+    DefaultWithLocalSwarm,
     /// Use a custom discovery mechanism.
     Custom(Box<dyn Discovery>),
 }
@@ -126,6 +145,7 @@ impl Default for Builder<iroh_bytes::store::mem::Store> {
             gc_policy: GcPolicy::Disabled,
             docs_store: iroh_sync::store::Store::memory(),
             node_discovery: Default::default(),
+            provider_options: ProviderOptions::default(),
             #[cfg(any(test, feature = "test-utils"))]
             insecure_skip_relay_cert_verify: false,
         }
@@ -150,6 +170,7 @@ impl<D: Map> Builder<D> {
             gc_policy: GcPolicy::Disabled,
             docs_store,
             node_discovery: Default::default(),
+            provider_options: ProviderOptions::default(),
             #[cfg(any(test, feature = "test-utils"))]
             insecure_skip_relay_cert_verify: false,
         }
@@ -211,6 +232,7 @@ where
             gc_policy: self.gc_policy,
             docs_store,
             node_discovery: self.node_discovery,
+            provider_options: self.provider_options,
             #[cfg(any(test, feature = "test-utils"))]
             insecure_skip_relay_cert_verify: false,
         })
@@ -230,6 +252,7 @@ where
             gc_policy: self.gc_policy,
             docs_store: self.docs_store,
             node_discovery: self.node_discovery,
+            provider_options: self.provider_options,
             #[cfg(any(test, feature = "test-utils"))]
             insecure_skip_relay_cert_verify: self.insecure_skip_relay_cert_verify,
         }
@@ -256,6 +279,7 @@ where
             gc_policy: self.gc_policy,
             docs_store: self.docs_store,
             node_discovery: self.node_discovery,
+            provider_options: self.provider_options,
             #[cfg(any(test, feature = "test-utils"))]
             insecure_skip_relay_cert_verify: self.insecure_skip_relay_cert_verify,
         })
@@ -292,6 +316,50 @@ where
         self
     }
 
+    /// Sets the handler for provider defined get requests.
+    ///
+    /// By default all provider defined get requests are rejected.
+    pub fn custom_get_handler(mut self, handler: Arc<dyn CustomGetHandler>) -> Self {
+        self.provider_options.custom_get_handler = handler;
+        self
+    }
+
+    /// Sets the handler that decides whether to store data pushed by other nodes.
+    ///
+    /// By default all push requests are rejected.
+    pub fn push_handler(mut self, handler: Arc<dyn PushHandler>) -> Self {
+        self.provider_options.push_handler = handler;
+        self
+    }
+
+    /// Sets the maximum size in bytes of the data of a single push request.
+    ///
+    /// Larger push requests are rejected without asking the push handler. Defaults to
+    /// [`iroh_bytes::provider::DEFAULT_MAX_PUSH_SIZE`].
+    pub fn max_push_size(mut self, max_push_size: u64) -> Self {
+        self.provider_options.max_push_size = max_push_size;
+        self
+    }
+
+    /// Sets the handler that decides which blob requests are served to which nodes.
+    ///
+    /// By default all requests are served.
+    pub fn request_authorization_handler(
+        mut self,
+        handler: Arc<dyn RequestAuthorizationHandler>,
+    ) -> Self {
+        self.provider_options.authorization_handler = Some(handler);
+        self
+    }
+
+    /// Sets the limits for serving blobs to other nodes.
+    ///
+    /// By default there are no limits.
+    pub fn upload_limits(mut self, limits: UploadLimits) -> Self {
+        self.provider_options.limiter = UploadLimiter::new(limits);
+        self
+    }
+
     /// Binds the node service to a different socket.
     ///
     /// By default it binds to `127.0.0.1:11204`.
@@ -351,6 +419,14 @@ where
                 ]);
                 Some(Box::new(discovery))
             }
+            NodeDiscoveryConfig::DefaultWithLocalSwarm => {
+                let discovery = ConcurrentDiscovery::from_services(vec![
+                    Box::new(DnsDiscovery::n0_dns()),
+                    Box::new(PkarrPublisher::n0_dns(self.secret_key.clone())),
+                    Box::new(LocalSwarmDiscovery::new(self.secret_key.clone())?),
+                ]);
+                Some(Box::new(discovery))
+            }
         };
 
         let endpoint = MagicEndpoint::builder()
@@ -426,6 +502,7 @@ where
             rt: lp.clone(),
             sync,
             downloader,
+            provider_options: self.provider_options,
         });
         let task = {
             let gossip = gossip.clone();
@@ -685,13 +762,14 @@ async fn handle_connection<D: BaoStore>(
     sync: SyncEngine,
 ) -> Result<()> {
     match alpn.as_bytes() {
-        GOSSIP_ALPN => gossip.handle_connection(connecting.await?).await?,
-        SYNC_ALPN => sync.handle_connection(connecting).await?,
+        GOSSIP_ALPN | GOSSIP_ALPN_V0 => gossip.handle_connection(connecting.await?).await?,
+        SYNC_ALPN | SYNC_ALPN_V1 => sync.handle_connection(connecting).await?,
         alpn if alpn == iroh_bytes::protocol::ALPN => {
             iroh_bytes::provider::handle_connection(
                 connecting,
                 node.db.clone(),
                 node.callbacks.clone(),
+                node.provider_options.clone(),
                 node.rt.clone(),
             )
             .await
@@ -29,7 +29,7 @@ use live::{LiveActor, ToLiveActor};
 
 pub use self::live::SyncEvent;
 pub use self::state::{Origin, SyncReason};
-pub use iroh_sync::net::SYNC_ALPN;
+pub use iroh_sync::net::{SYNC_ALPN, SYNC_ALPN_V1};
 
 /// Capacity of the channel for the [`ToLiveActor`] messages.
 const ACTOR_CHANNEL_CAP: usize = 64;
@@ -222,7 +222,7 @@ impl SyncEngine {
         Box::pin(fut).into_stream().try_flatten()
     }
 
-    /// Handle an incoming iroh-sync connection.
+    /// Handle an incoming iroh-sync connection, with either [`SYNC_ALPN`] or [`SYNC_ALPN_V1`].
     pub async fn handle_connection(&self, conn: quinn::Connecting) -> anyhow::Result<()> {
         self.to_live_actor
             .send(ToLiveActor::HandleConnection { conn })
@@ -11,12 +11,16 @@ use iroh_bytes::HashAndFormat;
 use iroh_bytes::{store::EntryStatus, Hash};
 use iroh_gossip::{net::Gossip, proto::TopicId};
 use iroh_net::NodeId;
-use iroh_net::{key::PublicKey, MagicEndpoint, NodeAddr};
+use iroh_net::{
+    key::PublicKey,
+    magic_endpoint::{get_alpn, get_remote_node_id},
+    MagicEndpoint, NodeAddr,
+};
 use iroh_sync::{
     actor::{OpenOpts, SyncHandle},
     net::{
-        connect_and_sync, handle_connection, AbortReason, AcceptError, AcceptOutcome, ConnectError,
-        SyncFinished,
+        connect_and_sync, handle_stream, AbortReason, AcceptError, AcceptOutcome, ConnectError,
+        SyncFinished, SYNC_ALPN_V1,
     },
     AuthorHeads, ContentStatus, NamespaceId, SignedEntry,
 };
@@ -25,7 +29,7 @@ use tokio::{
     sync::{self, mpsc, oneshot},
     task::JoinSet,
 };
-use tracing::{debug, error, info, instrument, trace, warn, Instrument, Span};
+use tracing::{debug, error, error_span, info, instrument, trace, warn, Instrument, Span};
 
 use super::gossip::ToGossipActor;
 use super::state::{NamespaceStates, Origin, SyncReason};
@@ -83,6 +87,13 @@ pub enum ToLiveActor {
     HandleConnection {
         conn: quinn::Connecting,
     },
+    HandleSyncStream {
+        peer: PublicKey,
+        #[debug("quinn::SendStream")]
+        send: quinn::SendStream,
+        #[debug("quinn::RecvStream")]
+        recv: quinn::RecvStream,
+    },
     AcceptSyncRequest {
         namespace: NamespaceId,
         peer: PublicKey,
@@ -156,6 +167,8 @@ pub struct LiveActor<B: iroh_bytes::store::Store> {
     running_sync_connect: JoinSet<SyncConnectRes>,
     /// Running sync futures (from accept).
     running_sync_accept: JoinSet<SyncAcceptRes>,
+    /// Tasks accepting the sync streams of incoming connections.
+    running_accept_loops: JoinSet<()>,
     /// Running download futures.
     download_tasks: JoinSet<DownloadRes>,
     /// Content hashes which are wanted but not yet queued because no provider was found.
@@ -196,6 +209,7 @@ impl<B: iroh_bytes::store::Store> LiveActor<B> {
             gossip_actor_tx,
             running_sync_connect: Default::default(),
             running_sync_accept: Default::default(),
+            running_accept_loops: Default::default(),
             subscribers: Default::default(),
             download_tasks: Default::default(),
             state: Default::default(),
@@ -245,6 +259,10 @@ impl<B: iroh_bytes::store::Store> LiveActor<B> {
                     let res = res.context("running_sync_accept closed")?;
                     self.on_sync_via_accept_finished(res).await;
                 }
+                Some(res) = self.running_accept_loops.join_next(), if !self.running_accept_loops.is_empty() => {
+                    trace!(?i, "tick: running_accept_loops");
+                    res.context("running_accept_loops closed")?;
+                }
                 Some(res) = self.download_tasks.join_next(), if !self.download_tasks.is_empty() => {
                     trace!(?i, "tick: pending_downloads");
                     let (namespace, hash, res) = res.context("pending_downloads closed")?;
@@ -313,6 +331,9 @@ impl<B: iroh_bytes::store::Store> LiveActor<B> {
             ToLiveActor::HandleConnection { conn } => {
                 self.handle_connection(conn).await;
             }
+            ToLiveActor::HandleSyncStream { peer, send, recv } => {
+                self.handle_sync_stream(peer, send, recv);
+            }
             ToLiveActor::AcceptSyncRequest {
                 namespace,
                 peer,
@@ -716,8 +737,62 @@ impl<B: iroh_bytes::store::Store> LiveActor<B> {
         }
     }
 
+    /// Accepts the sync requests of an incoming connection.
+    ///
+    /// Each sync request arrives on its own stream, the streams are passed back to the actor until
+    /// the connection closes. Connections of peers using [`SYNC_ALPN_V1`] carry only one sync
+    /// request, on their first stream.
     #[instrument("accept", skip_all)]
-    pub async fn handle_connection(&mut self, conn: quinn::Connecting) {
+    pub async fn handle_connection(&mut self, mut conn: quinn::Connecting) {
+        debug!("incoming connection");
+        let to_actor_tx = self.sync_actor_tx.clone();
+        self.running_accept_loops.spawn(
+            async move {
+                let single_request = match get_alpn(&mut conn).await {
+                    Ok(alpn) => alpn.as_bytes() == SYNC_ALPN_V1,
+                    Err(err) => {
+                        debug!(?err, "invalid handshake");
+                        return;
+                    }
+                };
+                let conn = match conn.await {
+                    Ok(conn) => conn,
+                    Err(err) => {
+                        debug!(?err, "failed to establish connection");
+                        return;
+                    }
+                };
+                let peer = match get_remote_node_id(&conn) {
+                    Ok(peer) => peer,
+                    Err(err) => {
+                        debug!(?err, "failed to read remote node id");
+                        return;
+                    }
+                };
+                loop {
+                    let (send, recv) = match conn.accept_bi().await {
+                        Ok(streams) => streams,
+                        Err(err) => {
+                            debug!(peer = %peer.fmt_short(), ?err, "connection closed");
+                            return;
+                        }
+                    };
+                    let msg = ToLiveActor::HandleSyncStream { peer, send, recv };
+                    if to_actor_tx.send(msg).await.is_err() || single_request {
+                        return;
+                    }
+                }
+            }
+            .instrument(Span::current()),
+        );
+    }
+
+    fn handle_sync_stream(
+        &mut self,
+        peer: PublicKey,
+        send: quinn::SendStream,
+        recv: quinn::RecvStream,
+    ) {
         let to_actor_tx = self.sync_actor_tx.clone();
         let accept_request_cb = move |namespace, peer| {
             let to_actor_tx = to_actor_tx.clone();
@@ -743,10 +818,11 @@ impl<B: iroh_bytes::store::Store> LiveActor<B> {
             }
             .boxed()
         };
-        debug!("incoming connection");
         let sync = self.sync.clone();
-        self.running_sync_accept
-            .spawn(async move { handle_connection(sync, conn, accept_request_cb).await });
+        self.running_sync_accept.spawn(
+            async move { handle_stream(sync, peer, send, recv, accept_request_cb).await }
+                .instrument(error_span!("accept", peer = %peer.fmt_short())),
+        );
     }
 
     pub fn accept_sync_request(
@@ -11,6 +11,7 @@ use iroh::node::{self, Node};
 use rand::RngCore;
 
 use iroh_bytes::{
+    format::directory::Directory,
     hashseq::HashSeq,
     store::{EntryStatus, MapMut, Store},
     util::Tag,
@@ -191,6 +192,65 @@ async fn gc_hashseq_impl() -> Result<()> {
     Ok(())
 }
 
+/// Test gc for nested directories, which protect their subdirectories from deletion.
+#[tokio::test]
+async fn gc_dag_impl() -> Result<()> {
+    let _ = tracing_subscriber::fmt::try_init();
+    let (node, bao_store, evs) = gc_test_node().await;
+    let tt1 = bao_store
+        .import_bytes(create_test_data(1234), BlobFormat::Raw)
+        .await?;
+    let tt2 = bao_store
+        .import_bytes(create_test_data(5678), BlobFormat::Raw)
+        .await?;
+    let mut sub = Directory::default();
+    sub.push_file("b".to_string(), *tt2.hash());
+    let tts = sub.store(&bao_store).await?;
+    let mut dir = Directory::default();
+    dir.push_file("a".to_string(), *tt1.hash());
+    dir.push_dir("sub".to_string(), *tts.hash());
+    let ttr = dir.store(&bao_store).await?;
+    let h1 = *tt1.hash();
+    let h2 = *tt2.hash();
+    let hs = *tts.hash();
+    let hr = *ttr.hash();
+    drop(tt1);
+    drop(tt2);
+    drop(tts);
+
+    // there is a temp tag for the root directory, so the whole tree should be there
+    step(&evs).await;
+    assert_eq!(bao_store.entry_status(&h1).await?, EntryStatus::Complete);
+    assert_eq!(bao_store.entry_status(&h2).await?, EntryStatus::Complete);
+    assert_eq!(bao_store.entry_status(&hs).await?, EntryStatus::Complete);
+    assert_eq!(bao_store.entry_status(&hr).await?, EntryStatus::Complete);
+    assert_eq!(Directory::load(&bao_store, &hs).await?, sub);
+    assert_eq!(Directory::load(&bao_store, &hr).await?, dir);
+
+    // make a permanent tag for the subdirectory only. The root and its file should be gone.
+    let tag = Tag::from("test");
+    bao_store
+        .set_tag(tag.clone(), Some(HashAndFormat::dag(hs)))
+        .await?;
+    drop(ttr);
+    step(&evs).await;
+    assert_eq!(bao_store.entry_status(&h1).await?, EntryStatus::NotFound);
+    assert_eq!(bao_store.entry_status(&h2).await?, EntryStatus::Complete);
+    assert_eq!(bao_store.entry_status(&hs).await?, EntryStatus::Complete);
+    assert_eq!(bao_store.entry_status(&hr).await?, EntryStatus::NotFound);
+    assert_eq!(Directory::load(&bao_store, &hs).await?, sub);
+
+    // delete the permanent tag, everything should be gone
+    bao_store.set_tag(tag, None).await?;
+    step(&evs).await;
+    assert_eq!(bao_store.entry_status(&h2).await?, EntryStatus::NotFound);
+    assert_eq!(bao_store.entry_status(&hs).await?, EntryStatus::NotFound);
+
+    node.shutdown();
+    node.await?;
+    Ok(())
+}
+
 #[cfg(feature = "fs-store")]
 mod file {
     use super::*;
@@ -1,7 +1,12 @@
 use std::{
     collections::BTreeMap,
     net::SocketAddr,
+    num::NonZeroU64,
     ops::Range,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
     time::{Duration, Instant},
 };
 
@@ -11,24 +16,39 @@ use futures::FutureExt;
 use iroh::{
     dial::Options,
     node::{Builder, Event},
+    rpc_protocol::{BlobDownloadRequest, DownloadMode},
 };
-use iroh_net::{key::SecretKey, NodeId};
+use iroh_net::{key::SecretKey, NodeAddr, NodeId};
 use quic_rpc::transport::misc::DummyServerEndpoint;
 use rand::RngCore;
+use testdir::testdir;
 use tokio::sync::mpsc;
 
-use bao_tree::{blake3, ChunkNum, ChunkRanges};
+use bao_tree::{blake3, io::BaoContentItem, ChunkNum, ChunkRanges};
 use iroh_bytes::{
-    format::collection::Collection,
+    export::export_directory,
+    format::{collection::Collection, directory::Directory},
     get::{
+        db::get_to_db,
         fsm::ConnectedNext,
-        fsm::{self, DecodeError},
+        fsm::{
+            self, AtBlobHeaderNextError, AtConnectedCustomNextError, BlobContentNext, DecodeError,
+        },
+        request::get_availability,
         Stats,
     },
-    protocol::{GetRequest, RangeSpecSeq},
-    provider,
-    store::{MapMut, Store},
-    BlobFormat, Hash,
+    protocol::{
+        AvailabilityRequest, BlobAvailability, CustomGetRequest, GetRequest, PushRequest,
+        PushResponse, RangeSpecSeq,
+    },
+    provider::{
+        self, Authorization, CustomGetHandler, EventSender, PushDecision, PushHandler,
+        RequestAuthorizationHandler, UploadLimits,
+    },
+    push::push,
+    store::{BaoBlobSize, ExportMode, Map, MapEntry, MapMut, Store},
+    util::{progress::IgnoreProgressSender, SetTagOption},
+    BlobFormat, Hash, HashAndFormat, Tag,
 };
 
 fn test_node<D: Store>(db: D) -> Builder<D, DummyServerEndpoint> {
@@ -638,3 +658,549 @@ async fn test_collection_stat() {
     .expect("timeout")
     .expect("get failed");
 }
+
+/// Ask for the available ranges of a collection and its children.
+#[tokio::test]
+async fn test_availability_request() {
+    let child1 = make_test_data(123456);
+    let child2 = make_test_data(345678);
+    let (db, hash) = create_test_db([("a", &child1), ("b", &child2)]);
+    let node = test_node(db.clone()).spawn().await.unwrap();
+    let addrs = node.local_endpoint_addresses().await.unwrap();
+    let peer_id = node.node_id();
+    tokio::time::timeout(Duration::from_secs(10), async move {
+        let connection = iroh::dial::dial(get_options(peer_id, addrs)).await?;
+        let request = AvailabilityRequest::hash_seq(hash);
+        let response = get_availability(&connection, request).await?;
+        // the collection, its metadata blob and the two children
+        assert_eq!(response.blobs.len(), 4);
+        assert_eq!(response.blobs[0].hash, hash);
+        assert!(response.blobs.iter().all(|blob| blob.is_complete()));
+        let sizes = response
+            .blobs
+            .iter()
+            .skip(2)
+            .map(|blob| blob.size)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            sizes,
+            vec![
+                Some(BaoBlobSize::Verified(child1.len() as u64)),
+                Some(BaoBlobSize::Verified(child2.len() as u64))
+            ]
+        );
+
+        // a blob the node does not have
+        let missing = blake3::hash(b"missing").into();
+        let response = get_availability(&connection, AvailabilityRequest::single(missing)).await?;
+        assert_eq!(response.blobs, vec![BlobAvailability::missing(missing)]);
+        anyhow::Ok(())
+    })
+    .await
+    .expect("timeout")
+    .expect("availability request failed");
+}
+
+/// Custom get handler that answers every query with the blob named in the query.
+#[derive(Debug)]
+struct NamedBlobHandler(BTreeMap<Bytes, Hash>);
+
+impl CustomGetHandler for NamedBlobHandler {
+    fn handle(&self, data: Bytes) -> futures::future::BoxFuture<'static, Result<GetRequest>> {
+        let hash = self.0.get(&data).copied();
+        async move {
+            let hash = hash.context("unknown name")?;
+            Ok(GetRequest::single(hash))
+        }
+        .boxed()
+    }
+}
+
+/// Let the provider pick the request based on a query.
+#[tokio::test]
+async fn test_custom_get_request() {
+    let expected = make_test_data(1024 * 64 + 1234);
+    let (db, hashes) = iroh_bytes::store::readonly_mem::Store::new([("test", &expected)]);
+    let hash = Hash::from(*hashes.values().next().unwrap());
+    let handler = NamedBlobHandler([(Bytes::from_static(b"latest"), hash)].into());
+    let node = test_node(db)
+        .custom_get_handler(Arc::new(handler))
+        .spawn()
+        .await
+        .unwrap();
+    let addrs = node.local_endpoint_addresses().await.unwrap();
+    let peer_id = node.node_id();
+    tokio::time::timeout(Duration::from_secs(10), async move {
+        let connection = iroh::dial::dial(get_options(peer_id, addrs)).await?;
+        let response = fsm::start_custom(connection.clone(), CustomGetRequest::new(&b"latest"[..]));
+        let connected = response.next().await?;
+        let (request, next) = connected.next().await?;
+        assert_eq!(request, GetRequest::single(hash));
+        let ConnectedNext::StartRoot(start) = next else {
+            panic!("expected start root");
+        };
+        let header = start.next();
+        let (_, actual) = header.concatenate_into_vec().await?;
+        assert_eq!(actual, expected);
+
+        // the handler can not answer this query
+        let response = fsm::start_custom(connection, CustomGetRequest::new(&b"unknown"[..]));
+        let connected = response.next().await?;
+        let res = connected.next().await;
+        assert!(matches!(res, Err(AtConnectedCustomNextError::NotFound)));
+        anyhow::Ok(())
+    })
+    .await
+    .expect("timeout")
+    .expect("get failed");
+}
+
+/// Authorization handler that denies one blob and only serves the start of another one.
+#[derive(Debug)]
+struct TestAuthorizationHandler {
+    denied: Hash,
+    narrowed: Hash,
+}
+
+impl RequestAuthorizationHandler for TestAuthorizationHandler {
+    fn authorize(
+        &self,
+        _node_id: NodeId,
+        request: GetRequest,
+    ) -> futures::future::BoxFuture<'static, Result<Authorization>> {
+        let authorization = if request.hash == self.denied {
+            Authorization::Deny
+        } else if request.hash == self.narrowed {
+            Authorization::Narrow(RangeSpecSeq::from_ranges([ChunkRanges::from(
+                ..ChunkNum(16),
+            )]))
+        } else {
+            Authorization::Allow
+        };
+        async move { Ok(authorization) }.boxed()
+    }
+}
+
+/// Check that the provider only serves what the authorization handler allows.
+#[tokio::test]
+async fn test_request_authorization() {
+    let public = make_test_data(1024 * 64 + 1234);
+    let secret = make_test_data(1234);
+    let (db, hashes) = iroh_bytes::store::readonly_mem::Store::new([
+        ("public", public.as_slice()),
+        ("secret", secret.as_slice()),
+    ]);
+    let public_hash = Hash::from(hashes["public"]);
+    let secret_hash = Hash::from(hashes["secret"]);
+    let handler = TestAuthorizationHandler {
+        denied: secret_hash,
+        narrowed: public_hash,
+    };
+    let node = test_node(db)
+        .request_authorization_handler(Arc::new(handler))
+        .spawn()
+        .await
+        .unwrap();
+    let addrs = node.local_endpoint_addresses().await.unwrap();
+    let peer_id = node.node_id();
+    tokio::time::timeout(Duration::from_secs(10), async move {
+        let connection = iroh::dial::dial(get_options(peer_id, addrs)).await?;
+
+        // the denied blob looks like it does not exist
+        let response = fsm::start(connection.clone(), GetRequest::single(secret_hash));
+        let connected = response.next().await?;
+        let ConnectedNext::StartRoot(start) = connected.next().await? else {
+            panic!("expected start root");
+        };
+        let res = start.next().next().await;
+        assert!(matches!(res, Err(AtBlobHeaderNextError::NotFound)));
+
+        // the narrowed blob is only sent up to the allowed ranges
+        let response = fsm::start(connection.clone(), GetRequest::single(public_hash));
+        let connected = response.next().await?;
+        let ConnectedNext::StartRoot(start) = connected.next().await? else {
+            panic!("expected start root");
+        };
+        let (mut content, size) = start.next().next().await?;
+        assert_eq!(size, public.len() as u64);
+        let mut received = Vec::new();
+        loop {
+            match content.next().await {
+                BlobContentNext::More((next, Ok(item))) => {
+                    if let BaoContentItem::Leaf(leaf) = item {
+                        assert_eq!(leaf.offset, received.len() as u64);
+                        received.extend_from_slice(&leaf.data);
+                    }
+                    content = next;
+                }
+                BlobContentNext::More((_, Err(_))) => break,
+                BlobContentNext::Done(_) => panic!("expected the transfer to be cut short"),
+            }
+        }
+        assert_eq!(received, &public[..1024 * 16]);
+
+        // availability only reports what may be fetched
+        let response =
+            get_availability(&connection, AvailabilityRequest::single(public_hash)).await?;
+        assert_eq!(
+            response.blobs[0].ranges.to_chunk_ranges(),
+            ChunkRanges::from(..ChunkNum(16))
+        );
+        let response =
+            get_availability(&connection, AvailabilityRequest::single(secret_hash)).await?;
+        assert_eq!(response.blobs, vec![BlobAvailability::missing(secret_hash)]);
+        anyhow::Ok(())
+    })
+    .await
+    .expect("timeout")
+    .expect("get failed");
+}
+
+/// Check that transfers are slowed down to the upload limit.
+#[tokio::test]
+async fn test_upload_limits() {
+    let expected = make_test_data(1024 * 96);
+    let (db, hashes) = iroh_bytes::store::readonly_mem::Store::new([("test", &expected)]);
+    let hash = Hash::from(*hashes.values().next().unwrap());
+    let limits = UploadLimits {
+        node_bytes_per_sec: NonZeroU64::new(1024 * 64),
+        ..Default::default()
+    };
+    let node = test_node(db).upload_limits(limits).spawn().await.unwrap();
+    let (events_sender, mut events_recv) = mpsc::unbounded_channel();
+    node.subscribe(move |event| {
+        let events_sender = events_sender.clone();
+        async move {
+            events_sender.send(event).ok();
+        }
+        .boxed()
+    })
+    .await
+    .unwrap();
+    let addrs = node.local_endpoint_addresses().await.unwrap();
+    let peer_id = node.node_id();
+    tokio::time::timeout(Duration::from_secs(10), async move {
+        let connection = iroh::dial::dial(get_options(peer_id, addrs)).await?;
+        let t0 = Instant::now();
+        let response = fsm::start(connection, GetRequest::single(hash));
+        let connected = response.next().await?;
+        let ConnectedNext::StartRoot(start) = connected.next().await? else {
+            panic!("expected start root");
+        };
+        let (_, actual) = start.next().concatenate_into_vec().await?;
+        assert_eq!(actual, expected);
+        // one second worth of data can be sent right away, the rest is throttled
+        assert!(t0.elapsed() >= Duration::from_millis(400));
+
+        let mut throttled = false;
+        while let Some(event) = events_recv.recv().await {
+            match event {
+                Event::ByteProvide(provider::Event::TransferThrottled { .. }) => throttled = true,
+                Event::ByteProvide(provider::Event::TransferCompleted { stats, .. }) => {
+                    assert!(throttled);
+                    assert!(stats.throttled > Duration::ZERO);
+                    break;
+                }
+                _ => {}
+            }
+        }
+        anyhow::Ok(())
+    })
+    .await
+    .expect("timeout")
+    .expect("get failed");
+}
+
+/// Push handler that stores everything up to a size limit under a fixed tag.
+#[derive(Debug)]
+struct QuotaPushHandler(u64);
+
+impl PushHandler for QuotaPushHandler {
+    fn accept(
+        &self,
+        _node_id: NodeId,
+        request: PushRequest,
+    ) -> futures::future::BoxFuture<'static, Result<PushDecision>> {
+        let decision = if request.size <= self.0 {
+            PushDecision::Accept {
+                tag: Some(SetTagOption::Named(Tag::from("pushed"))),
+            }
+        } else {
+            PushDecision::Reject
+        };
+        async move { Ok(decision) }.boxed()
+    }
+}
+
+#[derive(Debug, Clone)]
+struct NoEvents;
+
+impl EventSender for NoEvents {
+    fn send(&self, _event: provider::Event) -> futures::future::BoxFuture<()> {
+        async {}.boxed()
+    }
+}
+
+/// Push a blob to a node, which gets it over the same connection.
+#[tokio::test]
+async fn test_push_request() {
+    let data = make_test_data(1024 * 64 + 1234);
+    let (sender_db, hashes) = iroh_bytes::store::readonly_mem::Store::new([("test", &data)]);
+    let hash = Hash::from(*hashes.values().next().unwrap());
+    let receiver_db = iroh_bytes::store::mem::Store::new();
+    let node = test_node(receiver_db.clone())
+        .push_handler(Arc::new(QuotaPushHandler(1024 * 1024)))
+        .max_push_size(1024 * 512)
+        .spawn()
+        .await
+        .unwrap();
+    let addrs = node.local_endpoint_addresses().await.unwrap();
+    let peer_id = node.node_id();
+    tokio::time::timeout(Duration::from_secs(10), async move {
+        let connection = iroh::dial::dial(get_options(peer_id, addrs)).await?;
+        let size = data.len() as u64;
+
+        // too large for the quota
+        let request = PushRequest::single(hash, 1024 * 1024 * 1024);
+        let response = push(connection.clone(), sender_db.clone(), NoEvents, request).await?;
+        assert_eq!(response, PushResponse::Rejected);
+        assert!(receiver_db.get(&hash).await?.is_none());
+
+        // within the quota, but larger than the maximum push size
+        let request = PushRequest::single(hash, 1024 * 768);
+        let response = push(connection.clone(), sender_db.clone(), NoEvents, request).await?;
+        assert_eq!(response, PushResponse::Rejected);
+
+        // the data is larger than claimed
+        let request = PushRequest::single(hash, size - 1);
+        let response = push(connection.clone(), sender_db.clone(), NoEvents, request).await?;
+        assert_eq!(response, PushResponse::Failed);
+        assert!(!receiver_db
+            .get(&hash)
+            .await?
+            .is_some_and(|entry| entry.is_complete()));
+
+        let request = PushRequest::single(hash, size);
+        let response = push(connection, sender_db, NoEvents, request).await?;
+        assert_eq!(
+            response,
+            PushResponse::Stored {
+                tag: Some(Tag::from("pushed"))
+            }
+        );
+        let entry = receiver_db
+            .get(&hash)
+            .await?
+            .context("pushed blob missing")?;
+        assert!(entry.is_complete());
+        assert_eq!(entry.size(), BaoBlobSize::Verified(size));
+        anyhow::Ok(())
+    })
+    .await
+    .expect("timeout")
+    .expect("push failed");
+}
+
+/// Get a nested directory, level by level, and export it.
+#[tokio::test]
+async fn test_dag_get() {
+    let a = make_test_data(1234);
+    let b = make_test_data(1024 * 64 + 1234);
+    let sender_db = iroh_bytes::store::mem::Store::new();
+    let ta = sender_db
+        .import_bytes(a.clone().into(), BlobFormat::Raw)
+        .await
+        .unwrap();
+    let tb = sender_db
+        .import_bytes(b.clone().into(), BlobFormat::Raw)
+        .await
+        .unwrap();
+    let mut sub = Directory::default();
+    sub.push_file("b".to_string(), *tb.hash());
+    let tsub = sub.store(&sender_db).await.unwrap();
+    let mut dir = Directory::default();
+    dir.push_file("a".to_string(), *ta.hash());
+    dir.push_dir("sub".to_string(), *tsub.hash());
+    let tdir = dir.store(&sender_db).await.unwrap();
+    let root = HashAndFormat::dag(*tdir.hash());
+    let node = test_node(sender_db.clone()).spawn().await.unwrap();
+    let addrs = node.local_endpoint_addresses().await.unwrap();
+    let peer_id = node.node_id();
+    let receiver_db = iroh_bytes::store::mem::Store::new();
+    let outpath = testdir!().join("export");
+    tokio::time::timeout(Duration::from_secs(10), async move {
+        let connection = iroh::dial::dial(get_options(peer_id, addrs)).await?;
+        let get_conn = || async move { anyhow::Ok(connection) };
+        let stats = get_to_db(
+            &receiver_db,
+            get_conn,
+            &root,
+            IgnoreProgressSender::default(),
+        )
+        .await?;
+        assert!(stats.bytes_read > (a.len() + b.len()) as u64);
+        assert_eq!(Directory::load(&receiver_db, &root.hash).await?, dir);
+        assert_eq!(Directory::load(&receiver_db, tsub.hash()).await?, sub);
+
+        // everything is there, so getting again does not need a connection
+        let get_conn = || async move { Err(anyhow!("should not connect")) };
+        get_to_db(
+            &receiver_db,
+            get_conn,
+            &root,
+            IgnoreProgressSender::default(),
+        )
+        .await?;
+
+        export_directory(
+            &receiver_db,
+            root.hash,
+            outpath.clone(),
+            ExportMode::Copy,
+            IgnoreProgressSender::default(),
+        )
+        .await?;
+        assert_eq!(std::fs::read(outpath.join("a"))?, a);
+        assert_eq!(std::fs::read(outpath.join("sub").join("b"))?, b);
+        anyhow::Ok(())
+    })
+    .await
+    .expect("timeout")
+    .expect("get failed");
+}
+
+/// Download a collection of small blobs from two throttled providers, which both serve a part.
+#[tokio::test]
+async fn test_swarm_download() {
+    let _guard = iroh_test::logging::setup();
+    let blobs = (0..8)
+        .map(|i| (format!("blob{i}"), make_test_data(1024 * 32 + i * 1024)))
+        .collect::<Vec<_>>();
+    let limits = UploadLimits {
+        node_bytes_per_sec: NonZeroU64::new(1024 * 64),
+        ..Default::default()
+    };
+    let mut providers = Vec::new();
+    let mut served = Vec::new();
+    let mut addrs = Vec::new();
+    let mut hash = None;
+    for _ in 0..2 {
+        let (db, collection_hash) = create_test_db(blobs.clone());
+        hash = Some(collection_hash);
+        let node = test_node(db).upload_limits(limits).spawn().await.unwrap();
+        let bytes = Arc::new(AtomicU64::new(0));
+        let bytes2 = bytes.clone();
+        node.subscribe(move |event| {
+            if let Event::ByteProvide(provider::Event::TransferCompleted { stats, .. }) = event {
+                bytes2.fetch_add(stats.send.total().size, Ordering::SeqCst);
+            }
+            async {}.boxed()
+        })
+        .await
+        .unwrap();
+        let node_addr = NodeAddr::from_parts(
+            node.node_id(),
+            None,
+            node.local_endpoint_addresses().await.unwrap(),
+        );
+        addrs.push(node_addr);
+        served.push(bytes);
+        providers.push(node);
+    }
+    let hash = hash.unwrap();
+
+    let node = test_node(iroh_bytes::store::mem::Store::new())
+        .spawn()
+        .await
+        .unwrap();
+    let request = BlobDownloadRequest {
+        hash,
+        format: BlobFormat::HashSeq,
+        nodes: addrs,
+        tag: SetTagOption::Auto,
+        mode: DownloadMode::Queued,
+    };
+    tokio::time::timeout(Duration::from_secs(30), async {
+        node.client().blobs.download(request).await?.finish().await
+    })
+    .await
+    .expect("timeout")
+    .expect("download failed");
+
+    for (name, data) in &blobs {
+        let expected = Hash::new(data);
+        let actual = node.client().blobs.read_to_bytes(expected).await.unwrap();
+        assert_eq!(&actual, data, "wrong data for {name}");
+    }
+    for (i, bytes) in served.iter().enumerate() {
+        let bytes = bytes.load(Ordering::SeqCst);
+        assert!(bytes > 0, "provider {i} did not serve any data");
+    }
+}
+
+/// Download a collection from a single provider, which must send every byte only once.
+#[tokio::test]
+async fn test_swarm_download_no_duplicate_data() {
+    let _guard = iroh_test::logging::setup();
+    // sizes which are not a multiple of the chunk group size
+    let blobs = (0..8)
+        .map(|i| (format!("blob{i}"), make_test_data(1024 * 40 + i * 1024 + 7)))
+        .collect::<Vec<_>>();
+    let total: u64 = blobs.iter().map(|(_, data)| data.len() as u64).sum();
+    let (db, hash) = create_test_db(blobs.clone());
+    let provider = test_node(db).spawn().await.unwrap();
+    let served = Arc::new(AtomicU64::new(0));
+    let served2 = served.clone();
+    provider
+        .subscribe(move |event| {
+            if let Event::ByteProvide(provider::Event::TransferCompleted { stats, .. }) = event {
+                served2.fetch_add(stats.send.total().size, Ordering::SeqCst);
+            }
+            async {}.boxed()
+        })
+        .await
+        .unwrap();
+    let addr = NodeAddr::from_parts(
+        provider.node_id(),
+        None,
+        provider.local_endpoint_addresses().await.unwrap(),
+    );
+
+    let node = test_node(iroh_bytes::store::mem::Store::new())
+        .spawn()
+        .await
+        .unwrap();
+    let request = BlobDownloadRequest {
+        hash,
+        format: BlobFormat::HashSeq,
+        nodes: vec![addr],
+        tag: SetTagOption::Auto,
+        mode: DownloadMode::Queued,
+    };
+    tokio::time::timeout(Duration::from_secs(30), async {
+        node.client().blobs.download(request).await?.finish().await
+    })
+    .await
+    .expect("timeout")
+    .expect("download failed");
+
+    // the provider reports completed transfers asynchronously
+    let served = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            let served = served.load(Ordering::SeqCst);
+            if served >= total {
+                break served;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("provider did not report the transfers");
+    // the hash seq, size headers and hashes of the encoding are well below 1 KiB per blob,
+    // while fetching any chunk group twice would cost at least 8 KiB
+    let overhead = 1024 * blobs.len() as u64;
+    assert!(
+        served <= total + overhead,
+        "served {served} bytes for {total} bytes of data"
+    );
+}
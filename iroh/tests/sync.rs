@@ -13,7 +13,11 @@ use iroh::{
     node::{Builder, Node},
     rpc_protocol::ShareMode,
 };
-use iroh_net::key::{PublicKey, SecretKey};
+use iroh_net::{
+    key::{PublicKey, SecretKey},
+    pool::PoolEvent,
+    MagicEndpoint,
+};
 use quic_rpc::transport::misc::DummyServerEndpoint;
 use rand::{CryptoRng, Rng, SeedableRng};
 use tracing::{debug, error_span, info, Instrument};
@@ -22,8 +26,10 @@ use tracing_subscriber::{prelude::*, EnvFilter};
 use iroh_bytes::Hash;
 use iroh_net::relay::RelayMode;
 use iroh_sync::{
+    actor::{OpenOpts, SyncHandle},
+    net::{connect_and_sync, handle_connection, AcceptOutcome, SYNC_ALPN, SYNC_ALPN_V1},
     store::{DownloadPolicy, FilterKind, Query},
-    AuthorId, ContentStatus,
+    Author, AuthorId, Capability, ContentStatus, NamespaceSecret,
 };
 
 const TIMEOUT: Duration = Duration::from_secs(60);
@@ -120,6 +126,103 @@ async fn sync_simple() -> Result<()> {
     Ok(())
 }
 
+/// Documents can be synced from peers which only speak version 1 of the sync protocol, and from
+/// peers speaking version 2, where consecutive syncs share a single pooled connection.
+#[tokio::test]
+async fn sync_alpn_versions() -> Result<()> {
+    setup_logging();
+    let mut rng = test_rng(b"sync_alpn_versions");
+
+    // A peer speaking version 2: a regular node.
+    let node = spawn_node(0, &mut rng).await?;
+    let client = node.client();
+    let author = client.authors.create().await?;
+    let doc = client.docs.create().await?;
+    doc.set_bytes(author, b"v2".to_vec(), b"hello".to_vec())
+        .await?;
+    let v2_capability = doc.share(ShareMode::Write).await?.capability;
+    let v2_addr = node.my_addr().await?;
+
+    // A peer speaking only version 1.
+    let v1_sync = SyncHandle::spawn(iroh_sync::store::Store::memory(), None, "v1".into());
+    let v1_capability = Capability::Write(NamespaceSecret::new(&mut rng));
+    let v1_namespace = v1_sync.import_namespace(v1_capability.clone()).await?;
+    v1_sync
+        .open(v1_namespace, OpenOpts::default().sync())
+        .await?;
+    let v1_author = v1_sync.import_author(Author::new(&mut rng)).await?;
+    let v1_content = Hash::new(b"hello");
+    v1_sync
+        .insert_local(v1_namespace, v1_author, "v1".into(), v1_content, 5)
+        .await?;
+    let v1_endpoint = MagicEndpoint::builder()
+        .secret_key(SecretKey::generate_with_rng(&mut rng))
+        .alpns(vec![SYNC_ALPN_V1.to_vec()])
+        .relay_mode(RelayMode::Disabled)
+        .bind(0)
+        .await?;
+    let v1_addr = v1_endpoint.my_addr().await?;
+    let v1_server = tokio::task::spawn({
+        let endpoint = v1_endpoint.clone();
+        let sync = v1_sync.clone();
+        async move {
+            while let Some(connecting) = endpoint.accept().await {
+                let sync = sync.clone();
+                tokio::task::spawn(async move {
+                    let res =
+                        handle_connection(sync, connecting, |_, _| async { AcceptOutcome::Allow })
+                            .await;
+                    debug!(?res, "v1 sync finished");
+                });
+            }
+        }
+    });
+
+    // The syncing peer.
+    let sync = SyncHandle::spawn(iroh_sync::store::Store::memory(), None, "alice".into());
+    let endpoint = MagicEndpoint::builder()
+        .secret_key(SecretKey::generate_with_rng(&mut rng))
+        .relay_mode(RelayMode::Disabled)
+        .bind(0)
+        .await?;
+    let mut pool_events = endpoint.connection_pool().subscribe();
+
+    let namespace = sync.import_namespace(v1_capability).await?;
+    sync.open(namespace, OpenOpts::default().sync()).await?;
+    let finished = connect_and_sync(&endpoint, &sync, namespace, v1_addr.clone()).await?;
+    assert_eq!(finished.outcome.num_recv, 1);
+    let entry = sync
+        .get_exact(namespace, v1_author, "v1".into(), false)
+        .await?
+        .context("entry from the v1 peer not synced")?;
+    assert_eq!(entry.content_hash(), v1_content);
+
+    let namespace = sync.import_namespace(v2_capability).await?;
+    sync.open(namespace, OpenOpts::default().sync()).await?;
+    let finished = connect_and_sync(&endpoint, &sync, namespace, v2_addr.clone()).await?;
+    assert_eq!(finished.outcome.num_recv, 1);
+    sync.get_exact(namespace, author, "v2".into(), false)
+        .await?
+        .context("entry from the v2 peer not synced")?;
+    // Syncing again reuses the pooled connection.
+    let finished = connect_and_sync(&endpoint, &sync, namespace, v2_addr.clone()).await?;
+    assert_eq!(finished.outcome.num_recv, 0);
+
+    // Only the connection to the v2 peer was pooled, and it was dialed only once.
+    let mut connected = Vec::new();
+    while let Ok(event) = pool_events.try_recv() {
+        if let PoolEvent::Connected { node_id, alpn } = event {
+            connected.push((node_id, alpn));
+        }
+    }
+    assert_eq!(connected, vec![(v2_addr.node_id, SYNC_ALPN.to_vec())]);
+    assert_eq!(endpoint.connection_pool().len(), 1);
+
+    v1_server.abort();
+    node.shutdown();
+    Ok(())
+}
+
 /// Test subscribing to replica events (without sync)
 #[tokio::test]
 async fn sync_subscribe_no_sync() -> Result<()> {